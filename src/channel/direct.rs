@@ -1,8 +1,10 @@
 use super::ChannelStream;
+use crate::rmux::{redact_addr, RmuxError};
 
 use std::net::Shutdown;
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 
 struct DirectChannelStream {
@@ -30,15 +32,38 @@ impl ChannelStream for DirectChannelStream {
     }
 }
 
+// like TcpStream::connect, with a lookup failure returned as RmuxError::DnsFailure
+async fn resolve_and_connect(addr: &str) -> Result<TcpStream, std::io::Error> {
+    let addrs = match tokio::net::lookup_host(addr).await {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            debug!("failed to resolve {}:{}", redact_addr(addr), e);
+            return Err(RmuxError::DnsFailure.into());
+        }
+    };
+    let mut last_err = None;
+    for a in addrs {
+        match TcpStream::connect(&a).await {
+            Ok(conn) => return Ok(conn),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| RmuxError::DnsFailure.into()))
+}
+
+pub async fn connect_direct(addr: &str) -> Result<TcpStream, std::io::Error> {
+    let conn = resolve_and_connect(addr);
+    let dur = std::time::Duration::from_secs(3);
+    tokio::time::timeout(dur, conn).await?
+}
+
 pub async fn get_direct_stream(
     addr: String,
+    initial_data: Vec<u8>,
 ) -> Result<Box<dyn ChannelStream + Send>, std::io::Error> {
-    let conn = TcpStream::connect(&addr);
-    let dur = std::time::Duration::from_secs(3);
-    let s = tokio::time::timeout(dur, conn).await?;
-
-    match s {
-        Ok(c) => Ok(Box::new(DirectChannelStream::new(c))),
-        Err(e) => Err(e),
+    let mut c = connect_direct(addr.as_str()).await?;
+    if !initial_data.is_empty() {
+        c.write_all(&initial_data[..]).await?;
     }
+    Ok(Box::new(DirectChannelStream::new(c)))
 }
@@ -3,10 +3,15 @@ mod rmux;
 mod routine;
 //mod ws;
 
+use crate::rmux::FinReason;
+use std::future::Future;
+use std::pin::Pin;
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
 
-pub use self::routine::routine_channels;
+pub use self::direct::connect_direct;
+pub(crate) use self::rmux::init_rmux_client;
+pub use self::routine::{routine_channels, DEFAULT_ROUTINE_INTERVAL_SECS};
 
 pub trait ChannelStream {
     fn split(
@@ -16,16 +21,22 @@ pub trait ChannelStream {
         Box<dyn AsyncWrite + Send + Unpin + '_>,
     );
     fn close(&mut self) -> std::io::Result<()>;
+    // resolves once the target is connected, or with why it couldn't be; streams connected
+    // before they're returned resolve right away
+    fn connect_result(&self) -> Pin<Box<dyn Future<Output = Result<(), FinReason>> + Send>> {
+        Box::pin(futures::future::ready(Ok(())))
+    }
 }
 
 pub async fn get_channel_stream(
     channel: String,
     addr: String,
+    initial_data: Vec<u8>,
 ) -> Result<Box<dyn ChannelStream + Send>, std::io::Error> {
     //irect::get_direct_stream(addr).await
     if channel == "direct" {
-        direct::get_direct_stream(addr).await
+        direct::get_direct_stream(addr, initial_data).await
     } else {
-        rmux::get_rmux_stream(channel.as_str(), addr).await
+        rmux::get_rmux_stream(channel.as_str(), addr, initial_data).await
     }
 }
@@ -2,10 +2,27 @@ use super::ChannelStream;
 use crate::config::ChannelConfig;
 
 use crate::rmux::{
-    create_stream, new_auth_event, process_rmux_session, read_encrypt_event, write_encrypt_event,
-    AuthRequest, AuthResponse, CryptoContext, MuxContext,
+    create_stream_with_data, decode_auth, new_auth_event, process_rmux_session, read_encrypt_event,
+    record_auth_failure, set_channel_auth_only_frames, set_channel_conn_pool,
+    set_channel_data_quantum, set_channel_dial_limit, set_channel_dial_retry,
+    set_channel_initial_send_credit, set_channel_max_sessions, set_channel_max_stream_lifetime,
+    set_channel_multipath, set_channel_orphan_data_policy, set_channel_password,
+    set_channel_quality_thresholds, set_channel_saturation_thresholds, set_channel_self_addrs,
+    set_channel_send_dwell_limit, set_channel_stream_proto, set_channel_stream_wait,
+    set_channel_udp_idle_timeout, set_channel_unknown_flags_policy, set_channel_write_coalescing,
+    start_establishing, write_encrypt_event, AuthRequest, AuthResponse, CryptoContext, MuxContext,
+    OrphanDataPolicy, QualityThresholds, RmuxError, SaturationThresholds, UnknownFlagsPolicy,
+    WriteBatching, DEFAULT_CONN_POOL_IDLE_SECS, DEFAULT_DIAL_RETRY_DELAY_MS,
+    DEFAULT_DIAL_RETRY_MAX_MS, DEFAULT_MAX_ORPHAN_DATA_FRAMES, DEFAULT_MAX_PENDING_STREAMS,
+    DEFAULT_MAX_WAITING_STREAMS, DEFAULT_STREAM_WINDOW, DEFAULT_UDP_IDLE_SECS,
+    DEFAULT_WRITE_BATCH_FRAMES,
+};
+#[cfg(feature = "quic")]
+use crate::utils::quic_connect;
+use crate::utils::{
+    make_io_error, tls_client_config, tls_connect, AsyncTcpStream, AsyncTokioIO, WebsocketReader,
+    WebsocketWriter,
 };
-use crate::utils::{make_io_error, AsyncTcpStream, AsyncTokioIO, WebsocketReader, WebsocketWriter};
 //use crate::utils::make_io_error;
 use async_tls::TlsConnector;
 use bytes::BytesMut;
@@ -26,31 +43,43 @@ where
     R: AsyncRead + Unpin + Sized,
     W: AsyncWrite + Unpin + Sized,
 {
+    let establishing = start_establishing().await;
     let sid = 0 as u32;
     let auth = AuthRequest {
         //key: String::from(key),
         method: String::from(config.cipher.method.as_str()),
     };
-    let ev = new_auth_event(sid, &auth);
     let key = String::from(config.cipher.key.as_str());
     let method = String::from(config.cipher.method.as_str());
-    let mut rctx = CryptoContext::new(method.as_str(), key.as_str(), 0);
-    let mut wctx = CryptoContext::new(method.as_str(), key.as_str(), 0);
+    let channel = config.name.as_str();
+    set_channel_password(channel, config.cipher.password.as_deref().unwrap_or(""));
+    set_channel_auth_only_frames(channel, config.cipher.auth_only.unwrap_or(false));
+    let ev = new_auth_event(channel, sid, &auth);
+    let mut rctx = CryptoContext::new_for_channel(channel, method.as_str(), key.as_str(), 0);
+    let mut wctx = CryptoContext::new_for_channel(channel, method.as_str(), key.as_str(), 0);
     write_encrypt_event(&mut wctx, wi, ev).await?;
     let mut recv_buf = BytesMut::new();
     let recv_ev = match read_encrypt_event(&mut rctx, ri, &mut recv_buf).await {
-        Err(e) => return Err(make_io_error(e.description())),
+        Err(e) => {
+            if RmuxError::from_io(&e) == Some(RmuxError::AuthFailure) {
+                record_auth_failure(channel, 0, None);
+            }
+            return Err(make_io_error(e.description()));
+        }
         Ok(None) => return Err(make_io_error("can NOT read first auth envent.")),
         Ok(Some(ev)) => ev,
     };
-    let decoded: AuthResponse = bincode::deserialize(&recv_ev.body[..]).unwrap();
+    let (decoded, peer): (AuthResponse, _) = match decode_auth(&recv_ev.body[..]) {
+        Ok(m) => m,
+        Err(_) => return Err(make_io_error("invalid auth response.")),
+    };
     if !decoded.success {
         //let _ = c.shutdown(std::net::Shutdown::Both);
         return Err(std::io::Error::from(ErrorKind::ConnectionRefused));
     }
-    let rctx = CryptoContext::new(method.as_str(), key.as_str(), decoded.rand);
-    let wctx = CryptoContext::new(method.as_str(), key.as_str(), decoded.rand);
-    let ctx = MuxContext::new(
+    let rctx = CryptoContext::new_for_channel(channel, method.as_str(), key.as_str(), decoded.rand);
+    let wctx = CryptoContext::new_for_channel(channel, method.as_str(), key.as_str(), decoded.rand);
+    let mut ctx = MuxContext::new(
         config.name.as_str(),
         session_id,
         rctx,
@@ -58,6 +87,117 @@ where
         config.max_alive_mins as u64 * 60,
         &mut recv_buf,
     );
+    ctx.set_peer_hello(&peer);
+    ctx.set_establish_permit(establishing);
+    ctx.set_ping_idle_secs(config.ping_interval_sec);
+    ctx.set_max_alive_bytes(config.max_alive_bytes.unwrap_or(0));
+    if let Some(ratio) = config.rotation_jitter_ratio {
+        ctx.set_rotation_jitter_ratio(ratio);
+    }
+    ctx.set_stream_recv_window(config.stream_recv_window.unwrap_or(DEFAULT_STREAM_WINDOW));
+    ctx.set_weight(config.weight.unwrap_or(1));
+    ctx.set_stream_keepalive_secs(config.stream_keepalive_secs.unwrap_or(0));
+    ctx.set_write_batching(
+        match (config.write_batch_bytes, config.write_batch_frames) {
+            (Some(n), _) => WriteBatching::Bytes(n as usize),
+            (None, Some(0)) => WriteBatching::Off,
+            (None, Some(n)) => WriteBatching::Frames(n as usize),
+            (None, None) => WriteBatching::Frames(DEFAULT_WRITE_BATCH_FRAMES),
+        },
+    );
+    set_channel_multipath(channel, config.multipath.unwrap_or(false));
+    if let Some(n) = config.max_concurrent_dials {
+        set_channel_dial_limit(channel, n as usize);
+    }
+    set_channel_dial_retry(
+        channel,
+        config.dial_retry_attempts.unwrap_or(1),
+        config
+            .dial_retry_delay_ms
+            .map_or(DEFAULT_DIAL_RETRY_DELAY_MS, u64::from),
+        config
+            .dial_retry_max_ms
+            .map_or(DEFAULT_DIAL_RETRY_MAX_MS, u64::from),
+    );
+    set_channel_saturation_thresholds(
+        channel,
+        SaturationThresholds {
+            max_send_queue_depth: config.max_send_queue_depth.unwrap_or(0),
+            max_recv_queue_depth: config.max_recv_queue_depth.unwrap_or(0),
+            max_streams: config.max_streams_per_session.unwrap_or(0),
+            max_pending_streams: config
+                .max_pending_streams
+                .unwrap_or(DEFAULT_MAX_PENDING_STREAMS),
+        },
+    );
+    let mut quality = QualityThresholds::default();
+    if let Some(q) = &config.quality {
+        let ms = |v: Option<u32>, d| v.map_or(d, |ms| std::time::Duration::from_millis(ms as u64));
+        quality.degraded_rtt = ms(q.degraded_rtt_ms, quality.degraded_rtt);
+        quality.bad_rtt = ms(q.bad_rtt_ms, quality.bad_rtt);
+        quality.degraded_stall_ratio = q
+            .degraded_stall_ratio
+            .unwrap_or(quality.degraded_stall_ratio);
+        quality.bad_stall_ratio = q.bad_stall_ratio.unwrap_or(quality.bad_stall_ratio);
+        quality.degraded_errors = q.degraded_errors.unwrap_or(quality.degraded_errors);
+        quality.bad_errors = q.bad_errors.unwrap_or(quality.bad_errors);
+        quality.degraded_recv_idle_secs = q
+            .degraded_recv_idle_secs
+            .unwrap_or(quality.degraded_recv_idle_secs);
+        quality.bad_recv_idle_secs = q.bad_recv_idle_secs.unwrap_or(quality.bad_recv_idle_secs);
+    }
+    set_channel_quality_thresholds(channel, quality);
+    set_channel_stream_wait(
+        channel,
+        config.stream_wait_ms.unwrap_or(0) as u64,
+        config
+            .max_waiting_streams
+            .unwrap_or(DEFAULT_MAX_WAITING_STREAMS) as usize,
+    );
+    set_channel_stream_proto(
+        channel,
+        config.stream_proto.as_deref().unwrap_or(""),
+        config.socks5_username.as_deref().unwrap_or(""),
+        config.socks5_password.as_deref().unwrap_or(""),
+    );
+    set_channel_self_addrs(channel, config.self_addrs.as_deref().unwrap_or(&[]));
+    set_channel_data_quantum(channel, config.data_quantum.unwrap_or(0) as usize);
+    set_channel_send_dwell_limit(channel, config.send_dwell_limit_ms.unwrap_or(0) as u64);
+    set_channel_write_coalescing(
+        channel,
+        config.write_coalesce_bytes.unwrap_or(0) as usize,
+        config.write_coalesce_ms.unwrap_or(0) as u64,
+    );
+    set_channel_max_stream_lifetime(channel, config.max_stream_lifetime_secs.unwrap_or(0) as u64);
+    set_channel_udp_idle_timeout(
+        channel,
+        config
+            .udp_idle_secs
+            .map_or(DEFAULT_UDP_IDLE_SECS, u64::from),
+    );
+    set_channel_unknown_flags_policy(
+        channel,
+        UnknownFlagsPolicy::with_max_frames(config.max_unknown_frames.unwrap_or(0)),
+    );
+    let orphan_data = config.orphan_data.as_deref().unwrap_or("fin");
+    let max_orphan_frames = config
+        .max_orphan_data_frames
+        .unwrap_or(DEFAULT_MAX_ORPHAN_DATA_FRAMES);
+    match OrphanDataPolicy::from_name(orphan_data, max_orphan_frames) {
+        Some(policy) => set_channel_orphan_data_policy(channel, policy),
+        None => {
+            warn!("unknown orphan_data:{}, using fin", orphan_data);
+            set_channel_orphan_data_policy(channel, OrphanDataPolicy::ReplyFin);
+        }
+    }
+    set_channel_initial_send_credit(channel, config.initial_send_credit.unwrap_or(0));
+    set_channel_max_sessions(channel, config.max_sessions.unwrap_or(0) as usize);
+    if let Some(n) = config.conn_pool_size {
+        let idle_secs = config
+            .conn_pool_idle_secs
+            .map_or(DEFAULT_CONN_POOL_IDLE_SECS, u64::from);
+        set_channel_conn_pool(channel, idle_secs, n as usize);
+    }
     process_rmux_session(
         ctx, // config.name.as_str(),
         // session_id,
@@ -72,6 +212,31 @@ where
     Ok(())
 }
 
+#[cfg(feature = "quic")]
+async fn init_quic_client(
+    config: ChannelConfig,
+    session_id: u32,
+    addr: String,
+    domain: String,
+) -> Result<(), std::io::Error> {
+    let connect = quic_connect(addr.as_str(), domain.as_str(), config.ca.as_deref());
+    let dur = std::time::Duration::from_secs(5);
+    let (conn, mut send, mut recv) = tokio::time::timeout(dur, connect).await??;
+    let rc = init_client(config, session_id, &mut recv, &mut send).await;
+    conn.close();
+    rc
+}
+
+#[cfg(not(feature = "quic"))]
+async fn init_quic_client(
+    _config: ChannelConfig,
+    _session_id: u32,
+    _addr: String,
+    _domain: String,
+) -> Result<(), std::io::Error> {
+    Err(make_io_error("quic support is not enabled in this build"))
+}
+
 pub async fn init_rmux_client(
     config: ChannelConfig,
     session_id: u32,
@@ -99,7 +264,8 @@ pub async fn init_rmux_client(
         format!(
             "{}:{}",
             conn_url.host().as_ref().unwrap(),
-            conn_url.port_or_known_default().unwrap()
+            // tls:// has no known default port
+            conn_url.port_or_known_default().unwrap_or(443)
         )
     };
     info!("connect rmux:{} to addr:{}", url, addr);
@@ -110,6 +276,11 @@ pub async fn init_rmux_client(
         conn_url.host_str().unwrap()
     };
 
+    if conn_url.scheme() == "quic" {
+        let domain = String::from(domain);
+        return init_quic_client(config, session_id, addr, domain).await;
+    }
+
     let conn = TcpStream::connect(&addr);
     let dur = std::time::Duration::from_secs(5);
     let s = tokio::time::timeout(dur, conn).await?;
@@ -126,6 +297,21 @@ pub async fn init_rmux_client(
                 return rc;
             }
         }
+        "tls" => {
+            let tls_config = tls_client_config(
+                config.ca.as_deref(),
+                config.cert_pin.as_deref(),
+                config.alpn.as_deref(),
+            )?;
+            info!("TLS connect {:?}", domain);
+            let tls_stream = tls_connect(conn, domain, tls_config).await?;
+            let (mut read, mut write) = tokio::io::split(tls_stream);
+            let rc = init_client(config, session_id, &mut read, &mut write).await;
+            let _ = write.shutdown().await;
+            if rc.is_err() {
+                return rc;
+            }
+        }
         "ws" => {
             let ws = match tokio_tungstenite::client_async(url, conn).await {
                 Err(e) => return Err(make_io_error(e.description())),
@@ -172,7 +358,8 @@ pub async fn init_rmux_client(
 pub async fn get_rmux_stream(
     channel: &str,
     addr: String,
+    initial_data: Vec<u8>,
 ) -> Result<Box<dyn ChannelStream + Send>, std::io::Error> {
-    let stream = create_stream(channel, "tcp", addr.as_str()).await?;
+    let stream = create_stream_with_data(channel, "tcp", addr.as_str(), initial_data).await?;
     Ok(Box::new(stream))
 }
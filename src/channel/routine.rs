@@ -1,16 +1,18 @@
-use super::rmux::init_rmux_client;
+use super::init_rmux_client;
 use crate::config::ChannelConfig;
-use crate::rmux::{get_channel_session_size, routine_all_sessions};
+use crate::rmux::{get_channel_session_size, next_tunnel_id, routine_all_sessions};
 use chrono::{Local, Timelike};
 use futures::FutureExt;
 use rand::Rng;
-use std::sync::atomic::{AtomicU32, Ordering};
 use std::time::{Duration, SystemTime};
 use tokio::time;
 
-pub async fn routine_channels(cfgs: Option<Vec<ChannelConfig>>) {
+pub const DEFAULT_ROUTINE_INTERVAL_SECS: u64 = 30;
+
+// sessions are pinged & checked every routine_interval_secs +- a third of it
+pub async fn routine_channels(cfgs: Option<Vec<ChannelConfig>>, routine_interval_secs: u64) {
+    let jitter = (routine_interval_secs / 3) as i64;
     let mut interval = time::interval(Duration::from_secs(5));
-    let session_id_seed = AtomicU32::new(0);
     let mut ping_time: u64 = 0;
     loop {
         interval.tick().await;
@@ -25,11 +27,7 @@ pub async fn routine_channels(cfgs: Option<Vec<ChannelConfig>>) {
                     let n = channel_cfg.conns_per_host as usize - count;
                     for _ in 0..n {
                         let init_cfg = channel_cfg.clone();
-                        let f = init_rmux_client(
-                            init_cfg,
-                            session_id_seed.fetch_add(1, Ordering::SeqCst),
-                        )
-                        .map(|r| {
+                        let f = init_rmux_client(init_cfg, next_tunnel_id()).map(|r| {
                             if let Err(e) = r {
                                 error!("Failed to init_rmux_client; error={}", e);
                             }
@@ -41,11 +39,13 @@ pub async fn routine_channels(cfgs: Option<Vec<ChannelConfig>>) {
         }
         match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
             Ok(n) => {
-                let rand_inc: i64 = {
+                let rand_inc: i64 = if jitter > 0 {
                     let mut rng = rand::thread_rng();
-                    rng.gen_range(-10, 10)
+                    rng.gen_range(-jitter, jitter)
+                } else {
+                    0
                 };
-                if n.as_secs() - ping_time > (30 + rand_inc) as u64 {
+                if n.as_secs() - ping_time > (routine_interval_secs as i64 + rand_inc) as u64 {
                     routine_all_sessions().await;
                     ping_time = n.as_secs();
                 }
@@ -1,5 +1,7 @@
+use crate::utils::IpCidr;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 
 // lazy_static! {
 //     static ref GLOBAL_CONFIG: Mutex<Config> = Mutex::new(Config::new());
@@ -10,31 +12,123 @@ pub struct LogConfig {
     pub logtostderr: bool,
     pub level: String,
     pub logdir: String,
+    // "hash" or "truncate" target addresses in logs, they're logged as is by default
+    pub redact_targets: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PACConfig {
+    // regex of the target "host:port", may be left out for the other conditions
+    #[serde(default)]
     pub host: String,
+    // "direct", "reject" or the name of a channel
     pub channel: String,
+    // target domains equal to or under one of these, like "example.com"
+    pub domain_suffix: Option<Vec<String>>,
+    // more of them from a file, one per line
+    pub domain_suffix_file: Option<String>,
+    // target ips within one of these networks, like "10.0.0.0/8"
+    pub ip_cidr: Option<Vec<String>>,
+    // target ips located in one of these countries(ISO 3166 codes like "US"), see geoip_file
+    pub geoip: Option<Vec<String>>,
+    // domain targets are resolved for ip_cidr and geoip, they never match them otherwise
+    pub resolve: Option<bool>,
     #[serde(skip)]
     pub re: Option<Regex>,
+    #[serde(skip)]
+    pub(crate) suffixes: HashSet<String>,
+    #[serde(skip)]
+    pub(crate) cidrs: Vec<IpCidr>,
+}
+
+fn normalize_domain(domain: &str) -> String {
+    domain.trim().trim_matches('.').to_ascii_lowercase()
 }
 
 impl PACConfig {
     pub fn init(&mut self) {
-        if self.re.is_none() {
+        if self.re.is_none() && !self.host.is_empty() {
             self.re = Some(Regex::new(self.host.as_str()).unwrap());
         }
+        self.suffixes = self
+            .domain_suffix
+            .iter()
+            .flatten()
+            .map(|d| normalize_domain(d))
+            .collect();
+        if let Some(path) = self.domain_suffix_file.as_deref() {
+            match std::fs::read_to_string(path) {
+                Ok(s) => self.suffixes.extend(
+                    s.lines()
+                        .filter(|l| !l.trim().is_empty() && !l.trim().starts_with('#'))
+                        .map(normalize_domain),
+                ),
+                Err(e) => error!(
+                    "failed to read domain_suffix_file:{} with error:{}",
+                    path, e
+                ),
+            }
+        }
+        self.cidrs = Vec::new();
+        for c in self.ip_cidr.iter().flatten() {
+            match IpCidr::parse(c) {
+                Some(cidr) => self.cidrs.push(cidr),
+                None => error!("invalid ip_cidr:{} in pac rule", c),
+            }
+        }
     }
     pub fn is_match(&self, addr: &str) -> bool {
-        self.re.as_ref().unwrap().is_match(addr)
+        self.re.as_ref().map_or(false, |re| re.is_match(addr))
+    }
+    // host is lower case
+    pub fn is_domain_match(&self, host: &str) -> bool {
+        if self.suffixes.is_empty() {
+            return false;
+        }
+        let mut domain = host;
+        loop {
+            if self.suffixes.contains(domain) {
+                return true;
+            }
+            match domain.find('.') {
+                Some(pos) => domain = &domain[pos + 1..],
+                None => return false,
+            }
+        }
+    }
+    pub fn has_ip_conditions(&self) -> bool {
+        !self.cidrs.is_empty() || self.geoip.as_ref().map_or(false, |g| !g.is_empty())
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CipherConfig {
+    // may be omitted with a password
+    #[serde(default)]
     pub key: String,
     pub method: String,
+    // session keys are derived from it instead of using the key as is
+    pub password: Option<String>,
+    // frame bodies are only authenticated, used if both ends set it; the tunnels of a server
+    // must all agree on it
+    pub auth_only: Option<bool>,
+    // server only, clients still using the key replaced by this one are let in for
+    // previous_key_secs (default 3600) while they move over
+    pub previous_key: Option<String>,
+    pub previous_key_secs: Option<u32>,
+}
+
+// thresholds of the session quality classes, the unset ones keep their default
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct QualityConfig {
+    pub degraded_rtt_ms: Option<u32>,
+    pub bad_rtt_ms: Option<u32>,
+    pub degraded_stall_ratio: Option<f64>,
+    pub bad_stall_ratio: Option<f64>,
+    pub degraded_errors: Option<u32>,
+    pub bad_errors: Option<u32>,
+    pub degraded_recv_idle_secs: Option<u32>,
+    pub bad_recv_idle_secs: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -44,11 +138,71 @@ pub struct ChannelConfig {
     pub cipher: CipherConfig,
     pub ping_interval_sec: u32,
     pub conns_per_host: u32,
+    // live sessions of the channel, sessions established past it are rejected
+    pub max_sessions: Option<u32>,
     pub max_alive_mins: u32,
+    pub max_alive_bytes: Option<u64>,
+    pub rotation_jitter_ratio: Option<f64>,
+    pub multipath: Option<bool>,
+    pub stream_recv_window: Option<u32>,
+    pub weight: Option<u32>,
+    pub max_concurrent_dials: Option<u32>,
+    pub dial_retry_attempts: Option<u32>,
+    pub dial_retry_delay_ms: Option<u32>,
+    pub dial_retry_max_ms: Option<u32>,
+    // sessions at any of these get no new streams, with all saturated opening a stream fails
+    pub max_send_queue_depth: Option<u32>,
+    pub max_recv_queue_depth: Option<u32>,
+    pub max_streams_per_session: Option<u32>,
+    // streams whose SYN isn't taken by the session's event loop yet, defaults to 512
+    pub max_pending_streams: Option<u32>,
+    // new streams go to the sessions of the best quality class there is
+    pub quality: Option<QualityConfig>,
+    // new streams wait this long for a usable session, e.g. while all are rotated out
+    pub stream_wait_ms: Option<u32>,
+    pub max_waiting_streams: Option<u32>,
+    pub stream_keepalive_secs: Option<u32>,
+    // frames coalesced per connection write, 0 writes each frame alone; bytes takes precedence
+    pub write_batch_frames: Option<u32>,
+    pub write_batch_bytes: Option<u32>,
+    // bytes each stream may deliver per round robin turn, unset keeps the strict event order
+    pub data_quantum: Option<u32>,
+    // streams with a frame waiting longer in the send queue are reset
+    pub send_dwell_limit_ms: Option<u32>,
+    // small writes of a stream are gathered up to this many bytes or ms into one DATA event
+    pub write_coalesce_bytes: Option<u32>,
+    pub write_coalesce_ms: Option<u32>,
+    // streams are closed this many secs after their open however busy, 0 for no limit
+    pub max_stream_lifetime_secs: Option<u32>,
+    // SOCKS5 UDP associations over the channel end after secs without a datagram, default 60
+    pub udp_idle_secs: Option<u32>,
+    // the session is closed on this many frames of unknown flags, 1 on the first one
+    pub max_unknown_frames: Option<u32>,
+    // DATA for unknown streams: "fin"(default) FINs the stream, "drop" only counts it, "close"
+    // FINs it and closes the session on max_orphan_data_frames of them
+    pub orphan_data: Option<String>,
+    pub max_orphan_data_frames: Option<u32>,
+    // send window new streams start with, above the default the first burst needs no grant
+    pub initial_send_credit: Option<u32>,
+    // opt-in reuse of outbound conns of streams opened by the peer, unsafe for stateful targets
+    pub conn_pool_size: Option<u32>,
+    pub conn_pool_idle_secs: Option<u32>,
     pub proxy: Option<String>,
     pub work_time_frame: Option<[u8; 2]>,
     pub sni: Option<String>,
     pub sni_proxy: Option<String>,
+    // PEM file of extra CA to verify the server cert of quic & tls channel
+    pub ca: Option<String>,
+    // hex SHA-256 of the server cert of tls channel, replaces the CA check
+    pub cert_pin: Option<String>,
+    // ALPN protocols the tls channel offers, e.g. ["h2", "http/1.1"] to look like a browser
+    pub alpn: Option<Vec<String>>,
+    // "socks5" or "http-connect" has the server connect targets through its upstream of it
+    pub stream_proto: Option<String>,
+    pub socks5_username: Option<String>,
+    pub socks5_password: Option<String>,
+    // addresses reaching this proxy from the peer, streams the peer opens to them are refused
+    pub self_addrs: Option<Vec<String>>,
 }
 
 impl ChannelConfig {
@@ -65,6 +219,47 @@ pub struct TunnelConfig {
     pub listen: String,
     pub cipher: Option<CipherConfig>,
     pub pac: Vec<PACConfig>,
+    pub max_concurrent_dials: Option<u32>,
+    // attempts of a failed outbound dial, with the delay doubling from dial_retry_delay_ms,
+    // given up dial_retry_max_ms after the first
+    pub dial_retry_attempts: Option<u32>,
+    pub dial_retry_delay_ms: Option<u32>,
+    pub dial_retry_max_ms: Option<u32>,
+    // recv window of the streams clients open, independent of the one each client advertises
+    pub stream_recv_window: Option<u32>,
+    pub data_quantum: Option<u32>,
+    pub send_dwell_limit_ms: Option<u32>,
+    pub write_coalesce_bytes: Option<u32>,
+    pub write_coalesce_ms: Option<u32>,
+    pub max_stream_lifetime_secs: Option<u32>,
+    // UDP associations of clients end after secs without a datagram, default 60
+    pub udp_idle_secs: Option<u32>,
+    pub max_unknown_frames: Option<u32>,
+    pub orphan_data: Option<String>,
+    pub max_orphan_data_frames: Option<u32>,
+    // live client sessions over all listeners, sessions established past it are rejected
+    pub max_sessions: Option<u32>,
+    pub conn_pool_size: Option<u32>,
+    pub conn_pool_idle_secs: Option<u32>,
+    // ws listener: only websocket requests to this path are upgraded, any path if unset;
+    // other requests are answered like a plain web server with ws_site_page as its index
+    pub ws_path: Option<String>,
+    pub ws_site_page: Option<String>,
+    // PEM cert chain & private key files, required by quic & tls listener
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    // ALPN protocols the tls listener selects from, in order of preference
+    pub alpn: Option<Vec<String>>,
+    // upstream for streams with proto "socks5", credentials are used unless the stream has its own
+    pub socks5_upstream: Option<String>,
+    pub socks5_username: Option<String>,
+    pub socks5_password: Option<String>,
+    // upstream for streams with proto "http-connect", an HTTP proxy taking CONNECT
+    pub http_connect_upstream: Option<String>,
+    pub http_connect_username: Option<String>,
+    pub http_connect_password: Option<String>,
+    // other addresses reaching this listener, e.g. a public ip, refused as stream targets
+    pub self_addrs: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -73,4 +268,18 @@ pub struct Config {
     pub tunnel: Vec<TunnelConfig>,
     // pub server: Vec<ServerConfig>,
     pub channel: Option<Vec<ChannelConfig>>,
+    // secs between the heartbeat & housekeeping rounds over all sessions, default 30
+    pub routine_interval_secs: Option<u32>,
+    // bytes all sessions and streams may buffer before peers are slowed down, unlimited if unset
+    pub buffer_budget: Option<u64>,
+    // tasks relaying streams opened by peers, further SYNs are refused, unlimited if unset
+    pub max_relay_tasks: Option<u32>,
+    // sessions in their handshake & setup at once, further ones wait, unlimited if unset
+    pub max_establishing_sessions: Option<u32>,
+    // retired sessions kept for their last streams, the ones retired first are closed past it
+    pub max_retired_sessions: Option<u32>,
+    // records every frame of every session in plaintext to this file, for debugging only
+    pub frame_tap_file: Option<String>,
+    // "network,country" lines like "1.0.1.0/24,CN" for the geoip conditions of pac rules
+    pub geoip_file: Option<String>,
 }
@@ -15,7 +15,7 @@ pub use self::config::Config;
 
 mod channel;
 pub mod config;
-mod rmux;
+pub mod rmux;
 mod tunnel;
 mod utils;
 
@@ -23,6 +23,7 @@ use futures::FutureExt;
 use std::error::Error;
 
 pub async fn start_rsnova(cfg: config::Config) -> Result<(), Box<dyn Error>> {
+    let redact_targets = cfg.log.redact_targets.clone();
     let mut logger = flexi_logger::Logger::with_str(cfg.log.level.as_str());
     if !cfg.log.logdir.is_empty() {
         logger = logger
@@ -40,6 +41,20 @@ pub async fn start_rsnova(cfg: config::Config) -> Result<(), Box<dyn Error>> {
     }
     logger.start().unwrap();
 
+    let redaction = match redact_targets.as_deref() {
+        None | Some("off") => rmux::AddrRedaction::Off,
+        Some("hash") => rmux::AddrRedaction::Hash,
+        Some("truncate") => rmux::AddrRedaction::Truncate,
+        Some(v) => {
+            warn!(
+                "unknown redact_targets:{}, target addresses are logged as is",
+                v
+            );
+            rmux::AddrRedaction::Off
+        }
+    };
+    rmux::set_addr_redaction(redaction);
+
     for c in cfg.tunnel {
         info!("Start rsnova client at {} ", c.listen);
         let handle = tunnel::start_tunnel_server(c).map(|r| {
@@ -50,7 +65,38 @@ pub async fn start_rsnova(cfg: config::Config) -> Result<(), Box<dyn Error>> {
         tokio::spawn(handle);
     }
 
-    channel::routine_channels(cfg.channel).await;
+    if let Some(budget) = cfg.buffer_budget {
+        rmux::set_buffer_budget(budget as usize);
+    }
+    if let Some(n) = cfg.max_relay_tasks {
+        rmux::set_max_relay_tasks(n as usize);
+    }
+    if let Some(n) = cfg.max_establishing_sessions {
+        rmux::set_max_establishing_sessions(n as usize);
+    }
+    if let Some(n) = cfg.max_retired_sessions {
+        rmux::set_max_retired_sessions(n as usize);
+    }
+    if let Some(path) = cfg.frame_tap_file.as_deref() {
+        match rmux::file_frame_tap(path) {
+            Ok(tap) => {
+                warn!("Recording plaintext frames of all sessions to {}", path);
+                rmux::set_frame_tap(Some(tap));
+            }
+            Err(e) => error!("Failed to create frame tap file {}; error={}", path, e),
+        }
+    }
+    if let Some(path) = cfg.geoip_file.as_deref() {
+        match tunnel::load_geoip_file(path) {
+            Ok(n) => info!("Loaded {} networks from geoip file {}", n, path),
+            Err(e) => error!("Failed to load geoip file {}; error={}", path, e),
+        }
+    }
+
+    let routine_interval_secs = cfg
+        .routine_interval_secs
+        .map_or(channel::DEFAULT_ROUTINE_INTERVAL_SECS, u64::from);
+    channel::routine_channels(cfg.channel, routine_interval_secs).await;
 
     Ok(())
 }
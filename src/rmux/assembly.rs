@@ -0,0 +1,105 @@
+use super::message::MAX_SPANNING_INITIAL_DATA_LEN;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// a reassembled SYN body, room for the largest initial data and the other fields
+pub(crate) const MAX_SYN_LEN: usize = MAX_SPANNING_INITIAL_DATA_LEN + 64 * 1024;
+// parts of a SYN whose last frame hasn't come by then are dropped
+const SYN_ASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+// SYNs of one session being reassembled at once
+const MAX_ASSEMBLING_SYNS: usize = 64;
+
+// The FLAG_SYN_PART frames of a session by stream id, until the SYN completing them.
+#[derive(Default)]
+pub(crate) struct SynAssembler {
+    parts: HashMap<u32, (Instant, Vec<u8>)>,
+}
+
+impl SynAssembler {
+    // false if the part is refused, the SYN would grow over MAX_SYN_LEN or too many are
+    // pending; what was gathered of it is dropped
+    pub(crate) fn push(&mut self, sid: u32, part: &[u8]) -> bool {
+        if !self.parts.contains_key(&sid) && self.parts.len() >= MAX_ASSEMBLING_SYNS {
+            self.expire();
+            if self.parts.len() >= MAX_ASSEMBLING_SYNS {
+                return false;
+            }
+        }
+        let (_, body) = self
+            .parts
+            .entry(sid)
+            .or_insert_with(|| (Instant::now(), Vec::new()));
+        if body.len() + part.len() > MAX_SYN_LEN {
+            self.parts.remove(&sid);
+            return false;
+        }
+        body.extend_from_slice(part);
+        true
+    }
+    // the whole body of the SYN, the body of its frame as is without parts before it
+    pub(crate) fn complete(&mut self, sid: u32, last: Vec<u8>) -> Option<Vec<u8>> {
+        let (_, mut body) = match self.parts.remove(&sid) {
+            Some(p) => p,
+            None => return Some(last),
+        };
+        if body.len() + last.len() > MAX_SYN_LEN {
+            return None;
+        }
+        body.extend_from_slice(&last[..]);
+        Some(body)
+    }
+    // drops the SYNs waiting too long for their last frame, returns their stream ids
+    pub(crate) fn expire(&mut self) -> Vec<u32> {
+        let expired: Vec<u32> = self
+            .parts
+            .iter()
+            .filter(|(_, (since, _))| since.elapsed() >= SYN_ASSEMBLY_TIMEOUT)
+            .map(|(sid, _)| *sid)
+            .collect();
+        for sid in expired.iter() {
+            self.parts.remove(sid);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::event::{new_syn_events, FLAG_SYN, FLAG_SYN_PART, SYN_PART_LEN};
+    use super::super::message::ConnectRequest;
+    use super::*;
+
+    #[test]
+    fn test_syn_reassembly() {
+        let req = ConnectRequest {
+            proto: String::from("tcp"),
+            addr: String::from("192.0.2.1:80"),
+            initial_data: vec![9u8; 3 * SYN_PART_LEN],
+            ..Default::default()
+        };
+        let events = new_syn_events(3, &req, true);
+        assert_eq!(events.len(), 4);
+        let mut assembler = SynAssembler::default();
+        for ev in events[..3].iter() {
+            assert_eq!(ev.header.flags(), FLAG_SYN_PART);
+            assert!(assembler.push(3, &ev.body[..]));
+        }
+        let last = events.last().unwrap();
+        assert_eq!(last.header.flags(), FLAG_SYN);
+        let body = assembler.complete(3, last.body.clone()).unwrap();
+        assert_eq!(ConnectRequest::decode(&body[..]).unwrap(), req);
+
+        // a small SYN is a single frame and passes through as is
+        let events = new_syn_events(5, &ConnectRequest::default(), true);
+        assert_eq!(events.len(), 1);
+        let body = assembler.complete(5, events[0].body.clone()).unwrap();
+        assert_eq!(body, events[0].body);
+
+        let part = vec![0u8; SYN_PART_LEN];
+        for _ in 0..MAX_SYN_LEN / SYN_PART_LEN {
+            assert!(assembler.push(7, &part[..]));
+        }
+        assert!(!assembler.push(7, &part[..]));
+        assert!(assembler.parts.is_empty());
+    }
+}
@@ -0,0 +1,85 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Credit a stream still grants per window update while the budget is exceeded, the peer's
+// send window shrinks toward it instead of the buffers growing further.
+pub(crate) const PRESSURE_WINDOW_CREDIT: u32 = 4 * 1024;
+
+static BUFFER_BUDGET: AtomicUsize = AtomicUsize::new(0);
+static BUFFER_USAGE: AtomicUsize = AtomicUsize::new(0);
+
+// Bytes all sessions and streams may buffer before advertised windows are shrunk,
+// 0 for no limit. Nothing is dropped over the budget, peers are just slowed down.
+pub fn set_buffer_budget(bytes: usize) {
+    BUFFER_BUDGET.store(bytes, Ordering::SeqCst);
+}
+
+pub fn buffer_budget() -> usize {
+    BUFFER_BUDGET.load(Ordering::SeqCst)
+}
+
+// bytes currently buffered by all sessions and streams
+pub fn buffer_usage() -> usize {
+    BUFFER_USAGE.load(Ordering::SeqCst)
+}
+
+pub(crate) fn over_buffer_budget() -> bool {
+    let budget = buffer_budget();
+    budget > 0 && buffer_usage() > budget
+}
+
+// Bytes one owner charged to the budget, what's left is credited back on drop.
+#[derive(Default)]
+pub(crate) struct BufferCharge {
+    bytes: AtomicUsize,
+}
+
+impl BufferCharge {
+    pub(crate) fn charge(&self, n: usize) {
+        self.bytes.fetch_add(n, Ordering::SeqCst);
+        BUFFER_USAGE.fetch_add(n, Ordering::SeqCst);
+    }
+    // never credits more than was charged, some data reaches the buffers uncharged
+    pub(crate) fn credit(&self, n: usize) {
+        let prev = self
+            .bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |b| {
+                Some(b.saturating_sub(n))
+            })
+            .unwrap();
+        BUFFER_USAGE.fetch_sub(std::cmp::min(prev, n), Ordering::SeqCst);
+    }
+    // charge or credit the difference so the owner holds n bytes
+    pub(crate) fn set(&self, n: usize) {
+        let prev = self.bytes.swap(n, Ordering::SeqCst);
+        if n > prev {
+            BUFFER_USAGE.fetch_add(n - prev, Ordering::SeqCst);
+        } else {
+            BUFFER_USAGE.fetch_sub(prev - n, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Drop for BufferCharge {
+    fn drop(&mut self) {
+        self.set(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_charge() {
+        let c = BufferCharge::default();
+        c.charge(100);
+        c.credit(30);
+        assert_eq!(c.bytes.load(Ordering::SeqCst), 70);
+        // crediting more than is left stops at 0
+        c.credit(100);
+        assert_eq!(c.bytes.load(Ordering::SeqCst), 0);
+        c.set(4096);
+        c.set(1024);
+        assert_eq!(c.bytes.load(Ordering::SeqCst), 1024);
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Time as seen by the lifecycle checks of a session: its age, idle time, heartbeat and
+// rotation. Set with MuxContext::set_clock, e.g. a MockClock to step a session through them
+// in tests without sleeping.
+pub trait Clock: Send + Sync {
+    fn now_unix_secs(&self) -> u32;
+    fn now_instant(&self) -> Instant;
+}
+
+// the real clocks, the default
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_unix_secs(&self) -> u32 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32
+    }
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// Starts at the real time and stands still until advanced.
+pub struct MockClock {
+    start_unix_secs: u32,
+    start: Instant,
+    advanced: Mutex<Duration>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self {
+            start_unix_secs: SystemClock.now_unix_secs(),
+            start: Instant::now(),
+            advanced: Mutex::new(Duration::default()),
+        }
+    }
+}
+
+impl MockClock {
+    pub fn advance(&self, d: Duration) {
+        *self.advanced.lock().unwrap() += d;
+    }
+}
+
+impl Clock for MockClock {
+    fn now_unix_secs(&self) -> u32 {
+        self.start_unix_secs + self.advanced.lock().unwrap().as_secs() as u32
+    }
+    fn now_instant(&self) -> Instant {
+        self.start + *self.advanced.lock().unwrap()
+    }
+}
@@ -1,15 +1,202 @@
 use bytes::{Buf, BufMut, BytesMut};
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 //use tokio::io::read_exact;
 use tokio::prelude::*;
 
 use ring::aead::*;
+use ring::{hkdf, pbkdf2};
 
+use super::error::RmuxError;
 use super::event::*;
 
 pub const METHOD_AES128_GCM: &str = "aes128gcm";
 pub const METHOD_CHACHA20_POLY1305: &str = "chacha20poly1305";
 pub const METHOD_NONE: &str = "none";
 
+pub const DEFAULT_MAX_EVENT_BODY_LEN: u32 = 1024 * 1024;
+// max frames sealed/opened with one key before the session must be closed,
+// far below the point where the 64bit nonce counter could wrap back to a used value.
+pub const DEFAULT_NONCE_LIMIT: u64 = 1 << 48;
+
+pub const PASSWORD_KEY_LEN: usize = 32;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+// salt of the key a channel's password is stretched into once, sessions derive their own
+// keys from it with the nonce of the handshake
+const CHANNEL_PASSWORD_SALT: &[u8] = b"rsnova channel password";
+const SESSION_KEY_INFO: &[u8] = b"rsnova session key";
+
+static MAX_EVENT_BODY_LEN: AtomicU32 = AtomicU32::new(DEFAULT_MAX_EVENT_BODY_LEN);
+
+pub fn set_max_event_body_len(n: u32) {
+    MAX_EVENT_BODY_LEN.store(n, Ordering::SeqCst);
+}
+
+fn check_body_len(header: &Header) -> Result<(), DecryptError> {
+    let max = MAX_EVENT_BODY_LEN.load(Ordering::SeqCst);
+    if FLAG_WIN_UPDATE != header.flags() && header.len() > max {
+        error!(
+            "[{}]Event body len:{} exceed max len:{}",
+            header.stream_id,
+            header.len(),
+            max
+        );
+        return Err((0, "event body too large"));
+    }
+    Ok(())
+}
+
+lazy_static! {
+    static ref CHANNEL_KEYS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    static ref CHANNEL_PASSWORDS: Mutex<HashMap<String, (String, [u8; PASSWORD_KEY_LEN])>> =
+        Mutex::new(HashMap::new());
+    static ref AUTH_ONLY_CHANNELS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    static ref SHARED_AUTH_ONLY: Mutex<HashMap<String, bool>> = Mutex::new(HashMap::new());
+    static ref KEY_ROTATIONS: Mutex<HashMap<String, KeyRotation>> = Mutex::new(HashMap::new());
+}
+
+pub const DEFAULT_PREVIOUS_KEY_SECS: u32 = 3600;
+
+// the channel's key was replaced, the previous one still authenticates handshakes for a while
+struct KeyRotation {
+    epoch: u32,
+    key: String,
+    previous_key: String,
+    until: Instant,
+}
+
+// Sessions of the channel announce CAP_AUTH_ONLY_FRAMES, once both ends did the frame bodies
+// are sent in the clear with a tag over the whole frame. It saves the cipher's CPU where
+// only tampering matters, the auth handshake itself is always encrypted. Off by default.
+pub fn set_channel_auth_only_frames(channel: &str, enable: bool) {
+    let mut channels = AUTH_ONLY_CHANNELS.lock().unwrap();
+    if enable {
+        channels.insert(String::from(channel));
+    } else {
+        channels.remove(channel);
+    }
+}
+
+// For a channel several configs set up, like the unnamed one all tunnels of a server share:
+// the first of them decides and false is returned for a different setting, which is ignored.
+pub(crate) fn share_channel_auth_only_frames(channel: &str, enable: bool) -> bool {
+    let mut shared = SHARED_AUTH_ONLY.lock().unwrap();
+    if let Some(v) = shared.get(channel) {
+        return *v == enable;
+    }
+    shared.insert(String::from(channel), enable);
+    set_channel_auth_only_frames(channel, enable);
+    true
+}
+
+pub(crate) fn auth_only_frames(channel: &str) -> bool {
+    AUTH_ONLY_CHANNELS.lock().unwrap().contains(channel)
+}
+
+// In place of the key in the config of a client channel, sessions of different channels
+// are then keyed independently. Servers can't tell channels apart before the handshake:
+// the sessions of all their tunnels are of the unnamed channel, each tunnel authenticates
+// with its own cipher key and a key set for "" would replace it for all of them.
+pub fn set_channel_key(channel: &str, key: &str) {
+    CHANNEL_KEYS
+        .lock()
+        .unwrap()
+        .insert(String::from(channel), String::from(key));
+}
+
+pub fn get_channel_key(channel: &str, default_key: &str) -> String {
+    match CHANNEL_KEYS.lock().unwrap().get(channel) {
+        Some(k) => k.clone(),
+        None => String::from(default_key),
+    }
+}
+
+// Replaces the key of the channel without dropping its streams. New sessions use the new key,
+// the server still authenticates clients with the previous one for grace. Sessions of the
+// previous key get no new streams while the channel has others, are retired once their
+// streams are done and shut down when grace runs out. Setting the same keys again keeps the
+// running rotation. Channels with a password aren't affected.
+pub fn rotate_channel_key(channel: &str, previous_key: &str, key: &str, grace: Duration) {
+    let mut rotations = KEY_ROTATIONS.lock().unwrap();
+    let epoch = match rotations.get(channel) {
+        Some(r) if r.key == key && r.previous_key == previous_key => return,
+        Some(r) => r.epoch + 1,
+        None => 1,
+    };
+    set_channel_key(channel, key);
+    rotations.insert(
+        String::from(channel),
+        KeyRotation {
+            epoch,
+            key: String::from(key),
+            previous_key: String::from(previous_key),
+            until: Instant::now() + grace,
+        },
+    );
+}
+
+// bumped by each rotation of the channel's key
+pub(crate) fn channel_key_epoch(channel: &str) -> u32 {
+    KEY_ROTATIONS
+        .lock()
+        .unwrap()
+        .get(channel)
+        .map_or(0, |r| r.epoch)
+}
+
+// while the rotation's grace lasts
+pub(crate) fn previous_channel_key(channel: &str) -> Option<String> {
+    KEY_ROTATIONS
+        .lock()
+        .unwrap()
+        .get(channel)
+        .filter(|r| Instant::now() < r.until)
+        .map(|r| r.previous_key.clone())
+}
+
+// PBKDF2-HMAC-SHA256, slow on purpose so a captured session can't be brute forced cheaply
+pub fn derive_password_key(password: &str, salt: &[u8]) -> [u8; PASSWORD_KEY_LEN] {
+    let mut key = [0u8; PASSWORD_KEY_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        salt,
+        password.as_bytes(),
+        &mut key,
+    );
+    key
+}
+
+// Sessions of the channel use keys derived from the password instead of the configured key,
+// both ends must set the same one. An empty password goes back to the key.
+pub fn set_channel_password(channel: &str, password: &str) {
+    let mut passwords = CHANNEL_PASSWORDS.lock().unwrap();
+    if password.is_empty() {
+        passwords.remove(channel);
+        return;
+    }
+    // set again for every connection of a channel, the KDF only runs on a change
+    if passwords.get(channel).map_or(false, |(p, _)| p == password) {
+        return;
+    }
+    let key = derive_password_key(password, CHANNEL_PASSWORD_SALT);
+    passwords.insert(String::from(channel), (String::from(password), key));
+}
+
+// the nonce is picked at random by the server for every session, so is each session's key
+fn derive_session_key(master: &[u8], nonce: u64) -> [u8; PASSWORD_KEY_LEN] {
+    let salt = hkdf::Salt::new(hkdf::HKDF_SHA256, &nonce.to_le_bytes());
+    let mut key = [0u8; PASSWORD_KEY_LEN];
+    salt.extract(master)
+        .expand(&[SESSION_KEY_INFO], hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut key))
+        .unwrap();
+    key
+}
+
 struct CryptoNonceSequence {
     nonce: u64,
 }
@@ -22,7 +209,7 @@ impl CryptoNonceSequence {
 
 impl NonceSequence for CryptoNonceSequence {
     fn advance(&mut self) -> Result<Nonce, ring::error::Unspecified> {
-        self.nonce += 1;
+        self.nonce = self.nonce.wrapping_add(1);
         let mut d = [0u8; NONCE_LEN];
         let v = self.nonce.to_le_bytes();
         d[0..8].copy_from_slice(&v[..]);
@@ -31,14 +218,24 @@ impl NonceSequence for CryptoNonceSequence {
 }
 
 pub struct CryptoContext {
-    pub key: String,
+    method: String,
+    key: Vec<u8>,
     pub nonce: u64,
+    start_nonce: u64,
+    nonce_limit: u64,
+    // bodies are authenticated but not encrypted
+    auth_only: bool,
+    // of the channel's key it was made with
+    key_epoch: u32,
     sealing_key: Option<SealingKey<CryptoNonceSequence>>,
     opening_key: Option<OpeningKey<CryptoNonceSequence>>,
 }
 
 type DecryptError = (u32, &'static str);
 
+const NONCE_EXHAUSTED: &str = "nonce exhausted";
+const AUTH_FAILED: &str = "Decrypt error";
+
 // type EncryptFunc = fn(ctx: &CryptoContext, ev: &Event, out: &mut BytesMut);
 // type DecryptFunc = fn(ctx: &CryptoContext, buf: &mut BytesMut) -> Result<Event, DecryptError>;
 
@@ -58,38 +255,113 @@ impl CryptoContext {
         while key.len() < 32 {
             key.push('F');
         }
+        Self::with_key(method, key.as_bytes(), nonce)
+    }
+
+    // the key needs at least 32 bytes
+    pub fn with_key(method: &str, k: &[u8], nonce: u64) -> Self {
+        let key = Vec::from(k);
         let aes_key = key.clone();
         match method {
             METHOD_CHACHA20_POLY1305 => CryptoContext {
+                method: String::from(method),
                 nonce,
-                sealing_key: Some(make_key(
-                    &CHACHA20_POLY1305,
-                    &aes_key.as_bytes()[0..32],
-                    nonce,
-                )),
-                opening_key: Some(make_key(
-                    &CHACHA20_POLY1305,
-                    &aes_key.as_bytes()[0..32],
-                    nonce,
-                )),
+                start_nonce: nonce,
+                nonce_limit: DEFAULT_NONCE_LIMIT,
+                auth_only: false,
+                key_epoch: 0,
+                sealing_key: Some(make_key(&CHACHA20_POLY1305, &aes_key[0..32], nonce)),
+                opening_key: Some(make_key(&CHACHA20_POLY1305, &aes_key[0..32], nonce)),
                 key,
             },
             METHOD_NONE => CryptoContext {
+                method: String::from(method),
                 key,
                 nonce,
+                start_nonce: nonce,
+                nonce_limit: DEFAULT_NONCE_LIMIT,
+                auth_only: false,
+                key_epoch: 0,
                 sealing_key: None,
                 opening_key: None,
             },
             METHOD_AES128_GCM => CryptoContext {
+                method: String::from(method),
                 key,
                 nonce,
-                sealing_key: Some(make_key(&AES_128_GCM, &aes_key.as_bytes()[0..16], nonce)),
-                opening_key: Some(make_key(&AES_128_GCM, &aes_key.as_bytes()[0..16], nonce)),
+                start_nonce: nonce,
+                nonce_limit: DEFAULT_NONCE_LIMIT,
+                auth_only: false,
+                key_epoch: 0,
+                sealing_key: Some(make_key(&AES_128_GCM, &aes_key[0..16], nonce)),
+                opening_key: Some(make_key(&AES_128_GCM, &aes_key[0..16], nonce)),
             },
             _ => panic!("not supported crypto method."),
         }
     }
 
+    pub fn method(&self) -> &str {
+        self.method.as_str()
+    }
+
+    pub fn set_nonce_limit(&mut self, limit: u64) {
+        self.nonce_limit = limit;
+    }
+
+    // both ends of a session must switch at the same frame
+    pub fn set_auth_only(&mut self, enable: bool) {
+        self.auth_only = enable;
+    }
+
+    pub fn is_auth_only(&self) -> bool {
+        self.auth_only
+    }
+
+    pub fn is_nonce_exhausted(&self) -> bool {
+        if self.sealing_key.is_none() && self.opening_key.is_none() {
+            return false;
+        }
+        self.nonce.wrapping_sub(self.start_nonce) >= self.nonce_limit
+    }
+
+    // Derives the key from a human password, both ends must use the same salt. Every call
+    // runs the full KDF, derive_password_key once for contexts sharing a key.
+    pub fn from_password(method: &str, password: &str, salt: &[u8], nonce: u64) -> Self {
+        Self::with_key(method, &derive_password_key(password, salt)[..], nonce)
+    }
+
+    pub fn new_for_channel(channel: &str, method: &str, default_key: &str, nonce: u64) -> Self {
+        let mut ctx = match CHANNEL_PASSWORDS.lock().unwrap().get(channel) {
+            Some((_, master)) => {
+                Self::with_key(method, &derive_session_key(&master[..], nonce)[..], nonce)
+            }
+            None => Self::new(
+                method,
+                get_channel_key(channel, default_key).as_str(),
+                nonce,
+            ),
+        };
+        ctx.key_epoch = channel_key_epoch(channel);
+        ctx
+    }
+
+    // with the key a handshake authenticated, the channel's previous key while a rotation of
+    // it lasts or else the current one
+    pub(crate) fn new_for_channel_key(channel: &str, method: &str, key: &str, nonce: u64) -> Self {
+        if previous_channel_key(channel).as_deref() == Some(key)
+            && get_channel_key(channel, key) != key
+        {
+            let mut ctx = Self::new(method, key, nonce);
+            ctx.key_epoch = channel_key_epoch(channel).saturating_sub(1);
+            return ctx;
+        }
+        Self::new_for_channel(channel, method, key, nonce)
+    }
+
+    pub fn key_epoch(&self) -> u32 {
+        self.key_epoch
+    }
+
     // fn get_decrypt_nonce(&self) -> Nonce {
     //     let mut d = [0u8; NONCE_LEN];
     //     let v = self.nonce.to_le_bytes();
@@ -106,7 +378,7 @@ impl CryptoContext {
 
     fn skip32_decrypt_key(&self) -> [u8; 10] {
         let mut sk: [u8; 10] = Default::default();
-        sk[0..10].copy_from_slice(&self.key.as_bytes()[0..10]);
+        sk[0..10].copy_from_slice(&self.key[0..10]);
         let dk = self.nonce.to_le_bytes();
         for i in 2..10 {
             sk[i] |= dk[i - 2];
@@ -116,7 +388,7 @@ impl CryptoContext {
     fn skip32_encrypt_key(&self) -> [u8; 10] {
         //let mut key = [0; 32];
         let mut sk: [u8; 10] = Default::default();
-        sk[0..10].copy_from_slice(&self.key.as_bytes()[0..10]);
+        sk[0..10].copy_from_slice(&self.key[0..10]);
         let dk = self.nonce.to_le_bytes();
         for i in 2..10 {
             sk[i] |= dk[i - 2];
@@ -136,8 +408,28 @@ impl CryptoContext {
             let e1 = skip32::encode(&sk, ev.header.flag_len);
             let e2 = skip32::encode(&sk, ev.header.stream_id);
             out.reserve(EVENT_HEADER_LEN);
+            let start = out.len();
             out.put_u32_le(e1);
             out.put_u32_le(e2);
+            if self.auth_only {
+                // the tag covers the frame as sent, its header included
+                if !ev.body.is_empty() {
+                    out.put_slice(&ev.body[..]);
+                    let sealed = self
+                        .sealing_key
+                        .as_mut()
+                        .unwrap()
+                        .seal_in_place_separate_tag(Aad::from(&out[start..]), &mut []);
+                    match sealed {
+                        Ok(tag) => out.put_slice(tag.as_ref()),
+                        Err(e) => {
+                            error!("encrypt error:{} {}", e, out.len());
+                        }
+                    }
+                }
+                self.nonce = self.nonce.wrapping_add(1);
+                return;
+            }
             if !ev.body.is_empty() {
                 match self
                     .sealing_key
@@ -153,7 +445,7 @@ impl CryptoContext {
             }
             out.put_slice(&ev.body[..]);
             //warn!("[{}]send bytes {}", self.nonce, out.len());
-            self.nonce += 1;
+            self.nonce = self.nonce.wrapping_add(1);
         }
         //self.nonce += 1;
     }
@@ -180,6 +472,7 @@ impl CryptoContext {
                     remote: true,
                 });
             }
+            check_body_len(&header)?;
             if buf.len() - EVENT_HEADER_LEN < header.len() as usize {
                 return Err((
                     header.len() + EVENT_HEADER_LEN as u32 - buf.len() as u32,
@@ -197,6 +490,9 @@ impl CryptoContext {
                 remote: true,
             })
         } else {
+            if self.is_nonce_exhausted() {
+                return Err((0, NONCE_EXHAUSTED));
+            }
             if buf.len() < EVENT_HEADER_LEN {
                 return Err((EVENT_HEADER_LEN as u32 - buf.len() as u32, ""));
             }
@@ -214,13 +510,14 @@ impl CryptoContext {
             let flags = header.flags();
             if (FLAG_WIN_UPDATE == flags) || 0 == header.len() {
                 buf.advance(EVENT_HEADER_LEN);
-                self.nonce += 1;
+                self.nonce = self.nonce.wrapping_add(1);
                 return Ok(Event {
                     header,
                     body: vec![],
                     remote: true,
                 });
             }
+            check_body_len(&header)?;
             let opening_key = self.opening_key.as_mut().unwrap();
             let expected_len = header.len() as usize + opening_key.algorithm().tag_len();
             // error!(
@@ -236,8 +533,34 @@ impl CryptoContext {
                 let missing = expected_len + EVENT_HEADER_LEN - buf.len();
                 return Err((missing as u32, ""));
             }
-            buf.advance(EVENT_HEADER_LEN);
             let dlen = header.len() as usize;
+            let tag_len = opening_key.algorithm().tag_len();
+            if self.auth_only {
+                let frame_len = EVENT_HEADER_LEN + dlen;
+                let mut tag = [0u8; MAX_TAG_LEN];
+                tag[..tag_len].copy_from_slice(&buf[frame_len..frame_len + tag_len]);
+                if let Err(e) =
+                    opening_key.open_in_place(Aad::from(&buf[..frame_len]), &mut tag[..tag_len])
+                {
+                    error!(
+                        "authenticate error:{} for event:{} {} {}",
+                        e,
+                        header.stream_id,
+                        header.flags(),
+                        self.nonce,
+                    );
+                    return Err((0, AUTH_FAILED));
+                }
+                let out = Vec::from(&buf[EVENT_HEADER_LEN..frame_len]);
+                buf.advance(frame_len + tag_len);
+                self.nonce = self.nonce.wrapping_add(1);
+                return Ok(Event {
+                    header,
+                    body: out,
+                    remote: true,
+                });
+            }
+            buf.advance(EVENT_HEADER_LEN);
             // info!(
             //     "decrypt event:{} {} {} {} {}",
             //     header.stream_id,
@@ -266,12 +589,12 @@ impl CryptoContext {
                         buf.len(),
                         self.nonce,
                     );
-                    return Err((0, "Decrypt error"));
+                    return Err((0, AUTH_FAILED));
                 }
             }
             let out = Vec::from(&buf[0..dlen]);
             buf.advance(dlen + opening_key.algorithm().tag_len());
-            self.nonce += 1;
+            self.nonce = self.nonce.wrapping_add(1);
             Ok(Event {
                 header,
                 body: out,
@@ -291,38 +614,15 @@ where
 {
     let mut next_read_n: u32 = 0;
     loop {
-        if recv_buf.is_empty() {
-            recv_buf.clear();
-        }
-        if next_read_n > 0 && next_read_n < 4096 {
-            next_read_n = 4096;
-        }
-        if next_read_n > 0 {
-            recv_buf.reserve(next_read_n as usize);
-            let pos = recv_buf.len();
-            let cap = recv_buf.capacity();
-            unsafe {
-                recv_buf.set_len(cap);
-            }
-            // info!(
-            //     "Start read at pos:{}, available buf:{}",
-            //     pos,
-            //     recv_buf[pos..].len()
-            // );
-            let n = reader.read(&mut recv_buf[pos..]).await?;
-            if 0 == n {
-                return Ok(None);
-            }
-            unsafe {
-                recv_buf.set_len(pos + n);
-            }
+        if next_read_n > 0 && !read_more(reader, recv_buf, next_read_n).await? {
+            return Ok(None);
         }
         let r = ctx.decrypt(recv_buf);
         match r {
             Ok(ev) => return Ok(Some(ev)),
             Err((n, reason)) => {
                 if !reason.is_empty() {
-                    return Err(std::io::Error::new(std::io::ErrorKind::Other, reason));
+                    return Err(decrypt_io_error(reason));
                 }
                 next_read_n = n;
             }
@@ -330,6 +630,115 @@ where
     }
 }
 
+// false on EOF at a frame boundary, a clean close
+async fn read_more<T>(
+    reader: &mut T,
+    recv_buf: &mut BytesMut,
+    n: u32,
+) -> Result<bool, std::io::Error>
+where
+    T: AsyncRead + Unpin + ?Sized,
+{
+    if recv_buf.is_empty() {
+        recv_buf.clear();
+    }
+    recv_buf.reserve(std::cmp::max(n, 4096) as usize);
+    let pos = recv_buf.len();
+    let cap = recv_buf.capacity();
+    unsafe {
+        recv_buf.set_len(cap);
+    }
+    let n = reader.read(&mut recv_buf[pos..]).await?;
+    if 0 == n {
+        unsafe {
+            recv_buf.set_len(pos);
+        }
+        // EOF at a frame boundary is a clean close, within a frame the peer died mid-write
+        if recv_buf.is_empty() {
+            return Ok(false);
+        }
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("connection closed within a frame, {} bytes read", pos),
+        ));
+    }
+    unsafe {
+        recv_buf.set_len(pos + n);
+    }
+    Ok(true)
+}
+
+fn decrypt_io_error(reason: &'static str) -> std::io::Error {
+    if reason == NONCE_EXHAUSTED {
+        return std::io::Error::new(std::io::ErrorKind::Other, reason);
+    }
+    if reason == AUTH_FAILED {
+        return std::io::Error::new(std::io::ErrorKind::InvalidData, RmuxError::AuthFailure);
+    }
+    // a frame failing the size check, corrupt or from a peer gone wrong
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+}
+
+// Reads the first frame of a handshake on the channel, authenticated with its key or while a
+// rotation lasts with the previous one. Returns the frame and the key that opened it.
+pub async fn read_auth_event<T>(
+    channel: &str,
+    method: &str,
+    default_key: &str,
+    reader: &mut T,
+    recv_buf: &mut BytesMut,
+) -> Result<Option<(Event, String)>, std::io::Error>
+where
+    T: AsyncRead + Unpin + ?Sized,
+{
+    let key = get_channel_key(channel, default_key);
+    let previous = match previous_channel_key(channel) {
+        Some(k) if k != key && !CHANNEL_PASSWORDS.lock().unwrap().contains_key(channel) => k,
+        _ => {
+            let mut ctx = CryptoContext::new_for_channel(channel, method, default_key, 0);
+            let ev = read_encrypt_event(&mut ctx, reader, recv_buf).await?;
+            return Ok(ev.map(|ev| (ev, key)));
+        }
+    };
+    // a wrong key reads a garbage header, so each key is tried on a copy of what's read
+    let mut keys = vec![key, previous];
+    loop {
+        let mut next_read_n = u32::max_value();
+        let mut failure = "";
+        let mut i = 0;
+        while i < keys.len() {
+            let mut ctx = CryptoContext::new(method, keys[i].as_str(), 0);
+            let mut trial = recv_buf.clone();
+            match ctx.decrypt(&mut trial) {
+                // window updates and empty frames skip the AEAD, so only an authenticated
+                // auth frame proves the key
+                Ok(ev) if ev.header.flags() == FLAG_AUTH && !ev.body.is_empty() => {
+                    *recv_buf = trial;
+                    return Ok(Some((ev, keys.swap_remove(i))));
+                }
+                Ok(_) => {
+                    failure = AUTH_FAILED;
+                    keys.remove(i);
+                }
+                Err((n, "")) => {
+                    next_read_n = std::cmp::min(next_read_n, n);
+                    i += 1;
+                }
+                Err((_, reason)) => {
+                    failure = reason;
+                    keys.remove(i);
+                }
+            }
+        }
+        if keys.is_empty() {
+            return Err(decrypt_io_error(failure));
+        }
+        if !read_more(reader, recv_buf, next_read_n).await? {
+            return Ok(None);
+        }
+    }
+}
+
 pub async fn write_encrypt_event<'a, T>(
     ctx: &'a mut CryptoContext,
     writer: &'a mut T,
@@ -352,15 +761,19 @@ mod tests {
     use std::str;
     #[test]
     fn test_crypto1() {
-        let ev = new_fin_event(100);
+        let mut ev = new_fin_event(100, false);
         let mut encrypt_ctx = CryptoContext::new(
             METHOD_CHACHA20_POLY1305,
             "21321321321321312321321321212asdfasdasdas1",
             21321312,
         );
-        let mut decrypt_ctx = encrypt_ctx.clone();
+        let mut decrypt_ctx = CryptoContext::new(
+            METHOD_CHACHA20_POLY1305,
+            "21321321321321312321321321212asdfasdasdas1",
+            21321312,
+        );
         let mut buf = BytesMut::new();
-        encrypt_ctx.encrypt(&ev, &mut buf);
+        encrypt_ctx.encrypt(&mut ev, &mut buf);
         println!("encoded buf len:{} {}", buf.capacity(), buf.len());
 
         let r = decrypt_ctx.decrypt(&mut buf).unwrap();
@@ -372,14 +785,19 @@ mod tests {
     #[test]
     fn test_crypto2() {
         let s = "hello,world";
-        let ev = new_data_event(100, s.as_bytes());
+        let mut ev = new_data_event(100, s.as_bytes(), false);
         let mut ctx = CryptoContext::new(
             METHOD_CHACHA20_POLY1305,
             "21321321321321312321321321212asdfasdasdas1",
             21321312,
         );
+        let mut decrypt_ctx = CryptoContext::new(
+            METHOD_CHACHA20_POLY1305,
+            "21321321321321312321321321212asdfasdasdas1",
+            21321312,
+        );
         let mut buf = BytesMut::new();
-        ctx.encrypt(&ev, &mut buf);
+        ctx.encrypt(&mut ev, &mut buf);
         println!(
             "encoded buf len:{} {} {} {}",
             buf.capacity(),
@@ -388,7 +806,7 @@ mod tests {
             ev.header.stream_id
         );
 
-        let r = ctx.decrypt(&mut buf).unwrap();
+        let r = decrypt_ctx.decrypt(&mut buf).unwrap();
         println!(
             "decode event len:{} {}",
             r.header.flag_len, r.header.stream_id
@@ -401,14 +819,14 @@ mod tests {
 
     #[test]
     fn test_crypto3() {
-        let ev = new_fin_event(100);
+        let mut ev = new_fin_event(100, false);
         let mut ctx = CryptoContext::new(
             "none",
             "21321321321321312321321321212asdfasdasdas1",
             21321312,
         );
         let mut buf = BytesMut::new();
-        ctx.encrypt(&ev, &mut buf);
+        ctx.encrypt(&mut ev, &mut buf);
         println!("encoded buf len:{} {}", buf.capacity(), buf.len());
 
         let r = ctx.decrypt(&mut buf).unwrap();
@@ -420,14 +838,14 @@ mod tests {
     #[test]
     fn test_crypto4() {
         let s = "hello,world";
-        let ev = new_data_event(100, s.as_bytes());
+        let mut ev = new_data_event(100, s.as_bytes(), false);
         let mut ctx = CryptoContext::new(
             "none",
             "21321321321321312321321321212asdfasdasdas1",
             21321312,
         );
         let mut buf = BytesMut::new();
-        ctx.encrypt(&ev, &mut buf);
+        ctx.encrypt(&mut ev, &mut buf);
         println!(
             "encoded buf len:{} {} {} {}",
             buf.capacity(),
@@ -450,14 +868,19 @@ mod tests {
     #[test]
     fn test_crypto5() {
         let s = "hello,world";
-        let ev = new_data_event(100, s.as_bytes());
+        let mut ev = new_data_event(100, s.as_bytes(), false);
         let mut ctx = CryptoContext::new(
             "aes128gcm",
             "21321321321321312321321321212asdfasdasdas1",
             21321312,
         );
+        let mut decrypt_ctx = CryptoContext::new(
+            "aes128gcm",
+            "21321321321321312321321321212asdfasdasdas1",
+            21321312,
+        );
         let mut buf = BytesMut::new();
-        ctx.encrypt(&ev, &mut buf);
+        ctx.encrypt(&mut ev, &mut buf);
         println!(
             "encoded buf len:{} {} {} {}",
             buf.capacity(),
@@ -466,7 +889,7 @@ mod tests {
             ev.header.stream_id
         );
 
-        let r = ctx.decrypt(&mut buf).unwrap();
+        let r = decrypt_ctx.decrypt(&mut buf).unwrap();
         println!(
             "decode event len:{} {}",
             r.header.flag_len, r.header.stream_id
@@ -476,4 +899,255 @@ mod tests {
         assert_eq!(buf.len(), 0);
         assert_eq!(str::from_utf8(&r.body[..]).unwrap(), s);
     }
+
+    #[test]
+    fn test_nonce_exhausted() {
+        let key = "21321321321321312321321321212asdfasdasdas1";
+        let start = u64::max_value() - 1;
+        let mut encrypt_ctx = CryptoContext::new(METHOD_CHACHA20_POLY1305, key, start);
+        let mut decrypt_ctx = CryptoContext::new(METHOD_CHACHA20_POLY1305, key, start);
+        encrypt_ctx.set_nonce_limit(2);
+        decrypt_ctx.set_nonce_limit(2);
+        let mut buf = BytesMut::new();
+        for _ in 0..3 {
+            assert!(!decrypt_ctx.is_nonce_exhausted());
+            let mut ev = new_data_event(100, "hello".as_bytes(), false);
+            encrypt_ctx.encrypt(&mut ev, &mut buf);
+        }
+        assert!(encrypt_ctx.is_nonce_exhausted());
+        assert!(decrypt_ctx.decrypt(&mut buf).is_ok());
+        assert!(decrypt_ctx.decrypt(&mut buf).is_ok());
+        assert!(decrypt_ctx.is_nonce_exhausted());
+        assert!(decrypt_ctx.decrypt(&mut buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_event_close_kinds() {
+        let key = "21321321321321312321321321212asdfasdasdas1";
+        let mut frame = BytesMut::new();
+        let mut ev = new_data_event(100, "hello".as_bytes(), false);
+        CryptoContext::new(METHOD_CHACHA20_POLY1305, key, 1).encrypt(&mut ev, &mut frame);
+        let read = |data: Vec<u8>| async move {
+            let mut ctx = CryptoContext::new(METHOD_CHACHA20_POLY1305, key, 1);
+            let mut recv_buf = BytesMut::new();
+            read_encrypt_event(&mut ctx, &mut &data[..], &mut recv_buf).await
+        };
+
+        assert!(read(frame.to_vec()).await.unwrap().is_some());
+        assert!(read(Vec::new()).await.unwrap().is_none());
+        let truncated = frame[..frame.len() - 3].to_vec();
+        let err = read(truncated).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        let mut tampered = frame.to_vec();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        let err = read(tampered).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(RmuxError::from_io(&err), Some(RmuxError::AuthFailure));
+    }
+
+    #[test]
+    fn test_auth_only_frames() {
+        let key = "21321321321321312321321321212asdfasdasdas1";
+        let new_ctx = |method| {
+            let mut ctx = CryptoContext::new(method, key, 7);
+            ctx.set_auth_only(true);
+            ctx
+        };
+        for method in &[METHOD_CHACHA20_POLY1305, METHOD_AES128_GCM] {
+            let mut encrypt_ctx = new_ctx(method);
+            let mut decrypt_ctx = new_ctx(method);
+            let mut buf = BytesMut::new();
+            encrypt_ctx.encrypt(&mut new_data_event(1, b"hello", false), &mut buf);
+            encrypt_ctx.encrypt(&mut new_fin_event(1, false), &mut buf);
+            // the body goes out as is
+            assert_eq!(&buf[EVENT_HEADER_LEN..EVENT_HEADER_LEN + 5], b"hello");
+            let mut tampered = buf.clone();
+            tampered[EVENT_HEADER_LEN] ^= 1;
+            assert!(new_ctx(method).decrypt(&mut tampered).is_err());
+            let mut full = CryptoContext::new(method, key, 7);
+            assert!(full.decrypt(&mut buf.clone()).is_err());
+
+            assert_eq!(&decrypt_ctx.decrypt(&mut buf).unwrap().body[..], b"hello");
+            assert_eq!(decrypt_ctx.decrypt(&mut buf).unwrap().header.stream_id, 1);
+            assert!(buf.is_empty());
+        }
+    }
+
+    // an oversized frame fails on its header alone, its body is never waited for
+    #[test]
+    fn test_body_len_checked_on_header() {
+        let key = "21321321321321312321321321212asdfasdasdas1";
+        let max = DEFAULT_MAX_EVENT_BODY_LEN as usize;
+        for method in &[METHOD_NONE, METHOD_CHACHA20_POLY1305] {
+            let header_of = |len: usize| {
+                let mut ctx = CryptoContext::new(method, key, 7);
+                let mut buf = BytesMut::new();
+                ctx.encrypt(&mut new_data_event(1, &vec![0u8; len][..], false), &mut buf);
+                buf.truncate(EVENT_HEADER_LEN);
+                buf
+            };
+            let mut buf = header_of(max + 1);
+            let err = CryptoContext::new(method, key, 7).decrypt(&mut buf);
+            assert_eq!(err.unwrap_err(), (0, "event body too large"));
+
+            // up to the max the rest of the frame is read
+            let mut buf = header_of(max);
+            let (missing, msg) = CryptoContext::new(method, key, 7)
+                .decrypt(&mut buf)
+                .unwrap_err();
+            assert!(missing as usize >= max);
+            assert_eq!(msg, "");
+        }
+    }
+
+    #[test]
+    fn test_shared_auth_only_frames() {
+        let channel = "test_shared_auth_only_frames";
+        assert!(share_channel_auth_only_frames(channel, true));
+        assert!(share_channel_auth_only_frames(channel, true));
+        assert!(!share_channel_auth_only_frames(channel, false));
+        assert!(auth_only_frames(channel));
+        set_channel_auth_only_frames(channel, false);
+    }
+
+    // auth-only frames skip the cipher, only the tag is computed over them
+    #[test]
+    fn test_auth_only_encrypt_time() {
+        let key = "21321321321321312321321321212asdfasdasdas1";
+        let body = vec![1u8; 16 * 1024];
+        let time = |auth_only| {
+            let mut ctx = CryptoContext::new(METHOD_CHACHA20_POLY1305, key, 7);
+            ctx.set_auth_only(auth_only);
+            let mut buf = BytesMut::new();
+            let start = Instant::now();
+            for _ in 0..2000 {
+                ctx.encrypt(&mut new_data_event(1, &body[..], false), &mut buf);
+                buf.clear();
+            }
+            start.elapsed()
+        };
+        // warm up
+        time(false);
+        let full = time(false);
+        let auth_only = time(true);
+        info!("encrypt 32MB full:{:?} auth only:{:?}", full, auth_only);
+        assert!(auth_only < full);
+    }
+
+    #[test]
+    fn test_password_keys() {
+        let salt = b"test_password_keys";
+        let mut encrypt_ctx =
+            CryptoContext::from_password(METHOD_CHACHA20_POLY1305, "pass", salt, 7);
+        let mut decrypt_ctx =
+            CryptoContext::from_password(METHOD_CHACHA20_POLY1305, "pass", salt, 7);
+        let mut buf = BytesMut::new();
+        encrypt_ctx.encrypt(&mut new_data_event(1, b"hello", false), &mut buf);
+        let ev = decrypt_ctx.decrypt(&mut buf).unwrap();
+        assert_eq!(&ev.body[..], b"hello");
+
+        let channel = "test_password_keys";
+        set_channel_password(channel, "pass");
+        let encrypt = |nonce| {
+            let mut ctx =
+                CryptoContext::new_for_channel(channel, METHOD_CHACHA20_POLY1305, "", nonce);
+            let mut buf = BytesMut::new();
+            ctx.encrypt(&mut new_data_event(1, b"hello", false), &mut buf);
+            buf
+        };
+        // the password itself is no key
+        let mut buf = encrypt(7);
+        let mut other = CryptoContext::new_for_channel(channel, METHOD_CHACHA20_POLY1305, "", 7);
+        assert_eq!(&other.decrypt(&mut buf).unwrap().body[..], b"hello");
+        let mut buf = encrypt(8);
+        let mut wrong = CryptoContext::new(METHOD_CHACHA20_POLY1305, "pass", 8);
+        assert!(wrong.decrypt(&mut buf).is_err());
+        set_channel_password(channel, "");
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation() {
+        let channel = "test_key_rotation";
+        let (old, new) = ("rotated out key", "rotated in key");
+        rotate_channel_key(channel, old, new, Duration::from_secs(60));
+        assert_eq!(channel_key_epoch(channel), 1);
+        // set again, e.g. by a reload, it's the same rotation
+        rotate_channel_key(channel, old, new, Duration::from_secs(60));
+        assert_eq!(channel_key_epoch(channel), 1);
+        let read = |key: &'static str| async move {
+            let mut frame = BytesMut::new();
+            let mut ev = new_data_event(0, b"auth", false);
+            ev.header.set_flag(FLAG_AUTH);
+            CryptoContext::new(METHOD_CHACHA20_POLY1305, key, 0).encrypt(&mut ev, &mut frame);
+            let mut recv_buf = BytesMut::new();
+            let data = frame.to_vec();
+            read_auth_event(
+                channel,
+                METHOD_CHACHA20_POLY1305,
+                old,
+                &mut &data[..],
+                &mut recv_buf,
+            )
+            .await
+        };
+        for key in [old, new].iter() {
+            let (ev, used) = read(*key).await.unwrap().unwrap();
+            assert_eq!(&ev.body[..], b"auth");
+            assert_eq!(used, *key);
+        }
+        let err = read("never the key").await.unwrap_err();
+        assert_eq!(RmuxError::from_io(&err), Some(RmuxError::AuthFailure));
+
+        let method = METHOD_CHACHA20_POLY1305;
+        assert_eq!(
+            CryptoContext::new_for_channel_key(channel, method, old, 3).key_epoch(),
+            0
+        );
+        assert_eq!(
+            CryptoContext::new_for_channel_key(channel, method, new, 3).key_epoch(),
+            1
+        );
+
+        // once grace ran out only the new key is taken
+        rotate_channel_key(channel, new, old, Duration::from_secs(0));
+        assert_eq!(channel_key_epoch(channel), 2);
+        assert!(previous_channel_key(channel).is_none());
+        assert!(read(new).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_previous_key_garbage_header() {
+        let channel = "test_previous_key_garbage_header";
+        let method = METHOD_CHACHA20_POLY1305;
+        // the current key is tried first, on about 1 in 256 previous keys its garbage header
+        // reads as a window update
+        for i in 0..2048 {
+            let old = format!("previous key {}", i);
+            rotate_channel_key(
+                channel,
+                old.as_str(),
+                "current key",
+                Duration::from_secs(60),
+            );
+            let mut frame = BytesMut::new();
+            let mut ev = new_data_event(0, b"auth", false);
+            ev.header.set_flag(FLAG_AUTH);
+            CryptoContext::new(method, old.as_str(), 0).encrypt(&mut ev, &mut frame);
+            let data = frame.to_vec();
+            let mut recv_buf = BytesMut::new();
+            let (ev, used) = read_auth_event(
+                channel,
+                method,
+                "current key",
+                &mut &data[..],
+                &mut recv_buf,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert_eq!(&ev.body[..], b"auth");
+            assert_eq!(used, old);
+        }
+    }
 }
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::delay_for;
+
+// SYNs allowed to wait for a dial permit, as a multiple of the limit
+const DIAL_QUEUE_FACTOR: usize = 4;
+
+pub const DEFAULT_DIAL_RETRY_DELAY_MS: u64 = 200;
+pub const DEFAULT_DIAL_RETRY_MAX_MS: u64 = 5000;
+
+struct DialLimiter {
+    limit: usize,
+    sem: Arc<Semaphore>,
+    waiting: AtomicUsize,
+}
+
+#[derive(Clone, Copy)]
+struct DialRetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    // no attempt starts after this much time since the first
+    max_total: Duration,
+}
+
+lazy_static! {
+    static ref DIAL_LIMITERS: Mutex<HashMap<String, Arc<DialLimiter>>> = Mutex::new(HashMap::new());
+    static ref DIAL_RETRY_POLICIES: Mutex<HashMap<String, DialRetryPolicy>> =
+        Mutex::new(HashMap::new());
+}
+
+// bound concurrent outbound dials of streams opened by the peer, 0 removes the limit
+pub fn set_channel_dial_limit(channel: &str, limit: usize) {
+    let mut limiters = DIAL_LIMITERS.lock().unwrap();
+    if limit == 0 {
+        limiters.remove(channel);
+        return;
+    }
+    if let Some(l) = limiters.get(channel) {
+        if l.limit == limit {
+            return;
+        }
+    }
+    limiters.insert(
+        String::from(channel),
+        Arc::new(DialLimiter {
+            limit,
+            sem: Arc::new(Semaphore::new(limit)),
+            waiting: AtomicUsize::new(0),
+        }),
+    );
+}
+
+pub(crate) struct DialTicket {
+    limiter: Option<Arc<DialLimiter>>,
+    // counted in the limiter's waiting SYNs until the first permit
+    waiting: bool,
+}
+
+// returns None if the channel already has too many SYNs waiting to dial
+pub(crate) fn get_dial_ticket(channel: &str) -> Option<DialTicket> {
+    let limiter = DIAL_LIMITERS.lock().unwrap().get(channel).cloned();
+    if let Some(l) = &limiter {
+        if l.waiting.fetch_add(1, Ordering::SeqCst) >= l.limit * DIAL_QUEUE_FACTOR {
+            l.waiting.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+    }
+    let waiting = limiter.is_some();
+    Some(DialTicket { limiter, waiting })
+}
+
+impl DialTicket {
+    // the dial permit is released once the returned value is dropped, a retry acquires again
+    pub(crate) async fn acquire(&mut self) -> Option<OwnedSemaphorePermit> {
+        let l = self.limiter.clone()?;
+        let permit = l.sem.clone().acquire_owned().await;
+        if self.waiting {
+            self.waiting = false;
+            l.waiting.fetch_sub(1, Ordering::SeqCst);
+        }
+        Some(permit)
+    }
+}
+
+impl Drop for DialTicket {
+    fn drop(&mut self) {
+        if let Some(l) = &self.limiter {
+            if self.waiting {
+                l.waiting.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+// Retry failed outbound dials of streams opened by the peer, the delay doubles after each
+// attempt. No attempt starts max_total_ms after the first, max_attempts <= 1 fails fast.
+pub fn set_channel_dial_retry(
+    channel: &str,
+    max_attempts: u32,
+    base_delay_ms: u64,
+    max_total_ms: u64,
+) {
+    let mut policies = DIAL_RETRY_POLICIES.lock().unwrap();
+    if max_attempts <= 1 {
+        policies.remove(channel);
+        return;
+    }
+    policies.insert(
+        String::from(channel),
+        DialRetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(base_delay_ms),
+            max_total: Duration::from_millis(max_total_ms),
+        },
+    );
+}
+
+// each attempt holds a dial permit of the ticket, it's released while backing off
+pub(crate) async fn dial_with_retry<F, Fut, T>(
+    channel: &str,
+    ticket: &mut DialTicket,
+    mut dial: F,
+) -> std::io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = std::io::Result<T>>,
+{
+    let policy = DIAL_RETRY_POLICIES.lock().unwrap().get(channel).copied();
+    let started = Instant::now();
+    let mut attempt = 1;
+    loop {
+        let result = {
+            let _permit = ticket.acquire().await;
+            dial().await
+        };
+        let err = match result {
+            Ok(c) => return Ok(c),
+            Err(e) => e,
+        };
+        let policy = match policy {
+            Some(p) if attempt < p.max_attempts => p,
+            _ => return Err(err),
+        };
+        let delay = policy.base_delay * (1 << std::cmp::min(attempt - 1, 10));
+        if started.elapsed() + delay >= policy.max_total {
+            return Err(err);
+        }
+        warn!(
+            "[{}]Dial attempt {} failed:{}, retry in {:?}",
+            channel, attempt, err, delay
+        );
+        delay_for(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    #[tokio::test]
+    async fn test_dial_retry() {
+        let channel = "test_dial_retry";
+        set_channel_dial_limit(channel, 1);
+        set_channel_dial_retry(channel, 3, 10, 1000);
+        let counter = AtomicU32::new(0);
+        let attempts = &counter;
+        let dial = move || async move {
+            if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                return Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+            }
+            Ok(())
+        };
+        let mut ticket = get_dial_ticket(channel).unwrap();
+        assert!(dial_with_retry(channel, &mut ticket, dial).await.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // the total time cap stops retrying before max_attempts
+        set_channel_dial_retry(channel, 10, 50, 120);
+        attempts.store(0, Ordering::SeqCst);
+        let fail = move || async move {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+        };
+        assert!(dial_with_retry(channel, &mut ticket, fail).await.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        drop(ticket);
+        let l = DIAL_LIMITERS.lock().unwrap().get(channel).cloned().unwrap();
+        assert_eq!(l.waiting.load(Ordering::SeqCst), 0);
+        assert_eq!(l.sem.available_permits(), 1);
+    }
+}
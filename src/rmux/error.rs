@@ -0,0 +1,42 @@
+use std::error::Error;
+use std::fmt;
+
+// Carried inside the io::Error returned by the rmux API, use RmuxError::from_io to tell
+// them apart from plain IO failures.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RmuxError {
+    // every session of the channel is saturated, the caller should shed load or fall back
+    Overloaded,
+    // the channel already has as many live sessions as set_channel_max_sessions allows
+    TooManySessions,
+    // a frame failed authentication, it was tampered with or the peer has another key
+    AuthFailure,
+    // the target's name didn't resolve, dialers wrap the lookup error in it so the stream's
+    // FIN tells a DNS failure apart from other dial errors
+    DnsFailure,
+}
+
+impl RmuxError {
+    pub fn from_io(e: &std::io::Error) -> Option<RmuxError> {
+        e.get_ref()?.downcast_ref::<RmuxError>().copied()
+    }
+}
+
+impl fmt::Display for RmuxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RmuxError::Overloaded => write!(f, "all sessions of the channel are overloaded"),
+            RmuxError::TooManySessions => write!(f, "the channel has its max sessions"),
+            RmuxError::AuthFailure => write!(f, "frame failed authentication"),
+            RmuxError::DnsFailure => write!(f, "failed to resolve the target"),
+        }
+    }
+}
+
+impl Error for RmuxError {}
+
+impl From<RmuxError> for std::io::Error {
+    fn from(e: RmuxError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::Other, e)
+    }
+}
@@ -1,16 +1,39 @@
 //use tokio::codec::{Decoder, Encoder};
+use super::hooks::FinReason;
+use super::message::{ConnectRequest, Hello};
+use super::multipath::MP_DATA_HEADER_LEN;
+use std::convert::TryInto;
 
 pub const FLAG_SYN: u8 = 1;
 pub const FLAG_FIN: u8 = 2;
 pub const FLAG_DATA: u8 = 3;
+// The header len is an additive credit, the bytes the receiver consumed since its last update.
+// The sender adds it to the stream's send window, so updates may arrive in any order or close
+// together without one overwriting another.
 pub const FLAG_WIN_UPDATE: u8 = 4;
 pub const FLAG_PING: u8 = 5;
 pub const FLAG_AUTH: u8 = 6;
 pub const FLAG_SHUTDOWN: u8 = 7;
 pub const FLAG_PONG: u8 = 8;
 pub const FLAG_ROUTINE: u8 = 9;
+pub const FLAG_MP_DATA: u8 = 10;
+// DATA prefixed with a per stream sequence(u32), only sent while the seq check is on
+pub const FLAG_SEQ_DATA: u8 = 11;
+// Session level, the sender is over its buffer budget and asks the peer to hold its DATA.
+// A PAUSE lapses unless refreshed, RESUME ends it early. Both are idempotent.
+pub const FLAG_PAUSE: u8 = 12;
+pub const FLAG_RESUME: u8 = 13;
+// The target of the stream is connected, a FIN coming first means the connect failed.
+pub const FLAG_CONNECTED: u8 = 14;
+// A leading piece of a SYN body too large for one frame, the SYN carries the last piece.
+pub const FLAG_SYN_PART: u8 = 15;
+
+// SYN bodies over it are split into FLAG_SYN_PART frames of this size for peers knowing them
+pub const SYN_PART_LEN: usize = 16 * 1024;
 
 pub const EVENT_HEADER_LEN: usize = 8;
+// the len field of the header is 24 bits
+pub const MAX_WINDOW_UPDATE_CREDIT: u32 = 0xFF_FFFF;
 
 pub fn get_event_type_str(flags: u8) -> &'static str {
     match flags {
@@ -22,6 +45,12 @@ pub fn get_event_type_str(flags: u8) -> &'static str {
         FLAG_AUTH => "FLAG_AUTH",
         FLAG_SHUTDOWN => "FLAG_SHUTDOWN",
         FLAG_PONG => "FLAG_PONG",
+        FLAG_MP_DATA => "FLAG_MP_DATA",
+        FLAG_SEQ_DATA => "FLAG_SEQ_DATA",
+        FLAG_PAUSE => "FLAG_PAUSE",
+        FLAG_RESUME => "FLAG_RESUME",
+        FLAG_CONNECTED => "FLAG_CONNECTED",
+        FLAG_SYN_PART => "FLAG_SYN_PART",
         _ => "INVALID",
     }
 }
@@ -64,11 +93,154 @@ pub struct Event {
     pub remote: bool,
 }
 
+// What a frame carries, its body parsed by its flags. The body of a SYN stays encoded since
+// it may be the last of several frames, see FLAG_SYN_PART.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    Auth(Vec<u8>),
+    Syn(Vec<u8>),
+    SynPart(Vec<u8>),
+    // frames is set for a multipath stream, the count sent over all its paths
+    Fin {
+        reason: FinReason,
+        frames: Option<u32>,
+    },
+    Data(Vec<u8>),
+    SeqData {
+        seq: u32,
+        data: Vec<u8>,
+    },
+    // an empty data joins the session as a path of the multipath stream
+    MpData {
+        mp_id: u64,
+        seq: u32,
+        data: Vec<u8>,
+    },
+    WindowUpdate {
+        credit: u32,
+    },
+    Ping,
+    Pong,
+    Shutdown,
+    Routine,
+    Pause,
+    Resume,
+    Connected,
+    // the flags of a frame whose body is too short for them
+    Malformed(u8),
+    Unknown(u8),
+}
+
+impl EventKind {
+    pub fn flags(&self) -> u8 {
+        match self {
+            EventKind::Auth(_) => FLAG_AUTH,
+            EventKind::Syn(_) => FLAG_SYN,
+            EventKind::SynPart(_) => FLAG_SYN_PART,
+            EventKind::Fin { .. } => FLAG_FIN,
+            EventKind::Data(_) => FLAG_DATA,
+            EventKind::SeqData { .. } => FLAG_SEQ_DATA,
+            EventKind::MpData { .. } => FLAG_MP_DATA,
+            EventKind::WindowUpdate { .. } => FLAG_WIN_UPDATE,
+            EventKind::Ping => FLAG_PING,
+            EventKind::Pong => FLAG_PONG,
+            EventKind::Shutdown => FLAG_SHUTDOWN,
+            EventKind::Routine => FLAG_ROUTINE,
+            EventKind::Pause => FLAG_PAUSE,
+            EventKind::Resume => FLAG_RESUME,
+            EventKind::Connected => FLAG_CONNECTED,
+            EventKind::Malformed(flags) | EventKind::Unknown(flags) => *flags,
+        }
+    }
+}
+
+fn new_event(sid: u32, flags: u8, body: Vec<u8>, remote: bool) -> Event {
+    Event {
+        header: Header {
+            flag_len: get_flag_len(body.len() as u32, flags),
+            stream_id: sid,
+        },
+        body,
+        remote,
+    }
+}
+
 impl Event {
     #[allow(dead_code)]
     pub fn is_empty(&self) -> bool {
         self.header.flags() == 0 as u8
     }
+    // the frame as the new_*_event functions build it, bodies are moved, not copied
+    pub fn from_kind(sid: u32, kind: EventKind, remote: bool) -> Event {
+        let flags = kind.flags();
+        let mut ev = match kind {
+            EventKind::Auth(body) | EventKind::Syn(body) | EventKind::SynPart(body) => {
+                new_event(sid, flags, body, remote)
+            }
+            EventKind::Data(data) => new_event(sid, flags, data, remote),
+            EventKind::Fin {
+                reason,
+                frames: Some(frames),
+            } => new_mp_fin_event(sid, frames, reason),
+            EventKind::Fin { reason, .. } => new_fin_event_with_reason(sid, reason),
+            EventKind::SeqData { seq, data } => new_seq_data_event(sid, seq, &data[..]),
+            EventKind::MpData { mp_id, seq, data } => {
+                let mut ev = new_mp_data_event(mp_id, seq, &data[..]);
+                ev.header.stream_id = sid;
+                ev
+            }
+            EventKind::WindowUpdate { credit } => new_window_update_event(sid, credit, remote),
+            _ => new_event(sid, flags, Vec::new(), remote),
+        };
+        ev.remote = remote;
+        ev
+    }
+    pub fn into_kind(self) -> EventKind {
+        let flags = self.header.flags();
+        let body = self.body;
+        match flags {
+            FLAG_AUTH => EventKind::Auth(body),
+            FLAG_SYN => EventKind::Syn(body),
+            FLAG_SYN_PART => EventKind::SynPart(body),
+            FLAG_FIN => EventKind::Fin {
+                reason: get_fin_reason(&body[..]),
+                frames: if body.len() >= 4 {
+                    Some(u32::from_le_bytes(body[0..4].try_into().unwrap()))
+                } else {
+                    None
+                },
+            },
+            FLAG_DATA => EventKind::Data(body),
+            FLAG_SEQ_DATA if body.len() >= 4 => {
+                let seq = u32::from_le_bytes(body[0..4].try_into().unwrap());
+                let mut data = body;
+                data.drain(..4);
+                EventKind::SeqData { seq, data }
+            }
+            FLAG_MP_DATA if body.len() >= MP_DATA_HEADER_LEN => {
+                let mp_id = u64::from_le_bytes(body[0..8].try_into().unwrap());
+                let seq = u32::from_le_bytes(body[8..12].try_into().unwrap());
+                let mut data = body;
+                data.drain(..MP_DATA_HEADER_LEN);
+                EventKind::MpData { mp_id, seq, data }
+            }
+            FLAG_SEQ_DATA | FLAG_MP_DATA => EventKind::Malformed(flags),
+            FLAG_WIN_UPDATE => EventKind::WindowUpdate {
+                credit: self.header.len(),
+            },
+            FLAG_PING => EventKind::Ping,
+            FLAG_PONG => EventKind::Pong,
+            FLAG_SHUTDOWN => EventKind::Shutdown,
+            FLAG_ROUTINE => EventKind::Routine,
+            FLAG_PAUSE => EventKind::Pause,
+            FLAG_RESUME => EventKind::Resume,
+            FLAG_CONNECTED => EventKind::Connected,
+            _ => EventKind::Unknown(flags),
+        }
+    }
+    pub fn kind(&self) -> EventKind {
+        self.clone().into_kind()
+    }
 }
 
 #[allow(dead_code)]
@@ -83,20 +255,42 @@ pub fn new_empty_event(remote: bool) -> Event {
     }
 }
 
-pub fn new_auth_event<T: serde::Serialize>(sid: u32, msg: &T) -> Event {
-    let data = bincode::serialize(msg).unwrap();
+// the local hello follows the message, see decode_auth
+pub fn new_auth_event<T: serde::Serialize>(channel: &str, sid: u32, msg: &T) -> Event {
+    let mut data = bincode::serialize(msg).unwrap();
+    data.extend_from_slice(&bincode::serialize(&Hello::local_for(channel)).unwrap());
     let mut ev = new_data_event(sid, &data[..], false);
     ev.header.set_flag(FLAG_AUTH);
     ev
 }
 
-pub fn new_syn_event<T: serde::Serialize>(sid: u32, msg: &T) -> Event {
-    let data = bincode::serialize(msg).unwrap();
+pub fn new_syn_event(sid: u32, msg: &ConnectRequest) -> Event {
+    let data = msg.encode();
     let mut ev = new_data_event(sid, &data[..], false);
     ev.header.set_flag(FLAG_SYN);
     ev
 }
 
+// the SYN alone unless the body spans several frames, see FLAG_SYN_PART
+pub fn new_syn_events(sid: u32, msg: &ConnectRequest, spanning: bool) -> Vec<Event> {
+    let data = msg.encode();
+    if !spanning || data.len() <= SYN_PART_LEN {
+        return vec![new_syn_event(sid, msg)];
+    }
+    let mut events: Vec<Event> = data
+        .chunks(SYN_PART_LEN)
+        .map(|part| {
+            let mut ev = new_data_event(sid, part, false);
+            ev.header.set_flag(FLAG_SYN_PART);
+            ev
+        })
+        .collect();
+    if let Some(last) = events.last_mut() {
+        last.header.set_flag(FLAG_SYN);
+    }
+    events
+}
+
 pub fn new_fin_event(sid: u32, remote: bool) -> Event {
     Event {
         header: Header {
@@ -108,6 +302,35 @@ pub fn new_fin_event(sid: u32, remote: bool) -> Event {
     }
 }
 
+// Normal keeps the body empty so peers not knowing reasons read the FIN as before
+pub fn new_fin_event_with_reason(sid: u32, reason: FinReason) -> Event {
+    if reason == FinReason::Normal {
+        return new_fin_event(sid, false);
+    }
+    let mut ev = new_data_event(sid, &[reason as u8], false);
+    ev.header.set_flag(FLAG_FIN);
+    ev
+}
+
+// multipath FIN carries the count of frames sent over all paths, then the optional reason
+pub fn new_mp_fin_event(sid: u32, frames: u32, reason: FinReason) -> Event {
+    let mut body = frames.to_le_bytes().to_vec();
+    if reason != FinReason::Normal {
+        body.push(reason as u8);
+    }
+    let mut ev = new_data_event(sid, &body[..], false);
+    ev.header.set_flag(FLAG_FIN);
+    ev
+}
+
+// the reason is the last byte of a plain (1 byte) or multipath (5 bytes) FIN body
+pub fn get_fin_reason(body: &[u8]) -> FinReason {
+    match body.len() {
+        1 | 5 => FinReason::from_u8(body[body.len() - 1]),
+        _ => FinReason::Normal,
+    }
+}
+
 pub fn new_shutdown_event(sid: u32, remote: bool) -> Event {
     Event {
         header: Header {
@@ -151,6 +374,38 @@ pub fn new_pong_event(sid: u32, remote: bool) -> Event {
     }
 }
 
+pub fn new_connected_event(sid: u32) -> Event {
+    Event {
+        header: Header {
+            flag_len: get_flag_len(0, FLAG_CONNECTED),
+            stream_id: sid,
+        },
+        body: Vec::new(),
+        remote: false,
+    }
+}
+
+pub fn new_pause_event() -> Event {
+    Event {
+        header: Header {
+            flag_len: get_flag_len(0, FLAG_PAUSE),
+            stream_id: 0,
+        },
+        body: Vec::new(),
+        remote: false,
+    }
+}
+pub fn new_resume_event() -> Event {
+    Event {
+        header: Header {
+            flag_len: get_flag_len(0, FLAG_RESUME),
+            stream_id: 0,
+        },
+        body: Vec::new(),
+        remote: false,
+    }
+}
+
 pub fn new_data_event(sid: u32, buf: &[u8], remote: bool) -> Event {
     Event {
         header: Header {
@@ -161,6 +416,20 @@ pub fn new_data_event(sid: u32, buf: &[u8], remote: bool) -> Event {
         remote,
     }
 }
+pub fn new_seq_data_event(sid: u32, seq: u32, buf: &[u8]) -> Event {
+    let mut body = Vec::with_capacity(buf.len() + 4);
+    body.extend_from_slice(&seq.to_le_bytes());
+    body.extend_from_slice(buf);
+    Event {
+        header: Header {
+            flag_len: get_flag_len(body.len() as u32, FLAG_SEQ_DATA),
+            stream_id: sid,
+        },
+        body,
+        remote: false,
+    }
+}
+
 pub fn new_window_update_event(sid: u32, len: u32, remote: bool) -> Event {
     Event {
         header: Header {
@@ -171,3 +440,72 @@ pub fn new_window_update_event(sid: u32, len: u32, remote: bool) -> Event {
         remote,
     }
 }
+
+pub fn new_mp_data_event(mp_id: u64, seq: u32, buf: &[u8]) -> Event {
+    let mut body = Vec::with_capacity(buf.len() + 12);
+    body.extend_from_slice(&mp_id.to_le_bytes());
+    body.extend_from_slice(&seq.to_le_bytes());
+    body.extend_from_slice(buf);
+    Event {
+        header: Header {
+            flag_len: get_flag_len(body.len() as u32, FLAG_MP_DATA),
+            stream_id: 0,
+        },
+        body,
+        remote: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_kind_round_trip() {
+        let kinds = vec![
+            EventKind::Syn(ConnectRequest::default().encode()),
+            EventKind::Fin {
+                reason: FinReason::Normal,
+                frames: None,
+            },
+            EventKind::Fin {
+                reason: FinReason::PolicyDenied,
+                frames: Some(7),
+            },
+            EventKind::Data(vec![1, 2, 3]),
+            EventKind::SeqData {
+                seq: 9,
+                data: vec![4, 5],
+            },
+            EventKind::MpData {
+                mp_id: 11,
+                seq: 2,
+                data: Vec::new(),
+            },
+            EventKind::WindowUpdate { credit: 64 * 1024 },
+            EventKind::Ping,
+            EventKind::Pause,
+        ];
+        for kind in kinds {
+            let ev = Event::from_kind(3, kind.clone(), true);
+            assert_eq!(ev.header.flags(), kind.flags());
+            assert_eq!(ev.header.stream_id, 3);
+            assert!(ev.remote);
+            assert_eq!(ev.into_kind(), kind);
+        }
+        // built the same as before
+        let ev = Event::from_kind(5, EventKind::Data(vec![7u8; 10]), false);
+        assert_eq!(ev.header.len(), 10);
+        assert_eq!(
+            new_fin_event_with_reason(5, FinReason::Reset).kind(),
+            EventKind::Fin {
+                reason: FinReason::Reset,
+                frames: None,
+            }
+        );
+        let mut short = new_seq_data_event(5, 1, &[]);
+        short.body.truncate(2);
+        assert_eq!(short.kind(), EventKind::Malformed(FLAG_SEQ_DATA));
+        assert_eq!(new_empty_event(false).kind(), EventKind::Unknown(0));
+    }
+}
@@ -0,0 +1,140 @@
+use super::stream::{DEFAULT_STREAM_WINDOW, MAX_STREAM_WINDOW};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+// Per stream flow control policy. The stream calls it from both its reader and writer,
+// so implementations keep their state in atomics or behind a lock.
+pub trait FlowController: Send + Sync {
+    // initial window the peer advertised in its SYN
+    fn set_peer_window(&self, window: u32);
+    fn on_data_sent(&self, len: usize);
+    // credit granted by a window update from the peer
+    fn on_window_update(&self, credit: u32);
+    // bytes that may still be sent, writing stalls while it's not positive
+    fn send_window(&self) -> i32;
+    // bytes handed to the local reader
+    fn on_data_received(&self, len: usize);
+    // credit to grant the peer now, 0 to wait for more data to be read
+    fn next_advertised_window(&self) -> u32;
+    // the credit returned by next_advertised_window was queued to the peer
+    fn on_window_advertised(&self, credit: u32);
+}
+
+// receives the recv window of the stream
+pub type FlowControllerFactory = Arc<dyn Fn(u32) -> Box<dyn FlowController> + Send + Sync>;
+
+lazy_static! {
+    static ref FLOW_CONTROLLER_FACTORY: RwLock<Option<FlowControllerFactory>> = RwLock::new(None);
+    static ref INITIAL_SEND_CREDITS: Mutex<HashMap<String, u32>> = Mutex::new(HashMap::new());
+}
+
+// Streams opened on the channel start with this send window instead of DEFAULT_STREAM_WINDOW,
+// so the first burst needn't wait a round trip for the peer's grant. The peer learns it from
+// the SYN and holds back what exceeds its recv window from its first credits. Values up to
+// the default remove the setting.
+pub fn set_channel_initial_send_credit(channel: &str, credit: u32) {
+    let mut credits = INITIAL_SEND_CREDITS.lock().unwrap();
+    if credit <= DEFAULT_STREAM_WINDOW {
+        credits.remove(channel);
+        return;
+    }
+    credits.insert(String::from(channel), credit);
+}
+
+pub(crate) fn get_initial_send_credit(channel: &str) -> Option<u32> {
+    INITIAL_SEND_CREDITS.lock().unwrap().get(channel).copied()
+}
+
+// applies to streams created afterwards, None restores WindowFlowController
+pub fn set_flow_controller_factory(factory: Option<FlowControllerFactory>) {
+    *FLOW_CONTROLLER_FACTORY.write().unwrap() = factory;
+}
+
+pub(crate) fn new_flow_controller(recv_window: u32) -> Box<dyn FlowController> {
+    let factory = FLOW_CONTROLLER_FACTORY.read().unwrap().clone();
+    match factory {
+        Some(f) => f(recv_window),
+        None => Box::new(WindowFlowController::new(recv_window)),
+    }
+}
+
+// Static window: credit is granted back once enough of the recv window has been read.
+pub struct WindowFlowController {
+    recv_window: u32,
+    send_window: AtomicI32,
+    // read but not yet advertised
+    recv_pending: AtomicI32,
+}
+
+impl WindowFlowController {
+    pub fn new(recv_window: u32) -> Self {
+        Self {
+            recv_window: std::cmp::min(recv_window, MAX_STREAM_WINDOW),
+            send_window: AtomicI32::new(DEFAULT_STREAM_WINDOW as i32),
+            recv_pending: AtomicI32::new(0),
+        }
+    }
+}
+
+impl FlowController for WindowFlowController {
+    fn set_peer_window(&self, window: u32) {
+        let window = std::cmp::min(window, MAX_STREAM_WINDOW) as i32;
+        self.send_window.store(window, Ordering::SeqCst);
+    }
+    fn on_data_sent(&self, len: usize) {
+        self.send_window.fetch_sub(len as i32, Ordering::SeqCst);
+    }
+    fn on_window_update(&self, credit: u32) {
+        // saturates, a window wrapped to negative would stall the stream for good
+        let credit = std::cmp::min(credit, MAX_STREAM_WINDOW) as i32;
+        let mut current = self.send_window.load(Ordering::SeqCst);
+        loop {
+            let next = current.saturating_add(credit);
+            match self.send_window.compare_exchange(
+                current,
+                next,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return,
+                Err(v) => current = v,
+            }
+        }
+    }
+    fn send_window(&self) -> i32 {
+        self.send_window.load(Ordering::SeqCst)
+    }
+    fn on_data_received(&self, len: usize) {
+        self.recv_pending.fetch_add(len as i32, Ordering::SeqCst);
+    }
+    fn next_advertised_window(&self) -> u32 {
+        // report early enough for small windows, or the sender may never get credit back
+        let min_report_window = std::cmp::min(32 * 1024, self.recv_window as i32 / 2);
+        let pending = self.recv_pending.load(Ordering::SeqCst);
+        if pending < min_report_window {
+            return 0;
+        }
+        pending as u32
+    }
+    fn on_window_advertised(&self, credit: u32) {
+        self.recv_pending.fetch_sub(credit as i32, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oversized_windows() {
+        let flow = WindowFlowController::new(u32::max_value());
+        flow.set_peer_window(u32::max_value());
+        assert_eq!(flow.send_window(), i32::max_value());
+        flow.on_data_sent(1024);
+        flow.on_window_update(u32::max_value());
+        assert_eq!(flow.send_window(), i32::max_value());
+        flow.on_data_received(64 * 1024);
+        assert_eq!(flow.next_advertised_window(), 64 * 1024);
+    }
+}
@@ -0,0 +1,70 @@
+use super::session::channel_is_healthy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref CHANNEL_GROUPS: Mutex<HashMap<String, Vec<String>>> = Mutex::new(HashMap::new());
+}
+
+// Streams created on the group name go to its first healthy member, in the given order.
+// Groups don't nest, members naming a group are dropped. No members removes the group.
+pub fn define_channel_group(name: &str, members: Vec<String>) {
+    let mut groups = CHANNEL_GROUPS.lock().unwrap();
+    let members: Vec<String> = members
+        .into_iter()
+        .filter(|m| {
+            let nested = m == name || groups.contains_key(m);
+            if nested {
+                warn!("Channel group:{} ignores member:{} naming a group", name, m);
+            }
+            !nested
+        })
+        .collect();
+    if members.is_empty() {
+        groups.remove(name);
+        return;
+    }
+    groups.insert(String::from(name), members);
+}
+
+// the channel itself if it's no group, with no healthy member the first one is used so
+// the caller gets its usual wait or error
+pub(crate) fn resolve_channel(channel: &str) -> String {
+    let members = match CHANNEL_GROUPS.lock().unwrap().get(channel) {
+        Some(members) => members.clone(),
+        None => return String::from(channel),
+    };
+    for m in members.iter() {
+        if channel_is_healthy(m.as_str()) {
+            return m.clone();
+        }
+    }
+    members[0].clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_resolution() {
+        define_channel_group(
+            "test_group_outer",
+            vec![String::from("test_group_a"), String::from("test_group_b")],
+        );
+        // nothing is healthy, the first member takes it
+        assert_eq!(resolve_channel("test_group_outer"), "test_group_a");
+        assert_eq!(resolve_channel("test_group_a"), "test_group_a");
+
+        define_channel_group(
+            "test_group_nested",
+            vec![
+                String::from("test_group_outer"),
+                String::from("test_group_c"),
+            ],
+        );
+        assert_eq!(resolve_channel("test_group_nested"), "test_group_c");
+        define_channel_group("test_group_self", vec![String::from("test_group_self")]);
+        assert_eq!(resolve_channel("test_group_self"), "test_group_self");
+    }
+}
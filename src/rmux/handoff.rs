@@ -0,0 +1,239 @@
+use super::session::{session_exists, session_snapshots};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+// bumped whenever the snapshot's fields change, a snapshot of another version is refused
+pub const HANDOFF_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StreamSnapshot {
+    pub stream_id: u32,
+    pub proto: String,
+    pub target: String,
+    pub metadata: BTreeMap<String, String>,
+    pub send_bytes: u32,
+    pub recv_bytes: u32,
+    pub age_secs: u64,
+    // opened with a resume token, so the peer could carry it on over another session
+    pub resumable: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SessionSnapshot {
+    pub channel: String,
+    pub session_id: u32,
+    pub cipher: String,
+    pub version: u32,
+    pub capabilities: u64,
+    // 1 for the sessions of a client, 2 for the ones of the server
+    pub stream_id_seed: u32,
+    pub age_secs: u64,
+    pub retired: bool,
+    // sent and received over the session's life
+    pub total_bytes: u64,
+    pub streams: Vec<StreamSnapshot>,
+}
+
+// What a process knows of its sessions and streams, to be handed to the process replacing
+// it. The connections and crypto state of the sessions aren't part of it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HandoffSnapshot {
+    pub version: u32,
+    pub taken_unix_secs: u64,
+    pub sessions: Vec<SessionSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandoffError {
+    // the snapshot was taken by a process with another HANDOFF_VERSION
+    Version(u32),
+    // the channel lists the session more than once
+    DuplicateSession {
+        channel: String,
+        session_id: u32,
+    },
+    // a stream id of 0 or listed twice in its session
+    InvalidStream {
+        channel: String,
+        session_id: u32,
+        stream_id: u32,
+    },
+}
+
+impl fmt::Display for HandoffError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandoffError::Version(v) => write!(
+                f,
+                "snapshot version {} while {} is expected",
+                v, HANDOFF_VERSION
+            ),
+            HandoffError::DuplicateSession {
+                channel,
+                session_id,
+            } => write!(f, "[{}][{}]session listed twice", channel, session_id),
+            HandoffError::InvalidStream {
+                channel,
+                session_id,
+                stream_id,
+            } => write!(
+                f,
+                "[{}][{}]invalid stream {}",
+                channel, session_id, stream_id
+            ),
+        }
+    }
+}
+
+impl Error for HandoffError {}
+
+// Takes over a session of a valid snapshot, e.g. from a connection passed along with it,
+// and returns whether the session was registered again.
+pub type SessionReimporter = Arc<dyn Fn(&SessionSnapshot) -> bool + Send + Sync>;
+
+lazy_static! {
+    static ref SESSION_REIMPORTER: RwLock<Option<SessionReimporter>> = RwLock::new(None);
+}
+
+pub fn set_session_reimporter(cb: Option<SessionReimporter>) {
+    *SESSION_REIMPORTER.write().unwrap() = cb;
+}
+
+// the live and retired sessions of every channel, with their streams
+pub fn export_metadata() -> HandoffSnapshot {
+    HandoffSnapshot {
+        version: HANDOFF_VERSION,
+        taken_unix_secs: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        sessions: session_snapshots(),
+    }
+}
+
+fn validate(snapshot: &HandoffSnapshot) -> Result<(), HandoffError> {
+    if snapshot.version != HANDOFF_VERSION {
+        return Err(HandoffError::Version(snapshot.version));
+    }
+    let mut sessions = HashSet::new();
+    for s in snapshot.sessions.iter() {
+        if !sessions.insert((s.channel.as_str(), s.session_id)) {
+            return Err(HandoffError::DuplicateSession {
+                channel: s.channel.clone(),
+                session_id: s.session_id,
+            });
+        }
+        let mut streams = HashSet::new();
+        for st in s.streams.iter() {
+            if st.stream_id == 0 || !streams.insert(st.stream_id) {
+                return Err(HandoffError::InvalidStream {
+                    channel: s.channel.clone(),
+                    session_id: s.session_id,
+                    stream_id: st.stream_id,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+// Checks a snapshot of the process handing off and logs its sessions, each one the
+// reimporter takes is registered again. Without a reimporter nothing is taken over and the
+// peers reconnect as they would after a restart. Sessions whose id is live here already are
+// skipped. Returns the sessions taken over.
+pub fn reimport_metadata(snapshot: &HandoffSnapshot) -> Result<usize, HandoffError> {
+    validate(snapshot)?;
+    let reimporter = SESSION_REIMPORTER.read().unwrap().clone();
+    let mut taken = 0;
+    for s in snapshot.sessions.iter() {
+        let (channel, session_id) = (s.channel.as_str(), s.session_id);
+        if session_exists(channel, session_id) {
+            warn!(channel, session_id, "handed off session is live already");
+            continue;
+        }
+        let reimported = match &reimporter {
+            Some(f) => f(s),
+            None => false,
+        };
+        info!(
+            channel,
+            session_id,
+            streams = s.streams.len(),
+            age_secs = s.age_secs,
+            reimported,
+            "handed off session"
+        );
+        if reimported {
+            taken += 1;
+        }
+    }
+    Ok(taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(stream_id: u32) -> StreamSnapshot {
+        StreamSnapshot {
+            stream_id,
+            proto: String::from("tcp"),
+            target: String::from("192.0.2.1:443"),
+            metadata: BTreeMap::new(),
+            send_bytes: 100,
+            recv_bytes: 2000,
+            age_secs: 5,
+            resumable: false,
+        }
+    }
+
+    #[test]
+    fn test_reimport_metadata() {
+        let channel = "test_reimport_metadata";
+        let mut snapshot = HandoffSnapshot {
+            version: HANDOFF_VERSION,
+            taken_unix_secs: 0,
+            sessions: vec![SessionSnapshot {
+                channel: String::from(channel),
+                session_id: 7,
+                cipher: String::from("chacha20poly1305"),
+                version: 1,
+                capabilities: 0,
+                stream_id_seed: 1,
+                age_secs: 60,
+                retired: false,
+                total_bytes: 2100,
+                streams: vec![stream(1), stream(3)],
+            }],
+        };
+        let data = bincode::serialize(&snapshot).unwrap();
+        let decoded: HandoffSnapshot = bincode::deserialize(&data[..]).unwrap();
+        assert_eq!(decoded, snapshot);
+        // nothing is taken over without a reimporter
+        assert_eq!(reimport_metadata(&decoded), Ok(0));
+
+        snapshot.sessions[0].streams.push(stream(3));
+        assert_eq!(
+            reimport_metadata(&snapshot),
+            Err(HandoffError::InvalidStream {
+                channel: String::from(channel),
+                session_id: 7,
+                stream_id: 3,
+            })
+        );
+        snapshot.sessions[0].streams.pop();
+        snapshot.sessions.push(snapshot.sessions[0].clone());
+        assert!(reimport_metadata(&snapshot).is_err());
+        snapshot.sessions.pop();
+        snapshot.version = HANDOFF_VERSION + 1;
+        assert_eq!(
+            reimport_metadata(&snapshot),
+            Err(HandoffError::Version(HANDOFF_VERSION + 1))
+        );
+    }
+}
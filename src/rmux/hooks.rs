@@ -0,0 +1,269 @@
+use super::error::RmuxError;
+use super::stats::ThroughputSample;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    Open {
+        channel: String,
+        session_id: u32,
+        stream_id: u32,
+        proto: String,
+        addr: String,
+        // labels the requester attached to the stream
+        metadata: BTreeMap<String, String>,
+    },
+    Close {
+        channel: String,
+        session_id: u32,
+        stream_id: u32,
+        send_bytes: u32,
+        recv_bytes: u32,
+        duration: Duration,
+        // the peer's reason if it closed first, else the local one
+        reason: FinReason,
+    },
+}
+
+// Carried as a single byte in the FIN body, an empty body means Normal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinReason {
+    Normal = 0,
+    // the target connection closed or failed
+    TargetClosed = 1,
+    // the stream's keepalive probe went unanswered
+    IdleTimeout = 2,
+    Reset = 3,
+    // the stream was denied by the auth callback or a limit
+    PolicyDenied = 4,
+    // the stream outlived the channel's max stream lifetime, the client may open it anew
+    LifetimeExceeded = 5,
+    // the target couldn't be connected, sent in place of TargetClosed to peers with
+    // CAP_CONNECT_ERRORS: it refused, didn't answer in time, had no route or no address
+    ConnectRefused = 6,
+    ConnectTimeout = 7,
+    Unreachable = 8,
+    DnsFailure = 9,
+}
+
+impl FinReason {
+    // unknown reasons of newer peers read as Normal
+    pub fn from_u8(v: u8) -> FinReason {
+        match v {
+            1 => FinReason::TargetClosed,
+            2 => FinReason::IdleTimeout,
+            3 => FinReason::Reset,
+            4 => FinReason::PolicyDenied,
+            5 => FinReason::LifetimeExceeded,
+            6 => FinReason::ConnectRefused,
+            7 => FinReason::ConnectTimeout,
+            8 => FinReason::Unreachable,
+            9 => FinReason::DnsFailure,
+            _ => FinReason::Normal,
+        }
+    }
+    // why dialing a target failed, TargetClosed if the error doesn't tell
+    pub fn from_connect_error(e: &std::io::Error) -> FinReason {
+        if RmuxError::from_io(e) == Some(RmuxError::DnsFailure) {
+            return FinReason::DnsFailure;
+        }
+        match e.kind() {
+            std::io::ErrorKind::ConnectionRefused => return FinReason::ConnectRefused,
+            std::io::ErrorKind::TimedOut => return FinReason::ConnectTimeout,
+            std::io::ErrorKind::PermissionDenied => return FinReason::PolicyDenied,
+            _ => {}
+        }
+        #[cfg(unix)]
+        {
+            use nix::errno::Errno;
+            let unreachable = [Errno::ENETUNREACH as i32, Errno::EHOSTUNREACH as i32];
+            if e.raw_os_error().map_or(false, |n| unreachable.contains(&n)) {
+                return FinReason::Unreachable;
+            }
+        }
+        FinReason::TargetClosed
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionCloseReason {
+    // no data sent or received for too long
+    IdleTimeout,
+    // pong lagged the ping too long, the session was retired and shut down
+    HeartbeatTimeout,
+    // retired by age or transfered bytes, closed once its last stream closed
+    Retired,
+    // the peer closed the connection
+    PeerClosed,
+    // the connection closed in the middle of a frame, e.g. the peer died while writing it
+    TruncatedFrame,
+    // a frame failed decryption, the connection may have been tampered with
+    AuthFailure,
+    // reading or writing the connection failed
+    IoError,
+    // the peer sent frames with unknown flags, over the channel's UnknownFlagsPolicy, DATA
+    // for unknown streams over its OrphanDataPolicy, or a frame over the max body len
+    ProtocolError,
+    LocalShutdown,
+    // authenticated with the channel's previous key, still open when its rotation ran out
+    KeyRotated,
+}
+
+// What the idle close policy judges a session by, on every routine tick.
+#[derive(Debug, Clone)]
+pub struct SessionSummary {
+    pub channel: String,
+    pub session_id: u32,
+    pub age: Duration,
+    // since data was last sent or received on the connection
+    pub io_idle_secs: u32,
+    pub streams: usize,
+    // since the most recently active stream moved data, None without streams
+    pub stream_idle_secs: Option<u32>,
+    pub retired: bool,
+    // sent and received over the session's life
+    pub total_bytes: u64,
+}
+
+// closes retired sessions without streams, and sessions idle for 5 minutes
+pub fn default_idle_close_policy(s: &SessionSummary) -> bool {
+    (s.retired && s.streams == 0) || s.io_idle_secs >= 300
+}
+
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Established {
+        channel: String,
+        session_id: u32,
+    },
+    Closed {
+        channel: String,
+        session_id: u32,
+        reason: SessionCloseReason,
+        duration: Duration,
+    },
+}
+
+// Progress of one relay between a local conn and a stream or target, by its tunnel or stream id.
+#[derive(Debug, Clone)]
+pub struct RelayProgress {
+    pub id: u32,
+    // copied so far from the local side to the remote side
+    pub client_to_server: u64,
+    pub server_to_client: u64,
+    // since either direction last moved data, as of the interval reports
+    pub stalled_for: Duration,
+    // the last report, both directions finished
+    pub done: bool,
+}
+
+// called from the relay's task, on the copy path for the every_bytes reports, so it should be quick
+pub type RelayProgressCallback = Arc<dyn Fn(&RelayProgress) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct RelayProgressOptions {
+    pub callback: RelayProgressCallback,
+    // report each time either direction passes another multiple of it, 0 for none
+    pub every_bytes: u64,
+    // report this often while the relay runs, e.g. to spot stalled transfers
+    pub interval: Option<Duration>,
+}
+
+pub type StreamCallback = Arc<dyn Fn(StreamEvent) + Send + Sync>;
+// return false to deny the stream, the peer would receive an immediate FIN
+pub type StreamAuthCallback = Arc<dyn Fn(&StreamEvent) -> bool + Send + Sync>;
+pub type SessionCallback = Arc<dyn Fn(SessionEvent) + Send + Sync>;
+// called every routine tick without any lock held
+pub type ThroughputSampler = Arc<dyn Fn(&[ThroughputSample]) + Send + Sync>;
+// maps the service name a stream with proto "service" asks for & its metadata to the addr
+// to dial, None denies the stream
+pub type ServiceResolver =
+    Arc<dyn Fn(&str, &BTreeMap<String, String>) -> Option<String> + Send + Sync>;
+// return true to close the session, called from its event loop so it should be quick
+pub type IdleClosePolicy = Arc<dyn Fn(&SessionSummary) -> bool + Send + Sync>;
+
+lazy_static! {
+    static ref STREAM_CALLBACK: RwLock<Option<StreamCallback>> = RwLock::new(None);
+    static ref STREAM_AUTH_CALLBACK: RwLock<Option<StreamAuthCallback>> = RwLock::new(None);
+    static ref SESSION_CALLBACK: RwLock<Option<SessionCallback>> = RwLock::new(None);
+    static ref THROUGHPUT_SAMPLER: RwLock<Option<ThroughputSampler>> = RwLock::new(None);
+    static ref SERVICE_RESOLVER: RwLock<Option<ServiceResolver>> = RwLock::new(None);
+    static ref IDLE_CLOSE_POLICY: RwLock<Option<IdleClosePolicy>> = RwLock::new(None);
+    static ref RELAY_PROGRESS: RwLock<Option<RelayProgressOptions>> = RwLock::new(None);
+}
+
+pub fn set_stream_callback(cb: Option<StreamCallback>) {
+    *STREAM_CALLBACK.write().unwrap() = cb;
+}
+
+pub fn set_stream_auth_callback(cb: Option<StreamAuthCallback>) {
+    *STREAM_AUTH_CALLBACK.write().unwrap() = cb;
+}
+
+pub fn set_session_callback(cb: Option<SessionCallback>) {
+    *SESSION_CALLBACK.write().unwrap() = cb;
+}
+
+pub fn set_throughput_sampler(cb: Option<ThroughputSampler>) {
+    *THROUGHPUT_SAMPLER.write().unwrap() = cb;
+}
+
+pub fn set_service_resolver(cb: Option<ServiceResolver>) {
+    *SERVICE_RESOLVER.write().unwrap() = cb;
+}
+
+// None restores default_idle_close_policy
+pub fn set_idle_close_policy(cb: Option<IdleClosePolicy>) {
+    *IDLE_CLOSE_POLICY.write().unwrap() = cb;
+}
+
+// taken by relays started after it's set, relays copy without any counting while it's None
+pub fn set_relay_progress(opts: Option<RelayProgressOptions>) {
+    *RELAY_PROGRESS.write().unwrap() = opts;
+}
+
+pub(crate) fn relay_progress() -> Option<RelayProgressOptions> {
+    RELAY_PROGRESS.read().unwrap().clone()
+}
+
+pub(crate) fn should_close_idle(s: &SessionSummary) -> bool {
+    let cb = IDLE_CLOSE_POLICY.read().unwrap().clone();
+    match cb {
+        Some(f) => f(s),
+        None => default_idle_close_policy(s),
+    }
+}
+
+pub(crate) fn notify_stream_event(ev: StreamEvent) {
+    let cb = STREAM_CALLBACK.read().unwrap().clone();
+    if let Some(f) = cb {
+        f(ev);
+    }
+}
+
+pub(crate) fn authorize_stream(ev: &StreamEvent) -> bool {
+    let cb = STREAM_AUTH_CALLBACK.read().unwrap().clone();
+    match cb {
+        Some(f) => f(ev),
+        None => true,
+    }
+}
+
+// without a resolver every service is unknown
+pub(crate) fn resolve_service(name: &str, metadata: &BTreeMap<String, String>) -> Option<String> {
+    let cb = SERVICE_RESOLVER.read().unwrap().clone();
+    cb.and_then(|f| f(name, metadata))
+}
+
+pub(crate) fn throughput_sampler() -> Option<ThroughputSampler> {
+    THROUGHPUT_SAMPLER.read().unwrap().clone()
+}
+
+pub(crate) fn notify_session_event(ev: SessionEvent) {
+    let cb = SESSION_CALLBACK.read().unwrap().clone();
+    if let Some(f) = cb {
+        f(ev);
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Mutex;
+
+// a stream relayed through more mux hops than this is assumed to be looping
+pub const MAX_STREAM_HOPS: u8 = 8;
+
+lazy_static! {
+    static ref SELF_ADDRS: Mutex<Vec<SocketAddr>> = Mutex::new(Vec::new());
+    static ref CHANNEL_SELF_ADDRS: Mutex<HashMap<String, Vec<SocketAddr>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn resolve(addrs: &[String]) -> Vec<SocketAddr> {
+    let mut resolved = Vec::new();
+    for a in addrs {
+        match a.to_socket_addrs() {
+            Ok(iter) => resolved.extend(iter),
+            Err(e) => warn!("Invalid self addr:{} with error:{}", a, e),
+        }
+    }
+    resolved
+}
+
+// Addresses of this process like its listeners, streams of any channel targeting
+// them are refused.
+pub fn add_self_addr(addr: &str) {
+    let resolved = resolve(&[String::from(addr)]);
+    SELF_ADDRS.lock().unwrap().extend(resolved);
+}
+
+// Addresses that reach this proxy only from the channel's peer, e.g. a public ip or a name
+// behind a port mapping. Host names are resolved once here.
+pub fn set_channel_self_addrs(channel: &str, addrs: &[String]) {
+    let mut self_addrs = CHANNEL_SELF_ADDRS.lock().unwrap();
+    if addrs.is_empty() {
+        self_addrs.remove(channel);
+        return;
+    }
+    self_addrs.insert(String::from(channel), resolve(addrs));
+}
+
+// a listener on the unspecified address is also reached over loopback
+fn addr_matches(target: &SocketAddr, self_addr: &SocketAddr) -> bool {
+    if target.port() != self_addr.port() {
+        return false;
+    }
+    target.ip() == self_addr.ip()
+        || (self_addr.ip().is_unspecified()
+            && (target.ip().is_loopback() || target.ip().is_unspecified()))
+}
+
+pub(crate) async fn is_self_addr(channel: &str, target: &str) -> bool {
+    let mut self_addrs = SELF_ADDRS.lock().unwrap().clone();
+    if let Some(addrs) = CHANNEL_SELF_ADDRS.lock().unwrap().get(channel) {
+        self_addrs.extend_from_slice(&addrs[..]);
+    }
+    if self_addrs.is_empty() {
+        return false;
+    }
+    let targets = match tokio::net::lookup_host(target).await {
+        Ok(iter) => iter,
+        // the dial would fail the same way
+        Err(_) => return false,
+    };
+    for t in targets {
+        if self_addrs.iter().any(|s| addr_matches(&t, s)) {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_self_addr() {
+        let channel = "test_self_addr";
+        set_channel_self_addrs(channel, &[String::from("0.0.0.0:48199")]);
+        assert!(is_self_addr(channel, "127.0.0.1:48199").await);
+        assert!(!is_self_addr(channel, "127.0.0.1:48198").await);
+
+        set_channel_self_addrs(channel, &[String::from("10.1.2.3:443")]);
+        assert!(is_self_addr(channel, "10.1.2.3:443").await);
+        assert!(!is_self_addr(channel, "127.0.0.1:443").await);
+        set_channel_self_addrs(channel, &[]);
+        assert!(!is_self_addr(channel, "10.1.2.3:443").await);
+    }
+}
@@ -1,9 +1,165 @@
+use super::crypto::auth_only_frames;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Cursor;
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[derive(PartialEq, Debug, Clone, Default)]
 pub struct ConnectRequest {
     pub proto: String,
     pub addr: String,
+    // 0 means a plain stream bound to one session
+    pub multipath_id: u64,
+    // first bytes to write to the target right after it's connected
+    pub initial_data: Vec<u8>,
+    // recv buffer cap of the requester, 0 means DEFAULT_STREAM_WINDOW
+    pub recv_window: u32,
+    // auth for the upstream proxy of proto "socks5" or "http-connect", empty uses the peer's
+    // configured one
+    pub username: String,
+    pub password: String,
+    // opaque labels of the stream for routing, accounting or policy on the peer
+    pub metadata: BTreeMap<String, String>,
+    // mux hops the request was relayed over before this one, to cap multi proxy loops
+    pub hops: u8,
+    // set for resumable streams, with a non zero offset it reopens one
+    pub resume: Option<ResumeToken>,
+    // send window the requester starts with instead of DEFAULT_STREAM_WINDOW, 0 for the default
+    pub initial_credit: u32,
+    // local DATA of the stream overtakes that of lower priorities on both sides, up to
+    // MAX_STREAM_PRIORITY
+    pub priority: u8,
+}
+
+// Identifies a resumable stream across sessions. recv_offset is how much of the target's
+// output the requester already received, the peer skips it after it redialed the target.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub struct ResumeToken {
+    pub stream_key: u64,
+    pub recv_offset: u64,
+}
+
+// larger payloads are sent as normal DATA events after the SYN
+pub const MAX_INITIAL_DATA_LEN: usize = 16 * 1024;
+// for peers taking SYNs over several frames
+pub const MAX_SPANNING_INITIAL_DATA_LEN: usize = 256 * 1024;
+// total bytes of the metadata keys & values
+pub const MAX_METADATA_LEN: usize = 1024;
+pub const MAX_STREAM_PRIORITY: u8 = 7;
+
+// Optional fields are appended after (proto, addr) in declaration order, older peers
+// ignore the trailing bytes since bincode::deserialize allows them.
+impl ConnectRequest {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = bincode::serialize(&(&self.proto, &self.addr)).unwrap();
+        // only write up to the last non default field
+        let level = if self.priority != 0 {
+            9
+        } else if self.initial_credit != 0 {
+            8
+        } else if self.resume.is_some() {
+            7
+        } else if self.hops != 0 {
+            6
+        } else if !self.metadata.is_empty() {
+            5
+        } else if !self.username.is_empty() || !self.password.is_empty() {
+            4
+        } else if self.recv_window != 0 {
+            3
+        } else if !self.initial_data.is_empty() {
+            2
+        } else if self.multipath_id != 0 {
+            1
+        } else {
+            0
+        };
+        if level >= 1 {
+            data.extend_from_slice(&bincode::serialize(&self.multipath_id).unwrap());
+        }
+        if level >= 2 {
+            data.extend_from_slice(&bincode::serialize(&self.initial_data).unwrap());
+        }
+        if level >= 3 {
+            data.extend_from_slice(&bincode::serialize(&self.recv_window).unwrap());
+        }
+        if level >= 4 {
+            data.extend_from_slice(&bincode::serialize(&(&self.username, &self.password)).unwrap());
+        }
+        if level >= 5 {
+            data.extend_from_slice(&bincode::serialize(&self.metadata).unwrap());
+        }
+        if level >= 6 {
+            data.extend_from_slice(&bincode::serialize(&self.hops).unwrap());
+        }
+        if level >= 7 {
+            data.extend_from_slice(&bincode::serialize(&self.resume).unwrap());
+        }
+        if level >= 8 {
+            data.extend_from_slice(&bincode::serialize(&self.initial_credit).unwrap());
+        }
+        if level >= 9 {
+            data.extend_from_slice(&bincode::serialize(&self.priority).unwrap());
+        }
+        data
+    }
+    pub fn decode(data: &[u8]) -> bincode::Result<Self> {
+        let mut cursor = Cursor::new(data);
+        let (proto, addr): (String, String) = bincode::deserialize_from(&mut cursor)?;
+        let mut req = Self {
+            proto,
+            addr,
+            ..Default::default()
+        };
+        if (cursor.position() as usize) < data.len() {
+            req.multipath_id = bincode::deserialize_from(&mut cursor)?;
+        }
+        if (cursor.position() as usize) < data.len() {
+            req.initial_data = bincode::deserialize_from(&mut cursor)?;
+        }
+        if (cursor.position() as usize) < data.len() {
+            req.recv_window = bincode::deserialize_from(&mut cursor)?;
+        }
+        if (cursor.position() as usize) < data.len() {
+            let (username, password) = bincode::deserialize_from(&mut cursor)?;
+            req.username = username;
+            req.password = password;
+        }
+        if (cursor.position() as usize) < data.len() {
+            req.metadata = bincode::deserialize_from(&mut cursor)?;
+        }
+        if (cursor.position() as usize) < data.len() {
+            req.hops = bincode::deserialize_from(&mut cursor)?;
+        }
+        if (cursor.position() as usize) < data.len() {
+            req.resume = bincode::deserialize_from(&mut cursor)?;
+        }
+        if (cursor.position() as usize) < data.len() {
+            req.initial_credit = bincode::deserialize_from(&mut cursor)?;
+        }
+        if (cursor.position() as usize) < data.len() {
+            let priority: u8 = bincode::deserialize_from(&mut cursor)?;
+            req.priority = std::cmp::min(priority, MAX_STREAM_PRIORITY);
+        }
+        Ok(req)
+    }
+}
+
+// A datagram on a stream with proto "udp", addr is its target when sent by the requester and
+// the source of a reply when sent by the peer.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct UdpDatagram {
+    pub addr: String,
+    pub data: Vec<u8>,
+}
+
+impl UdpDatagram {
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+    pub fn decode(data: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(data)
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -19,3 +175,146 @@ pub struct AuthResponse {
     pub rand: u64,
     pub method: String,
 }
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Capability bits, a feature is only used if both peers announced it.
+// ConnectRequest fields after (proto, addr), without it initial data follows the SYN as DATA
+pub const CAP_CONNECT_EXT: u64 = 1;
+// multipath streams spread over several sessions with FLAG_MP_DATA
+pub const CAP_MULTIPATH: u64 = 1 << 1;
+// FLAG_PAUSE/FLAG_RESUME on memory pressure
+pub const CAP_SESSION_PAUSE: u64 = 1 << 2;
+// FLAG_CONNECTED once the target of a stream opened by the peer is connected
+pub const CAP_CONNECT_RESULT: u64 = 1 << 3;
+// ConnectRequest.resume, the target's output is skipped up to the resume offset
+pub const CAP_STREAM_RESUME: u64 = 1 << 4;
+// ConnectRequest.initial_credit, the requester may send that much before any window update
+pub const CAP_INITIAL_CREDIT: u64 = 1 << 5;
+// frame bodies authenticated but not encrypted, only announced for channels opted in with
+// set_channel_auth_only_frames
+pub const CAP_AUTH_ONLY_FRAMES: u64 = 1 << 6;
+// SYN bodies spanning several frames, see FLAG_SYN_PART
+pub const CAP_SYN_PARTS: u64 = 1 << 7;
+// FIN reasons telling why a target couldn't be connected, e.g. FinReason::ConnectRefused,
+// sent as TargetClosed to peers without it
+pub const CAP_CONNECT_ERRORS: u64 = 1 << 8;
+pub const LOCAL_CAPABILITIES: u64 = CAP_CONNECT_EXT
+    | CAP_MULTIPATH
+    | CAP_SESSION_PAUSE
+    | CAP_CONNECT_RESULT
+    | CAP_STREAM_RESUME
+    | CAP_INITIAL_CREDIT
+    | CAP_SYN_PARTS
+    | CAP_CONNECT_ERRORS;
+
+// Appended by both sides after the auth message. Peers before it send none and ignore it
+// as trailing bytes, they're treated as version 0 without any capability.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub struct Hello {
+    pub version: u32,
+    pub capabilities: u64,
+}
+
+impl Hello {
+    pub fn local() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities: LOCAL_CAPABILITIES,
+        }
+    }
+    // with the bits the channel opted in to
+    pub fn local_for(channel: &str) -> Self {
+        let mut hello = Self::local();
+        if auth_only_frames(channel) {
+            hello.capabilities |= CAP_AUTH_ONLY_FRAMES;
+        }
+        hello
+    }
+    // bits unknown to this side are dropped here
+    pub fn negotiate(&self, peer: &Hello) -> Hello {
+        Hello {
+            version: std::cmp::min(self.version, peer.version),
+            capabilities: self.capabilities & peer.capabilities,
+        }
+    }
+    pub fn has(&self, cap: u64) -> bool {
+        self.capabilities & cap == cap
+    }
+}
+
+// decode an auth message and the peer's hello following it
+pub fn decode_auth<T: DeserializeOwned>(data: &[u8]) -> bincode::Result<(T, Hello)> {
+    let mut cursor = Cursor::new(data);
+    let msg: T = bincode::deserialize_from(&mut cursor)?;
+    let mut hello = Hello::default();
+    if (cursor.position() as usize) < data.len() {
+        hello = bincode::deserialize_from(&mut cursor)?;
+    }
+    Ok((msg, hello))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_request_optional_fields() {
+        let req = ConnectRequest {
+            proto: String::from("socks5"),
+            addr: String::from("example.com:443"),
+            username: String::from("u"),
+            ..Default::default()
+        };
+        assert_eq!(ConnectRequest::decode(&req.encode()[..]).unwrap(), req);
+        let mut req = req;
+        req.metadata
+            .insert(String::from("app"), String::from("backup"));
+        assert_eq!(ConnectRequest::decode(&req.encode()[..]).unwrap(), req);
+        req.hops = 2;
+        assert_eq!(ConnectRequest::decode(&req.encode()[..]).unwrap(), req);
+        req.resume = Some(ResumeToken {
+            stream_key: 7,
+            recv_offset: 4096,
+        });
+        assert_eq!(ConnectRequest::decode(&req.encode()[..]).unwrap(), req);
+        req.initial_credit = 512 * 1024;
+        assert_eq!(ConnectRequest::decode(&req.encode()[..]).unwrap(), req);
+        req.priority = 5;
+        assert_eq!(ConnectRequest::decode(&req.encode()[..]).unwrap(), req);
+        // a legacy peer still reads (proto, addr)
+        let (proto, addr): (String, String) = bincode::deserialize(&req.encode()[..]).unwrap();
+        assert_eq!(
+            (proto.as_str(), addr.as_str()),
+            ("socks5", "example.com:443")
+        );
+    }
+
+    #[test]
+    fn test_hello_fallback_and_unknown_bits() {
+        let req = AuthRequest {
+            method: String::from("chacha20poly1305"),
+        };
+        // a legacy peer sends no hello
+        let data = bincode::serialize(&req).unwrap();
+        let (decoded, hello): (AuthRequest, Hello) = decode_auth(&data[..]).unwrap();
+        assert_eq!(decoded, req);
+        assert_eq!(hello, Hello::default());
+        assert_eq!(Hello::local().negotiate(&hello), Hello::default());
+
+        // a newer peer may announce bits this side doesn't know
+        let peer = Hello {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: LOCAL_CAPABILITIES | 1 << 63,
+        };
+        let mut data = bincode::serialize(&req).unwrap();
+        data.extend_from_slice(&bincode::serialize(&peer).unwrap());
+        let (_, hello): (AuthRequest, Hello) = decode_auth(&data[..]).unwrap();
+        let negotiated = Hello::local().negotiate(&hello);
+        assert_eq!(negotiated.version, PROTOCOL_VERSION);
+        assert_eq!(negotiated.capabilities, LOCAL_CAPABILITIES);
+        // and a legacy server just ignores the trailing hello
+        let decoded: AuthRequest = bincode::deserialize(&data[..]).unwrap();
+        assert_eq!(decoded, req);
+    }
+}
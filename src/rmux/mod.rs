@@ -1,13 +1,118 @@
+mod assembly;
+mod budget;
+mod clock;
 mod crypto;
+mod dial;
+mod error;
 mod event;
+mod flow;
+mod group;
+mod handoff;
+mod hooks;
+mod loops;
 mod message;
+mod multipath;
+mod pause;
+mod pool;
+mod probe;
+mod quality;
+mod redact;
+mod scheduler;
+mod security;
 mod session;
+mod stats;
 mod stream;
+mod tap;
+mod tasks;
+#[cfg(test)]
+mod testing;
+mod traffic;
+mod udp;
+mod upstream;
 
-pub use self::crypto::{read_encrypt_event, write_encrypt_event, CryptoContext};
-pub use self::event::{new_auth_event, Event, FLAG_AUTH};
-pub use self::message::{AuthRequest, AuthResponse};
+pub use self::budget::{buffer_budget, buffer_usage, set_buffer_budget};
+pub use self::clock::{Clock, MockClock, SystemClock};
+pub(crate) use self::crypto::share_channel_auth_only_frames;
+pub use self::crypto::{
+    derive_password_key, get_channel_key, read_auth_event, read_encrypt_event, rotate_channel_key,
+    set_channel_auth_only_frames, set_channel_key, set_channel_password, set_max_event_body_len,
+    write_encrypt_event, CryptoContext, DEFAULT_MAX_EVENT_BODY_LEN, DEFAULT_PREVIOUS_KEY_SECS,
+};
+pub use self::dial::{
+    set_channel_dial_limit, set_channel_dial_retry, DEFAULT_DIAL_RETRY_DELAY_MS,
+    DEFAULT_DIAL_RETRY_MAX_MS,
+};
+pub use self::error::RmuxError;
+pub use self::event::{new_auth_event, Event, EventKind, FLAG_AUTH};
+pub use self::flow::{
+    set_channel_initial_send_credit, set_flow_controller_factory, FlowController,
+    FlowControllerFactory, WindowFlowController,
+};
+pub use self::group::define_channel_group;
+pub use self::handoff::{
+    export_metadata, reimport_metadata, set_session_reimporter, HandoffError, HandoffSnapshot,
+    SessionReimporter, SessionSnapshot, StreamSnapshot, HANDOFF_VERSION,
+};
+pub(crate) use self::hooks::relay_progress;
+pub use self::hooks::{
+    default_idle_close_policy, set_idle_close_policy, set_relay_progress, set_service_resolver,
+    set_session_callback, set_stream_auth_callback, set_stream_callback, set_throughput_sampler,
+    FinReason, IdleClosePolicy, RelayProgress, RelayProgressCallback, RelayProgressOptions,
+    ServiceResolver, SessionCallback, SessionCloseReason, SessionEvent, SessionSummary,
+    StreamAuthCallback, StreamCallback, StreamEvent, ThroughputSampler,
+};
+pub use self::loops::{add_self_addr, set_channel_self_addrs, MAX_STREAM_HOPS};
+pub use self::message::{
+    decode_auth, AuthRequest, AuthResponse, Hello, ResumeToken, UdpDatagram, CAP_AUTH_ONLY_FRAMES,
+    CAP_CONNECT_ERRORS, CAP_CONNECT_EXT, CAP_CONNECT_RESULT, CAP_INITIAL_CREDIT, CAP_MULTIPATH,
+    CAP_SESSION_PAUSE, CAP_STREAM_RESUME, CAP_SYN_PARTS, MAX_INITIAL_DATA_LEN, MAX_METADATA_LEN,
+    MAX_SPANNING_INITIAL_DATA_LEN, MAX_STREAM_PRIORITY, PROTOCOL_VERSION,
+};
+pub use self::multipath::set_channel_multipath;
+pub use self::pool::{set_channel_conn_pool, DEFAULT_CONN_POOL_IDLE_SECS};
+pub use self::probe::{probe, probe_with_payload, ProbeError, ProbeResult};
+pub use self::quality::{set_channel_quality_thresholds, QualityThresholds, SessionQuality};
+pub use self::redact::{redact_addr, set_addr_redaction, AddrRedaction};
+pub use self::scheduler::set_channel_data_quantum;
+pub(crate) use self::security::record_auth_failure;
+pub use self::security::{
+    set_auth_failure_alarm, AuthFailure, AuthFailureAlarm, AUTH_FAILURE_WINDOW,
+};
 pub use self::session::{
-    create_stream, get_channel_session_size, handle_rmux_session, process_rmux_session,
-    routine_all_sessions, MuxContext,
+    channel_is_healthy, channel_stats, close_stream, create_resumable_stream, create_stream,
+    create_stream_with_data, create_stream_with_metadata, create_stream_with_priority,
+    evacuate_session, get_channel_session_size, handle_rmux_session, list_streams, next_tunnel_id,
+    process_rmux_session, resume_stream, routine_all_sessions, session_params,
+    set_channel_max_alive_secs, set_channel_max_sessions, set_channel_orphan_data_policy,
+    set_channel_saturation_thresholds, set_channel_send_dwell_limit, set_channel_stream_wait,
+    set_channel_unknown_flags_policy, set_lock_hold_tracking, set_max_retired_sessions,
+    set_session_weight, shutdown_all, shutdown_channel, MuxContext, OrphanDataPolicy,
+    SaturationThresholds, UnknownFlagsPolicy, WriteBatching, DEFAULT_MAX_ORPHAN_DATA_FRAMES,
+    DEFAULT_MAX_PENDING_STREAMS, DEFAULT_MAX_WAITING_STREAMS, DEFAULT_WRITE_BATCH_FRAMES,
+};
+pub use self::stats::{
+    metrics_snapshot, MetricsSnapshot, SessionParams, SessionStats, StreamStats, ThroughputSample,
+};
+pub(crate) use self::stream::SendWindowGate;
+pub use self::stream::{
+    set_channel_max_stream_lifetime, set_channel_write_coalescing, set_data_seq_check,
+    ConnectResult, DEFAULT_STREAM_WINDOW, MAX_STREAM_WINDOW,
+};
+pub use self::tap::{
+    file_frame_tap, read_tapped_frame, set_frame_tap, write_tapped_frame, FrameDirection, FrameTap,
+    TappedFrame,
+};
+pub(crate) use self::tasks::start_establishing;
+pub use self::tasks::{
+    establishing_session_count, max_establishing_sessions, max_relay_tasks, relay_task_count,
+    set_max_establishing_sessions, set_max_relay_tasks,
+};
+pub use self::traffic::{channel_throughput, channel_total_bytes};
+pub(crate) use self::udp::{get_udp_idle_timeout, run_association, touch, UdpNat};
+pub use self::udp::{
+    read_udp_datagram, set_channel_udp_idle_timeout, write_udp_datagram, DEFAULT_UDP_IDLE_SECS,
+};
+pub use self::upstream::{
+    set_channel_dialer, set_channel_http_connect_upstream, set_channel_socks5_upstream,
+    set_channel_stream_proto, DialFuture, Dialer,
 };
@@ -0,0 +1,323 @@
+use super::event::{new_mp_data_event, Event};
+use super::hooks::FinReason;
+use super::stream::MuxStream;
+use crate::utils::make_io_error;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+// MP_DATA body: [multipath id: u64][seq: u32][payload], an empty payload joins the session as a path
+pub const MP_DATA_HEADER_LEN: usize = 12;
+
+// frames lost with a dead session leave a gap that would never be filled
+pub(crate) const MP_GAP_TIMEOUT: Duration = Duration::from_secs(15);
+const MP_MAX_PENDING_FRAMES: usize = 4096;
+
+lazy_static! {
+    static ref MULTIPATH_CHANNELS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    // keyed by the channel of the sessions carrying it, only those may join it as paths
+    static ref MULTIPATH_STREAMS: Mutex<HashMap<(String, u64), Arc<MultipathStream>>> =
+        Mutex::new(HashMap::new());
+}
+
+pub fn set_channel_multipath(channel: &str, enable: bool) {
+    let mut channels = MULTIPATH_CHANNELS.lock().unwrap();
+    if enable {
+        channels.insert(String::from(channel));
+    } else {
+        channels.remove(channel);
+    }
+}
+
+pub(crate) fn is_multipath_channel(channel: &str) -> bool {
+    MULTIPATH_CHANNELS.lock().unwrap().contains(channel)
+}
+
+struct Reassembler {
+    next_seq: u32,
+    pending: HashMap<u32, Vec<u8>>,
+    ready: VecDeque<Vec<u8>>,
+    gap_since: Option<Instant>,
+    fin_seq: Option<u32>,
+    fin_reason: FinReason,
+    data_tx: Option<mpsc::Sender<Vec<u8>>>,
+}
+
+impl Reassembler {
+    fn push(&mut self, seq: u32, data: Vec<u8>) {
+        // duplicated or already delivered frame
+        if seq.wrapping_sub(self.next_seq) > u32::max_value() / 2 {
+            return;
+        }
+        self.pending.insert(seq, data);
+        while let Some(d) = self.pending.remove(&self.next_seq) {
+            self.ready.push_back(d);
+            self.next_seq = self.next_seq.wrapping_add(1);
+        }
+        if self.pending.is_empty() {
+            self.gap_since = None;
+        } else if self.gap_since.is_none() {
+            self.gap_since = Some(Instant::now());
+        }
+    }
+    async fn flush(&mut self) {
+        if let Some(tx) = &mut self.data_tx {
+            while let Some(d) = self.ready.pop_front() {
+                if tx.send(d).await.is_err() {
+                    self.ready.clear();
+                    return;
+                }
+            }
+        }
+    }
+    fn is_broken(&self) -> bool {
+        if self.pending.len() > MP_MAX_PENDING_FRAMES {
+            return true;
+        }
+        match self.gap_since {
+            Some(t) => t.elapsed() > MP_GAP_TIMEOUT,
+            None => false,
+        }
+    }
+}
+
+pub(crate) struct MultipathStream {
+    channel: String,
+    id: u64,
+    paths: Mutex<Vec<(u32, mpsc::Sender<Event>)>>,
+    version: AtomicU32,
+    send_seq: AtomicU32,
+    recv: tokio::sync::Mutex<Reassembler>,
+    stream: Mutex<Option<MuxStream>>,
+    created: Instant,
+}
+
+impl MultipathStream {
+    fn new(channel: &str, id: u64) -> Self {
+        Self {
+            channel: String::from(channel),
+            id,
+            paths: Mutex::new(Vec::new()),
+            version: AtomicU32::new(0),
+            send_seq: AtomicU32::new(0),
+            recv: tokio::sync::Mutex::new(Reassembler {
+                next_seq: 0,
+                pending: HashMap::new(),
+                ready: VecDeque::new(),
+                gap_since: None,
+                fin_seq: None,
+                fin_reason: FinReason::Normal,
+                data_tx: None,
+            }),
+            stream: Mutex::new(None),
+            created: Instant::now(),
+        }
+    }
+    pub(crate) fn add_path(&self, session_id: u32, tx: mpsc::Sender<Event>) {
+        let mut paths = self.paths.lock().unwrap();
+        if paths.iter().any(|(id, _)| *id == session_id) {
+            return;
+        }
+        paths.push((session_id, tx));
+        self.version.fetch_add(1, Ordering::SeqCst);
+    }
+    fn remove_path(&self, session_id: u32) {
+        let mut paths = self.paths.lock().unwrap();
+        paths.retain(|(id, _)| *id != session_id);
+        self.version.fetch_add(1, Ordering::SeqCst);
+    }
+    pub(crate) fn sent_frames(&self) -> u32 {
+        self.send_seq.load(Ordering::SeqCst)
+    }
+    // returns true if frames sent before the FIN are still in flight on other paths,
+    // the stream is closed once all of them are delivered
+    pub(crate) async fn defer_fin(&self, frames: u32, reason: FinReason) -> bool {
+        let mut recv = self.recv.lock().await;
+        if recv.next_seq == frames {
+            return false;
+        }
+        recv.fin_seq = Some(frames);
+        recv.fin_reason = reason;
+        if recv.gap_since.is_none() {
+            recv.gap_since = Some(Instant::now());
+        }
+        true
+    }
+    fn close_stream(&self, by_peer: bool, reason: FinReason) {
+        let stream = self.stream.lock().unwrap().take();
+        if let Some(mut s) = stream {
+            if by_peer {
+                s.close_by_peer(reason);
+            } else {
+                let _ = s.close_with_reason(reason);
+            }
+        }
+    }
+    // called once the owner stream exists, frames arrived before that are delivered here
+    pub(crate) async fn attach(&self, stream: &MuxStream) {
+        *self.stream.lock().unwrap() = Some(stream.clone());
+        let mut recv = self.recv.lock().await;
+        recv.data_tx = stream.data_sender();
+        recv.flush().await;
+    }
+}
+
+pub(crate) fn get_or_create(channel: &str, id: u64) -> Arc<MultipathStream> {
+    MULTIPATH_STREAMS
+        .lock()
+        .unwrap()
+        .entry((String::from(channel), id))
+        .or_insert_with(|| Arc::new(MultipathStream::new(channel, id)))
+        .clone()
+}
+
+pub(crate) fn remove(mp: &MultipathStream) {
+    MULTIPATH_STREAMS
+        .lock()
+        .unwrap()
+        .remove(&(mp.channel.clone(), mp.id));
+    mp.stream.lock().unwrap().take();
+}
+
+pub(crate) async fn handle_mp_data(
+    channel: &str,
+    session_id: u32,
+    evtx: mpsc::Sender<Event>,
+    id: u64,
+    seq: u32,
+    data: Vec<u8>,
+) {
+    let mp = get_or_create(channel, id);
+    if data.is_empty() {
+        mp.add_path(session_id, evtx);
+        return;
+    }
+    let mut recv = mp.recv.lock().await;
+    recv.push(seq, data);
+    recv.flush().await;
+    if recv.fin_seq == Some(recv.next_seq) {
+        let reason = recv.fin_reason;
+        drop(recv);
+        mp.close_stream(true, reason);
+    }
+}
+
+// close multipath streams which can no longer be reassembled in order
+pub(crate) fn routine_multipath_streams() {
+    let mut broken = Vec::new();
+    {
+        let mut streams = MULTIPATH_STREAMS.lock().unwrap();
+        streams.retain(|(_, id), mp| {
+            // frames of a denied or already closed stream
+            if mp.stream.lock().unwrap().is_none() && mp.created.elapsed() > MP_GAP_TIMEOUT {
+                return false;
+            }
+            if let Ok(recv) = mp.recv.try_lock() {
+                if recv.is_broken() {
+                    error!(
+                        "[{}]Close multipath stream with {} pending frames.",
+                        id,
+                        recv.pending.len()
+                    );
+                    broken.push(mp.clone());
+                    return false;
+                }
+            }
+            true
+        });
+    }
+    for mp in broken {
+        mp.close_stream(false, FinReason::Reset);
+    }
+}
+
+pub(crate) struct MultipathWriter {
+    mp: Arc<MultipathStream>,
+    paths: Vec<(u32, mpsc::Sender<Event>)>,
+    version: u32,
+    cursor: usize,
+    ev: Option<Event>,
+}
+
+impl MultipathWriter {
+    pub(crate) fn new(mp: Arc<MultipathStream>) -> Self {
+        Self {
+            mp,
+            paths: Vec::new(),
+            version: u32::max_value(),
+            cursor: 0,
+            ev: None,
+        }
+    }
+
+    // stripe frames over all paths in round robin, a frame is never sent twice
+    pub(crate) fn poll_send(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            let version = self.mp.version.load(Ordering::SeqCst);
+            if version != self.version {
+                self.paths = self.mp.paths.lock().unwrap().clone();
+                self.version = version;
+            }
+            if self.paths.is_empty() {
+                return Poll::Ready(Err(make_io_error("no multipath session")));
+            }
+            let idx = self.cursor % self.paths.len();
+            let (session_id, tx) = &mut self.paths[idx];
+            match tx.poll_ready(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(_)) => {
+                    let sid = *session_id;
+                    self.mp.remove_path(sid);
+                    continue;
+                }
+                Poll::Ready(Ok(())) => {}
+            }
+            let ev = match self.ev.take() {
+                Some(ev) => ev,
+                None => {
+                    let seq = self.mp.send_seq.fetch_add(1, Ordering::SeqCst);
+                    new_mp_data_event(self.mp.id, seq, buf)
+                }
+            };
+            match tx.try_send(ev) {
+                Ok(()) => {
+                    self.cursor = self.cursor.wrapping_add(1);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(TrySendError::Full(ev)) | Err(TrySendError::Closed(ev)) => {
+                    // keep the sequenced frame and retry it on another path
+                    let sid = *session_id;
+                    self.ev = Some(ev);
+                    self.mp.remove_path(sid);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_paths_join_within_channel() {
+        let (tx, _rx) = mpsc::channel(1);
+        let mp = get_or_create("test_paths_join_within_channel", 42);
+        // a session of another channel naming the same id gets a stream of its own
+        handle_mp_data("test_paths_join_other", 7, tx.clone(), 42, 0, Vec::new()).await;
+        assert!(mp.paths.lock().unwrap().is_empty());
+        handle_mp_data("test_paths_join_within_channel", 7, tx, 42, 0, Vec::new()).await;
+        assert_eq!(mp.paths.lock().unwrap().len(), 1);
+        remove(&mp);
+        remove(&get_or_create("test_paths_join_other", 42));
+    }
+}
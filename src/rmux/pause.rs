@@ -0,0 +1,130 @@
+use super::event::{
+    new_pause_event, new_resume_event, Event, FLAG_DATA, FLAG_FIN, FLAG_MP_DATA, FLAG_SEQ_DATA,
+};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// A PAUSE holds the peer's DATA this long unless it's refreshed, so a lost RESUME or a
+// pressured side gone quiet can't stall the session for good.
+pub(crate) const PAUSE_LEASE: Duration = Duration::from_secs(1);
+
+// Local DATA held back while the peer asked to pause. FINs wait too so they stay behind
+// the DATA of their stream, everything else goes out as usual.
+#[derive(Default)]
+pub(crate) struct SendGate {
+    paused_until: Option<Instant>,
+    held: VecDeque<Event>,
+}
+
+impl SendGate {
+    // a PAUSE while paused just extends it
+    pub(crate) fn pause(&mut self) {
+        self.paused_until = Some(Instant::now() + PAUSE_LEASE);
+    }
+    pub(crate) fn resume(&mut self) {
+        self.paused_until = None;
+    }
+    pub(crate) fn is_paused(&mut self) -> bool {
+        match self.paused_until {
+            Some(t) if Instant::now() < t => true,
+            Some(_) => {
+                self.paused_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+    // until the pause lapses, the event loop wakes up by then to release the held DATA
+    pub(crate) fn remaining(&self) -> Option<Duration> {
+        self.paused_until
+            .map(|t| t.saturating_duration_since(Instant::now()))
+    }
+    // the event back if it may be sent now
+    pub(crate) fn hold(&mut self, ev: Event) -> Option<Event> {
+        let flags = ev.header.flags();
+        let gated = flags == FLAG_DATA
+            || flags == FLAG_SEQ_DATA
+            || flags == FLAG_MP_DATA
+            || flags == FLAG_FIN;
+        if gated && (!self.held.is_empty() || self.is_paused()) {
+            self.held.push_back(ev);
+            return None;
+        }
+        Some(ev)
+    }
+    // the held events in order once the pause ended
+    pub(crate) fn release(&mut self) -> Vec<Event> {
+        if self.held.is_empty() || self.is_paused() {
+            return Vec::new();
+        }
+        self.held.drain(..).collect()
+    }
+}
+
+// What this side told the peer about its memory pressure.
+#[derive(Default)]
+pub(crate) struct PressureSignal {
+    last_pause: Option<Instant>,
+}
+
+impl PressureSignal {
+    // PAUSE or RESUME to send, None if the peer already knows. A PAUSE is sent again
+    // halfway through its lease while the pressure lasts.
+    pub(crate) fn check(&mut self, pressured: bool) -> Option<Event> {
+        match self.last_pause {
+            Some(t) if pressured && t.elapsed() < PAUSE_LEASE / 2 => None,
+            _ if pressured => {
+                self.last_pause = Some(Instant::now());
+                Some(new_pause_event())
+            }
+            Some(_) => {
+                self.last_pause = None;
+                Some(new_resume_event())
+            }
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::event::{
+        new_data_event, new_fin_event, new_window_update_event, FLAG_PAUSE, FLAG_RESUME,
+    };
+    use super::*;
+
+    #[test]
+    fn test_send_gate() {
+        let mut gate = SendGate::default();
+        assert!(gate.hold(new_data_event(1, b"a", false)).is_some());
+        gate.pause();
+        gate.pause();
+        assert!(gate.hold(new_data_event(1, b"b", false)).is_none());
+        assert!(gate.hold(new_window_update_event(1, 10, false)).is_some());
+        assert!(gate.hold(new_fin_event(1, false)).is_none());
+        assert!(gate.release().is_empty());
+        gate.resume();
+        gate.resume();
+        // nothing overtakes the held events
+        assert!(gate.hold(new_data_event(2, b"c", false)).is_none());
+        let flags: Vec<u8> = gate.release().iter().map(|e| e.header.flags()).collect();
+        assert_eq!(flags, vec![FLAG_DATA, FLAG_FIN, FLAG_DATA]);
+        assert!(gate.hold(new_data_event(2, b"d", false)).is_some());
+
+        // a pause lapses by itself
+        gate.paused_until = Some(Instant::now());
+        assert!(!gate.is_paused());
+    }
+
+    #[test]
+    fn test_pressure_signal() {
+        let mut signal = PressureSignal::default();
+        assert!(signal.check(false).is_none());
+        assert_eq!(signal.check(true).unwrap().header.flags(), FLAG_PAUSE);
+        assert!(signal.check(true).is_none());
+        signal.last_pause = Some(Instant::now() - PAUSE_LEASE);
+        assert_eq!(signal.check(true).unwrap().header.flags(), FLAG_PAUSE);
+        assert_eq!(signal.check(false).unwrap().header.flags(), FLAG_RESUME);
+        assert!(signal.check(false).is_none());
+    }
+}
@@ -0,0 +1,101 @@
+use futures::FutureExt;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+pub const DEFAULT_CONN_POOL_IDLE_SECS: u64 = 60;
+
+#[derive(Clone, Copy)]
+struct ConnPoolConfig {
+    max_idle: Duration,
+    max_size: usize,
+}
+
+// idle conns are keyed by (channel, proto, addr)
+type PoolKey = (String, String, String);
+
+lazy_static! {
+    static ref CONN_POOL_CONFIGS: Mutex<HashMap<String, ConnPoolConfig>> =
+        Mutex::new(HashMap::new());
+    static ref IDLE_CONNS: Mutex<HashMap<PoolKey, VecDeque<(TcpStream, Instant)>>> =
+        Mutex::new(HashMap::new());
+}
+
+// Keep the outbound conns of streams opened by the peer for reuse once the stream closes.
+// Only safe for targets where a conn carries independent requests, e.g. HTTP keep-alive
+// origins, so it's off unless enabled for the channel. max_size 0 disables it.
+pub fn set_channel_conn_pool(channel: &str, max_idle_secs: u64, max_size: usize) {
+    if max_size == 0 {
+        CONN_POOL_CONFIGS.lock().unwrap().remove(channel);
+        IDLE_CONNS.lock().unwrap().retain(|k, _| k.0 != channel);
+        return;
+    }
+    CONN_POOL_CONFIGS.lock().unwrap().insert(
+        String::from(channel),
+        ConnPoolConfig {
+            max_idle: Duration::from_secs(max_idle_secs),
+            max_size,
+        },
+    );
+}
+
+pub(crate) fn conn_pool_enabled(channel: &str) -> bool {
+    CONN_POOL_CONFIGS.lock().unwrap().contains_key(channel)
+}
+
+// an idle conn must have nothing to read, EOF or stale data means it can't be reused
+fn is_reusable(conn: &mut TcpStream) -> bool {
+    let mut buf = [0u8; 1];
+    conn.peek(&mut buf).now_or_never().is_none()
+}
+
+pub(crate) fn take_idle_conn(channel: &str, proto: &str, addr: &str) -> Option<TcpStream> {
+    let max_idle = CONN_POOL_CONFIGS.lock().unwrap().get(channel)?.max_idle;
+    let key = (
+        String::from(channel),
+        String::from(proto),
+        String::from(addr),
+    );
+    let mut idle_conns = IDLE_CONNS.lock().unwrap();
+    let conns = idle_conns.get_mut(&key)?;
+    // most recently used first, it's the most likely one still alive
+    while let Some((mut conn, since)) = conns.pop_back() {
+        if since.elapsed() < max_idle && is_reusable(&mut conn) {
+            return Some(conn);
+        }
+    }
+    idle_conns.remove(&key);
+    None
+}
+
+pub(crate) fn put_idle_conn(channel: &str, proto: &str, addr: &str, conn: TcpStream) {
+    let max_size = match CONN_POOL_CONFIGS.lock().unwrap().get(channel) {
+        Some(cfg) => cfg.max_size,
+        None => return,
+    };
+    let key = (
+        String::from(channel),
+        String::from(proto),
+        String::from(addr),
+    );
+    let mut idle_conns = IDLE_CONNS.lock().unwrap();
+    let conns = idle_conns.entry(key).or_insert_with(VecDeque::new);
+    if conns.len() >= max_size {
+        conns.pop_front();
+    }
+    conns.push_back((conn, Instant::now()));
+}
+
+pub(crate) fn routine_conn_pool() {
+    let configs = CONN_POOL_CONFIGS.lock().unwrap().clone();
+    let mut idle_conns = IDLE_CONNS.lock().unwrap();
+    idle_conns.retain(|key, conns| {
+        let max_idle = match configs.get(&key.0) {
+            Some(cfg) => cfg.max_idle,
+            None => return false,
+        };
+        conns.retain(|(_, since)| since.elapsed() < max_idle);
+        !conns.is_empty()
+    });
+}
@@ -0,0 +1,102 @@
+use super::hooks::FinReason;
+use super::session::create_stream;
+use super::stream::MuxStream;
+use crate::channel::ChannelStream;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbeError {
+    // no stream could be opened on the channel, e.g. it has no live session
+    Open(String),
+    // the peer couldn't connect the target
+    Refused(FinReason),
+    // writing the payload or reading its reply failed
+    Io(String),
+    Timeout,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProbeResult {
+    // until the target was connected, or until the probe failed
+    pub latency: Duration,
+    // from writing the payload to the first bytes of the reply
+    pub round_trip: Option<Duration>,
+    pub error: Option<ProbeError>,
+}
+
+impl ProbeResult {
+    fn failed(start: Instant, error: ProbeError) -> Self {
+        Self {
+            latency: start.elapsed(),
+            round_trip: None,
+            error: Some(error),
+        }
+    }
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+// One shot check that the channel reaches the target, for health checks scheduled by the
+// application. Peers without CAP_CONNECT_RESULT report every target connected, a probe with
+// a payload still catches them.
+pub async fn probe(channel: &str, proto: &str, addr: &str, wait: Duration) -> ProbeResult {
+    probe_with_payload(channel, proto, addr, Vec::new(), wait).await
+}
+
+// The payload is written once the target is connected and the first bytes of its reply are
+// awaited, e.g. a ping of the target's protocol. The whole probe is bounded by wait.
+pub async fn probe_with_payload(
+    channel: &str,
+    proto: &str,
+    addr: &str,
+    payload: Vec<u8>,
+    wait: Duration,
+) -> ProbeResult {
+    let start = Instant::now();
+    let mut stream = match timeout(wait, create_stream(channel, proto, addr)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => return ProbeResult::failed(start, ProbeError::Open(e.to_string())),
+        Err(_) => return ProbeResult::failed(start, ProbeError::Timeout),
+    };
+    let remaining = wait.checked_sub(start.elapsed()).unwrap_or_default();
+    let result = match timeout(remaining, check_stream(&mut stream, payload, start)).await {
+        Ok(result) => result,
+        Err(_) => ProbeResult::failed(start, ProbeError::Timeout),
+    };
+    let _ = stream.close();
+    result
+}
+
+async fn check_stream(stream: &mut MuxStream, payload: Vec<u8>, start: Instant) -> ProbeResult {
+    if let Err(reason) = stream.connect_result().await {
+        return ProbeResult::failed(start, ProbeError::Refused(reason));
+    }
+    let mut result = ProbeResult {
+        latency: start.elapsed(),
+        round_trip: None,
+        error: None,
+    };
+    if payload.is_empty() {
+        return result;
+    }
+    let sent = Instant::now();
+    let (mut r, mut w) = stream.split();
+    if let Err(e) = w.write_all(&payload[..]).await {
+        result.error = Some(ProbeError::Io(e.to_string()));
+        return result;
+    }
+    let mut reply = [0u8; 1];
+    match r.read(&mut reply).await {
+        Ok(0) => {
+            result.error = Some(ProbeError::Io(String::from(
+                "target closed without a reply",
+            )));
+        }
+        Ok(_) => result.round_trip = Some(sent.elapsed()),
+        Err(e) => result.error = Some(ProbeError::Io(e.to_string())),
+    }
+    result
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Ordered from best to worst, new streams go to the sessions of the best class available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SessionQuality {
+    Good = 0,
+    Degraded = 1,
+    Bad = 2,
+}
+
+impl Default for SessionQuality {
+    fn default() -> Self {
+        SessionQuality::Good
+    }
+}
+
+impl SessionQuality {
+    pub(crate) fn from_u8(v: u8) -> Self {
+        match v {
+            0 => SessionQuality::Good,
+            1 => SessionQuality::Degraded,
+            _ => SessionQuality::Bad,
+        }
+    }
+}
+
+// A session reaching any degraded_* value is Degraded, any bad_* one Bad. A value of 0
+// disables the check. Signals are taken on each routine tick and cover the time since the
+// previous one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityThresholds {
+    // of the last answered ping
+    pub degraded_rtt: Duration,
+    pub bad_rtt: Duration,
+    // time the session's streams waited for send window, summed over the streams, per
+    // second of the tick
+    pub degraded_stall_ratio: f64,
+    pub bad_stall_ratio: f64,
+    // stream keepalive timeouts, data sequence gaps & frames of unknown flags in the tick
+    pub degraded_errors: u32,
+    pub bad_errors: u32,
+    // secs nothing came from the peer while streams are open
+    pub degraded_recv_idle_secs: u32,
+    pub bad_recv_idle_secs: u32,
+}
+
+impl Default for QualityThresholds {
+    fn default() -> Self {
+        Self {
+            degraded_rtt: Duration::from_millis(500),
+            bad_rtt: Duration::from_secs(2),
+            degraded_stall_ratio: 0.5,
+            bad_stall_ratio: 2.0,
+            degraded_errors: 1,
+            bad_errors: 5,
+            degraded_recv_idle_secs: 60,
+            bad_recv_idle_secs: 120,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct QualitySignals {
+    pub rtt: Option<Duration>,
+    pub stall_ratio: f64,
+    pub errors: u32,
+    pub recv_idle_secs: u32,
+    pub streams: u32,
+}
+
+lazy_static! {
+    static ref QUALITY_THRESHOLDS: Mutex<HashMap<String, QualityThresholds>> =
+        Mutex::new(HashMap::new());
+}
+
+pub fn set_channel_quality_thresholds(channel: &str, thresholds: QualityThresholds) {
+    QUALITY_THRESHOLDS
+        .lock()
+        .unwrap()
+        .insert(String::from(channel), thresholds);
+}
+
+pub(crate) fn get_quality_thresholds(channel: &str) -> QualityThresholds {
+    QUALITY_THRESHOLDS
+        .lock()
+        .unwrap()
+        .get(channel)
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn reaches(s: &QualitySignals, rtt: Duration, stall_ratio: f64, errors: u32, idle: u32) -> bool {
+    s.rtt
+        .map_or(false, |v| rtt > Duration::default() && v >= rtt)
+        || (stall_ratio > 0.0 && s.stall_ratio >= stall_ratio)
+        || (errors > 0 && s.errors >= errors)
+        || (idle > 0 && s.streams > 0 && s.recv_idle_secs >= idle)
+}
+
+pub(crate) fn classify_session(s: &QualitySignals, t: &QualityThresholds) -> SessionQuality {
+    if reaches(
+        s,
+        t.bad_rtt,
+        t.bad_stall_ratio,
+        t.bad_errors,
+        t.bad_recv_idle_secs,
+    ) {
+        SessionQuality::Bad
+    } else if reaches(
+        s,
+        t.degraded_rtt,
+        t.degraded_stall_ratio,
+        t.degraded_errors,
+        t.degraded_recv_idle_secs,
+    ) {
+        SessionQuality::Degraded
+    } else {
+        SessionQuality::Good
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_session() {
+        let t = QualityThresholds::default();
+        let mut s = QualitySignals {
+            rtt: Some(Duration::from_millis(80)),
+            streams: 3,
+            recv_idle_secs: 2,
+            ..Default::default()
+        };
+        assert_eq!(classify_session(&s, &t), SessionQuality::Good);
+        s.stall_ratio = 0.8;
+        assert_eq!(classify_session(&s, &t), SessionQuality::Degraded);
+        s.rtt = Some(Duration::from_secs(3));
+        assert_eq!(classify_session(&s, &t), SessionQuality::Bad);
+
+        // an idle session without streams isn't judged by its silence
+        let s = QualitySignals {
+            recv_idle_secs: 600,
+            ..Default::default()
+        };
+        assert_eq!(classify_session(&s, &t), SessionQuality::Good);
+
+        let off = QualityThresholds {
+            degraded_stall_ratio: 0.0,
+            bad_stall_ratio: 0.0,
+            ..t
+        };
+        let s = QualitySignals {
+            stall_ratio: 10.0,
+            ..Default::default()
+        };
+        assert_eq!(classify_session(&s, &off), SessionQuality::Good);
+        assert!(SessionQuality::Good < SessionQuality::Degraded);
+    }
+}
@@ -0,0 +1,110 @@
+use ring::digest;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// How target addresses appear in logs & stats dumps, the stats API always has them in full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddrRedaction {
+    Off,
+    // a salted digest, the same target gives the same value until the process restarts
+    Hash,
+    // the port and the network or domain the target belongs to
+    Truncate,
+}
+
+static ADDR_REDACTION: AtomicU8 = AtomicU8::new(0);
+
+lazy_static! {
+    static ref HASH_SALT: [u8; 16] = rand::random();
+}
+
+pub fn set_addr_redaction(redaction: AddrRedaction) {
+    let v = match redaction {
+        AddrRedaction::Off => 0,
+        AddrRedaction::Hash => 1,
+        AddrRedaction::Truncate => 2,
+    };
+    ADDR_REDACTION.store(v, Ordering::SeqCst);
+}
+
+fn addr_redaction() -> AddrRedaction {
+    match ADDR_REDACTION.load(Ordering::SeqCst) {
+        1 => AddrRedaction::Hash,
+        2 => AddrRedaction::Truncate,
+        _ => AddrRedaction::Off,
+    }
+}
+
+fn hash_addr(addr: &str) -> String {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(&HASH_SALT[..]);
+    ctx.update(addr.as_bytes());
+    let d = ctx.finish();
+    let hex: Vec<String> = d.as_ref()[0..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("#{}", hex.concat())
+}
+
+fn truncate_host(host: &str) -> String {
+    match host
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse::<IpAddr>()
+    {
+        Ok(IpAddr::V4(ip)) => {
+            let o = ip.octets();
+            format!("{}.{}.{}.x", o[0], o[1], o[2])
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let s = ip.segments();
+            format!("[{:x}:{:x}:{:x}::x]", s[0], s[1], s[2])
+        }
+        Err(_) => {
+            let labels: Vec<&str> = host.rsplitn(3, '.').collect();
+            if labels.len() < 3 {
+                return String::from(host);
+            }
+            format!("*.{}.{}", labels[1], labels[0])
+        }
+    }
+}
+
+fn truncate_addr(addr: &str) -> String {
+    // a bare ipv6 address has no port
+    match addr.rfind(':') {
+        Some(i) if addr.find(':') == Some(i) || addr[..i].ends_with(']') => {
+            format!("{}{}", truncate_host(&addr[..i]), &addr[i..])
+        }
+        _ => truncate_host(addr),
+    }
+}
+
+// the target address as it may be logged
+pub fn redact_addr(addr: &str) -> String {
+    match addr_redaction() {
+        AddrRedaction::Off => String::from(addr),
+        AddrRedaction::Hash => hash_addr(addr),
+        AddrRedaction::Truncate => truncate_addr(addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_addr() {
+        assert_eq!(truncate_addr("10.1.2.3:443"), "10.1.2.x:443");
+        assert_eq!(truncate_addr("[2001:db8:1:2::1]:80"), "[2001:db8:1::x]:80");
+        assert_eq!(truncate_addr("2001:db8:1:2::1"), "[2001:db8:1::x]");
+        assert_eq!(
+            truncate_addr("mail.corp.example.com:25"),
+            "*.example.com:25"
+        );
+        assert_eq!(truncate_addr("example.com:443"), "example.com:443");
+        assert_eq!(hash_addr("example.com:443"), hash_addr("example.com:443"));
+        assert_ne!(hash_addr("example.com:443"), hash_addr("example.com:80"));
+        assert!(!hash_addr("example.com:443").contains("example"));
+    }
+}
@@ -0,0 +1,320 @@
+use super::budget::BufferCharge;
+use super::event::{Event, FLAG_DATA, FLAG_FIN, FLAG_SEQ_DATA};
+use super::message::MAX_STREAM_PRIORITY;
+use super::stream::MuxStream;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// how long the event loop waits for new events before retrying streams whose reader was behind
+pub(crate) const DATA_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+// events taken off the event queue ahead of the loop to be reordered by priority
+const INBOX_LOOKAHEAD: usize = 32;
+
+lazy_static! {
+    static ref DATA_QUANTUMS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+}
+
+// Deliver DATA of the channel's sessions round robin across streams, each stream may hand
+// up to quantum bytes to its reader per round so one busy stream can't hold up the others.
+// 0 keeps the strict arrival order, handy for debugging. Applies to sessions started after.
+pub fn set_channel_data_quantum(channel: &str, quantum: usize) {
+    let mut quantums = DATA_QUANTUMS.lock().unwrap();
+    if quantum == 0 {
+        quantums.remove(channel);
+        return;
+    }
+    quantums.insert(String::from(channel), quantum);
+}
+
+fn get_data_quantum(channel: &str) -> usize {
+    DATA_QUANTUMS
+        .lock()
+        .unwrap()
+        .get(channel)
+        .copied()
+        .unwrap_or(0)
+}
+
+#[derive(Default)]
+struct StreamQueue {
+    data: VecDeque<Vec<u8>>,
+    deficit: usize,
+}
+
+// Deficit round robin over the DATA received for the streams of one session. Each stream's
+// backlog is bounded by its recv window since the peer waits for window updates.
+pub(crate) struct DataScheduler {
+    quantum: usize,
+    queues: HashMap<u32, StreamQueue>,
+    // backlogged streams in round robin order
+    active: VecDeque<u32>,
+    queued: BufferCharge,
+}
+
+impl DataScheduler {
+    pub(crate) fn new(channel: &str) -> Self {
+        Self {
+            quantum: get_data_quantum(channel),
+            queues: HashMap::new(),
+            active: VecDeque::new(),
+            queued: BufferCharge::default(),
+        }
+    }
+    pub(crate) fn enabled(&self) -> bool {
+        self.quantum > 0
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.active.is_empty()
+    }
+    pub(crate) fn push(&mut self, sid: u32, data: Vec<u8>) {
+        self.queued.charge(data.len());
+        let q = self.queues.entry(sid).or_default();
+        if q.data.is_empty() {
+            self.active.push_back(sid);
+        }
+        q.data.push_back(data);
+    }
+    // hand everything queued for the stream to it in order, before its FIN is handled
+    pub(crate) async fn flush(&mut self, sid: u32, streams: &mut HashMap<u32, MuxStream>) {
+        let q = match self.queues.remove(&sid) {
+            Some(q) => q,
+            None => return,
+        };
+        self.active.retain(|id| *id != sid);
+        for data in q.data {
+            self.queued.credit(data.len());
+            if let Some(stream) = streams.get_mut(&sid) {
+                stream.offer_data(data).await;
+            }
+        }
+    }
+    // one round over the backlogged streams, data of closed streams is dropped
+    pub(crate) fn deliver(&mut self, streams: &mut HashMap<u32, MuxStream>) {
+        for _ in 0..self.active.len() {
+            let sid = match self.active.pop_front() {
+                Some(id) => id,
+                None => break,
+            };
+            let q = self.queues.get_mut(&sid).unwrap();
+            let stream = match streams.get_mut(&sid) {
+                Some(s) => s,
+                None => {
+                    for data in self.queues.remove(&sid).unwrap().data {
+                        self.queued.credit(data.len());
+                    }
+                    continue;
+                }
+            };
+            q.deficit += self.quantum;
+            while let Some(data) = q.data.pop_front() {
+                let len = data.len();
+                if len > q.deficit {
+                    q.data.push_front(data);
+                    break;
+                }
+                match stream.try_offer_data(data) {
+                    Ok(()) => {
+                        q.deficit -= len;
+                        self.queued.credit(len);
+                    }
+                    Err(data) => {
+                        q.data.push_front(data);
+                        break;
+                    }
+                }
+            }
+            if q.data.is_empty() {
+                self.queues.remove(&sid);
+            } else {
+                self.active.push_back(sid);
+            }
+        }
+    }
+}
+
+// Takes the events of a session off its queue and lets the local DATA and FIN of streams with
+// a priority overtake the others, higher priorities first. Everything else keeps the arrival
+// order, as do the events of each stream since its priority never changes.
+#[derive(Default)]
+pub(crate) struct PriorityInbox {
+    fifo: VecDeque<Event>,
+    // by priority - 1
+    lanes: [VecDeque<Event>; MAX_STREAM_PRIORITY as usize],
+}
+
+impl PriorityInbox {
+    fn priority_of(ev: &Event, streams: &HashMap<u32, MuxStream>) -> u8 {
+        if ev.remote {
+            return 0;
+        }
+        match ev.header.flags() {
+            FLAG_DATA | FLAG_SEQ_DATA | FLAG_FIN => streams
+                .get(&ev.header.stream_id)
+                .map_or(0, |s| s.priority()),
+            _ => 0,
+        }
+    }
+    fn push(&mut self, ev: Event, streams: &HashMap<u32, MuxStream>) {
+        match Self::priority_of(&ev, streams) {
+            0 => self.fifo.push_back(ev),
+            p => self.lanes[p as usize - 1].push_back(ev),
+        }
+    }
+    fn len(&self) -> usize {
+        self.fifo.len() + self.lanes.iter().map(|l| l.len()).sum::<usize>()
+    }
+    fn pop(&mut self) -> Option<Event> {
+        for lane in self.lanes.iter_mut().rev() {
+            if let Some(ev) = lane.pop_front() {
+                return Some(ev);
+            }
+        }
+        self.fifo.pop_front()
+    }
+    fn fill(&mut self, rx: &mut mpsc::Receiver<Event>, streams: &HashMap<u32, MuxStream>) {
+        while self.len() < INBOX_LOOKAHEAD {
+            match rx.try_recv() {
+                Ok(ev) => self.push(ev, streams),
+                Err(_) => break,
+            }
+        }
+    }
+    // None once the queue is closed and nothing is left
+    pub(crate) async fn recv(
+        &mut self,
+        rx: &mut mpsc::Receiver<Event>,
+        streams: &HashMap<u32, MuxStream>,
+    ) -> Option<Event> {
+        self.fill(rx, streams);
+        if let Some(ev) = self.pop() {
+            return Some(ev);
+        }
+        let ev = rx.recv().await?;
+        self.push(ev, streams);
+        self.fill(rx, streams);
+        self.pop()
+    }
+    // A local stream's events may be taken before its SYN added it to the streams, move them
+    // to its lane once it's there, ahead of any later ones.
+    pub(crate) fn promote(&mut self, streams: &HashMap<u32, MuxStream>) {
+        if self
+            .fifo
+            .iter()
+            .all(|ev| Self::priority_of(ev, streams) == 0)
+        {
+            return;
+        }
+        for ev in std::mem::replace(&mut self.fifo, VecDeque::new()) {
+            self.push(ev, streams);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::event::{
+        new_data_event, new_fin_event, new_window_update_event, FLAG_WIN_UPDATE,
+    };
+    use super::super::message::ConnectRequest;
+    use super::super::stream::DEFAULT_STREAM_WINDOW;
+    use super::*;
+    use crate::channel::ChannelStream;
+    use futures::task::noop_waker_ref;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::AsyncRead;
+    use tokio::sync::mpsc;
+
+    fn read_available<R: AsyncRead + Unpin + ?Sized>(r: &mut R) -> usize {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut buf = [0u8; 64 * 1024];
+        let mut n = 0;
+        while let Poll::Ready(Ok(len)) = Pin::new(&mut *r).poll_read(&mut cx, &mut buf[..]) {
+            if len == 0 {
+                break;
+            }
+            n += len;
+        }
+        n
+    }
+
+    #[test]
+    fn test_round_robin_delivery() {
+        let channel = "test_round_robin_delivery";
+        set_channel_data_quantum(channel, 1000);
+        let mut sched = DataScheduler::new(channel);
+        assert!(sched.enabled());
+        let (evtx, _evrx) = mpsc::channel(1024);
+        let new_stream = |sid| {
+            let req = ConnectRequest::default();
+            MuxStream::new(channel, 0, sid, evtx.clone(), req, DEFAULT_STREAM_WINDOW)
+        };
+        let (mut s1, mut s2) = (new_stream(1), new_stream(2));
+        let mut streams = HashMap::new();
+        streams.insert(1, s1.clone());
+        streams.insert(2, s2.clone());
+        // stream 1 dumps a burst before stream 2's single event
+        for _ in 0..8 {
+            sched.push(1, vec![1u8; 1000]);
+        }
+        sched.push(2, vec![2u8; 1000]);
+
+        let (mut r1, _) = s1.split();
+        let (mut r2, _) = s2.split();
+        sched.deliver(&mut streams);
+        assert_eq!(read_available(&mut r1), 1000);
+        assert_eq!(read_available(&mut r2), 1000);
+        while !sched.is_empty() {
+            sched.deliver(&mut streams);
+        }
+        assert_eq!(read_available(&mut r1), 7000);
+    }
+
+    #[tokio::test]
+    async fn test_priority_inbox() {
+        let (evtx, mut evrx) = mpsc::channel(1024);
+        let new_stream = |sid, priority| {
+            let req = ConnectRequest {
+                priority,
+                ..Default::default()
+            };
+            MuxStream::new("", 0, sid, evtx.clone(), req, DEFAULT_STREAM_WINDOW)
+        };
+        let mut streams = HashMap::new();
+        streams.insert(1, new_stream(1, 0));
+        streams.insert(3, new_stream(3, 5));
+        let mut tx = evtx.clone();
+        tx.try_send(new_data_event(1, b"bulk", false)).unwrap();
+        tx.try_send(new_window_update_event(1, 10, false)).unwrap();
+        tx.try_send(new_data_event(3, b"ssh", false)).unwrap();
+        tx.try_send(new_data_event(1, b"bulk", false)).unwrap();
+        tx.try_send(new_fin_event(3, false)).unwrap();
+        // stream 5 isn't known yet, its data waits in order until it's promoted
+        tx.try_send(new_data_event(5, b"late", false)).unwrap();
+
+        let mut inbox = PriorityInbox::default();
+        let mut order = Vec::new();
+        for _ in 0..3 {
+            let ev = inbox.recv(&mut evrx, &streams).await.unwrap();
+            order.push((ev.header.stream_id, ev.header.flags()));
+        }
+        streams.insert(5, new_stream(5, 7));
+        inbox.promote(&streams);
+        while let Some(ev) = inbox.pop() {
+            order.push((ev.header.stream_id, ev.header.flags()));
+        }
+        assert_eq!(
+            order,
+            vec![
+                (3, FLAG_DATA),
+                (3, FLAG_FIN),
+                (1, FLAG_DATA),
+                (5, FLAG_DATA),
+                (1, FLAG_WIN_UPDATE),
+                (1, FLAG_DATA),
+            ]
+        );
+    }
+}
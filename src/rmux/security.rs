@@ -0,0 +1,112 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+// failures of one peer are counted together while each comes within this of the previous
+pub const AUTH_FAILURE_WINDOW: Duration = Duration::from_secs(600);
+// peers tracked at once, the quiet ones are forgotten first when it's reached
+const MAX_TRACKED_PEERS: usize = 4096;
+
+// A frame that failed authentication, the connection was tampered with or the peer uses
+// another key. Always fatal to the session or handshake it happened in.
+#[derive(Debug, Clone)]
+pub struct AuthFailure {
+    pub channel: String,
+    // 0 if it happened in the auth handshake
+    pub session_id: u32,
+    // ip of the remote end where known, e.g. of clients connecting over plain tcp
+    pub peer: Option<String>,
+    // failures of the peer within AUTH_FAILURE_WINDOW, this one included, 1 without a peer
+    pub peer_failures: u32,
+}
+
+// called for every failure, e.g. to alert once peer_failures grows
+pub type AuthFailureAlarm = Arc<dyn Fn(&AuthFailure) + Send + Sync>;
+
+lazy_static! {
+    static ref AUTH_FAILURE_ALARM: RwLock<Option<AuthFailureAlarm>> = RwLock::new(None);
+    static ref CHANNEL_AUTH_FAILURES: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+    static ref PEER_AUTH_FAILURES: Mutex<HashMap<String, (Instant, u32)>> =
+        Mutex::new(HashMap::new());
+}
+
+pub fn set_auth_failure_alarm(cb: Option<AuthFailureAlarm>) {
+    *AUTH_FAILURE_ALARM.write().unwrap() = cb;
+}
+
+// by channel, "" for the sessions of the server, over the process's life
+pub(crate) fn auth_failure_counts() -> BTreeMap<String, u64> {
+    CHANNEL_AUTH_FAILURES.lock().unwrap().clone()
+}
+
+fn count_peer_failure(peer: &str) -> u32 {
+    let mut peers = PEER_AUTH_FAILURES.lock().unwrap();
+    let now = Instant::now();
+    if peers.len() >= MAX_TRACKED_PEERS && !peers.contains_key(peer) {
+        peers.retain(|_, (last, _)| now.duration_since(*last) < AUTH_FAILURE_WINDOW);
+        if peers.len() >= MAX_TRACKED_PEERS {
+            let quietest = peers
+                .iter()
+                .min_by_key(|(_, (last, _))| *last)
+                .map(|(p, _)| p.clone());
+            if let Some(p) = quietest {
+                peers.remove(&p);
+            }
+        }
+    }
+    let entry = peers.entry(String::from(peer)).or_insert((now, 0));
+    if now.duration_since(entry.0) >= AUTH_FAILURE_WINDOW {
+        entry.1 = 0;
+    }
+    entry.0 = now;
+    entry.1 += 1;
+    entry.1
+}
+
+pub(crate) fn record_auth_failure(channel: &str, session_id: u32, peer: Option<&str>) {
+    *CHANNEL_AUTH_FAILURES
+        .lock()
+        .unwrap()
+        .entry(String::from(channel))
+        .or_insert(0) += 1;
+    let failure = AuthFailure {
+        channel: String::from(channel),
+        session_id,
+        peer: peer.map(String::from),
+        peer_failures: peer.map_or(1, count_peer_failure),
+    };
+    warn!(
+        channel,
+        session_id,
+        peer = failure.peer.as_deref().unwrap_or(""),
+        peer_failures = failure.peer_failures,
+        "frame failed authentication"
+    );
+    let cb = AUTH_FAILURE_ALARM.read().unwrap().clone();
+    if let Some(f) = cb {
+        f(&failure);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auth_failure_counts() {
+        let channel = "test_auth_failure_counts";
+        let peer = "192.0.2.7";
+        record_auth_failure(channel, 0, Some(peer));
+        record_auth_failure(channel, 3, Some(peer));
+        record_auth_failure(channel, 4, None);
+        assert_eq!(auth_failure_counts().get(channel), Some(&3));
+        assert_eq!(count_peer_failure(peer), 3);
+
+        // a peer quiet for the window starts over
+        if let Some(t) = Instant::now().checked_sub(AUTH_FAILURE_WINDOW) {
+            PEER_AUTH_FAILURES.lock().unwrap().get_mut(peer).unwrap().0 = t;
+            assert_eq!(count_peer_failure(peer), 1);
+        }
+    }
+}
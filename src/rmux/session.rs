@@ -1,35 +1,365 @@
-use super::crypto::{read_encrypt_event, CryptoContext};
+use super::assembly::SynAssembler;
+use super::budget::{over_buffer_budget, BufferCharge};
+use super::clock::{Clock, SystemClock};
+use super::crypto::{channel_key_epoch, previous_channel_key, read_encrypt_event, CryptoContext};
+use super::dial::{dial_with_retry, get_dial_ticket, DialTicket};
+use super::error::RmuxError;
 use super::event::{
-    get_event_type_str, new_ping_event, new_pong_event, new_routine_event, new_shutdown_event,
-    new_syn_event, new_window_update_event, Event, FLAG_DATA, FLAG_FIN, FLAG_PING, FLAG_PONG,
-    FLAG_ROUTINE, FLAG_SHUTDOWN, FLAG_SYN, FLAG_WIN_UPDATE,
+    get_event_type_str, new_data_event, new_fin_event_with_reason, new_mp_data_event,
+    new_ping_event, new_pong_event, new_routine_event, new_shutdown_event, new_syn_events,
+    new_window_update_event, Event, EventKind, EVENT_HEADER_LEN, FLAG_DATA, FLAG_FIN, FLAG_MP_DATA,
+    FLAG_SEQ_DATA,
 };
-use super::message::ConnectRequest;
-use super::stream::MuxStream;
-use crate::channel::get_channel_stream;
+use super::flow::get_initial_send_credit;
+use super::group::resolve_channel;
+use super::handoff::{SessionSnapshot, StreamSnapshot};
+use super::hooks::{
+    authorize_stream, notify_session_event, notify_stream_event, resolve_service,
+    should_close_idle, throughput_sampler, FinReason, SessionCloseReason, SessionEvent,
+    SessionSummary, StreamEvent,
+};
+use super::loops::{is_self_addr, MAX_STREAM_HOPS};
+use super::message::{
+    ConnectRequest, Hello, ResumeToken, CAP_AUTH_ONLY_FRAMES, CAP_CONNECT_ERRORS, CAP_CONNECT_EXT,
+    CAP_CONNECT_RESULT, CAP_INITIAL_CREDIT, CAP_MULTIPATH, CAP_SESSION_PAUSE, CAP_STREAM_RESUME,
+    CAP_SYN_PARTS, MAX_INITIAL_DATA_LEN, MAX_METADATA_LEN, MAX_SPANNING_INITIAL_DATA_LEN,
+    MAX_STREAM_PRIORITY,
+};
+use super::multipath::{self, handle_mp_data, is_multipath_channel, routine_multipath_streams};
+use super::pause::{PressureSignal, SendGate};
+use super::pool::{conn_pool_enabled, put_idle_conn, routine_conn_pool, take_idle_conn};
+use super::quality::{classify_session, get_quality_thresholds, QualitySignals, SessionQuality};
+use super::redact::redact_addr;
+use super::scheduler::{DataScheduler, PriorityInbox, DATA_RETRY_INTERVAL};
+use super::security::record_auth_failure;
+use super::stats::{SessionParams, SessionStats, StreamStats, ThroughputSample};
+use super::stream::{
+    get_max_stream_lifetime, MuxStream, StreamHandle, DEFAULT_STREAM_WINDOW, MAX_STREAM_WINDOW,
+};
+use super::tap::{tap_frame, FrameDirection};
+use super::tasks::{start_relay_task, EstablishPermit};
+use super::udp::handle_udp_rmux_stream;
+use super::upstream::{get_channel_dialer, get_proxy_upstream, get_stream_proto, DialFuture};
 use crate::channel::ChannelStream;
-use crate::tunnel::relay;
-use crate::utils::{make_io_error, VBuf};
-use bytes::BytesMut;
-use futures::future::join3;
+use crate::channel::{connect_direct, get_channel_stream};
+use crate::tunnel::{relay, relay_reusable};
+use crate::utils::{
+    http_connect_proxy_connect, make_error, make_io_error, socks5_proxy_connect, VBuf,
+};
+use bytes::{Buf, BytesMut};
+use futures::future::{join3, join_all};
 use futures::FutureExt;
 use rand::Rng;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::error::Error;
-use std::sync::{Arc, Mutex};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::task::{Context, Poll};
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::error::TryRecvError;
 use tokio::sync::oneshot;
+use tokio::time::{delay_for, timeout};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 
-use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+// a session is considered dead once its pong lags the ping by more than this
+const HEARTBEAT_TIMEOUT_SECS: i64 = 60;
+// retire a session before its stream id seed could wrap, ids are never reused on a session
+const STREAM_ID_RETIRE_THRESHOLD: u32 = u32::max_value() - 1024 * 1024;
+// sessions are rotated at a jittered limit so they don't all reconnect at once,
+// by default +-10% of the limit but at most +-60 secs of the age
+const DEFAULT_ROTATION_JITTER_RATIO: f64 = 0.1;
+const DEFAULT_MAX_AGE_JITTER_SECS: f64 = 60.0;
 
 lazy_static! {
     static ref CHANNEL_SESSIONS: Mutex<ChannelSessionManager> =
         Mutex::new(ChannelSessionManager::new());
+    static ref TUNNEL_ID_SEED: AtomicU32 = AtomicU32::new(0);
+    static ref SATURATION_THRESHOLDS: Mutex<HashMap<String, SaturationThresholds>> =
+        Mutex::new(HashMap::new());
+    static ref STREAM_WAIT_CONFIGS: Mutex<HashMap<String, StreamWaitConfig>> =
+        Mutex::new(HashMap::new());
+    static ref SEND_DWELL_LIMITS: Mutex<HashMap<String, Duration>> = Mutex::new(HashMap::new());
+    static ref UNKNOWN_FLAGS_POLICIES: Mutex<HashMap<String, UnknownFlagsPolicy>> =
+        Mutex::new(HashMap::new());
+    static ref ORPHAN_DATA_POLICIES: Mutex<HashMap<String, OrphanDataPolicy>> =
+        Mutex::new(HashMap::new());
+    static ref CHANNEL_ORPHAN_DATA: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+    static ref MAX_SESSIONS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+}
+
+// A session reaching any of these gets no new streams, 0 disables the check and all but the
+// pending streams are off by default. The session's event & send queues hold 16 entries, a bulk
+// transfer alone may keep the send queue full, so a depth threshold should allow for some
+// waiting senders. Pending streams are those whose SYN the event loop hasn't picked up yet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SaturationThresholds {
+    pub max_send_queue_depth: u32,
+    pub max_recv_queue_depth: u32,
+    pub max_streams: u32,
+    pub max_pending_streams: u32,
+}
+
+pub const DEFAULT_MAX_PENDING_STREAMS: u32 = 512;
+
+impl Default for SaturationThresholds {
+    fn default() -> Self {
+        Self {
+            max_send_queue_depth: 0,
+            max_recv_queue_depth: 0,
+            max_streams: 0,
+            max_pending_streams: DEFAULT_MAX_PENDING_STREAMS,
+        }
+    }
+}
+
+pub fn set_channel_saturation_thresholds(channel: &str, thresholds: SaturationThresholds) {
+    SATURATION_THRESHOLDS
+        .lock()
+        .unwrap()
+        .insert(String::from(channel), thresholds);
+}
+
+const STREAM_WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+pub const DEFAULT_MAX_WAITING_STREAMS: u32 = 256;
+
+struct StreamWaitConfig {
+    max_wait: Duration,
+    max_queued: usize,
+    queued: Arc<AtomicUsize>,
+}
+
+// Let stream creation wait up to max_wait_ms for a usable session instead of failing at once,
+// with at most max_queued requests waiting. 0 for either disables it.
+pub fn set_channel_stream_wait(channel: &str, max_wait_ms: u64, max_queued: usize) {
+    let mut configs = STREAM_WAIT_CONFIGS.lock().unwrap();
+    if max_wait_ms == 0 || max_queued == 0 {
+        configs.remove(channel);
+        return;
+    }
+    // keep the count of requests already waiting
+    let cfg = configs
+        .entry(String::from(channel))
+        .or_insert_with(|| StreamWaitConfig {
+            max_wait: Duration::default(),
+            max_queued: 0,
+            queued: Arc::new(AtomicUsize::new(0)),
+        });
+    cfg.max_wait = Duration::from_millis(max_wait_ms);
+    cfg.max_queued = max_queued;
+}
+
+// A stream whose frame waited longer than this in the send queue is reset, so a bulk stream
+// behind a slow connection stops adding to the delay of the others. Its frames already queued
+// are sealed with consecutive nonces and still go out. 0 disables it, the default.
+pub fn set_channel_send_dwell_limit(channel: &str, limit_ms: u64) {
+    let mut limits = SEND_DWELL_LIMITS.lock().unwrap();
+    if limit_ms == 0 {
+        limits.remove(channel);
+        return;
+    }
+    limits.insert(String::from(channel), Duration::from_millis(limit_ms));
+}
+
+fn get_send_dwell_limit(channel: &str) -> Option<Duration> {
+    SEND_DWELL_LIMITS.lock().unwrap().get(channel).copied()
+}
+
+// What a session does with frames of flags it doesn't know, e.g. from a buggy or hostile peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnknownFlagsPolicy {
+    // log them and go on, the default
+    Tolerate,
+    // close the session on the given count of them
+    Threshold(u32),
+    // close the session on the first one
+    Strict,
+}
+
+impl UnknownFlagsPolicy {
+    // closing on max_frames of them, 0 for never
+    pub fn with_max_frames(max_frames: u32) -> Self {
+        match max_frames {
+            0 => UnknownFlagsPolicy::Tolerate,
+            1 => UnknownFlagsPolicy::Strict,
+            n => UnknownFlagsPolicy::Threshold(n),
+        }
+    }
+    fn should_close(self, count: u32) -> bool {
+        match self {
+            UnknownFlagsPolicy::Tolerate => false,
+            UnknownFlagsPolicy::Threshold(n) => count >= n,
+            UnknownFlagsPolicy::Strict => true,
+        }
+    }
+}
+
+// sessions established after the call use the policy
+pub fn set_channel_unknown_flags_policy(channel: &str, policy: UnknownFlagsPolicy) {
+    let mut policies = UNKNOWN_FLAGS_POLICIES.lock().unwrap();
+    if policy == UnknownFlagsPolicy::Tolerate {
+        policies.remove(channel);
+        return;
+    }
+    policies.insert(String::from(channel), policy);
+}
+
+fn get_unknown_flags_policy(channel: &str) -> UnknownFlagsPolicy {
+    UNKNOWN_FLAGS_POLICIES
+        .lock()
+        .unwrap()
+        .get(channel)
+        .copied()
+        .unwrap_or(UnknownFlagsPolicy::Tolerate)
+}
+
+pub const DEFAULT_MAX_ORPHAN_DATA_FRAMES: u32 = 16;
+
+// What a session does with DATA for a stream it doesn't know, mostly DATA the peer sent
+// before it got the FIN of a stream closed here. The data is dropped in any case.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OrphanDataPolicy {
+    // only count it
+    Drop,
+    // FIN the stream with FinReason::Reset so the peer stops sending, the default; a stream
+    // gets one FIN however much of its DATA follows
+    ReplyFin,
+    // like ReplyFin, and close the session on the given count of such frames
+    CloseAfter(u32),
+}
+
+impl OrphanDataPolicy {
+    // "drop", "fin" or "close", the latter closing the session on max_frames of them
+    pub fn from_name(name: &str, max_frames: u32) -> Option<Self> {
+        match name {
+            "drop" => Some(OrphanDataPolicy::Drop),
+            "fin" => Some(OrphanDataPolicy::ReplyFin),
+            "close" => Some(OrphanDataPolicy::CloseAfter(std::cmp::max(max_frames, 1))),
+            _ => None,
+        }
+    }
+}
+
+// sessions established after the call use the policy
+pub fn set_channel_orphan_data_policy(channel: &str, policy: OrphanDataPolicy) {
+    let mut policies = ORPHAN_DATA_POLICIES.lock().unwrap();
+    if policy == OrphanDataPolicy::ReplyFin {
+        policies.remove(channel);
+        return;
+    }
+    policies.insert(String::from(channel), policy);
+}
+
+fn get_orphan_data_policy(channel: &str) -> OrphanDataPolicy {
+    ORPHAN_DATA_POLICIES
+        .lock()
+        .unwrap()
+        .get(channel)
+        .copied()
+        .unwrap_or(OrphanDataPolicy::ReplyFin)
+}
+
+// DATA frames dropped for unknown streams by channel, "" for the server's sessions
+pub(crate) fn orphan_data_counts() -> BTreeMap<String, u64> {
+    CHANNEL_ORPHAN_DATA.lock().unwrap().clone()
+}
+
+#[derive(Debug, PartialEq)]
+enum OrphanDataAction {
+    Ignore,
+    ReplyFin,
+    CloseSession,
+}
+
+// streams answered with a FIN whose further DATA isn't answered again
+const MAX_ORPHAN_FINS: usize = 64;
+
+struct OrphanData {
+    policy: OrphanDataPolicy,
+    count: u32,
+    fins: VecDeque<u32>,
+}
+
+impl OrphanData {
+    fn new(channel: &str) -> Self {
+        Self {
+            policy: get_orphan_data_policy(channel),
+            count: 0,
+            fins: VecDeque::new(),
+        }
+    }
+    fn on_data(&mut self, channel: &str, sid: u32) -> OrphanDataAction {
+        self.count += 1;
+        *CHANNEL_ORPHAN_DATA
+            .lock()
+            .unwrap()
+            .entry(String::from(channel))
+            .or_insert(0) += 1;
+        match self.policy {
+            OrphanDataPolicy::Drop => return OrphanDataAction::Ignore,
+            OrphanDataPolicy::CloseAfter(n) if self.count >= n => {
+                return OrphanDataAction::CloseSession
+            }
+            _ => {}
+        }
+        if self.fins.contains(&sid) {
+            return OrphanDataAction::Ignore;
+        }
+        if self.fins.len() >= MAX_ORPHAN_FINS {
+            self.fins.pop_front();
+        }
+        self.fins.push_back(sid);
+        OrphanDataAction::ReplyFin
+    }
+}
+
+// a slot in the bounded wait queue of a channel, released on drop
+struct StreamWaiter {
+    deadline: Instant,
+    queued: Arc<AtomicUsize>,
+}
+
+impl StreamWaiter {
+    fn new(channel: &str) -> Option<Self> {
+        let configs = STREAM_WAIT_CONFIGS.lock().unwrap();
+        let cfg = configs.get(channel)?;
+        if cfg.queued.fetch_add(1, Ordering::SeqCst) >= cfg.max_queued {
+            cfg.queued.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Self {
+            deadline: Instant::now() + cfg.max_wait,
+            queued: cfg.queued.clone(),
+        })
+    }
+    fn expired(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+}
+
+impl Drop for StreamWaiter {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn get_saturation_thresholds(channel: &str) -> SaturationThresholds {
+    SATURATION_THRESHOLDS
+        .lock()
+        .unwrap()
+        .get(channel)
+        .cloned()
+        .unwrap_or_default()
+}
+
+// Sessions are looked up by id, so every listener and channel must take its ids from here
+// instead of a counter of its own.
+pub fn next_tunnel_id() -> u32 {
+    TUNNEL_ID_SEED.fetch_add(1, Ordering::SeqCst)
 }
 
 struct ChannelSessionManager {
@@ -44,31 +374,264 @@ impl ChannelSessionManager {
             retired: Vec::new(),
         }
     }
+    // the retired sessions over max, in the order they were retired
+    fn take_retired_overflow(&mut self, max: usize) -> Vec<MuxSession> {
+        if max == 0 || self.retired.len() <= max {
+            return Vec::new();
+        }
+        let n = self.retired.len() - max;
+        self.retired.drain(..n).collect()
+    }
 }
 
+static MAX_RETIRED_SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+// Retired sessions over n are shut down on the routine tick, those retired first and their
+// streams with them. 0, the default, keeps each until its last stream closes.
+pub fn set_max_retired_sessions(n: usize) {
+    MAX_RETIRED_SESSIONS.store(n, Ordering::SeqCst);
+}
+
+pub(crate) fn max_retired_sessions() -> usize {
+    MAX_RETIRED_SESSIONS.load(Ordering::SeqCst)
+}
+
+// the retired sessions still open and the streams they carry
+pub(crate) fn retired_session_counts() -> (usize, usize) {
+    let holder = lock_sessions();
+    let open = holder.retired.iter().filter(|s| !s.state.is_closed());
+    open.fold((0, 0), |(sessions, streams), s| {
+        (
+            sessions + 1,
+            streams + s.state.stream_count.load(Ordering::SeqCst) as usize,
+        )
+    })
+}
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static TRACK_LOCK_HOLD: AtomicBool = AtomicBool::new(false);
+static MAX_LOCK_HOLD_MICROS: AtomicU64 = AtomicU64::new(0);
+
+// Debug aid: track the longest hold of the lock over all sessions, it's reported by
+// metrics_snapshot. Off by default as it reads the clock on every window update.
+pub fn set_lock_hold_tracking(enable: bool) {
+    TRACK_LOCK_HOLD.store(enable, Ordering::SeqCst);
+}
+
+pub(crate) fn max_lock_hold_micros() -> u64 {
+    MAX_LOCK_HOLD_MICROS.load(Ordering::SeqCst)
+}
+
+struct SessionsGuard {
+    guard: MutexGuard<'static, ChannelSessionManager>,
+    since: Option<Instant>,
+}
+
+impl Deref for SessionsGuard {
+    type Target = ChannelSessionManager;
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl DerefMut for SessionsGuard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl Drop for SessionsGuard {
+    fn drop(&mut self) {
+        if let Some(since) = self.since {
+            let micros = since.elapsed().as_micros() as u64;
+            MAX_LOCK_HOLD_MICROS.fetch_max(micros, Ordering::SeqCst);
+        }
+    }
+}
+
+fn lock_sessions() -> SessionsGuard {
+    let guard = CHANNEL_SESSIONS.lock().unwrap();
+    let since = if TRACK_LOCK_HOLD.load(Ordering::SeqCst) {
+        Some(Instant::now())
+    } else {
+        None
+    };
+    SessionsGuard { guard, since }
+}
+
+#[derive(Default)]
 struct ChannelMuxSession {
     sessions: Vec<Option<MuxSession>>,
-    cursor: AtomicU32,
+    // slot in sessions of each live session, for the lookups on the window update path
+    session_ids: HashMap<u32, usize>,
+}
+
+impl ChannelMuxSession {
+    fn get(&self, id: u32) -> Option<&MuxSession> {
+        let idx = *self.session_ids.get(&id)?;
+        self.sessions[idx].as_ref()
+    }
+    fn get_mut(&mut self, id: u32) -> Option<&mut MuxSession> {
+        let idx = *self.session_ids.get(&id)?;
+        self.sessions[idx].as_mut()
+    }
+    fn insert(&mut self, session: MuxSession) {
+        let id = session.id;
+        let idx = match self.sessions.iter().position(|s| s.is_none()) {
+            Some(idx) => {
+                self.sessions[idx] = Some(session);
+                idx
+            }
+            None => {
+                self.sessions.push(Some(session));
+                self.sessions.len() - 1
+            }
+        };
+        self.session_ids.insert(id, idx);
+    }
+    fn remove(&mut self, id: u32) -> Option<MuxSession> {
+        let idx = self.session_ids.remove(&id)?;
+        self.sessions[idx].take()
+    }
+    // drop the ids of sessions taken out of their slot directly
+    fn prune_session_ids(&mut self) {
+        let sessions = &self.sessions;
+        self.session_ids.retain(|_, idx| sessions[*idx].is_some());
+    }
+    // smooth weighted round robin over the sessions of the channel's current key and best
+    // quality class there are, sessions with weight 0 or saturated are never selected
+    fn select_session(
+        &mut self,
+        thresholds: &SaturationThresholds,
+        key_epoch: u32,
+    ) -> Option<usize> {
+        let rank = |s: &MuxSession| (s.params.key_epoch < key_epoch, s.state.quality());
+        let preferred = self
+            .sessions
+            .iter()
+            .flatten()
+            .filter(|s| s.is_selectable(thresholds))
+            .map(|s| rank(s))
+            .min()?;
+        let mut total: i64 = 0;
+        let mut best: Option<(usize, i64)> = None;
+        for (i, session) in self.sessions.iter_mut().enumerate() {
+            if let Some(s) = session {
+                if !s.is_selectable(thresholds) || rank(s) != preferred {
+                    continue;
+                }
+                let weight = i64::from(s.weight.load(Ordering::SeqCst));
+                s.current_weight += weight;
+                total += weight;
+                if best.map_or(true, |(_, w)| s.current_weight > w) {
+                    best = Some((i, s.current_weight));
+                }
+            }
+        }
+        let (idx, _) = best?;
+        if let Some(s) = &mut self.sessions[idx] {
+            s.current_weight -= total;
+        }
+        Some(idx)
+    }
 }
 
 pub struct MuxSessionState {
-    last_ping_send_time: AtomicU32,
-    last_pong_recv_time: AtomicU32,
+    // monotonic millis since born_time, immune to wall clock steps, 0 means never
+    last_ping_send_millis: AtomicU64,
+    last_pong_recv_millis: AtomicU64,
     pub born_time: Instant,
+    clock: Arc<dyn Clock>,
     retired: AtomicBool,
+    // shut down, the peer's SYNs are refused
+    draining: AtomicBool,
     io_active_unix_secs: AtomicU32,
+    recv_active_unix_secs: AtomicU32,
     closed: AtomicBool,
+    recv_queue_depth: AtomicU32,
+    send_queue_depth: AtomicU32,
+    // streams known to the event loop
+    stream_count: AtomicU32,
+    // those streams by id, looked up by close_stream and list_streams
+    stream_ids: Mutex<HashMap<u32, StreamHandle>>,
+    // remote frames with flags the event loop doesn't know
+    unknown_frames: AtomicU32,
+    // those and the streams lost to keepalive timeouts or sequence gaps
+    errors: AtomicU32,
+    // SessionQuality as of the last routine tick, and what it was judged from
+    quality: AtomicU8,
+    quality_sampled_millis: AtomicU64,
+    sampled_stall_micros: AtomicU64,
+    sampled_errors: AtomicU32,
+    // bytes of the events in both queues, charged to the buffer budget
+    queued_buffers: BufferCharge,
+    total_bytes: AtomicU64,
+    // total_bytes at the last throughput sample
+    sampled_bytes: AtomicU64,
+    // of the frames taken off the send queue
+    send_dwell_max_micros: AtomicU64,
+    send_dwell_total_micros: AtomicU64,
+    send_dwell_frames: AtomicU64,
+    close_reason: Mutex<Option<SessionCloseReason>>,
 }
 
 impl MuxSessionState {
+    // the first teardown path to fire decides the reason
+    fn set_close_reason(&self, reason: SessionCloseReason) {
+        let mut r = self.close_reason.lock().unwrap();
+        if r.is_none() {
+            *r = Some(reason);
+        }
+    }
+    fn now_unix_secs(&self) -> u32 {
+        self.clock.now_unix_secs()
+    }
+    fn age(&self) -> Duration {
+        self.clock
+            .now_instant()
+            .saturating_duration_since(self.born_time)
+    }
+    fn mono_millis(&self) -> u64 {
+        self.age().as_millis() as u64 + 1
+    }
+    // in secs, negative while the last ping is unanswered
     fn ping_pong_gap(&self) -> i64 {
-        let t1 = self.last_ping_send_time.load(Ordering::SeqCst);
-        let t2 = self.last_pong_recv_time.load(Ordering::SeqCst);
+        let t1 = self.last_ping_send_millis.load(Ordering::SeqCst);
+        let t2 = self.last_pong_recv_millis.load(Ordering::SeqCst);
         if t1 > 0 && t2 > 0 {
-            return t2 as i64 - t1 as i64;
+            return (t2 as i64 - t1 as i64) / 1000;
         }
         0
     }
+    // of the last answered ping
+    fn rtt(&self) -> Option<Duration> {
+        let t1 = self.last_ping_send_millis.load(Ordering::SeqCst);
+        let t2 = self.last_pong_recv_millis.load(Ordering::SeqCst);
+        if t1 > 0 && t2 >= t1 {
+            return Some(Duration::from_millis(t2 - t1));
+        }
+        None
+    }
+    fn quality(&self) -> SessionQuality {
+        SessionQuality::from_u8(self.quality.load(Ordering::SeqCst))
+    }
+    fn record_send_dwell(&self, dwell: Duration) {
+        let micros = dwell.as_micros() as u64;
+        self.send_dwell_max_micros
+            .fetch_max(micros, Ordering::SeqCst);
+        self.send_dwell_total_micros
+            .fetch_add(micros, Ordering::SeqCst);
+        self.send_dwell_frames.fetch_add(1, Ordering::SeqCst);
+    }
+    fn avg_send_dwell(&self) -> Duration {
+        let frames = self.send_dwell_frames.load(Ordering::SeqCst);
+        if frames == 0 {
+            return Duration::default();
+        }
+        Duration::from_micros(self.send_dwell_total_micros.load(Ordering::SeqCst) / frames)
+    }
     fn is_retired(&self) -> bool {
         self.retired.load(Ordering::SeqCst)
     }
@@ -82,6 +645,13 @@ impl MuxSessionState {
         }
         now_unix_secs - secs
     }
+    fn get_recv_idle_secs(&self, now_unix_secs: u32) -> u32 {
+        let secs = self.recv_active_unix_secs.load(Ordering::SeqCst);
+        if secs == 0 {
+            return u32::max_value();
+        }
+        now_unix_secs.saturating_sub(secs)
+    }
 }
 
 pub struct MuxSession {
@@ -90,41 +660,138 @@ pub struct MuxSession {
     pendding_streams: Vec<MuxStream>,
     stream_id_seed: AtomicU32,
     state: Arc<MuxSessionState>,
-    max_alive_secs: u64,
+    max_alive_secs: AtomicU64,
+    max_alive_bytes: u64,
+    rotation_jitter_ratio: Option<f64>,
+    ping_idle_secs: u32,
+    stream_recv_window: u32,
+    weight: AtomicU32,
+    current_weight: i64,
+    hello: Hello,
+    params: Arc<SessionParams>,
 }
 
-fn store_mux_session(channel: &str, session: MuxSession) {
-    let cmap = &mut CHANNEL_SESSIONS.lock().unwrap().channels;
-    //info!("{}0 store cmap size:{}", channel, cmap.len());
-    if cmap.get_mut(channel).is_none() {
-        let csession = ChannelMuxSession {
-            sessions: Vec::new(),
-            cursor: AtomicU32::new(0),
-        };
-        cmap.insert(String::from(channel), csession);
+impl MuxSession {
+    fn is_saturated(&self, t: &SaturationThresholds) -> bool {
+        let over = |v: u32, max: u32| max > 0 && v >= max;
+        over(
+            self.state.send_queue_depth.load(Ordering::SeqCst),
+            t.max_send_queue_depth,
+        ) || over(
+            self.state.recv_queue_depth.load(Ordering::SeqCst),
+            t.max_recv_queue_depth,
+        ) || over(
+            self.state.stream_count.load(Ordering::SeqCst) + self.pendding_streams.len() as u32,
+            t.max_streams,
+        ) || over(self.pendding_streams.len() as u32, t.max_pending_streams)
     }
-    if let Some(csession) = cmap.get_mut(channel) {
-        for s in csession.sessions.iter_mut() {
-            if s.is_none() {
-                *s = Some(session);
-                return;
-            }
+    fn is_selectable(&self, t: &SaturationThresholds) -> bool {
+        self.weight.load(Ordering::SeqCst) > 0 && !self.is_saturated(t)
+    }
+    fn stats(&self, channel: &str, now_unix_secs: u32) -> SessionStats {
+        SessionStats {
+            channel: String::from(channel),
+            session_id: self.id,
+            age_secs: self.state.age().as_secs(),
+            retired: self.state.is_retired(),
+            closed: self.state.is_closed(),
+            ping_pong_gap: self.state.ping_pong_gap(),
+            io_idle_secs: self.state.get_io_idle_secs(now_unix_secs),
+            pending_streams: self.pendding_streams.len(),
+            recv_queue_depth: self.state.recv_queue_depth.load(Ordering::SeqCst),
+            send_queue_depth: self.state.send_queue_depth.load(Ordering::SeqCst),
+            weight: self.weight.load(Ordering::SeqCst),
+            version: self.hello.version,
+            capabilities: self.hello.capabilities,
+            max_send_dwell: Duration::from_micros(
+                self.state.send_dwell_max_micros.load(Ordering::SeqCst),
+            ),
+            avg_send_dwell: self.state.avg_send_dwell(),
+            quality: self.state.quality(),
         }
-        csession.sessions.push(Some(session));
     }
+    fn snapshot(&self) -> SessionSnapshot {
+        let mut streams: Vec<StreamSnapshot> = self
+            .state
+            .stream_ids
+            .lock()
+            .unwrap()
+            .values()
+            .map(|h| {
+                let st = h.stats();
+                StreamSnapshot {
+                    stream_id: st.stream_id,
+                    proto: h.target().proto.clone(),
+                    target: st.target,
+                    metadata: st.metadata,
+                    send_bytes: st.send_bytes,
+                    recv_bytes: st.recv_bytes,
+                    age_secs: st.age.as_secs(),
+                    resumable: h.is_resumable(),
+                }
+            })
+            .collect();
+        streams.sort_by_key(|s| s.stream_id);
+        SessionSnapshot {
+            channel: self.params.channel.clone(),
+            session_id: self.id,
+            cipher: self.params.cipher.clone(),
+            version: self.hello.version,
+            capabilities: self.hello.capabilities,
+            stream_id_seed: self.params.stream_id_seed,
+            age_secs: self.state.age().as_secs(),
+            retired: self.state.is_retired(),
+            total_bytes: self.state.total_bytes.load(Ordering::SeqCst),
+            streams,
+        }
+    }
+}
+
+// Caps the live sessions of the channel, retired ones don't count. A session established
+// past it is rejected with RmuxError::TooManySessions rather than evicting an older one, so
+// a reconnect storm fails loudly instead of churning the healthy sessions. 0 for no limit.
+pub fn set_channel_max_sessions(channel: &str, max: usize) {
+    let mut limits = MAX_SESSIONS.lock().unwrap();
+    if max == 0 {
+        limits.remove(channel);
+        return;
+    }
+    limits.insert(String::from(channel), max);
+}
+
+fn get_max_sessions(channel: &str) -> Option<usize> {
+    MAX_SESSIONS.lock().unwrap().get(channel).copied()
+}
+
+// fails if the id is already taken by a live session of the channel or a retired one, or
+// the channel has its max sessions
+fn store_mux_session(channel: &str, session: MuxSession) -> Result<(), std::io::Error> {
+    let max_sessions = get_max_sessions(channel);
+    let mut holder = lock_sessions();
+    if holder.retired.iter().any(|s| s.id == session.id) {
+        return Err(make_io_error("duplicate session id."));
+    }
+    //info!("{}0 store cmap size:{}", channel, cmap.len());
+    let csession = holder
+        .channels
+        .entry(String::from(channel))
+        .or_insert_with(ChannelMuxSession::default);
+    if csession.session_ids.contains_key(&session.id) {
+        return Err(make_io_error("duplicate session id."));
+    }
+    if max_sessions.map_or(false, |max| csession.session_ids.len() >= max) {
+        return Err(RmuxError::TooManySessions.into());
+    }
+    csession.insert(session);
+    Ok(())
 }
 
 fn erase_mux_session(channel: &str, sid: u32) {
-    let mut holder = CHANNEL_SESSIONS.lock().unwrap();
+    let mut holder = lock_sessions();
     let cmap = &mut holder.channels;
     if let Some(csession) = cmap.get_mut(channel) {
-        for s in csession.sessions.iter_mut() {
-            if let Some(ss) = s {
-                if ss.id == sid {
-                    let _ = s.take();
-                    return;
-                }
-            }
+        if csession.remove(sid).is_some() {
+            return;
         }
     }
     for i in 0..holder.retired.len() {
@@ -135,27 +802,38 @@ fn erase_mux_session(channel: &str, sid: u32) {
     }
 }
 
+// returns None once the ids of the session are used up
+fn alloc_stream_id(seed: &AtomicU32) -> Option<u32> {
+    let mut current = seed.load(Ordering::SeqCst);
+    loop {
+        let next = current.checked_add(2)?;
+        match seed.compare_exchange(current, next, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return Some(current),
+            Err(v) => current = v,
+        }
+    }
+}
+
 fn hanle_pendding_mux_streams(channel: &str, sid: u32, streams: &mut HashMap<u32, MuxStream>) {
-    let cmap = &mut CHANNEL_SESSIONS.lock().unwrap().channels;
-    if let Some(csession) = cmap.get_mut(channel) {
-        for cs in csession.sessions.iter_mut() {
-            if let Some(ss) = cs {
-                if ss.id == sid {
-                    loop {
-                        if let Some(s) = ss.pendding_streams.pop() {
-                            streams.insert(s.id(), s);
-                        } else {
-                            return;
-                        }
-                    }
-                }
+    let cmap = &mut lock_sessions().channels;
+    if let Some(ss) = cmap.get_mut(channel).and_then(|cs| cs.get_mut(sid)) {
+        while let Some(s) = ss.pendding_streams.pop() {
+            if streams.contains_key(&s.id()) {
+                error!(stream_id = s.id(), "stream id collides with a live stream");
+                continue;
             }
+            ss.state
+                .stream_ids
+                .lock()
+                .unwrap()
+                .insert(s.id(), s.handle());
+            streams.insert(s.id(), s);
         }
     }
 }
 
 pub fn get_channel_session_size(channel: &str) -> usize {
-    let cmap = &mut CHANNEL_SESSIONS.lock().unwrap().channels;
+    let cmap = &mut lock_sessions().channels;
     let mut len: usize = 0;
     if let Some(csession) = cmap.get_mut(channel) {
         for s in csession.sessions.iter() {
@@ -167,115 +845,852 @@ pub fn get_channel_session_size(channel: &str) -> usize {
     len
 }
 
+pub fn channel_is_healthy(channel: &str) -> bool {
+    let cmap = &lock_sessions().channels;
+    if let Some(csession) = cmap.get(channel) {
+        return csession.sessions.iter().flatten().any(|s| {
+            !s.state.is_retired()
+                && !s.state.is_closed()
+                && s.state.ping_pong_gap() >= -HEARTBEAT_TIMEOUT_SECS
+        });
+    }
+    false
+}
+
+pub fn set_channel_max_alive_secs(channel: &str, secs: u64) {
+    let cmap = &mut lock_sessions().channels;
+    if let Some(csession) = cmap.get_mut(channel) {
+        for s in csession.sessions.iter().flatten() {
+            s.max_alive_secs.store(secs, Ordering::SeqCst);
+        }
+    }
+}
+
+// weight 0 drains the session, no new stream would be created on it
+pub fn set_session_weight(channel: &str, session_id: u32, weight: u32) {
+    let cmap = &mut lock_sessions().channels;
+    if let Some(s) = cmap.get_mut(channel).and_then(|cs| cs.get_mut(session_id)) {
+        s.weight.store(weight, Ordering::SeqCst);
+    }
+}
+
+// what the session was established with, None once it's gone
+pub fn session_params(channel: &str, session_id: u32) -> Option<Arc<SessionParams>> {
+    let holder = lock_sessions();
+    if let Some(s) = holder
+        .channels
+        .get(channel)
+        .and_then(|cs| cs.get(session_id))
+    {
+        return Some(s.params.clone());
+    }
+    holder
+        .retired
+        .iter()
+        .find(|s| s.id == session_id && s.params.channel == channel)
+        .map(|s| s.params.clone())
+}
+
+// streams of a live or retired session, ordered by id
+pub fn list_streams(channel: &str, session_id: u32) -> Vec<StreamStats> {
+    let holder = lock_sessions();
+    let session = holder
+        .channels
+        .get(channel)
+        .and_then(|cs| cs.get(session_id))
+        .or_else(|| {
+            holder
+                .retired
+                .iter()
+                .find(|s| s.id == session_id && s.params.channel == channel)
+        });
+    let mut streams: Vec<StreamStats> = match session {
+        Some(s) => s
+            .state
+            .stream_ids
+            .lock()
+            .unwrap()
+            .values()
+            .map(|h| h.stats())
+            .collect(),
+        None => return Vec::new(),
+    };
+    streams.sort_by_key(|s| s.stream_id);
+    streams
+}
+
+// the live sessions of every channel and then the retired ones
+pub(crate) fn session_snapshots() -> Vec<SessionSnapshot> {
+    let holder = lock_sessions();
+    holder
+        .channels
+        .values()
+        .flat_map(|cs| cs.sessions.iter().flatten())
+        .chain(holder.retired.iter())
+        .map(|s| s.snapshot())
+        .collect()
+}
+
+// live or retired
+pub(crate) fn session_exists(channel: &str, session_id: u32) -> bool {
+    let holder = lock_sessions();
+    holder
+        .channels
+        .get(channel)
+        .and_then(|cs| cs.get(session_id))
+        .is_some()
+        || holder
+            .retired
+            .iter()
+            .any(|s| s.id == session_id && s.params.channel == channel)
+}
+
+pub fn channel_stats(channel: &str) -> Vec<SessionStats> {
+    let cmap = &lock_sessions().channels;
+    let mut stats = Vec::new();
+    if let Some(csession) = cmap.get(channel) {
+        for s in csession.sessions.iter().flatten() {
+            stats.push(s.stats(channel, s.state.now_unix_secs()));
+        }
+    }
+    stats
+}
+
+// limit moved by r(-1..1) times the jitter range, never below zero
+fn jitter_limit(limit: u64, range: f64, r: f64) -> u64 {
+    let v = limit as f64 + range * r;
+    if v < 0.0 {
+        return 0;
+    }
+    v as u64
+}
+
+// events of one session are sent in order, sessions are dispatched concurrently
 struct RoutineAction {
-    ev: Option<Event>,
+    events: Vec<Event>,
     sender: mpsc::Sender<Event>,
 }
 
 impl RoutineAction {
     fn new(ev: Event, sender: mpsc::Sender<Event>) -> Self {
         Self {
-            ev: Some(ev),
+            events: vec![ev],
             sender,
         }
     }
+    async fn dispatch(mut self) {
+        for ev in self.events.drain(..) {
+            if self.sender.send(ev).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// what the routine judges of a live session, copied out so the lock isn't held meanwhile
+struct SessionCheck {
+    channel: String,
+    id: u32,
+    state: Arc<MuxSessionState>,
+    event_tx: mpsc::Sender<Event>,
+    stream_id_seed: u32,
+    max_alive_secs: u64,
+    max_alive_bytes: u64,
+    rotation_jitter_ratio: Option<f64>,
+    ping_idle_secs: u32,
+    key_epoch: u32,
+}
+
+impl SessionCheck {
+    fn new(channel: &str, s: &MuxSession) -> Self {
+        Self {
+            channel: String::from(channel),
+            id: s.id,
+            state: s.state.clone(),
+            event_tx: s.event_tx.clone(),
+            stream_id_seed: s.stream_id_seed.load(Ordering::SeqCst),
+            max_alive_secs: s.max_alive_secs.load(Ordering::SeqCst),
+            max_alive_bytes: s.max_alive_bytes,
+            rotation_jitter_ratio: s.rotation_jitter_ratio,
+            ping_idle_secs: s.ping_idle_secs,
+            key_epoch: s.params.key_epoch,
+        }
+    }
+    fn sample(&self) -> ThroughputSample {
+        let total = self.state.total_bytes.load(Ordering::SeqCst);
+        let last = self.state.sampled_bytes.swap(total, Ordering::SeqCst);
+        ThroughputSample {
+            channel: self.channel.clone(),
+            session_id: self.id,
+            bytes: total.saturating_sub(last),
+            streams: self.state.stream_count.load(Ordering::SeqCst),
+            rtt: self.state.rtt(),
+        }
+    }
+    // classes the session by what it went through since the last tick
+    fn assess_quality(&self) {
+        let stall_micros: u64 = self
+            .state
+            .stream_ids
+            .lock()
+            .unwrap()
+            .values()
+            .map(|h| h.window_stall_time().as_micros() as u64)
+            .sum();
+        let errors = self.state.errors.load(Ordering::SeqCst);
+        let now = self.state.mono_millis();
+        let last = self
+            .state
+            .quality_sampled_millis
+            .swap(now, Ordering::SeqCst);
+        let elapsed_micros = now.saturating_sub(last).max(1) * 1000;
+        // streams closed since then take their stall time with them
+        let stalled = stall_micros.saturating_sub(
+            self.state
+                .sampled_stall_micros
+                .swap(stall_micros, Ordering::SeqCst),
+        );
+        let signals = QualitySignals {
+            rtt: self.state.rtt(),
+            stall_ratio: stalled as f64 / elapsed_micros as f64,
+            errors: errors.wrapping_sub(self.state.sampled_errors.swap(errors, Ordering::SeqCst)),
+            // a session that has received nothing yet is idle since its start
+            recv_idle_secs: std::cmp::min(
+                self.state.get_recv_idle_secs(self.state.now_unix_secs()),
+                self.state.age().as_secs() as u32,
+            ),
+            streams: self.state.stream_count.load(Ordering::SeqCst),
+        };
+        let quality = classify_session(&signals, &get_quality_thresholds(&self.channel));
+        let prev = self.state.quality.swap(quality as u8, Ordering::SeqCst);
+        if prev != quality as u8 {
+            info!(
+                channel = self.channel.as_str(),
+                session_id = self.id,
+                ?quality,
+                ?signals,
+                "session quality changed"
+            );
+        }
+    }
+    // past its max age or bytes, each moved by a random jitter
+    fn is_expired(&self) -> bool {
+        let (max_alive_secs, max_alive_bytes) = (self.max_alive_secs, self.max_alive_bytes);
+        if (max_alive_secs == 0 && max_alive_bytes == 0) || self.channel.is_empty() {
+            return false;
+        }
+        let r: f64 = {
+            let mut rng = rand::thread_rng();
+            rng.gen_range(-1.0, 1.0)
+        };
+        let (secs_range, bytes_range) = match self.rotation_jitter_ratio {
+            Some(ratio) => (
+                max_alive_secs as f64 * ratio,
+                max_alive_bytes as f64 * ratio,
+            ),
+            None => (
+                (max_alive_secs as f64 * DEFAULT_ROTATION_JITTER_RATIO)
+                    .min(DEFAULT_MAX_AGE_JITTER_SECS),
+                max_alive_bytes as f64 * DEFAULT_ROTATION_JITTER_RATIO,
+            ),
+        };
+        let cmp_secs = jitter_limit(max_alive_secs, secs_range, r);
+        let cmp_bytes = jitter_limit(max_alive_bytes, bytes_range, r);
+        let total_bytes = self.state.total_bytes.load(Ordering::SeqCst);
+        let expired = max_alive_secs > 0 && self.state.age().as_secs() > cmp_secs;
+        let exhausted = max_alive_bytes > 0 && total_bytes > cmp_bytes;
+        if expired || exhausted {
+            info!(
+                "[{}][{}]Retire session with age:{:?} transfered bytes:{}",
+                self.channel,
+                self.id,
+                self.state.age(),
+                total_bytes
+            );
+        }
+        expired || exhausted
+    }
 }
 
+// The lock is only held to copy out the sessions and later to move the ones to retire, the
+// checks, logging and dispatching run without it.
 pub async fn routine_all_sessions() {
     let mut actions = Vec::new();
+    let mut checks = Vec::new();
+    let mut closed = Vec::new();
     {
-        let mut holder = CHANNEL_SESSIONS.lock().unwrap();
-        let cmap = &mut holder.channels;
-        let mut retired = Vec::new();
-        for (channel, csession) in cmap.iter_mut() {
+        let mut holder = lock_sessions();
+        for (channel, csession) in holder.channels.iter_mut() {
             for session in csession.sessions.iter_mut() {
-                if let Some(s) = session {
-                    if s.state.ping_pong_gap() < -60 {
-                        error!("[{}]Session heartbeat timeout.", s.id);
-                        let shutdown = new_shutdown_event(0, false);
-                        actions.push(RoutineAction::new(shutdown, s.event_tx.clone()));
-                        s.state.retired.store(true, Ordering::SeqCst);
-                        retired.push(session.take().unwrap());
-                        continue;
-                    } else {
-                        if !channel.is_empty() {
-                            let ping = new_ping_event(0, false);
-                            actions.push(RoutineAction::new(ping, s.event_tx.clone()));
-                        }
-                        let r = new_routine_event(0);
-                        actions.push(RoutineAction::new(r, s.event_tx.clone()));
-                        if s.max_alive_secs > 0 && !channel.is_empty() {
-                            let rand_inc: i64 = {
-                                let mut rng = rand::thread_rng();
-                                rng.gen_range(-60, 60)
-                            };
-                            //let session_id = s.id;
-                            let cmp_secs = s.max_alive_secs as i64 + rand_inc;
-                            if s.state.born_time.elapsed().as_secs() > cmp_secs as u64 {
-                                s.state.retired.store(true, Ordering::SeqCst);
-                                retired.push(session.take().unwrap());
-                                //csession.session_ids.remove(&session_id);
-                            }
-                        }
-                    }
+                let is_closed = match session {
+                    Some(s) => s.state.is_closed(),
+                    None => continue,
+                };
+                if is_closed {
+                    closed.push(session.take().unwrap());
+                } else if let Some(s) = session {
+                    checks.push(SessionCheck::new(channel, s));
+                }
+            }
+            csession.prune_session_ids();
+        }
+        let retired = std::mem::replace(&mut holder.retired, Vec::new());
+        for s in retired {
+            if s.state.is_closed() {
+                closed.push(s);
+            } else {
+                actions.push(RoutineAction::new(new_routine_event(0), s.event_tx.clone()));
+                holder.retired.push(s);
+            }
+        }
+    }
+    for s in closed {
+        warn!("[{}]Remove closed session.", s.id);
+    }
+
+    let sampler = throughput_sampler();
+    let mut samples = Vec::new();
+    let mut retiring = Vec::new();
+    for c in checks {
+        if sampler.is_some() {
+            samples.push(c.sample());
+        }
+        if c.state.ping_pong_gap() < -HEARTBEAT_TIMEOUT_SECS {
+            error!("[{}]Session heartbeat timeout.", c.id);
+            c.state
+                .set_close_reason(SessionCloseReason::HeartbeatTimeout);
+            let shutdown = new_shutdown_event(0, false);
+            actions.push(RoutineAction::new(shutdown, c.event_tx.clone()));
+            retiring.push(c);
+            continue;
+        }
+        if c.key_epoch < channel_key_epoch(&c.channel) {
+            if previous_channel_key(&c.channel).is_none() {
+                info!(
+                    channel = c.channel.as_str(),
+                    session_id = c.id,
+                    "Close session of the previous key"
+                );
+                c.state.set_close_reason(SessionCloseReason::KeyRotated);
+                let shutdown = new_shutdown_event(0, false);
+                actions.push(RoutineAction::new(shutdown, c.event_tx.clone()));
+                retiring.push(c);
+                continue;
+            }
+            // the server keeps taking streams on it, its client may not have rotated yet
+            if !c.channel.is_empty() {
+                c.state.draining.store(true, Ordering::SeqCst);
+                if c.state.stream_count.load(Ordering::SeqCst) == 0 {
+                    info!(
+                        channel = c.channel.as_str(),
+                        session_id = c.id,
+                        "Retire drained session of the previous key"
+                    );
+                    actions.push(RoutineAction::new(new_routine_event(0), c.event_tx.clone()));
+                    retiring.push(c);
+                    continue;
                 }
             }
         }
-        for s in holder.retired.iter_mut() {
-            let r = new_routine_event(0);
-            actions.push(RoutineAction::new(r, s.event_tx.clone()));
+        c.assess_quality();
+        let mut events = Vec::new();
+        // recent inbound frames already prove the link alive
+        if !c.channel.is_empty()
+            && c.state.get_recv_idle_secs(c.state.now_unix_secs()) >= c.ping_idle_secs
+        {
+            events.push(new_ping_event(0, false));
+        }
+        events.push(new_routine_event(0));
+        actions.push(RoutineAction {
+            events,
+            sender: c.event_tx.clone(),
+        });
+        if c.stream_id_seed >= STREAM_ID_RETIRE_THRESHOLD {
+            info!(
+                channel = c.channel.as_str(),
+                session_id = c.id,
+                "Retire session with stream ids nearly exhausted"
+            );
+            retiring.push(c);
+        } else if c.is_expired() {
+            retiring.push(c);
+        }
+    }
+    let max_retired = max_retired_sessions();
+    if !retiring.is_empty() || max_retired > 0 {
+        let mut holder = lock_sessions();
+        for c in retiring {
+            // it may have closed meanwhile
+            let taken = holder
+                .channels
+                .get_mut(&c.channel)
+                .and_then(|cs| cs.remove(c.id));
+            if let Some(s) = taken {
+                s.state.retired.store(true, Ordering::SeqCst);
+                holder.retired.push(s);
+            }
+        }
+        for s in holder.take_retired_overflow(max_retired) {
+            warn!(
+                session_id = s.id,
+                streams = s.state.stream_count.load(Ordering::SeqCst),
+                max_retired,
+                "Close retired session over the limit"
+            );
+            s.state.set_close_reason(SessionCloseReason::Retired);
+            actions.push(RoutineAction::new(
+                new_shutdown_event(0, false),
+                s.event_tx.clone(),
+            ));
         }
-        holder.retired.append(&mut retired);
     }
-    for action in actions.iter_mut() {
-        let ev = action.ev.take().unwrap();
-        let _ = action.sender.send(ev).await;
+    if let Some(f) = sampler {
+        f(&samples[..]);
+    }
+    routine_multipath_streams();
+    routine_conn_pool();
+    // a session with a full event queue no longer holds up all the others
+    join_all(actions.into_iter().map(RoutineAction::dispatch)).await;
+}
+
+// Retires the sessions of the channel, or of all channels with None, and refuses the peer's
+// SYNs on them. Each is shut down once its streams are done, the rest when grace runs out.
+async fn drain_sessions(channel: Option<&str>, grace: Duration) {
+    let mut draining = Vec::new();
+    {
+        let mut holder = lock_sessions();
+        let mut taken = Vec::new();
+        for (name, csession) in holder.channels.iter_mut() {
+            if channel.map_or(false, |c| c != name.as_str()) {
+                continue;
+            }
+            taken.extend(csession.sessions.iter_mut().filter_map(|s| s.take()));
+            csession.prune_session_ids();
+        }
+        for s in taken.iter() {
+            draining.push((s.state.clone(), s.event_tx.clone()));
+        }
+        // sessions retired before don't know their channel anymore, only a full drain takes them
+        if channel.is_none() {
+            for s in holder.retired.iter() {
+                draining.push((s.state.clone(), s.event_tx.clone()));
+            }
+        }
+        for s in taken {
+            s.state.retired.store(true, Ordering::SeqCst);
+            holder.retired.push(s);
+        }
+    }
+    for (state, _) in draining.iter() {
+        state.draining.store(true, Ordering::SeqCst);
+    }
+    close_drained(draining, grace).await;
+}
+
+// shuts each session down once it has no streams left, or all of them once grace runs out
+async fn close_drained(
+    mut draining: Vec<(Arc<MuxSessionState>, mpsc::Sender<Event>)>,
+    grace: Duration,
+) {
+    let deadline = Instant::now() + grace;
+    while !draining.is_empty() {
+        let expired = Instant::now() >= deadline;
+        draining.retain(|(state, _)| !state.is_closed());
+        for (state, event_tx) in draining.iter_mut() {
+            if !expired && state.stream_count.load(Ordering::SeqCst) > 0 {
+                continue;
+            }
+            state.set_close_reason(SessionCloseReason::LocalShutdown);
+            // retried next round if the queue is full
+            let _ = event_tx.try_send(new_shutdown_event(0, false));
+        }
+        if expired {
+            for (state, _) in draining.iter() {
+                state.closed.store(true, Ordering::SeqCst);
+            }
+            break;
+        }
+        delay_for(DRAIN_POLL_INTERVAL).await;
     }
 }
 
+// Takes one session out of service, e.g. before upgrading its upstream: it gets no new streams
+// either way and each resumable stream opened on it is reopened on another session of the
+// channel, from where its reader stopped. The old stream is closed, its reader fails. The
+// results are by the id the stream had on the evacuated session, a failed one may still be
+// resumed later with its resume_token. The other streams run until they close, the session
+// is closed once they're done or grace runs out.
+pub async fn evacuate_session(
+    channel: &str,
+    session_id: u32,
+    grace: Duration,
+) -> Vec<(u32, Result<MuxStream, std::io::Error>)> {
+    let mut resumable = Vec::new();
+    let draining = {
+        let mut holder = lock_sessions();
+        let taken = holder
+            .channels
+            .get_mut(channel)
+            .and_then(|cs| cs.remove(session_id));
+        let s = match taken {
+            Some(s) => s,
+            None => return Vec::new(),
+        };
+        s.state.retired.store(true, Ordering::SeqCst);
+        s.state.draining.store(true, Ordering::SeqCst);
+        // ids of the streams opened by this side share the parity of the seed
+        let parity = s.stream_id_seed.load(Ordering::SeqCst) % 2;
+        for (sid, h) in s.state.stream_ids.lock().unwrap().iter() {
+            if sid % 2 == parity {
+                if let Some(token) = h.detach() {
+                    let target = h.target();
+                    resumable.push((
+                        *sid,
+                        target.proto.clone(),
+                        target.addr.clone(),
+                        target.initial_data.clone(),
+                        token,
+                    ));
+                }
+            }
+        }
+        let draining = vec![(s.state.clone(), s.event_tx.clone())];
+        holder.retired.push(s);
+        draining
+    };
+    info!(
+        channel,
+        session_id,
+        streams = resumable.len(),
+        "evacuate session"
+    );
+    let mut event_tx = draining[0].1.clone();
+    let mut results = Vec::new();
+    for (sid, proto, addr, initial_data, token) in resumable {
+        let _ = event_tx
+            .send(new_fin_event_with_reason(sid, FinReason::Reset))
+            .await;
+        let stream = resume_stream(channel, &proto, &addr, initial_data, token).await;
+        if let Err(e) = &stream {
+            warn!(
+                channel,
+                session_id,
+                stream_id = sid,
+                "stream not resumed; error={}",
+                e
+            );
+        }
+        results.push((sid, stream));
+    }
+    tokio::spawn(close_drained(draining, grace));
+    results
+}
+
+// Drains the channel's sessions: no new streams either way, each session closes once its
+// streams finish and what's still open after grace is closed. The channel reconnects as usual.
+pub async fn shutdown_channel(channel: &str, grace: Duration) {
+    drain_sessions(Some(channel), grace).await;
+}
+
+// Drains every session of all channels for a graceful stop, e.g. on SIGTERM. No session or
+// stream can be created after it. Safe to call again or while the routine runs.
+pub async fn shutdown_all(grace: Duration) {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    drain_sessions(None, grace).await;
+    let mut holder = lock_sessions();
+    holder.channels.clear();
+    holder.retired.clear();
+}
+
 pub async fn create_stream(
     channel: &str,
     proto: &str,
     addr: &str,
 ) -> Result<MuxStream, std::io::Error> {
-    let (stream, ev, ev_sender) = {
-        let mut stream: Option<MuxStream> = None;
-        let mut ev: Option<Event> = None;
-        let mut ev_sender: Option<mpsc::Sender<Event>> = None;
-
-        let cmap = &mut CHANNEL_SESSIONS.lock().unwrap().channels;
-        //let mut cmap: HashMap<String, ChannelMuxSession> = HashMap::new();
-        if let Some(csession) = cmap.get_mut(channel) {
-            for _ in 0..csession.sessions.len() {
-                let mut idx = csession.cursor.fetch_add(1, Ordering::SeqCst);
-                idx %= csession.sessions.len() as u32;
-                if let Some(session) = &mut csession.sessions.as_mut_slice()[idx as usize] {
-                    let creq = ConnectRequest {
-                        proto: String::from(proto),
-                        addr: String::from(addr),
-                    };
-                    let cev =
-                        new_syn_event(session.stream_id_seed.fetch_add(2, Ordering::SeqCst), &creq);
-                    let pendding_stream = MuxStream::new(
-                        channel,
-                        session.id,
-                        cev.header.stream_id,
-                        session.event_tx.clone(),
-                        creq,
-                    );
-                    session.pendding_streams.push(pendding_stream.clone());
-                    stream = Some(pendding_stream);
-                    ev = Some(cev);
-                    ev_sender = Some(session.event_tx.clone());
-                    break;
+    create_stream_with_data(channel, proto, addr, Vec::new()).await
+}
+
+// initial data is packed into the SYN so the server could write it to the target
+// without waiting for the first DATA event
+pub async fn create_stream_with_data(
+    channel: &str,
+    proto: &str,
+    addr: &str,
+    initial_data: Vec<u8>,
+) -> Result<MuxStream, std::io::Error> {
+    create_stream_with_metadata(channel, proto, addr, initial_data, BTreeMap::new()).await
+}
+
+// the metadata reaches the stream callbacks & stats on the peer, it's dropped for peers
+// without CAP_CONNECT_EXT
+pub async fn create_stream_with_metadata(
+    channel: &str,
+    proto: &str,
+    addr: &str,
+    initial_data: Vec<u8>,
+    metadata: BTreeMap<String, String>,
+) -> Result<MuxStream, std::io::Error> {
+    let opts = StreamOptions {
+        metadata,
+        ..Default::default()
+    };
+    open_stream(channel, proto, addr, initial_data, opts).await
+}
+
+// Local DATA of streams with a higher priority overtakes that of lower ones queued in the
+// session, on both sides since the SYN carries it. 0, the default, keeps the arrival order.
+pub async fn create_stream_with_priority(
+    channel: &str,
+    proto: &str,
+    addr: &str,
+    initial_data: Vec<u8>,
+    priority: u8,
+) -> Result<MuxStream, std::io::Error> {
+    let opts = StreamOptions {
+        priority: std::cmp::min(priority, MAX_STREAM_PRIORITY),
+        ..Default::default()
+    };
+    open_stream(channel, proto, addr, initial_data, opts).await
+}
+
+// A stream whose resume_token reopens it on another session of the channel, e.g. once its
+// own session died. Only for targets giving the same output for the same initial data,
+// like a download, since the peer dials the target again and skips what was received.
+pub async fn create_resumable_stream(
+    channel: &str,
+    proto: &str,
+    addr: &str,
+    initial_data: Vec<u8>,
+) -> Result<MuxStream, std::io::Error> {
+    let opts = StreamOptions {
+        resume: Some(ResumeToken {
+            stream_key: rand::random(),
+            recv_offset: 0,
+        }),
+        ..Default::default()
+    };
+    open_stream(channel, proto, addr, initial_data, opts).await
+}
+
+// the reader of the stream gets the target's output from the token's offset on
+pub async fn resume_stream(
+    channel: &str,
+    proto: &str,
+    addr: &str,
+    initial_data: Vec<u8>,
+    token: ResumeToken,
+) -> Result<MuxStream, std::io::Error> {
+    let opts = StreamOptions {
+        resume: Some(token),
+        ..Default::default()
+    };
+    open_stream(channel, proto, addr, initial_data, opts).await
+}
+
+// what the create_stream variants add to the SYN
+#[derive(Default)]
+struct StreamOptions {
+    metadata: BTreeMap<String, String>,
+    resume: Option<ResumeToken>,
+    priority: u8,
+}
+
+async fn open_stream(
+    channel: &str,
+    proto: &str,
+    addr: &str,
+    initial_data: Vec<u8>,
+    opts: StreamOptions,
+) -> Result<MuxStream, std::io::Error> {
+    if SHUTTING_DOWN.load(Ordering::SeqCst) {
+        return Err(make_io_error("shutting down."));
+    }
+    let channel = resolve_channel(channel);
+    let channel = channel.as_str();
+    if initial_data.len() > MAX_SPANNING_INITIAL_DATA_LEN {
+        return Err(make_io_error("initial data too large."));
+    }
+    if opts
+        .metadata
+        .iter()
+        .map(|(k, v)| k.len() + v.len())
+        .sum::<usize>()
+        > MAX_METADATA_LEN
+    {
+        return Err(make_io_error("stream metadata too large."));
+    }
+    let multipath = is_multipath_channel(channel);
+    let thresholds = get_saturation_thresholds(channel);
+    let mut joins = Vec::new();
+    let mut trailing_data = None;
+    let mut waiter: Option<StreamWaiter> = None;
+    let (stream, ev, ev_sender) = loop {
+        let found = {
+            let mut stream: Option<MuxStream> = None;
+            let mut ev: Option<Vec<Event>> = None;
+            let mut ev_sender: Option<mpsc::Sender<Event>> = None;
+            let mut multipath_id = 0;
+
+            let cmap = &mut lock_sessions().channels;
+            //let mut cmap: HashMap<String, ChannelMuxSession> = HashMap::new();
+            if let Some(csession) = cmap.get_mut(channel) {
+                let selected = csession.select_session(&thresholds, channel_key_epoch(channel));
+                if selected.is_none()
+                    && csession
+                        .sessions
+                        .iter()
+                        .flatten()
+                        .any(|s| s.weight.load(Ordering::SeqCst) > 0)
+                {
+                    warn!(channel, "all sessions saturated, new stream rejected");
+                    return Err(RmuxError::Overloaded.into());
+                }
+                if let Some(idx) = selected {
+                    if let Some(session) = &mut csession.sessions[idx] {
+                        // only peers taking SYNs over several frames get more than one's worth
+                        if initial_data.len() > MAX_INITIAL_DATA_LEN
+                            && !session.hello.has(CAP_SYN_PARTS)
+                        {
+                            return Err(make_io_error("initial data too large."));
+                        }
+                        let stream_id = match alloc_stream_id(&session.stream_id_seed) {
+                            Some(id) => id,
+                            None => {
+                                error!(channel, session_id = session.id, "stream ids exhausted");
+                                return Err(make_io_error("stream ids exhausted."));
+                            }
+                        };
+                        if multipath && session.hello.has(CAP_MULTIPATH) {
+                            multipath_id = rand::thread_rng().gen_range(1, u64::max_value());
+                        }
+                        let mut creq = ConnectRequest {
+                            proto: String::from(proto),
+                            addr: String::from(addr),
+                            multipath_id,
+                            initial_data: initial_data.clone(),
+                            recv_window: if session.stream_recv_window != DEFAULT_STREAM_WINDOW {
+                                session.stream_recv_window
+                            } else {
+                                0
+                            },
+                            username: String::new(),
+                            password: String::new(),
+                            metadata: opts.metadata.clone(),
+                            hops: 0,
+                            resume: None,
+                            initial_credit: 0,
+                            priority: opts.priority,
+                        };
+                        if let Some(token) = opts.resume {
+                            if session.hello.has(CAP_STREAM_RESUME) {
+                                creq.resume = Some(token);
+                            } else if token.recv_offset > 0 {
+                                return Err(make_io_error("peer can't resume streams."));
+                            }
+                        }
+                        // plain tcp streams follow the proto configured for the channel
+                        if proto == "tcp" {
+                            if let Some((proto, username, password)) = get_stream_proto(channel) {
+                                creq.proto = proto;
+                                creq.username = username;
+                                creq.password = password;
+                            }
+                        }
+                        let credit = get_initial_send_credit(channel)
+                            .filter(|_| session.hello.has(CAP_INITIAL_CREDIT));
+                        if let Some(credit) = credit {
+                            creq.initial_credit = credit;
+                        }
+                        if !session.hello.has(CAP_CONNECT_EXT) {
+                            // a legacy peer only reads (proto, addr) of the SYN
+                            creq.recv_window = 0;
+                            creq.username.clear();
+                            creq.password.clear();
+                            creq.metadata.clear();
+                            if !creq.initial_data.is_empty() {
+                                let data = std::mem::replace(&mut creq.initial_data, Vec::new());
+                                trailing_data = Some(new_data_event(stream_id, &data[..], false));
+                            }
+                        }
+                        let cevs =
+                            new_syn_events(stream_id, &creq, session.hello.has(CAP_SYN_PARTS));
+                        let mut pendding_stream = MuxStream::new(
+                            channel,
+                            session.id,
+                            stream_id,
+                            session.event_tx.clone(),
+                            creq,
+                            session.stream_recv_window,
+                        );
+                        pendding_stream.set_clock(session.state.clock.clone());
+                        if multipath_id > 0 {
+                            pendding_stream
+                                .set_multipath(multipath::get_or_create(channel, multipath_id));
+                        }
+                        // credited before the first data goes out, the SYN tells the peer
+                        if let Some(credit) = credit {
+                            pendding_stream.update_send_window(credit - DEFAULT_STREAM_WINDOW);
+                        }
+                        if let Some(data) = &trailing_data {
+                            pendding_stream.state.flow.on_data_sent(data.body.len());
+                        }
+                        if !session.hello.has(CAP_CONNECT_RESULT) {
+                            pendding_stream.assume_connected();
+                        }
+                        session.pendding_streams.push(pendding_stream.clone());
+                        stream = Some(pendding_stream);
+                        ev = Some(cevs);
+                        ev_sender = Some(session.event_tx.clone());
+                    }
+                }
+                if let Some(mp) = stream.as_ref().and_then(|s| s.multipath()) {
+                    // every other live session of the channel able to carry it joins as an extra path
+                    for s in csession.sessions.iter().flatten() {
+                        if s.state.is_retired()
+                            || s.state.is_closed()
+                            || !s.hello.has(CAP_MULTIPATH)
+                        {
+                            continue;
+                        }
+                        mp.add_path(s.id, s.event_tx.clone());
+                        if Some(s.id) != stream.as_ref().map(|v| v.state.session_id) {
+                            joins.push((
+                                new_mp_data_event(multipath_id, 0, &[]),
+                                s.event_tx.clone(),
+                            ));
+                        }
+                    }
                 }
             }
+            (stream, ev, ev_sender)
+        };
+        if found.0.is_some() {
+            break found;
+        }
+        // no usable session, e.g. all retired during rotation, wait briefly for a fresh one
+        if waiter.is_none() {
+            waiter = StreamWaiter::new(channel);
+        }
+        match &waiter {
+            Some(w) if !w.expired() => delay_for(STREAM_WAIT_POLL_INTERVAL).await,
+            _ => break found,
         }
-        (stream, ev, ev_sender)
     };
-    if stream.is_some() {
-        let _ = ev_sender.unwrap().send(ev.unwrap()).await;
-        return Ok(stream.unwrap());
+    if let Some(stream) = stream {
+        let mut ev_sender = ev_sender.unwrap();
+        for ev in ev.unwrap() {
+            let _ = ev_sender.send(ev).await;
+        }
+        if let Some(data) = trailing_data {
+            let _ = ev_sender.send(data).await;
+        }
+        for (join, mut tx) in joins {
+            let _ = tx.send(join).await;
+        }
+        if let Some(mp) = stream.multipath() {
+            mp.attach(&stream).await;
+        }
+        return Ok(stream);
     }
     Err(make_io_error("no channel found."))
 }
@@ -287,37 +1702,135 @@ pub fn report_update_window(
     stream_id: u32,
     window: u32,
 ) -> bool {
-    let cmap = &mut CHANNEL_SESSIONS.lock().unwrap().channels;
-    if let Some(csession) = cmap.get_mut(channel) {
-        for cs in csession.sessions.iter_mut() {
-            if let Some(ss) = cs {
-                if ss.id == session_id {
-                    let ev = new_window_update_event(stream_id, window, false);
-                    match ss.event_tx.poll_ready(cx) {
-                        Poll::Ready(Ok(())) => {}
-                        _ => {
-                            return false;
-                        }
-                    }
-                    if let Ok(()) = ss.event_tx.try_send(ev) {
-                        return true;
-                    }
-                }
+    let holder = &mut *lock_sessions();
+    let mut session = holder.retired.iter_mut().find(|s| s.id == session_id);
+    if let Some(s) = holder
+        .channels
+        .get_mut(channel)
+        .and_then(|cs| cs.get_mut(session_id))
+    {
+        session = Some(s);
+    }
+    if let Some(ss) = session {
+        let ev = new_window_update_event(stream_id, window, false);
+        match ss.event_tx.poll_ready(cx) {
+            Poll::Ready(Ok(())) => {}
+            _ => {
+                return false;
             }
         }
+        return ss.event_tx.try_send(ev).is_ok();
     }
     true
 }
 
-async fn handle_rmux_stream(mut stream: MuxStream) -> Result<(), Box<dyn Error>> {
+// Reset one stream of the session from outside, e.g. by an admin. Its relay sees EOF and
+// the peer a FIN with reason Reset. Returns false if no such stream is alive.
+pub fn close_stream(channel: &str, session_id: u32, stream_id: u32) -> bool {
+    let holder = &mut *lock_sessions();
+    let mut session = holder.retired.iter_mut().find(|s| s.id == session_id);
+    if let Some(s) = holder
+        .channels
+        .get_mut(channel)
+        .and_then(|cs| cs.get_mut(session_id))
+    {
+        session = Some(s);
+    }
+    match session {
+        Some(ss) if ss.state.stream_ids.lock().unwrap().contains_key(&stream_id) => {
+            let ev = new_fin_event_with_reason(stream_id, FinReason::Reset);
+            ss.event_tx.try_send(ev).is_ok()
+        }
+        _ => false,
+    }
+}
+
+async fn handle_rmux_stream(
+    mut stream: MuxStream,
+    mut ticket: DialTicket,
+) -> Result<(), Box<dyn Error>> {
     let stream_id = stream.state.stream_id;
-    let target = String::from(stream.target.addr.as_str());
-    let result = get_channel_stream(String::from("direct"), target).await;
+    let mut target = String::from(stream.target.addr.as_str());
+    let initial_data = std::mem::replace(&mut stream.target.initial_data, Vec::new());
+    // the addr of a service stream names the service, the registered resolver picks the target
+    if stream.target.proto == "service" {
+        match resolve_service(target.as_str(), &stream.target.metadata) {
+            Some(addr) => {
+                stream.target.proto = String::from("tcp");
+                stream.target.addr = addr.clone();
+                target = addr;
+            }
+            None => {
+                warn!(stream_id, service = target.as_str(), "unknown service");
+                let _ = stream.close_with_reason(FinReason::PolicyDenied);
+                return Err(make_error("unknown service"));
+            }
+        }
+    }
+    // dialing the proxy itself would loop the stream back into it
+    if is_self_addr(stream.state.channel.as_str(), target.as_str()).await {
+        warn!(
+            stream_id,
+            target = redact_addr(target.as_str()).as_str(),
+            "target is the proxy itself"
+        );
+        let _ = stream.close_with_reason(FinReason::PolicyDenied);
+        return Err(make_error("forwarding loop detected"));
+    }
+    if stream.target.proto == "unix" {
+        return handle_unix_rmux_stream(stream, ticket, target, initial_data).await;
+    }
+    // the targets come with each datagram, they're authorized one by one
+    if stream.target.proto == "udp" {
+        return handle_udp_rmux_stream(stream).await;
+    }
+    if stream.target.proto == "socks5" || stream.target.proto == "http-connect" {
+        return handle_proxied_rmux_stream(stream, ticket, target, initial_data).await;
+    }
+    if conn_pool_enabled(stream.state.channel.as_str()) {
+        return handle_pooled_rmux_stream(stream, ticket, target, initial_data).await;
+    }
+    // a permit only covers one dial attempt, it's released on success and failure
+    let channel = stream.state.channel.clone();
+    let dialer = get_channel_dialer(channel.as_str());
+    let proto = stream.target.proto.clone();
+    let result = dial_with_retry(channel.as_str(), &mut ticket, || -> DialFuture {
+        match &dialer {
+            Some(dial) => dial(proto.clone(), target.clone()),
+            None => Box::pin(get_channel_stream(
+                String::from("direct"),
+                target.clone(),
+                initial_data.clone(),
+            )),
+        }
+    })
+    .await;
     match result {
         Ok(mut remote) => {
+            if dialer.is_some() && !initial_data.is_empty() {
+                let (_, mut wo) = remote.split();
+                if let Err(e) = wo.write_all(&initial_data[..]).await {
+                    drop(wo);
+                    let _ = stream.close_with_reason(FinReason::TargetClosed);
+                    let _ = remote.close();
+                    return Err(Box::new(e));
+                }
+            }
+            let skipped = {
+                let (mut ro, _) = remote.split();
+                skip_resumed(&stream, &mut ro).await
+            };
+            if let Err(e) = skipped {
+                let _ = stream.close_with_reason(FinReason::TargetClosed);
+                let _ = remote.close();
+                return Err(Box::new(e));
+            }
+            stream.report_connected().await;
             {
+                let gate = stream.send_window_gate();
                 let (mut ri, mut wi) = stream.split();
                 let (mut ro, mut wo) = remote.split();
+                let mut ro = gate.gate(&mut ro);
                 relay(stream_id, &mut ri, &mut wi, &mut ro, &mut wo).await?;
             }
             let _ = stream.close();
@@ -325,58 +1838,394 @@ async fn handle_rmux_stream(mut stream: MuxStream) -> Result<(), Box<dyn Error>>
             Ok(())
         }
         Err(e) => {
-            let _ = stream.close();
+            let _ = stream.close_connect_failed(&e);
             Err(Box::new(e))
         }
     }
 }
 
+// drops the target's output the requester of a resumed stream already received
+async fn skip_resumed<R: AsyncRead + Unpin + ?Sized>(
+    stream: &MuxStream,
+    remote: &mut R,
+) -> std::io::Result<()> {
+    let offset = match stream.target.resume {
+        Some(token) if token.recv_offset > 0 => token.recv_offset,
+        _ => return Ok(()),
+    };
+    let mut skipped = (&mut *remote).take(offset);
+    let n = tokio::io::copy(&mut skipped, &mut tokio::io::sink()).await?;
+    if n < offset {
+        return Err(make_io_error("target ended before the resume offset."));
+    }
+    info!(
+        stream_id = stream.state.stream_id,
+        offset, "resumed stream skipped received output"
+    );
+    Ok(())
+}
+
+async fn proxy_connect(
+    proto: &str,
+    proxy: &str,
+    target: &str,
+    auth: Option<(&str, &str)>,
+) -> std::io::Result<TcpStream> {
+    if proto == "socks5" {
+        socks5_proxy_connect(proxy, target, auth).await
+    } else {
+        http_connect_proxy_connect(proxy, target, auth).await
+    }
+}
+
+// the target is connected through the upstream proxy of the stream's proto, "socks5" or
+// "http-connect"; a proxy refusing the credentials or the target denies the stream
+async fn handle_proxied_rmux_stream(
+    mut stream: MuxStream,
+    mut ticket: DialTicket,
+    target: String,
+    initial_data: Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    let stream_id = stream.state.stream_id;
+    let proto = stream.target.proto.clone();
+    let upstream = match get_proxy_upstream(stream.state.channel.as_str(), proto.as_str()) {
+        Some(u) => u,
+        None => {
+            let _ = stream.close();
+            return Err(make_error("no upstream configured for the stream proto"));
+        }
+    };
+    // credentials carried by the stream take precedence over the configured ones
+    let (username, password) = if stream.target.username.is_empty() {
+        (upstream.username.as_str(), upstream.password.as_str())
+    } else {
+        (
+            stream.target.username.as_str(),
+            stream.target.password.as_str(),
+        )
+    };
+    let auth = if username.is_empty() {
+        None
+    } else {
+        Some((username, password))
+    };
+    let channel = stream.state.channel.clone();
+    let result = dial_with_retry(channel.as_str(), &mut ticket, || {
+        proxy_connect(
+            proto.as_str(),
+            upstream.addr.as_str(),
+            target.as_str(),
+            auth,
+        )
+    })
+    .await;
+    let mut remote = match result {
+        Ok(c) => c,
+        Err(e) => {
+            error!(
+                stream_id,
+                target = redact_addr(target.as_str()).as_str(),
+                upstream = upstream.addr.as_str(),
+                proto = proto.as_str(),
+                "upstream connect failed:{}",
+                e
+            );
+            if e.kind() == std::io::ErrorKind::PermissionDenied {
+                let _ = stream.close_with_reason(FinReason::PolicyDenied);
+            } else {
+                let _ = stream.close_connect_failed(&e);
+            }
+            return Err(Box::new(e));
+        }
+    };
+    if !initial_data.is_empty() {
+        if let Err(e) = remote.write_all(&initial_data[..]).await {
+            let _ = stream.close();
+            return Err(Box::new(e));
+        }
+    }
+    if let Err(e) = skip_resumed(&stream, &mut remote).await {
+        let _ = stream.close_with_reason(FinReason::TargetClosed);
+        return Err(Box::new(e));
+    }
+    stream.report_connected().await;
+    {
+        let gate = stream.send_window_gate();
+        let (mut ri, mut wi) = stream.split();
+        let (mut ro, mut wo) = remote.split();
+        let mut ro = gate.gate(&mut ro);
+        relay(stream_id, &mut ri, &mut wi, &mut ro, &mut wo).await?;
+    }
+    let _ = stream.close();
+    let _ = remote.shutdown(std::net::Shutdown::Both);
+    Ok(())
+}
+
+// the addr of a unix stream is the path of a local socket
+#[cfg(unix)]
+async fn handle_unix_rmux_stream(
+    mut stream: MuxStream,
+    mut ticket: DialTicket,
+    path: String,
+    initial_data: Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    let stream_id = stream.state.stream_id;
+    let channel = stream.state.channel.clone();
+    let result = dial_with_retry(channel.as_str(), &mut ticket, || {
+        tokio::net::UnixStream::connect(path.clone())
+    })
+    .await;
+    let mut remote = match result {
+        Ok(c) => c,
+        Err(e) => {
+            let _ = stream.close_connect_failed(&e);
+            return Err(Box::new(e));
+        }
+    };
+    if !initial_data.is_empty() {
+        if let Err(e) = remote.write_all(&initial_data[..]).await {
+            let _ = stream.close_with_reason(FinReason::TargetClosed);
+            return Err(Box::new(e));
+        }
+    }
+    if let Err(e) = skip_resumed(&stream, &mut remote).await {
+        let _ = stream.close_with_reason(FinReason::TargetClosed);
+        return Err(Box::new(e));
+    }
+    stream.report_connected().await;
+    {
+        let gate = stream.send_window_gate();
+        let (mut ri, mut wi) = stream.split();
+        let (mut ro, mut wo) = remote.split();
+        let mut ro = gate.gate(&mut ro);
+        relay(stream_id, &mut ri, &mut wi, &mut ro, &mut wo).await?;
+    }
+    let _ = stream.close();
+    let _ = remote.shutdown(std::net::Shutdown::Both);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn handle_unix_rmux_stream(
+    mut stream: MuxStream,
+    _ticket: DialTicket,
+    _path: String,
+    _initial_data: Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    let _ = stream.close_with_reason(FinReason::PolicyDenied);
+    Err(make_error(
+        "unix socket targets are not supported on this platform",
+    ))
+}
+
+async fn handle_pooled_rmux_stream(
+    mut stream: MuxStream,
+    mut ticket: DialTicket,
+    target: String,
+    initial_data: Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    let stream_id = stream.state.stream_id;
+    let channel = stream.state.channel.clone();
+    let proto = stream.target.proto.clone();
+    let mut remote = match take_idle_conn(channel.as_str(), proto.as_str(), target.as_str()) {
+        Some(c) => {
+            info!(
+                stream_id,
+                target = redact_addr(target.as_str()).as_str(),
+                "reuse idle conn"
+            );
+            c
+        }
+        None => {
+            let dial = || connect_direct(target.as_str());
+            match dial_with_retry(channel.as_str(), &mut ticket, dial).await {
+                Ok(c) => c,
+                Err(e) => {
+                    let _ = stream.close_connect_failed(&e);
+                    return Err(Box::new(e));
+                }
+            }
+        }
+    };
+    if !initial_data.is_empty() {
+        if let Err(e) = remote.write_all(&initial_data[..]).await {
+            let _ = stream.close();
+            return Err(Box::new(e));
+        }
+    }
+    if let Err(e) = skip_resumed(&stream, &mut remote).await {
+        let _ = stream.close_with_reason(FinReason::TargetClosed);
+        return Err(Box::new(e));
+    }
+    stream.report_connected().await;
+    let reusable = {
+        let gate = stream.send_window_gate();
+        let (mut ri, mut wi) = stream.split();
+        relay_reusable(stream_id, &mut ri, &mut wi, &mut remote, &gate).await
+    };
+    let _ = stream.close();
+    if reusable {
+        put_idle_conn(channel.as_str(), proto.as_str(), target.as_str(), remote);
+    }
+    Ok(())
+}
+
 fn handle_syn(
     channel: &str,
     session_id: u32,
-    ev: Event,
+    sid: u32,
+    body: Vec<u8>,
     evtx: mpsc::Sender<Event>,
+    recv_window: u32,
+    report_connected: bool,
+    report_connect_errors: bool,
+    clock: &Arc<dyn Clock>,
 ) -> Option<MuxStream> {
-    let connect_req: ConnectRequest = match bincode::deserialize(&ev.body[..]) {
+    let connect_req = match ConnectRequest::decode(&body[..]) {
         Ok(m) => m,
         Err(err) => {
             error!(
-                "Failed to parse ConnectRequest with error:{} while data len:{} {}",
+                "Failed to parse ConnectRequest with error:{} while data len:{}",
                 err,
-                ev.body.len(),
-                ev.header.len(),
+                body.len(),
             );
             return None;
         }
     };
-    let sid = ev.header.stream_id;
     info!(
-        "[{}]Handle conn request:{} {}",
-        sid, connect_req.proto, connect_req.addr
+        stream_id = sid,
+        proto = connect_req.proto.as_str(),
+        addr = redact_addr(connect_req.addr.as_str()).as_str(),
+        initial_len = connect_req.initial_data.len(),
+        "handle conn request"
+    );
+    if connect_req.hops >= MAX_STREAM_HOPS {
+        warn!(
+            stream_id = sid,
+            addr = redact_addr(connect_req.addr.as_str()).as_str(),
+            hops = connect_req.hops,
+            "too many hops, conn request looped"
+        );
+        let mut evtx = evtx;
+        let _ = evtx.try_send(new_fin_event_with_reason(sid, FinReason::PolicyDenied));
+        return None;
+    }
+    let open_ev = StreamEvent::Open {
+        channel: String::from(channel),
+        session_id,
+        stream_id: sid,
+        proto: connect_req.proto.clone(),
+        addr: connect_req.addr.clone(),
+        metadata: connect_req.metadata.clone(),
+    };
+    if !authorize_stream(&open_ev) {
+        warn!(
+            stream_id = sid,
+            addr = redact_addr(connect_req.addr.as_str()).as_str(),
+            "conn request denied"
+        );
+        let mut evtx = evtx;
+        let _ = evtx.try_send(new_fin_event_with_reason(sid, FinReason::PolicyDenied));
+        return None;
+    }
+    let relay_task = match start_relay_task() {
+        Some(t) => t,
+        None => {
+            warn!(
+                stream_id = sid,
+                addr = redact_addr(connect_req.addr.as_str()).as_str(),
+                "too many relay tasks, conn request rejected"
+            );
+            let mut evtx = evtx;
+            let _ = evtx.try_send(new_fin_event_with_reason(sid, FinReason::PolicyDenied));
+            return None;
+        }
+    };
+    let ticket = match get_dial_ticket(channel) {
+        Some(t) => t,
+        None => {
+            warn!(
+                stream_id = sid,
+                addr = redact_addr(connect_req.addr.as_str()).as_str(),
+                "too many pending dials, conn request rejected"
+            );
+            let mut evtx = evtx;
+            let _ = evtx.try_send(new_fin_event_with_reason(sid, FinReason::PolicyDenied));
+            return None;
+        }
+    };
+    notify_stream_event(open_ev);
+    let multipath_id = connect_req.multipath_id;
+    let peer_window = std::cmp::min(connect_req.recv_window, MAX_STREAM_WINDOW);
+    // what the peer may send before our first grant
+    let peer_credit = std::cmp::max(connect_req.initial_credit, DEFAULT_STREAM_WINDOW);
+    let peer_credit = std::cmp::min(peer_credit, MAX_STREAM_WINDOW);
+    let mut stream = MuxStream::new(
+        channel,
+        session_id,
+        sid,
+        evtx.clone(),
+        connect_req,
+        recv_window,
     );
-    let stream = MuxStream::new(channel, session_id, sid, evtx, connect_req);
-    let handle = handle_rmux_stream(stream.clone()).map(move |r| {
+    stream.set_clock(clock.clone());
+    // without a window in the SYN the peer reads with the default one the stream starts with,
+    // the relay reads the target no faster than this window drains either way
+    if peer_window > 0 {
+        stream.set_send_window(peer_window);
+    }
+    if report_connected {
+        stream.set_report_connected();
+    }
+    if report_connect_errors {
+        stream.set_report_connect_errors();
+    }
+    // the peer starts sending with the default window or its initial credit, grant it the
+    // difference to ours or hold the difference back from the first credits; a lost grant
+    // leaves the default
+    if recv_window > peer_credit {
+        let grant = new_window_update_event(sid, recv_window - peer_credit, false);
+        let _ = evtx.clone().try_send(grant);
+    } else if recv_window < peer_credit {
+        stream
+            .state
+            .flow
+            .on_window_advertised(peer_credit - recv_window);
+    }
+    if multipath_id > 0 {
+        let mp = multipath::get_or_create(channel, multipath_id);
+        mp.add_path(session_id, evtx);
+        stream.set_multipath(mp);
+    }
+    let handle = handle_rmux_stream(stream.clone(), ticket).map(move |r| {
+        drop(relay_task);
         if let Err(e) = r {
-            error!("[{}]Failed to handle rmux stream; error={}", sid, e);
+            error!(stream_id = sid, "failed to handle rmux stream; error={}", e);
         }
     });
-    tokio::spawn(handle);
+    tokio::spawn(handle.instrument(info_span!("stream", stream_id = sid)));
     Some(stream)
 }
 
 fn get_streams_stat_info(streams: &mut HashMap<u32, MuxStream>) -> String {
     let mut info = String::new();
     for (id, stream) in streams.iter_mut() {
+        let st = stream.stats();
         info.push_str(
             format!(
-                "{}:target:{}, age:{:?}, send_bytes:{}, recv_bytes:{}, send_window:{}, closed:{}\n",
+                "{}:target:{}, age:{:?}, send_bytes:{}, recv_bytes:{}, send_window:{}, closed:{}, dropped_window_updates:{}, last_advertised_window:{}, window_updates:{}, window_stall:{:?}, max_window_stall:{:?}\n",
                 id,
-                stream.target.addr,
-                stream.state.born_time.elapsed(),
-                stream.state.total_send_bytes.load(Ordering::SeqCst),
-                stream.state.total_recv_bytes.load(Ordering::SeqCst),
-                stream.state.send_buf_window.load(Ordering::SeqCst),
-                stream.state.closed.load(Ordering::SeqCst),
+                redact_addr(st.target.as_str()),
+                st.age,
+                st.send_bytes,
+                st.recv_bytes,
+                st.send_window,
+                st.closed,
+                st.dropped_window_updates,
+                st.last_advertised_window,
+                st.window_updates,
+                st.window_stall_time,
+                st.max_window_stall,
             )
             .as_str(),
         );
@@ -384,26 +2233,52 @@ fn get_streams_stat_info(streams: &mut HashMap<u32, MuxStream>) -> String {
     info
 }
 
+// io idle this long nears the default idle close
+const NOTEWORTHY_IO_IDLE_SECS: u32 = 240;
+// io this recent counts as activity of the session's streams
+const ACTIVE_IO_SECS: u32 = 30;
+
+// The routine tick logs the full state of the sessions worth a look: closing, retired,
+// nearing the idle close or moving data for their streams. The rest only get a trace line.
+fn is_noteworthy(summary: &SessionSummary, closing: bool) -> bool {
+    closing
+        || summary.retired
+        || summary.io_idle_secs >= NOTEWORTHY_IO_IDLE_SECS
+        || (summary.streams > 0 && summary.io_idle_secs < ACTIVE_IO_SECS)
+}
+
 fn log_session_state(
     sid: u32,
     streams: &mut HashMap<u32, MuxStream>,
-    now_unix_secs: u32,
+    idle_secs: u32,
     session_state: &Arc<MuxSessionState>,
-) -> u32 {
+) {
     let mut stat_info = format!(
         "\n========================Session:{}====================\n",
         sid
     );
     stat_info.push_str(format!("Streams:{}\n", streams.len()).as_str());
-    stat_info.push_str(format!("Age:{:?}\n", session_state.born_time.elapsed()).as_str());
+    stat_info.push_str(format!("Age:{:?}\n", session_state.age()).as_str());
     stat_info.push_str(format!("PingPongGap:{}\n", session_state.ping_pong_gap()).as_str());
-    let idle_secs = session_state.get_io_idle_secs(now_unix_secs);
     stat_info.push_str(format!("IOIdleSecs:{}\n", idle_secs).as_str());
     stat_info.push_str(format!("Retired:{}\n", session_state.is_retired()).as_str());
     stat_info.push_str(format!("Closed:{}\n", session_state.is_closed()).as_str());
+    stat_info.push_str(
+        format!(
+            "RecvQueueDepth:{}\n",
+            session_state.recv_queue_depth.load(Ordering::SeqCst)
+        )
+        .as_str(),
+    );
+    stat_info.push_str(
+        format!(
+            "SendQueueDepth:{}\n",
+            session_state.send_queue_depth.load(Ordering::SeqCst)
+        )
+        .as_str(),
+    );
     stat_info.push_str(get_streams_stat_info(streams).as_str());
     warn!("{}", stat_info);
-    idle_secs
 }
 
 fn handle_ping_event(
@@ -413,29 +2288,50 @@ fn handle_ping_event(
     is_remote: bool,
 ) {
     if !is_remote {
-        let now_unix_secs = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as u32;
         session_state
-            .last_ping_send_time
-            .store(now_unix_secs, Ordering::SeqCst);
+            .last_ping_send_millis
+            .store(session_state.mono_millis(), Ordering::SeqCst);
     }
 }
 
 fn handle_routine_event(
+    channel: &str,
     sid: u32,
     streams: &mut HashMap<u32, MuxStream>,
     session_state: &Arc<MuxSessionState>,
 ) -> bool {
-    let now_unix_secs = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as u32;
-    let idle_io_secs = log_session_state(sid, streams, now_unix_secs, &session_state);
-    let should_close = (session_state.is_retired() && streams.is_empty()) || idle_io_secs >= 300;
+    let now_unix_secs = session_state.now_unix_secs();
+    let idle_io_secs = session_state.get_io_idle_secs(now_unix_secs);
+    let drained = session_state.is_retired() && streams.is_empty();
+    let summary = SessionSummary {
+        channel: String::from(channel),
+        session_id: sid,
+        age: session_state.age(),
+        io_idle_secs: idle_io_secs,
+        streams: streams.len(),
+        stream_idle_secs: streams.values().map(|s| s.idle_secs(now_unix_secs)).min(),
+        retired: session_state.is_retired(),
+        total_bytes: session_state.total_bytes.load(Ordering::SeqCst),
+    };
 
-    if should_close {
+    let closing = should_close_idle(&summary);
+    if is_noteworthy(&summary, closing) {
+        log_session_state(sid, streams, idle_io_secs, session_state);
+    } else {
+        trace!(
+            channel,
+            session_id = sid,
+            streams = summary.streams,
+            io_idle_secs = idle_io_secs,
+            "routine session check"
+        );
+    }
+    if closing {
+        session_state.set_close_reason(if drained {
+            SessionCloseReason::Retired
+        } else {
+            SessionCloseReason::IdleTimeout
+        });
         error!(
             "[{}]Close session since no data send/recv {} secs ago, stream count:{}",
             sid,
@@ -452,27 +2348,178 @@ fn handle_fin_event(
     sid: u32,
     streams: &mut HashMap<u32, MuxStream>,
     session_state: &Arc<MuxSessionState>,
+    remote: bool,
+    reason: FinReason,
 ) -> bool {
     if let Some(mut stream) = streams.remove(&sid) {
-        let _ = stream.close();
+        session_state.stream_ids.lock().unwrap().remove(&sid);
+        if remote {
+            stream.close_by_peer(reason);
+        } else {
+            let _ = stream.close_with_reason(reason);
+        }
     }
     if session_state.is_retired() && streams.is_empty() {
+        session_state.set_close_reason(SessionCloseReason::Retired);
         session_state.closed.store(true, Ordering::SeqCst);
         return true;
     }
     false
 }
 
+// close streams whose keepalive probe went unanswered and probe the ones idle for the interval,
+// the probe is a PING with the stream id which the peer answers with a PONG
+fn probe_idle_streams(
+    sid: u32,
+    streams: &mut HashMap<u32, MuxStream>,
+    session_state: &Arc<MuxSessionState>,
+    keepalive_secs: u32,
+) -> Vec<Event> {
+    let mut probes = Vec::new();
+    if keepalive_secs == 0 {
+        return probes;
+    }
+    let now_unix_secs = session_state.now_unix_secs();
+    let mut dead = Vec::new();
+    for (id, stream) in streams.iter() {
+        if stream.probe_expired(keepalive_secs, now_unix_secs) {
+            dead.push(*id);
+        } else if stream.should_probe(keepalive_secs, now_unix_secs) {
+            probes.push(new_ping_event(*id, false));
+        }
+    }
+    for id in dead {
+        warn!(
+            session_id = sid,
+            stream_id = id,
+            "close stream with unanswered keepalive"
+        );
+        if let Some(mut stream) = streams.remove(&id) {
+            session_state.stream_ids.lock().unwrap().remove(&id);
+            session_state.errors.fetch_add(1, Ordering::SeqCst);
+            let _ = stream.close_with_reason(FinReason::IdleTimeout);
+        }
+    }
+    probes
+}
+
+// FINs the streams past the channel's max lifetime, the close reports their final counters
+fn close_expired_streams(
+    channel: &str,
+    sid: u32,
+    streams: &mut HashMap<u32, MuxStream>,
+    session_state: &Arc<MuxSessionState>,
+) {
+    let max = match get_max_stream_lifetime(channel) {
+        Some(max) => max,
+        None => return,
+    };
+    let expired: Vec<u32> = streams
+        .iter()
+        .filter(|(_, s)| s.lifetime_exceeded(max))
+        .map(|(id, _)| *id)
+        .collect();
+    for id in expired {
+        if let Some(mut stream) = streams.remove(&id) {
+            session_state.stream_ids.lock().unwrap().remove(&id);
+            let stats = stream.stats();
+            info!(
+                session_id = sid,
+                stream_id = id,
+                send_bytes = stats.send_bytes,
+                recv_bytes = stats.recv_bytes,
+                "close stream past its max lifetime"
+            );
+            let _ = stream.close_with_reason(FinReason::LifetimeExceeded);
+        }
+    }
+}
+
+// an encrypted frame on its way to the send loop, an empty one tells it to stop
+struct QueuedFrame {
+    data: Vec<u8>,
+    stream_id: u32,
+    flags: u8,
+    queued_at: Instant,
+}
+
+impl QueuedFrame {
+    fn shutdown() -> Self {
+        Self {
+            data: Vec::new(),
+            stream_id: 0,
+            flags: 0,
+            queued_at: Instant::now(),
+        }
+    }
+}
+
 async fn send_local_event(
+    channel: &str,
+    tunnel_id: u32,
     mut ev: Event,
     wctx: &mut CryptoContext,
-    send_tx: &mut mpsc::Sender<Vec<u8>>,
+    send_tx: &mut mpsc::Sender<QueuedFrame>,
+    session_state: &Arc<MuxSessionState>,
 ) -> bool {
+    if wctx.is_nonce_exhausted() {
+        error!("Close session since crypto nonce exhausted.");
+        return false;
+    }
+    tap_frame(FrameDirection::Outbound, channel, tunnel_id, &ev);
+    let stream_id = ev.header.stream_id;
+    let flags = ev.header.flags();
     let mut buf = BytesMut::with_capacity(ev.body.len() + 64);
     wctx.encrypt(&mut ev, &mut buf);
     let evbuf = buf.to_vec();
-    let send_rc = send_tx.send(evbuf).await;
-    send_rc.is_ok()
+    let evlen = evbuf.len();
+    session_state
+        .send_queue_depth
+        .fetch_add(1, Ordering::SeqCst);
+    session_state.queued_buffers.charge(evlen);
+    let frame = QueuedFrame {
+        data: evbuf,
+        stream_id,
+        flags,
+        queued_at: Instant::now(),
+    };
+    let send_rc = send_tx.send(frame).await;
+    if send_rc.is_err() {
+        session_state
+            .send_queue_depth
+            .fetch_sub(1, Ordering::SeqCst);
+        session_state.queued_buffers.credit(evlen);
+        return false;
+    }
+    true
+}
+
+// false once the session is to be closed
+async fn answer_orphan_data(
+    channel: &str,
+    tunnel_id: u32,
+    sid: u32,
+    orphans: &mut OrphanData,
+    wctx: &mut CryptoContext,
+    send_tx: &mut mpsc::Sender<QueuedFrame>,
+    session_state: &Arc<MuxSessionState>,
+) -> bool {
+    match orphans.on_data(channel, sid) {
+        OrphanDataAction::Ignore => true,
+        OrphanDataAction::ReplyFin => {
+            let fin = new_fin_event_with_reason(sid, FinReason::Reset);
+            send_local_event(channel, tunnel_id, fin, wctx, send_tx, session_state).await
+        }
+        OrphanDataAction::CloseSession => {
+            error!(
+                count = orphans.count,
+                "too much data for unknown streams, close session"
+            );
+            session_state.set_close_reason(SessionCloseReason::ProtocolError);
+            session_state.closed.store(true, Ordering::SeqCst);
+            false
+        }
+    }
 }
 
 async fn handle_local_event<'a>(
@@ -480,25 +2527,45 @@ async fn handle_local_event<'a>(
     tunnel_id: u32,
     streams: &mut HashMap<u32, MuxStream>,
     session_state: &Arc<MuxSessionState>,
-    ev: Event,
+    sid: u32,
+    kind: EventKind,
     wctx: &mut CryptoContext,
-    send_tx: &mut mpsc::Sender<Vec<u8>>,
+    send_tx: &mut mpsc::Sender<QueuedFrame>,
+    stream_keepalive_secs: u32,
+    gate: &mut SendGate,
 ) -> bool {
-    if FLAG_SHUTDOWN == ev.header.flags() {
-        return false;
-    }
-    if FLAG_SYN == ev.header.flags() {
-        hanle_pendding_mux_streams(channel, tunnel_id, streams);
-    }
-    if FLAG_FIN == ev.header.flags()
-        && handle_fin_event(ev.header.stream_id, streams, &session_state)
-    {
-        return false;
+    match &kind {
+        EventKind::Shutdown => {
+            session_state.set_close_reason(SessionCloseReason::LocalShutdown);
+            return false;
+        }
+        EventKind::Syn(_) => hanle_pendding_mux_streams(channel, tunnel_id, streams),
+        EventKind::Fin { reason, .. } => {
+            if handle_fin_event(sid, streams, &session_state, false, *reason) {
+                return false;
+            }
+        }
+        EventKind::Routine => {
+            if handle_routine_event(channel, tunnel_id, streams, &session_state) {
+                return false;
+            }
+            close_expired_streams(channel, tunnel_id, streams, session_state);
+            for probe in
+                probe_idle_streams(tunnel_id, streams, session_state, stream_keepalive_secs)
+            {
+                if !send_local_event(channel, tunnel_id, probe, wctx, send_tx, session_state).await
+                {
+                    return false;
+                }
+            }
+            return true;
+        }
+        _ => {}
     }
-    if FLAG_ROUTINE == ev.header.flags() {
-        return !handle_routine_event(tunnel_id, streams, &session_state);
+    match gate.hold(Event::from_kind(sid, kind, false)) {
+        Some(ev) => send_local_event(channel, tunnel_id, ev, wctx, send_tx, session_state).await,
+        None => true,
     }
-    send_local_event(ev, wctx, send_tx).await
 }
 
 async fn process_event<'a>(
@@ -508,81 +2575,368 @@ async fn process_event<'a>(
     session_state: Arc<MuxSessionState>,
     mut event_rx: mpsc::Receiver<Event>,
     event_tx: mpsc::Sender<Event>,
-    mut send_tx: mpsc::Sender<Vec<u8>>,
+    mut send_tx: mpsc::Sender<QueuedFrame>,
+    stream_keepalive_secs: u32,
+    stream_recv_window: u32,
+    hello: Hello,
 ) {
+    let pause_signaling = hello.has(CAP_SESSION_PAUSE);
+    let unknown_flags_policy = get_unknown_flags_policy(channel);
     let mut streams = HashMap::new();
-    while !session_state.closed.load(Ordering::SeqCst) {
-        let rev = event_rx.recv().await;
+    let mut scheduler = DataScheduler::new(channel);
+    let mut inbox = PriorityInbox::default();
+    let mut gate = SendGate::default();
+    let mut pressure = PressureSignal::default();
+    let mut syn_parts = SynAssembler::default();
+    let mut orphans = OrphanData::new(channel);
+    'events: while !session_state.closed.load(Ordering::SeqCst) {
+        if pause_signaling {
+            if let Some(signal) = pressure.check(over_buffer_budget()) {
+                debug!(
+                    event = get_event_type_str(signal.header.flags()),
+                    "buffer pressure changed"
+                );
+                if !send_local_event(
+                    channel,
+                    tunnel_id,
+                    signal,
+                    &mut wctx,
+                    &mut send_tx,
+                    &session_state,
+                )
+                .await
+                {
+                    break;
+                }
+            }
+        }
+        for ev in gate.release() {
+            if !send_local_event(
+                channel,
+                tunnel_id,
+                ev,
+                &mut wctx,
+                &mut send_tx,
+                &session_state,
+            )
+            .await
+            {
+                break 'events;
+            }
+        }
+        if !scheduler.is_empty() {
+            scheduler.deliver(&mut streams);
+        }
+        // some readers were behind, retry them unless other events arrive first
+        let mut wait = if scheduler.is_empty() {
+            None
+        } else {
+            Some(DATA_RETRY_INTERVAL)
+        };
+        // and release the held DATA once the peer's pause lapses
+        if let Some(remaining) = gate.remaining() {
+            wait = Some(wait.map_or(remaining, |w| std::cmp::min(w, remaining)));
+        }
+        let rev = match wait {
+            None => inbox.recv(&mut event_rx, &streams).await,
+            Some(wait) => match timeout(wait, inbox.recv(&mut event_rx, &streams)).await {
+                Ok(rev) => rev,
+                Err(_) => continue,
+            },
+        };
+        session_state
+            .stream_count
+            .store(streams.len() as u32, Ordering::SeqCst);
         if let Some(ev) = rev {
-            if FLAG_PING == ev.header.flags() {
-                handle_ping_event(tunnel_id, &mut streams, &session_state, ev.remote);
+            if ev.remote {
+                session_state
+                    .recv_queue_depth
+                    .fetch_sub(1, Ordering::SeqCst);
+                session_state.queued_buffers.credit(ev.body.len());
+            }
+            let sid = ev.header.stream_id;
+            let remote = ev.remote;
+            let kind = ev.into_kind();
+            if EventKind::Ping == kind && sid == 0 {
+                handle_ping_event(tunnel_id, &mut streams, &session_state, remote);
             }
-            if !ev.remote {
+            if !remote {
+                let syn = if let EventKind::Syn(_) = kind {
+                    true
+                } else {
+                    false
+                };
+                if EventKind::Routine == kind {
+                    for sid in syn_parts.expire() {
+                        warn!(stream_id = sid, "SYN parts timed out, dropped");
+                    }
+                }
                 if handle_local_event(
                     channel,
                     tunnel_id,
                     &mut streams,
                     &session_state,
-                    ev,
+                    sid,
+                    kind,
                     &mut wctx,
                     &mut send_tx,
+                    stream_keepalive_secs,
+                    &mut gate,
                 )
                 .await
                 {
+                    if syn {
+                        inbox.promote(&streams);
+                    }
                     continue;
                 }
                 break;
             }
-            match ev.header.flags() {
-                FLAG_SYN => {
-                    if let Some(stream) = handle_syn(channel, tunnel_id, ev, event_tx.clone()) {
+            match kind {
+                EventKind::SynPart(part) => {
+                    if streams.contains_key(&sid) {
+                        error!(
+                            stream_id = sid,
+                            "SYN part collides with a live stream, dropped"
+                        );
+                        continue;
+                    }
+                    if !syn_parts.push(sid, &part[..]) {
+                        warn!(
+                            stream_id = sid,
+                            "too large or too many SYNs in parts, rejected"
+                        );
+                        let fin = new_fin_event_with_reason(sid, FinReason::PolicyDenied);
+                        if !send_local_event(
+                            channel,
+                            tunnel_id,
+                            fin,
+                            &mut wctx,
+                            &mut send_tx,
+                            &session_state,
+                        )
+                        .await
+                        {
+                            break;
+                        }
+                    }
+                }
+                EventKind::Syn(last) => {
+                    if streams.contains_key(&sid) {
+                        // a FIN would close the live stream, just drop the SYN
+                        error!(stream_id = sid, "SYN collides with a live stream, rejected");
+                        continue;
+                    }
+                    // the parts of a refused SYN are gone, its last frame finds none
+                    let body = match syn_parts.complete(sid, last) {
+                        Some(body) => body,
+                        None => {
+                            warn!(stream_id = sid, "SYN too large, rejected");
+                            let fin = new_fin_event_with_reason(sid, FinReason::PolicyDenied);
+                            if !send_local_event(
+                                channel,
+                                tunnel_id,
+                                fin,
+                                &mut wctx,
+                                &mut send_tx,
+                                &session_state,
+                            )
+                            .await
+                            {
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+                    if session_state.draining.load(Ordering::SeqCst) {
+                        debug!(stream_id = sid, "session draining, SYN rejected");
+                        let fin = new_fin_event_with_reason(sid, FinReason::PolicyDenied);
+                        if !send_local_event(
+                            channel,
+                            tunnel_id,
+                            fin,
+                            &mut wctx,
+                            &mut send_tx,
+                            &session_state,
+                        )
+                        .await
+                        {
+                            break;
+                        }
+                        continue;
+                    }
+                    if let Some(stream) = handle_syn(
+                        channel,
+                        tunnel_id,
+                        sid,
+                        body,
+                        event_tx.clone(),
+                        stream_recv_window,
+                        hello.has(CAP_CONNECT_RESULT),
+                        hello.has(CAP_CONNECT_ERRORS),
+                        &session_state.clock,
+                    ) {
+                        if let Some(mp) = stream.multipath() {
+                            mp.attach(&stream).await;
+                        }
+                        session_state
+                            .stream_ids
+                            .lock()
+                            .unwrap()
+                            .insert(stream.state.stream_id, stream.handle());
                         streams.entry(stream.state.stream_id).or_insert(stream);
-                    } else {
                     }
                 }
-                FLAG_FIN => {
-                    if handle_fin_event(ev.header.stream_id, &mut streams, &session_state) {
+                EventKind::Fin { reason, frames } => {
+                    scheduler.flush(sid, &mut streams).await;
+                    if let Some(frames) = frames {
+                        if let Some(mp) = streams.get(&sid).and_then(|s| s.multipath()) {
+                            if mp.defer_fin(frames, reason).await {
+                                streams.remove(&sid);
+                                session_state.stream_ids.lock().unwrap().remove(&sid);
+                            }
+                        }
+                    }
+                    if handle_fin_event(sid, &mut streams, &session_state, true, reason) {
                         break;
                     }
                 }
-                FLAG_DATA => {
-                    if let Some(stream) = streams.get_mut(&ev.header.stream_id) {
-                        stream.offer_data(ev.body).await;
+                EventKind::Data(data) => {
+                    if let Some(stream) = streams.get_mut(&sid) {
+                        if scheduler.enabled() {
+                            scheduler.push(sid, data);
+                        } else {
+                            stream.offer_data(data).await;
+                        }
                     } else {
                         warn!(
-                            "[{}][{}]No stream found for data event.",
-                            channel, ev.header.stream_id
+                            stream_id = sid,
+                            len = data.len(),
+                            "no stream found for data event"
                         );
+                        if !answer_orphan_data(
+                            channel,
+                            tunnel_id,
+                            sid,
+                            &mut orphans,
+                            &mut wctx,
+                            &mut send_tx,
+                            &session_state,
+                        )
+                        .await
+                        {
+                            break;
+                        }
+                    }
+                }
+                EventKind::SeqData { seq, data } => {
+                    if data.is_empty() {
+                        error!(stream_id = sid, seq, "invalid seq data event");
+                        continue;
+                    }
+                    let checked = match streams.get_mut(&sid) {
+                        Some(stream) => match stream.check_recv_seq(seq) {
+                            Ok(()) => {
+                                stream.offer_data(data).await;
+                                true
+                            }
+                            Err(expected) => {
+                                error!(stream_id = sid, seq, expected, "data sequence gap");
+                                session_state.errors.fetch_add(1, Ordering::SeqCst);
+                                false
+                            }
+                        },
+                        None => {
+                            warn!(stream_id = sid, seq, "no stream found for data event");
+                            if !answer_orphan_data(
+                                channel,
+                                tunnel_id,
+                                sid,
+                                &mut orphans,
+                                &mut wctx,
+                                &mut send_tx,
+                                &session_state,
+                            )
+                            .await
+                            {
+                                break;
+                            }
+                            true
+                        }
+                    };
+                    if !checked {
+                        if let Some(mut stream) = streams.remove(&sid) {
+                            session_state.stream_ids.lock().unwrap().remove(&sid);
+                            let _ = stream.close();
+                        }
                     }
                 }
-                FLAG_PING => {
+                EventKind::MpData { mp_id, seq, data } => {
+                    handle_mp_data(channel, tunnel_id, event_tx.clone(), mp_id, seq, data).await;
+                }
+                EventKind::Malformed(flags) => {
+                    error!(
+                        stream_id = sid,
+                        event = get_event_type_str(flags),
+                        "event too short for its flags"
+                    );
+                }
+                EventKind::Ping => {
+                    // a stream keepalive probe is only answered while the stream is alive
+                    if sid != 0 && !streams.contains_key(&sid) {
+                        warn!(stream_id = sid, "no stream found for keepalive probe");
+                        continue;
+                    }
                     if !send_local_event(
-                        new_pong_event(ev.header.stream_id, false),
+                        channel,
+                        tunnel_id,
+                        new_pong_event(sid, false),
                         &mut wctx,
                         &mut send_tx,
+                        &session_state,
                     )
                     .await
                     {
                         break;
                     }
                 }
-                FLAG_PONG => {
-                    session_state.last_pong_recv_time.store(
-                        SystemTime::now()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs() as u32,
-                        Ordering::SeqCst,
-                    );
+                EventKind::Pong if sid != 0 => {
+                    if let Some(stream) = streams.get(&sid) {
+                        stream.on_keepalive_pong();
+                    }
+                }
+                EventKind::Pong => {
+                    session_state
+                        .last_pong_recv_millis
+                        .store(session_state.mono_millis(), Ordering::SeqCst);
                 }
-                FLAG_WIN_UPDATE => {
-                    if let Some(stream) = streams.get_mut(&ev.header.stream_id) {
-                        stream.update_send_window(ev.header.len());
+                EventKind::WindowUpdate { credit } => {
+                    if let Some(stream) = streams.get_mut(&sid) {
+                        stream.update_send_window(credit);
                     }
                 }
-                _ => {
-                    error!("invalid flags:{}", ev.header.flags());
-                    //None
+                EventKind::Connected => {
+                    if let Some(stream) = streams.get(&sid) {
+                        stream.on_connected();
+                    }
+                }
+                EventKind::Pause => {
+                    gate.pause();
+                }
+                EventKind::Resume => {
+                    gate.resume();
+                }
+                // auth, shutdown & routine are never sent inside a session
+                kind => {
+                    error!(stream_id = sid, flags = kind.flags(), "invalid flags");
+                    let count = session_state.unknown_frames.fetch_add(1, Ordering::SeqCst) + 1;
+                    session_state.errors.fetch_add(1, Ordering::SeqCst);
+                    if unknown_flags_policy.should_close(count) {
+                        error!(count, "too many frames with invalid flags, close session");
+                        session_state.set_close_reason(SessionCloseReason::ProtocolError);
+                        session_state.closed.store(true, Ordering::SeqCst);
+                        break;
+                    }
                 }
             }
         } else {
@@ -590,13 +2944,68 @@ async fn process_event<'a>(
             break;
         }
     }
-    error!("[{}][{}]handle_event done", channel, tunnel_id);
+    error!(channel, tunnel_id, "handle_event done");
     session_state.closed.store(true, Ordering::SeqCst);
     for (_, stream) in streams.iter_mut() {
         let _ = stream.close();
     }
     event_rx.close();
-    let _ = send_tx.send(Vec::new()).await;
+    let _ = send_tx.send(QueuedFrame::shutdown()).await;
+}
+
+// Moves a frame off the send queue into the write batch. Returns its stream if the frame
+// waited beyond the limit, a stream is returned once until its FIN goes out.
+fn take_queued_frame(
+    frame: QueuedFrame,
+    vbuf: &mut VBuf,
+    session_state: &MuxSessionState,
+    dwell_limit: Option<Duration>,
+    reset_streams: &mut HashSet<u32>,
+) -> Option<u32> {
+    session_state
+        .send_queue_depth
+        .fetch_sub(1, Ordering::SeqCst);
+    session_state.queued_buffers.credit(frame.data.len());
+    let dwell = frame.queued_at.elapsed();
+    session_state.record_send_dwell(dwell);
+    let mut stuck = None;
+    if frame.stream_id != 0 {
+        if frame.flags == FLAG_FIN {
+            reset_streams.remove(&frame.stream_id);
+        } else if dwell_limit.map_or(false, |limit| dwell > limit)
+            && reset_streams.insert(frame.stream_id)
+        {
+            stuck = Some(frame.stream_id);
+        }
+    }
+    vbuf.push(frame.data);
+    stuck
+}
+
+// How many frames already queued the send loop writes to the connection at once. It never
+// waits for more, a batch is what's queued by the time the connection takes the write.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteBatching {
+    // a write per frame, for latency-critical channels
+    Off,
+    Frames(usize),
+    // frames until this many bytes are batched, for bulk channels
+    Bytes(usize),
+}
+
+pub const DEFAULT_WRITE_BATCH_FRAMES: usize = 60;
+
+impl WriteBatching {
+    fn is_full(&self, vbuf: &VBuf) -> bool {
+        if vbuf.is_full() {
+            return true;
+        }
+        match *self {
+            WriteBatching::Off => vbuf.vlen() >= 1,
+            WriteBatching::Frames(n) => vbuf.vlen() >= n,
+            WriteBatching::Bytes(n) => vbuf.remaining() >= n,
+        }
+    }
 }
 
 pub struct MuxContext<'a> {
@@ -605,6 +3014,17 @@ pub struct MuxContext<'a> {
     rctx: CryptoContext,
     wctx: CryptoContext,
     max_alive_secs: u64,
+    max_alive_bytes: u64,
+    rotation_jitter_ratio: Option<f64>,
+    ping_idle_secs: u32,
+    stream_recv_window: u32,
+    weight: u32,
+    stream_keepalive_secs: u32,
+    write_batching: WriteBatching,
+    hello: Hello,
+    clock: Arc<dyn Clock>,
+    peer_addr: Option<String>,
+    establishing: Option<EstablishPermit>,
     recv_buf: &'a mut BytesMut,
 }
 impl<'a> MuxContext<'a> {
@@ -622,9 +3042,64 @@ impl<'a> MuxContext<'a> {
             rctx,
             wctx,
             max_alive_secs,
+            max_alive_bytes: 0,
+            rotation_jitter_ratio: None,
+            ping_idle_secs: 0,
+            stream_recv_window: DEFAULT_STREAM_WINDOW,
+            weight: 1,
+            stream_keepalive_secs: 0,
+            write_batching: WriteBatching::Frames(DEFAULT_WRITE_BATCH_FRAMES),
+            hello: Hello::default(),
+            clock: Arc::new(SystemClock),
+            peer_addr: None,
+            establishing: None,
             recv_buf,
         }
     }
+    pub fn set_max_alive_bytes(&mut self, bytes: u64) {
+        self.max_alive_bytes = bytes;
+    }
+    // jitter of the max alive secs/bytes as a ratio of them, clamped to [0, 1]
+    pub fn set_rotation_jitter_ratio(&mut self, ratio: f64) {
+        self.rotation_jitter_ratio = Some(ratio.max(0.0).min(1.0));
+    }
+    pub fn set_ping_idle_secs(&mut self, secs: u32) {
+        self.ping_idle_secs = secs;
+    }
+    // recv buffer cap of the streams on this side of the session, each side sets its own. The
+    // client advertises it in the SYN, the server by a window update right after the SYN.
+    pub fn set_stream_recv_window(&mut self, window: u32) {
+        self.stream_recv_window = std::cmp::min(window, MAX_STREAM_WINDOW);
+    }
+    // relative share of new streams for this session, e.g. the capacity of its upstream
+    pub fn set_weight(&mut self, weight: u32) {
+        self.weight = weight;
+    }
+    // probe streams without data moved for this many secs and close them if unanswered, 0 disables it
+    pub fn set_stream_keepalive_secs(&mut self, secs: u32) {
+        self.stream_keepalive_secs = secs;
+    }
+    pub fn set_write_batching(&mut self, batching: WriteBatching) {
+        self.write_batching = batching;
+    }
+    // the hello received in the auth handshake, features missing in it are not used
+    pub fn set_peer_hello(&mut self, peer: &Hello) {
+        self.hello = Hello::local_for(self.channel).negotiate(peer);
+        let auth_only = self.hello.has(CAP_AUTH_ONLY_FRAMES);
+        self.rctx.set_auth_only(auth_only);
+        self.wctx.set_auth_only(auth_only);
+    }
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+    // ip of the remote end, reported with the frames failing authentication
+    pub fn set_peer_addr(&mut self, addr: &str) {
+        self.peer_addr = Some(String::from(addr));
+    }
+    // held until the session is stored and its event loop starts
+    pub(crate) fn set_establish_permit(&mut self, permit: EstablishPermit) {
+        self.establishing = Some(permit);
+    }
 }
 
 pub async fn process_rmux_session<'a, R, W>(
@@ -648,19 +3123,56 @@ where
     let wctx = ctx.wctx;
     let recv_buf = ctx.recv_buf;
     let max_alive_secs = ctx.max_alive_secs;
+    let peer_addr = ctx.peer_addr;
+    let establishing = ctx.establishing;
     let (mut event_tx, event_rx) = mpsc::channel::<Event>(16);
     let (send_tx, mut send_rx) = mpsc::channel(16);
 
     //let is_server = channel.is_empty();
 
     let seed = if channel.is_empty() { 2 } else { 1 };
+    let params = SessionParams {
+        channel: String::from(channel),
+        session_id: tunnel_id,
+        cipher: String::from(wctx.method()),
+        version: ctx.hello.version,
+        capabilities: ctx.hello.capabilities,
+        stream_id_seed: seed,
+        stream_recv_window: ctx.stream_recv_window,
+        max_alive_secs,
+        max_alive_bytes: ctx.max_alive_bytes,
+        ping_idle_secs: ctx.ping_idle_secs,
+        stream_keepalive_secs: ctx.stream_keepalive_secs,
+        write_batching: ctx.write_batching,
+        key_epoch: wctx.key_epoch(),
+    };
     let session_state = MuxSessionState {
-        last_ping_send_time: AtomicU32::new(0),
-        last_pong_recv_time: AtomicU32::new(0),
-        born_time: Instant::now(),
+        last_ping_send_millis: AtomicU64::new(0),
+        last_pong_recv_millis: AtomicU64::new(0),
+        born_time: ctx.clock.now_instant(),
+        clock: ctx.clock.clone(),
         retired: AtomicBool::new(false),
+        draining: AtomicBool::new(false),
         io_active_unix_secs: AtomicU32::new(0),
+        recv_active_unix_secs: AtomicU32::new(0),
         closed: AtomicBool::new(false),
+        recv_queue_depth: AtomicU32::new(0),
+        send_queue_depth: AtomicU32::new(0),
+        stream_count: AtomicU32::new(0),
+        stream_ids: Mutex::new(HashMap::new()),
+        unknown_frames: AtomicU32::new(0),
+        errors: AtomicU32::new(0),
+        quality: AtomicU8::new(SessionQuality::Good as u8),
+        quality_sampled_millis: AtomicU64::new(0),
+        sampled_stall_micros: AtomicU64::new(0),
+        sampled_errors: AtomicU32::new(0),
+        queued_buffers: BufferCharge::default(),
+        total_bytes: AtomicU64::new(0),
+        sampled_bytes: AtomicU64::new(0),
+        send_dwell_max_micros: AtomicU64::new(0),
+        send_dwell_total_micros: AtomicU64::new(0),
+        send_dwell_frames: AtomicU64::new(0),
+        close_reason: Mutex::new(None),
     };
     let session_state = Arc::new(session_state);
     //let send_session_state = session_state.clone();
@@ -671,14 +3183,30 @@ where
         pendding_streams: Vec::new(),
         stream_id_seed: AtomicU32::new(seed),
         state: session_state.clone(),
-        max_alive_secs,
+        max_alive_secs: AtomicU64::new(max_alive_secs),
+        max_alive_bytes: ctx.max_alive_bytes,
+        rotation_jitter_ratio: ctx.rotation_jitter_ratio,
+        ping_idle_secs: ctx.ping_idle_secs,
+        stream_recv_window: ctx.stream_recv_window,
+        weight: AtomicU32::new(ctx.weight),
+        current_weight: 0,
+        hello: ctx.hello,
+        params: Arc::new(params),
         //streams: HashMap::new(),
     };
-    info!(
-        "[{}][{}]Start tunnel session with crypto {} {}",
-        channel, tunnel_id, rctx.nonce, rctx.key
-    );
-    store_mux_session(channel, mux_session);
+    info!("[{}][{}]Start tunnel session", channel, tunnel_id);
+    if SHUTTING_DOWN.load(Ordering::SeqCst) {
+        return Err(make_io_error("shutting down."));
+    }
+    if let Err(e) = store_mux_session(channel, mux_session) {
+        error!(channel, tunnel_id, "session rejected; error={}", e);
+        return Err(e);
+    }
+    notify_session_event(SessionEvent::Established {
+        channel: String::from(channel),
+        session_id: tunnel_id,
+    });
+    drop(establishing);
 
     let (close_tx, close_rx) = oneshot::channel::<()>();
     let mut drop = close_rx.fuse();
@@ -688,41 +3216,89 @@ where
     let handle_recv_session_state = session_state.clone();
     let handle_send_session_state = session_state.clone();
     let handle_recv = async move {
+        // the read buffer grows to the largest event seen
+        let recv_buf_charge = BufferCharge::default();
         while !handle_recv_session_state.closed.load(Ordering::SeqCst) {
+            recv_buf_charge.set(recv_buf.capacity());
             select! {
                 recv_event = read_encrypt_event(&mut rctx, ri, recv_buf).fuse() => {
                     match recv_event {
                         Ok(Some(mut ev)) => {
-                            recv_session_state.io_active_unix_secs.store(
-                                SystemTime::now()
-                                    .duration_since(UNIX_EPOCH)
-                                    .unwrap()
-                                    .as_secs() as u32,
+                            let now_unix_secs = recv_session_state.now_unix_secs();
+                            recv_session_state
+                                .io_active_unix_secs
+                                .store(now_unix_secs, Ordering::SeqCst);
+                            recv_session_state
+                                .recv_active_unix_secs
+                                .store(now_unix_secs, Ordering::SeqCst);
+                            recv_session_state.total_bytes.fetch_add(
+                                (ev.body.len() + EVENT_HEADER_LEN) as u64,
                                 Ordering::SeqCst,
                             );
                             ev.remote = true;
-                            if FLAG_DATA != ev.header.flags() {
+                            tap_frame(FrameDirection::Inbound, channel, tunnel_id, &ev);
+                            if FLAG_DATA != ev.header.flags()
+                                && FLAG_MP_DATA != ev.header.flags()
+                                && FLAG_SEQ_DATA != ev.header.flags()
+                            {
                                 info!(
-                                    "[{}][{}][{}]remote recv event type:{}, len:{}",
-                                    channel,
-                                    tunnel_id,
-                                    ev.header.stream_id,
-                                    get_event_type_str(ev.header.flags()),
-                                    ev.header.len(),
+                                    stream_id = ev.header.stream_id,
+                                    event = get_event_type_str(ev.header.flags()),
+                                    len = ev.header.len(),
+                                    "remote recv event"
                                 );
                             }
+                            recv_session_state.recv_queue_depth.fetch_add(1, Ordering::SeqCst);
+                            let evlen = ev.body.len();
+                            recv_session_state.queued_buffers.charge(evlen);
                             let send_rc = handle_recv_event_tx.send(ev).await;
                             if send_rc.is_err(){
+                                recv_session_state.recv_queue_depth.fetch_sub(1, Ordering::SeqCst);
+                                recv_session_state.queued_buffers.credit(evlen);
                                 break;
                             }
                         }
                         Ok(None) => {
                             //handle_recv_session_state.closed.store(true, Ordering::SeqCst);
+                            handle_recv_session_state.set_close_reason(SessionCloseReason::PeerClosed);
                             break;
                         }
                         Err(err) => {
                             //handle_recv_session_state.closed.store(true, Ordering::SeqCst);
-                            error!("Close remote recv since of error:{}", err);
+                            match err.kind() {
+                                std::io::ErrorKind::UnexpectedEof => {
+                                    handle_recv_session_state
+                                        .set_close_reason(SessionCloseReason::TruncatedFrame);
+                                    debug!(channel, tunnel_id, "close remote recv: {}", err);
+                                }
+                                std::io::ErrorKind::InvalidData
+                                    if RmuxError::from_io(&err) == Some(RmuxError::AuthFailure) =>
+                                {
+                                    handle_recv_session_state
+                                        .set_close_reason(SessionCloseReason::AuthFailure);
+                                    record_auth_failure(channel, tunnel_id, peer_addr.as_deref());
+                                }
+                                std::io::ErrorKind::InvalidData => {
+                                    handle_recv_session_state
+                                        .set_close_reason(SessionCloseReason::ProtocolError);
+                                    error!(
+                                        channel,
+                                        tunnel_id,
+                                        "close remote recv since of invalid frame: {}",
+                                        err
+                                    );
+                                }
+                                _ => {
+                                    handle_recv_session_state
+                                        .set_close_reason(SessionCloseReason::IoError);
+                                    warn!(
+                                        channel,
+                                        tunnel_id,
+                                        "close remote recv since of error:{}",
+                                        err
+                                    );
+                                }
+                            }
                             break;
                         }
                     }
@@ -764,13 +3340,13 @@ where
             //     }
             // }
         }
-        error!("[{}][{}]handle_recv done", channel, tunnel_id);
+        error!(channel, tunnel_id, "handle_recv done");
         handle_recv_session_state
             .closed
             .store(true, Ordering::SeqCst);
         let shutdown_ev = new_shutdown_event(0, false);
         let _ = handle_recv_event_tx.send(shutdown_ev).await;
-        let _ = handle_recv_send_tx.send(Vec::new()).await;
+        let _ = handle_recv_send_tx.send(QueuedFrame::shutdown()).await;
     };
 
     // let handle_event_event_tx = event_tx.clone();
@@ -783,10 +3359,16 @@ where
         event_rx,
         event_tx.clone(),
         send_tx.clone(),
+        ctx.stream_keepalive_secs,
+        ctx.stream_recv_window,
+        ctx.hello,
     );
 
+    let write_batching = ctx.write_batching;
+    let send_dwell_limit = get_send_dwell_limit(channel);
     let handle_send = async {
         let mut vbuf = VBuf::new();
+        let mut reset_streams = HashSet::new();
         while !handle_send_session_state.closed.load(Ordering::SeqCst) {
             // if let Some(data) = send_rx.recv().await {
             //     if data.is_empty() {
@@ -807,25 +3389,38 @@ where
             //     break;
             // }
 
+            let mut stuck = Vec::new();
             if vbuf.vlen() == 0 {
-                if let Some(data) = send_rx.recv().await {
-                    if data.is_empty() {
+                if let Some(frame) = send_rx.recv().await {
+                    if frame.data.is_empty() {
                         break;
                     }
-                    vbuf.push(data);
+                    stuck.extend(take_queued_frame(
+                        frame,
+                        &mut vbuf,
+                        &session_state,
+                        send_dwell_limit,
+                        &mut reset_streams,
+                    ));
                 } else {
                     break;
                 }
             }
             let mut exit = false;
-            while vbuf.vlen() < 60 {
+            while !write_batching.is_full(&vbuf) {
                 match send_rx.try_recv() {
-                    Ok(data) => {
-                        if data.is_empty() {
+                    Ok(frame) => {
+                        if frame.data.is_empty() {
                             exit = true;
                             break;
                         } else {
-                            vbuf.push(data);
+                            stuck.extend(take_queued_frame(
+                                frame,
+                                &mut vbuf,
+                                &session_state,
+                                send_dwell_limit,
+                                &mut reset_streams,
+                            ));
                         }
                     }
                     Err(TryRecvError::Closed) => {
@@ -840,25 +3435,30 @@ where
             if exit {
                 break;
             }
-            session_state.io_active_unix_secs.store(
-                SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs() as u32,
-                Ordering::SeqCst,
-            );
+            for stream_id in stuck {
+                warn!(stream_id, "frame stuck in the send queue, stream reset");
+                let _ = event_tx.try_send(new_fin_event_with_reason(stream_id, FinReason::Reset));
+            }
+            session_state
+                .io_active_unix_secs
+                .store(session_state.now_unix_secs(), Ordering::SeqCst);
             match wi.write_buf(&mut vbuf).await {
                 Ok(n) => {
                     if 0 == n {
+                        session_state.set_close_reason(SessionCloseReason::PeerClosed);
                         break;
                     }
+                    session_state
+                        .total_bytes
+                        .fetch_add(n as u64, Ordering::SeqCst);
                 }
                 Err(_) => {
+                    session_state.set_close_reason(SessionCloseReason::IoError);
                     break;
                 }
             }
         }
-        error!("[{}][{}]handle_send done", channel, tunnel_id);
+        error!(channel, tunnel_id, "handle_send done");
         handle_send_session_state
             .closed
             .store(true, Ordering::SeqCst);
@@ -869,9 +3469,23 @@ where
         let _ = event_tx.send(shutdown_ev).await;
     };
 
-    join3(handle_recv, handle_event, handle_send).await;
+    let span = info_span!("session", channel, tunnel_id);
+    join3(handle_recv, handle_event, handle_send)
+        .instrument(span)
+        .await;
     erase_mux_session(channel, tunnel_id);
-    info!("[{}][{}]Close tunnel session", channel, tunnel_id);
+    let reason = session_state
+        .close_reason
+        .lock()
+        .unwrap()
+        .unwrap_or(SessionCloseReason::LocalShutdown);
+    info!(channel, tunnel_id, ?reason, "close tunnel session");
+    notify_session_event(SessionEvent::Closed {
+        channel: String::from(channel),
+        session_id: tunnel_id,
+        reason,
+        duration: session_state.age(),
+    });
     Ok(())
 }
 
@@ -879,14 +3493,27 @@ pub async fn handle_rmux_session(
     channel: &str,
     tunnel_id: u32,
     mut inbound: TcpStream,
-    rctx: CryptoContext,
-    wctx: CryptoContext,
+    method: &str,
+    default_key: &str,
+    nonce: u64,
     recv_buf: &mut BytesMut,
     max_alive_secs: u64,
+    stream_recv_window: u32,
+    peer: &Hello,
+    establishing: EstablishPermit,
     //cfg: &TunnelConfig,
 ) -> Result<(), std::io::Error> {
+    let rctx = CryptoContext::new_for_channel_key(channel, method, default_key, nonce);
+    let wctx = CryptoContext::new_for_channel_key(channel, method, default_key, nonce);
+    let peer_addr = inbound.peer_addr();
     let (mut ri, mut wi) = inbound.split();
-    let ctx = MuxContext::new(channel, tunnel_id, rctx, wctx, max_alive_secs, recv_buf);
+    let mut ctx = MuxContext::new(channel, tunnel_id, rctx, wctx, max_alive_secs, recv_buf);
+    ctx.set_stream_recv_window(stream_recv_window);
+    ctx.set_peer_hello(peer);
+    ctx.set_establish_permit(establishing);
+    if let Ok(addr) = peer_addr {
+        ctx.set_peer_addr(&addr.ip().to_string());
+    }
     process_rmux_session(
         ctx, // channel,
         // tunnel_id,
@@ -901,3 +3528,110 @@ pub async fn handle_rmux_session(
     let _ = inbound.shutdown(std::net::Shutdown::Both);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_id_never_wraps() {
+        let seed = AtomicU32::new(u32::max_value() - 4);
+        assert_eq!(alloc_stream_id(&seed), Some(u32::max_value() - 4));
+        assert_eq!(alloc_stream_id(&seed), Some(u32::max_value() - 2));
+        assert_eq!(alloc_stream_id(&seed), None);
+        assert_eq!(alloc_stream_id(&seed), None);
+        assert_eq!(seed.load(Ordering::SeqCst), u32::max_value());
+    }
+
+    #[test]
+    fn test_unknown_flags_policy() {
+        assert!(!UnknownFlagsPolicy::Tolerate.should_close(1000));
+        assert!(UnknownFlagsPolicy::Strict.should_close(1));
+        assert!(!UnknownFlagsPolicy::Threshold(3).should_close(2));
+        assert!(UnknownFlagsPolicy::Threshold(3).should_close(3));
+        assert_eq!(
+            UnknownFlagsPolicy::with_max_frames(0),
+            UnknownFlagsPolicy::Tolerate
+        );
+        assert_eq!(
+            UnknownFlagsPolicy::with_max_frames(1),
+            UnknownFlagsPolicy::Strict
+        );
+
+        let channel = "test_unknown_flags_policy";
+        set_channel_unknown_flags_policy(channel, UnknownFlagsPolicy::Strict);
+        assert_eq!(
+            get_unknown_flags_policy(channel),
+            UnknownFlagsPolicy::Strict
+        );
+        set_channel_unknown_flags_policy(channel, UnknownFlagsPolicy::Tolerate);
+        assert_eq!(
+            get_unknown_flags_policy(channel),
+            UnknownFlagsPolicy::Tolerate
+        );
+    }
+
+    #[test]
+    fn test_orphan_data() {
+        let channel = "test_orphan_data";
+        let mut orphans = OrphanData::new(channel);
+        assert_eq!(orphans.on_data(channel, 3), OrphanDataAction::ReplyFin);
+        // the rest of the stream's trailing DATA gets no further FIN
+        assert_eq!(orphans.on_data(channel, 3), OrphanDataAction::Ignore);
+        assert_eq!(orphans.on_data(channel, 5), OrphanDataAction::ReplyFin);
+        assert_eq!(orphan_data_counts().get(channel), Some(&3));
+
+        assert_eq!(OrphanDataPolicy::from_name("rst", 2), None);
+        set_channel_orphan_data_policy(channel, OrphanDataPolicy::from_name("drop", 0).unwrap());
+        let mut orphans = OrphanData::new(channel);
+        assert_eq!(orphans.on_data(channel, 7), OrphanDataAction::Ignore);
+        set_channel_orphan_data_policy(channel, OrphanDataPolicy::from_name("close", 2).unwrap());
+        let mut orphans = OrphanData::new(channel);
+        assert_eq!(orphans.on_data(channel, 7), OrphanDataAction::ReplyFin);
+        assert_eq!(orphans.on_data(channel, 9), OrphanDataAction::CloseSession);
+        set_channel_orphan_data_policy(channel, OrphanDataPolicy::ReplyFin);
+        assert_eq!(get_orphan_data_policy(channel), OrphanDataPolicy::ReplyFin);
+    }
+
+    #[test]
+    fn test_write_batching() {
+        let mut vbuf = VBuf::new();
+        vbuf.push(vec![0u8; 100]);
+        assert!(WriteBatching::Off.is_full(&vbuf));
+        assert!(!WriteBatching::Frames(2).is_full(&vbuf));
+        assert!(!WriteBatching::Bytes(150).is_full(&vbuf));
+        vbuf.push(vec![0u8; 100]);
+        assert!(WriteBatching::Frames(2).is_full(&vbuf));
+        assert!(WriteBatching::Bytes(150).is_full(&vbuf));
+    }
+
+    #[test]
+    fn test_jitter_limit_never_negative() {
+        assert_eq!(jitter_limit(30, 60.0, -1.0), 0);
+        assert_eq!(jitter_limit(30, 3.0, -1.0), 27);
+        assert_eq!(jitter_limit(2400, 60.0, 0.5), 2430);
+    }
+
+    #[test]
+    fn test_quiet_sessions_not_noteworthy() {
+        let mut summary = SessionSummary {
+            channel: String::new(),
+            session_id: 1,
+            age: Duration::from_secs(600),
+            io_idle_secs: 5,
+            streams: 0,
+            stream_idle_secs: None,
+            retired: false,
+            total_bytes: 0,
+        };
+        // only pings went over it lately
+        assert!(!is_noteworthy(&summary, false));
+        assert!(is_noteworthy(&summary, true));
+        summary.streams = 2;
+        assert!(is_noteworthy(&summary, false));
+        summary.io_idle_secs = 100;
+        assert!(!is_noteworthy(&summary, false));
+        summary.io_idle_secs = NOTEWORTHY_IO_IDLE_SECS;
+        assert!(is_noteworthy(&summary, false));
+    }
+}
@@ -0,0 +1,139 @@
+use super::budget::{buffer_budget, buffer_usage};
+use super::hooks::FinReason;
+use super::quality::SessionQuality;
+use super::security::auth_failure_counts;
+use super::session::{
+    max_lock_hold_micros, max_retired_sessions, orphan_data_counts, retired_session_counts,
+    WriteBatching,
+};
+use super::tasks::{
+    establishing_session_count, max_establishing_sessions, max_relay_tasks, relay_task_count,
+};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// What a session was set up with, fixed once it's established. Later changes like
+// set_channel_max_alive_secs aren't reflected.
+#[derive(Debug, Clone)]
+pub struct SessionParams {
+    pub channel: String,
+    pub session_id: u32,
+    pub cipher: String,
+    // negotiated with the peer in the auth handshake, 0 for peers without a hello
+    pub version: u32,
+    pub capabilities: u64,
+    // first local stream id, odd ids are opened by the client and even ones by the server
+    pub stream_id_seed: u32,
+    pub stream_recv_window: u32,
+    pub max_alive_secs: u64,
+    pub max_alive_bytes: u64,
+    pub ping_idle_secs: u32,
+    pub stream_keepalive_secs: u32,
+    pub write_batching: WriteBatching,
+    // of the channel's key the session was authenticated with, see rotate_channel_key
+    pub key_epoch: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionStats {
+    pub channel: String,
+    pub session_id: u32,
+    pub age_secs: u64,
+    pub retired: bool,
+    pub closed: bool,
+    pub ping_pong_gap: i64,
+    pub io_idle_secs: u32,
+    pub pending_streams: usize,
+    // remote events read from the connection but not yet handled by the event loop
+    pub recv_queue_depth: u32,
+    // encoded frames waiting to be written to the connection
+    pub send_queue_depth: u32,
+    pub weight: u32,
+    // negotiated with the peer in the auth handshake, 0 for peers without a hello
+    pub version: u32,
+    pub capabilities: u64,
+    // time frames waited in the send queue before their write, over the session's life
+    pub max_send_dwell: Duration,
+    pub avg_send_dwell: Duration,
+    // as of the last routine tick, sessions of the best class present get the new streams
+    pub quality: SessionQuality,
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamStats {
+    pub stream_id: u32,
+    pub target: String,
+    pub metadata: BTreeMap<String, String>,
+    pub priority: u8,
+    pub age: Duration,
+    pub send_bytes: u32,
+    pub recv_bytes: u32,
+    pub send_window: i32,
+    pub closed: bool,
+    // window updates that couldn't be queued, the credit is retried on the next read
+    pub dropped_window_updates: u32,
+    pub last_advertised_window: u32,
+    // the peer's FIN reason if it closed first, else ours, None while open
+    pub close_reason: Option<FinReason>,
+    // time the writer waited with no send window left, in total and at most at once
+    pub window_stall_time: Duration,
+    pub max_window_stall: Duration,
+    pub window_updates: u32,
+}
+
+// a live session's traffic over one routine tick, handed to the throughput sampler
+#[derive(Debug, Clone)]
+pub struct ThroughputSample {
+    pub channel: String,
+    pub session_id: u32,
+    // sent and received since the previous tick
+    pub bytes: u64,
+    pub streams: u32,
+    // of the last answered ping
+    pub rtt: Option<Duration>,
+}
+
+// process wide counters, cheap enough to poll for dashboards
+#[derive(Debug, Clone, Default)]
+pub struct MetricsSnapshot {
+    // bytes buffered by all sessions and streams, and the budget, 0 for no limit
+    pub buffer_usage: usize,
+    pub buffer_budget: usize,
+    // longest hold of the lock over all sessions, 0 unless set_lock_hold_tracking is on
+    pub max_lock_hold_micros: u64,
+    // tasks relaying streams opened by peers, and their limit, 0 for none
+    pub relay_tasks: usize,
+    pub max_relay_tasks: usize,
+    // sessions in their handshake & setup, and their limit, 0 for none
+    pub establishing_sessions: usize,
+    pub max_establishing_sessions: usize,
+    // frames failing authentication by channel, "" for the server's sessions, apart from
+    // the IO errors closing sessions
+    pub auth_failures: BTreeMap<String, u64>,
+    // retired sessions waiting for their streams to close, those streams, and the limit of
+    // such sessions, 0 for none
+    pub retired_sessions: usize,
+    pub retired_streams: usize,
+    pub max_retired_sessions: usize,
+    // DATA frames dropped since their stream was unknown by channel, "" for the server's
+    // sessions, e.g. DATA the peer sent before it got the stream's FIN
+    pub orphan_data: BTreeMap<String, u64>,
+}
+
+pub fn metrics_snapshot() -> MetricsSnapshot {
+    let (retired_sessions, retired_streams) = retired_session_counts();
+    MetricsSnapshot {
+        buffer_usage: buffer_usage(),
+        buffer_budget: buffer_budget(),
+        max_lock_hold_micros: max_lock_hold_micros(),
+        relay_tasks: relay_task_count(),
+        max_relay_tasks: max_relay_tasks(),
+        establishing_sessions: establishing_session_count(),
+        max_establishing_sessions: max_establishing_sessions(),
+        auth_failures: auth_failure_counts(),
+        retired_sessions,
+        retired_streams,
+        max_retired_sessions: max_retired_sessions(),
+        orphan_data: orphan_data_counts(),
+    }
+}
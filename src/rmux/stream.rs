@@ -1,73 +1,287 @@
-use super::event::{new_data_event, new_fin_event, Event};
-use super::message::ConnectRequest;
+use super::budget::{over_buffer_budget, BufferCharge, PRESSURE_WINDOW_CREDIT};
+use super::clock::{Clock, SystemClock};
+use super::event::{
+    new_connected_event, new_data_event, new_fin_event_with_reason, new_mp_fin_event,
+    new_seq_data_event, new_window_update_event, Event, MAX_WINDOW_UPDATE_CREDIT,
+};
+use super::flow::{new_flow_controller, FlowController};
+use super::hooks::{notify_stream_event, FinReason, StreamEvent};
+use super::message::{ConnectRequest, ResumeToken};
+use super::multipath::{self, MultipathStream, MultipathWriter};
 use super::session::report_update_window;
+use super::stats::StreamStats;
+use super::traffic::{get_channel_traffic, ChannelTraffic};
 
 use bytes::BytesMut;
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
+use std::future::Future;
 use std::pin::Pin;
-use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::task::{Context, Poll, Waker};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::oneshot;
+use tokio::time::delay_for;
 
 use crate::channel::ChannelStream;
 use crate::utils::{fill_read_buf, make_io_error};
 
+// Initial send window of every stream. A stream may cap its recv buffer at another size,
+// the SYN tells the peer so it sends no more than that before a window update.
+pub const DEFAULT_STREAM_WINDOW: u32 = 128 * 1024;
+// windows are counted in an i32, larger ones advertised or configured are clamped to it
+pub const MAX_STREAM_WINDOW: u32 = i32::max_value() as u32;
+
+// warn every this many window updates dropped on a stream
+const WINDOW_UPDATE_DROP_WARN_THRESHOLD: u32 = 16;
+
+static DATA_SEQ_CHECK: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref WRITE_COALESCING: Mutex<HashMap<String, (usize, Duration)>> =
+        Mutex::new(HashMap::new());
+    static ref MAX_STREAM_LIFETIMES: Mutex<HashMap<String, Duration>> = Mutex::new(HashMap::new());
+}
+
+// Debug aid: number DATA events per stream so the peer could detect loss or reordering
+// in the event pipeline. It costs 4 bytes per event and needs a peer that knows FLAG_SEQ_DATA.
+pub fn set_data_seq_check(enable: bool) {
+    DATA_SEQ_CHECK.store(enable, Ordering::SeqCst);
+}
+
+// Gather small writes of the channel's streams into one DATA event of up to max_bytes, or
+// whatever was written delay_ms after the first of them. Chatty targets then cost fewer
+// events and frames. 0 for either turns it off. Applies to streams split after.
+pub fn set_channel_write_coalescing(channel: &str, max_bytes: usize, delay_ms: u64) {
+    let mut coalescing = WRITE_COALESCING.lock().unwrap();
+    if max_bytes == 0 || delay_ms == 0 {
+        coalescing.remove(channel);
+        return;
+    }
+    coalescing.insert(
+        String::from(channel),
+        (max_bytes, Duration::from_millis(delay_ms)),
+    );
+}
+
+fn get_write_coalescing(channel: &str) -> Option<(usize, Duration)> {
+    WRITE_COALESCING.lock().unwrap().get(channel).copied()
+}
+
+// Close streams of the channel older than secs with FinReason::LifetimeExceeded however
+// busy they are, e.g. to rotate long lived websockets by policy. Checked on each routine
+// tick, 0 for no limit.
+pub fn set_channel_max_stream_lifetime(channel: &str, secs: u64) {
+    let mut lifetimes = MAX_STREAM_LIFETIMES.lock().unwrap();
+    if secs == 0 {
+        lifetimes.remove(channel);
+        return;
+    }
+    lifetimes.insert(String::from(channel), Duration::from_secs(secs));
+}
+
+pub(crate) fn get_max_stream_lifetime(channel: &str) -> Option<Duration> {
+    MAX_STREAM_LIFETIMES.lock().unwrap().get(channel).copied()
+}
+
 pub struct MuxStreamState {
     pub channel: String,
     pub session_id: u32,
     pub stream_id: u32,
-    pub send_buf_window: AtomicI32,
-    pub recv_buf_size: AtomicI32,
+    pub flow: Box<dyn FlowController>,
+    pub recv_window: u32,
     pub closed: AtomicBool,
+    // FIN received, the reader still drains data arrived before it
+    pub peer_closed: AtomicBool,
+    // FinReason carried by the peer's FIN, and the one sent with ours
+    peer_fin_reason: AtomicU8,
+    local_fin_reason: AtomicU8,
+    pub paused: AtomicBool,
+    close_notified: AtomicBool,
     pub total_recv_bytes: AtomicU32,
     pub total_send_bytes: AtomicU32,
+    // what the reader consumed, 64 bits since it's a resume offset
+    read_bytes: AtomicU64,
+    traffic: Arc<ChannelTraffic>,
+    send_seq: AtomicU32,
+    recv_seq: AtomicU32,
+    pub dropped_window_updates: AtomicU32,
+    pub last_advertised_window: AtomicU32,
+    // window stall accounting, micros since born_time the current stall began at plus one, 0
+    // while the writer isn't stalled
+    stall_since_micros: AtomicU64,
+    stall_total_micros: AtomicU64,
+    stall_max_micros: AtomicU64,
+    window_updates: AtomicU32,
+    // the peer reported the target connected, or doesn't report it at all
+    connected: AtomicBool,
+    // a stream opened by the peer sends FLAG_CONNECTED once its target is connected
+    report_connected: AtomicBool,
+    // and tells why it couldn't be in the FIN, see CAP_CONNECT_ERRORS
+    report_connect_errors: AtomicBool,
+    connect_waiters: Mutex<Vec<oneshot::Sender<Result<(), FinReason>>>>,
+    // received but not yet read, charged to the buffer budget
+    buffered: BufferCharge,
+    // unix secs of the last data moved either way, and of the pending keepalive probe
+    last_active_unix_secs: AtomicU32,
+    keepalive_probe_unix_secs: AtomicU32,
+    // the session's, for the activity times above
+    clock: Arc<dyn Clock>,
     pub born_time: Instant,
 }
 
 struct SharedIOState {
     waker: Option<Waker>,
+    read_waker: Option<Waker>,
+    // data arrived while paused, it's bounded by the recv window since no window update is sent
+    paused_queue: VecDeque<Vec<u8>>,
     data_tx: Option<mpsc::Sender<Vec<u8>>>,
     data_rx: Option<mpsc::Receiver<Vec<u8>>>,
+    // written but held back for coalescing, already counted against the send window
+    coalesced: Vec<u8>,
+    coalesce_flush_armed: bool,
 }
 
 impl MuxStreamState {
     fn close(&self) {
         self.closed.store(true, Ordering::SeqCst);
     }
+    fn peer_fin_reason(&self) -> Option<FinReason> {
+        if !self.peer_closed.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(FinReason::from_u8(
+            self.peer_fin_reason.load(Ordering::SeqCst),
+        ))
+    }
+    fn close_reason(&self) -> Option<FinReason> {
+        if let Some(r) = self.peer_fin_reason() {
+            return Some(r);
+        }
+        if !self.closed.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(FinReason::from_u8(
+            self.local_fin_reason.load(Ordering::SeqCst),
+        ))
+    }
+    fn touch(&self) {
+        self.last_active_unix_secs
+            .store(self.clock.now_unix_secs(), Ordering::SeqCst);
+    }
+    // resolves the waiting ConnectResults, the first result sticks
+    fn resolve_connect(&self, result: Result<(), FinReason>) {
+        let mut waiters = self.connect_waiters.lock().unwrap();
+        if result.is_ok() {
+            self.connected.store(true, Ordering::SeqCst);
+        }
+        for tx in waiters.drain(..) {
+            let _ = tx.send(result);
+        }
+    }
+    // the writer found no send window left
+    fn on_window_stall(&self) {
+        let now = self.born_time.elapsed().as_micros() as u64 + 1;
+        let _ =
+            self.stall_since_micros
+                .compare_exchange(0, now, Ordering::SeqCst, Ordering::SeqCst);
+    }
+    fn on_window_credit(&self) {
+        if self.flow.send_window() <= 0 {
+            return;
+        }
+        let since = self.stall_since_micros.swap(0, Ordering::SeqCst);
+        if since == 0 {
+            return;
+        }
+        let stalled = (self.born_time.elapsed().as_micros() as u64 + 1).saturating_sub(since);
+        self.stall_total_micros.fetch_add(stalled, Ordering::SeqCst);
+        self.stall_max_micros.fetch_max(stalled, Ordering::SeqCst);
+    }
+    // including the stall still going on
+    fn window_stall_time(&self) -> (Duration, Duration) {
+        let mut total = self.stall_total_micros.load(Ordering::SeqCst);
+        let mut max = self.stall_max_micros.load(Ordering::SeqCst);
+        let since = self.stall_since_micros.load(Ordering::SeqCst);
+        if since > 0 {
+            let stalled = (self.born_time.elapsed().as_micros() as u64 + 1).saturating_sub(since);
+            total += stalled;
+            max = std::cmp::max(max, stalled);
+        }
+        (Duration::from_micros(total), Duration::from_micros(max))
+    }
+    fn new_data_event(&self, buf: &[u8]) -> Event {
+        if DATA_SEQ_CHECK.load(Ordering::SeqCst) {
+            let seq = self.send_seq.fetch_add(1, Ordering::SeqCst);
+            new_seq_data_event(self.stream_id, seq, buf)
+        } else {
+            new_data_event(self.stream_id, buf, false)
+        }
+    }
+    fn on_data_written(&self, len: usize) {
+        self.flow.on_data_sent(len);
+        self.traffic.add_send(len);
+        self.touch();
+        self.total_send_bytes
+            .fetch_add(len as u32, Ordering::SeqCst);
+    }
 }
 
 struct MuxStreamReader {
     rx: mpsc::Receiver<Vec<u8>>,
     recv_buf: BytesMut,
     state: Arc<MuxStreamState>,
+    io_state: Arc<Mutex<SharedIOState>>,
 }
 
 impl MuxStreamReader {}
 
 fn inc_recv_buf_window(state: &MuxStreamState, inc: usize, cx: &mut Context<'_>) {
-    state.recv_buf_size.fetch_add(inc as i32, Ordering::SeqCst);
+    state.flow.on_data_received(inc);
+    state.buffered.credit(inc);
     state
         .total_recv_bytes
         .fetch_add(inc as u32, Ordering::SeqCst);
-    let min_report_window: i32 = 32 * 1024;
-    let current_recv_buf_size = state.recv_buf_size.load(Ordering::SeqCst);
-    if current_recv_buf_size >= min_report_window
-        && report_update_window(
-            cx,
-            state.channel.as_str(),
-            state.session_id,
-            state.stream_id,
-            current_recv_buf_size as u32,
-        )
-    {
-        state
-            .recv_buf_size
-            .fetch_sub(current_recv_buf_size, Ordering::SeqCst);
+    state.read_bytes.fetch_add(inc as u64, Ordering::SeqCst);
+    state.traffic.add_recv(inc);
+    if state.paused.load(Ordering::SeqCst) {
+        return;
+    }
+    // any credit above what fits in one event is granted by the next update
+    let mut window = std::cmp::min(
+        state.flow.next_advertised_window(),
+        MAX_WINDOW_UPDATE_CREDIT,
+    );
+    // the rest stays pending until the usage drops below the budget
+    if over_buffer_budget() {
+        window = std::cmp::min(window, PRESSURE_WINDOW_CREDIT);
+    }
+    if window == 0 {
+        return;
+    }
+    if report_update_window(
+        cx,
+        state.channel.as_str(),
+        state.session_id,
+        state.stream_id,
+        window,
+    ) {
+        state.flow.on_window_advertised(window);
+        state.last_advertised_window.store(window, Ordering::SeqCst);
+    } else {
+        let dropped = state.dropped_window_updates.fetch_add(1, Ordering::SeqCst) + 1;
+        if dropped % WINDOW_UPDATE_DROP_WARN_THRESHOLD == 0 {
+            warn!(
+                "[{}][{}]{} window updates dropped, pending window:{}",
+                state.session_id, state.stream_id, dropped, window
+            );
+        }
     }
 }
 
@@ -81,25 +295,47 @@ impl AsyncRead for MuxStreamReader {
             rx,
             recv_buf,
             state,
+            io_state,
         } = &mut *self;
         if state.closed.load(Ordering::SeqCst) {
             rx.close();
             return Poll::Ready(Err(make_io_error("closed")));
         }
+        {
+            let mut io_state = io_state.lock().unwrap();
+            if state.paused.load(Ordering::SeqCst) {
+                io_state.read_waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
         if !recv_buf.is_empty() {
             let n = fill_read_buf(recv_buf, buf);
             inc_recv_buf_window(&state, n, cx);
             return Poll::Ready(Ok(n));
         }
         recv_buf.clear();
-        match rx.poll_recv(cx) {
-            Poll::Ready(Some(b)) => {
+        // data queued while paused always comes after anything already in the channel
+        let next = match rx.poll_recv(cx) {
+            Poll::Ready(v) => v,
+            Poll::Pending => match io_state.lock().unwrap().paused_queue.pop_front() {
+                Some(b) => Some(b),
+                None => return Poll::Pending,
+            },
+        };
+        match next {
+            Some(b) => {
                 let mut copy_n = b.len();
                 if 0 == copy_n {
                     //close
                     //error!("[{}]####2 Close", state.stream_id);
                     state.close();
                     rx.close();
+                    // a reset by the peer isn't a clean EOF, let the relay tell them apart
+                    if state.peer_fin_reason() == Some(FinReason::Reset) {
+                        return Poll::Ready(Err(std::io::Error::from(
+                            std::io::ErrorKind::ConnectionReset,
+                        )));
+                    }
                     return Poll::Ready(Ok(0));
                 }
                 if copy_n > buf.len() {
@@ -113,22 +349,25 @@ impl AsyncRead for MuxStreamReader {
                 inc_recv_buf_window(&state, copy_n, cx);
                 Poll::Ready(Ok(copy_n))
             }
-            Poll::Ready(None) => {
+            None => {
                 //error!("[{}]####3 Close", state.stream_id);
                 state.close();
                 rx.close();
                 Poll::Ready(Ok(0))
             }
-            Poll::Pending => Poll::Pending,
         }
     }
 }
 
 struct MuxStreamWriter {
     tx: mpsc::Sender<Event>,
+    mp_writer: Option<MultipathWriter>,
+    coalesce: Option<(usize, Duration)>,
     state: Arc<MuxStreamState>,
     io_state: Arc<Mutex<SharedIOState>>,
 }
+// A write takes at most the peer's remaining send window. With none left it's Pending until
+// update_send_window brings a credit, the writer never buffers beyond the window.
 impl AsyncWrite for MuxStreamWriter {
     fn poll_write(
         mut self: Pin<&mut Self>,
@@ -137,17 +376,47 @@ impl AsyncWrite for MuxStreamWriter {
     ) -> Poll<Result<usize, std::io::Error>> {
         let Self {
             tx,
+            mp_writer,
+            coalesce,
             state,
             io_state,
         } = &mut *self;
-        if state.closed.load(Ordering::SeqCst) {
+        if state.closed.load(Ordering::SeqCst) || state.peer_closed.load(Ordering::SeqCst) {
             return Poll::Ready(Err(make_io_error("closed")));
         }
-        if state.send_buf_window.load(Ordering::SeqCst) < 0 {
-            io_state.lock().unwrap().waker = Some(cx.waker().clone());
-            return Poll::Pending;
+        let mut window = state.flow.send_window();
+        if window <= 0 {
+            let mut io_state = io_state.lock().unwrap();
+            // check again under the lock, or a credit arrived before the waker is stored is lost
+            window = state.flow.send_window();
+            if window <= 0 {
+                io_state.waker = Some(cx.waker().clone());
+                state.on_window_stall();
+                return Poll::Pending;
+            }
+        }
+        let buf = &buf[..std::cmp::min(buf.len(), window as usize)];
+        {
+            let mut io_state = io_state.lock().unwrap();
+            if state.paused.load(Ordering::SeqCst) {
+                io_state.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+        if let Some(w) = mp_writer {
+            return match w.poll_send(cx, buf) {
+                Poll::Pending => Poll::Pending,
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                Poll::Ready(Ok(())) => {
+                    state.on_data_written(buf.len());
+                    Poll::Ready(Ok(buf.len()))
+                }
+            };
         }
-        let ev = new_data_event(state.stream_id, buf, false);
+        if let Some((max_bytes, delay)) = *coalesce {
+            return poll_write_coalesced(cx, tx, state, io_state, buf, max_bytes, delay);
+        }
+        let ev = state.new_data_event(buf);
 
         // let future = tx.send(ev);
         // pin_mut!(future);
@@ -172,18 +441,38 @@ impl AsyncWrite for MuxStreamWriter {
         match tx.try_send(ev) {
             Err(e) => Poll::Ready(Err(make_io_error(e.description()))),
             Ok(()) => {
-                state
-                    .send_buf_window
-                    .fetch_sub(buf.len() as i32, Ordering::SeqCst);
-                state
-                    .total_send_bytes
-                    .fetch_add(buf.len() as u32, Ordering::SeqCst);
+                state.on_data_written(buf.len());
                 Poll::Ready(Ok(buf.len()))
             }
         }
     }
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
-        Poll::Ready(Ok(()))
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let Self {
+            tx,
+            state,
+            io_state,
+            ..
+        } = &mut *self;
+        if io_state.lock().unwrap().coalesced.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        match tx.poll_ready(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(make_io_error(e.description()))),
+            Poll::Ready(Ok(())) => {}
+        }
+        let mut io_state = io_state.lock().unwrap();
+        if io_state.coalesced.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        let data = std::mem::replace(&mut io_state.coalesced, Vec::new());
+        match tx.try_send(state.new_data_event(&data[..])) {
+            Err(e) => Poll::Ready(Err(make_io_error(e.description()))),
+            Ok(()) => Poll::Ready(Ok(())),
+        }
     }
     fn poll_shutdown(
         self: Pin<&mut Self>,
@@ -194,12 +483,207 @@ impl AsyncWrite for MuxStreamWriter {
     }
 }
 
+// Waits for the send window of a stream on behalf of whatever feeds its writer.
+#[derive(Clone)]
+pub(crate) struct SendWindowGate {
+    state: Arc<MuxStreamState>,
+    io_state: Arc<Mutex<SharedIOState>>,
+}
+
+impl SendWindowGate {
+    // the bytes the writer would take now, Pending until there are some; a closed stream
+    // never blocks, its writer fails instead
+    fn poll_window(&self, cx: &mut Context<'_>) -> Poll<usize> {
+        let state = &self.state;
+        if state.closed.load(Ordering::SeqCst) || state.peer_closed.load(Ordering::SeqCst) {
+            return Poll::Ready(usize::max_value());
+        }
+        let mut window = state.flow.send_window();
+        if window <= 0 {
+            let mut io_state = self.io_state.lock().unwrap();
+            window = state.flow.send_window();
+            if window <= 0 {
+                io_state.waker = Some(cx.waker().clone());
+                state.on_window_stall();
+                return Poll::Pending;
+            }
+        }
+        Poll::Ready(window as usize)
+    }
+    pub(crate) fn gate<'a, R: ?Sized>(&self, inner: &'a mut R) -> WindowGatedReader<'a, R> {
+        WindowGatedReader {
+            inner,
+            gate: self.clone(),
+        }
+    }
+}
+
+// Reads the target no faster than the peer's window drains, what the window can't take yet
+// stays in the target conn rather than in the relay.
+pub(crate) struct WindowGatedReader<'a, R: ?Sized> {
+    inner: &'a mut R,
+    gate: SendWindowGate,
+}
+
+impl<R: AsyncRead + Unpin + ?Sized> AsyncRead for WindowGatedReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let window = match self.gate.poll_window(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(n) => n,
+        };
+        let n = std::cmp::min(buf.len(), window);
+        Pin::new(&mut *self.inner).poll_read(cx, &mut buf[..n])
+    }
+}
+
+// Buffer the write until max_bytes are gathered, the held back bytes go out as one DATA event
+// ahead of the write that fills it. The event is queued under the io lock, so the timer's
+// flush, poll_flush and close can't reorder the data.
+fn poll_write_coalesced(
+    cx: &mut Context<'_>,
+    tx: &mut mpsc::Sender<Event>,
+    state: &Arc<MuxStreamState>,
+    io_state: &Arc<Mutex<SharedIOState>>,
+    buf: &[u8],
+    max_bytes: usize,
+    delay: Duration,
+) -> Poll<Result<usize, std::io::Error>> {
+    let full = io_state.lock().unwrap().coalesced.len() + buf.len() >= max_bytes;
+    if full {
+        match tx.poll_ready(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(make_io_error(e.description()))),
+            Poll::Ready(Ok(())) => {}
+        }
+    }
+    let mut shared = io_state.lock().unwrap();
+    shared.coalesced.extend_from_slice(buf);
+    if full {
+        let data = std::mem::replace(&mut shared.coalesced, Vec::new());
+        if let Err(e) = tx.try_send(state.new_data_event(&data[..])) {
+            return Poll::Ready(Err(make_io_error(e.description())));
+        }
+    } else if !shared.coalesce_flush_armed {
+        shared.coalesce_flush_armed = true;
+        spawn_coalesced_flush(tx.clone(), state.clone(), io_state.clone(), delay);
+    }
+    drop(shared);
+    state.on_data_written(buf.len());
+    Poll::Ready(Ok(buf.len()))
+}
+
+fn spawn_coalesced_flush(
+    mut tx: mpsc::Sender<Event>,
+    state: Arc<MuxStreamState>,
+    io_state: Arc<Mutex<SharedIOState>>,
+    delay: Duration,
+) {
+    tokio::spawn(async move {
+        delay_for(delay).await;
+        // with a slot reserved the send below can't fail on a full queue
+        if futures::future::poll_fn(|cx| tx.poll_ready(cx))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        let mut io_state = io_state.lock().unwrap();
+        io_state.coalesce_flush_armed = false;
+        if io_state.coalesced.is_empty() {
+            return;
+        }
+        let data = std::mem::replace(&mut io_state.coalesced, Vec::new());
+        let _ = tx.try_send(state.new_data_event(&data[..]));
+    });
+}
+
+// Resolves once the peer connected the stream's target, or with the reason of the FIN that
+// came instead. It's Ok right away for peers without CAP_CONNECT_RESULT.
+pub struct ConnectResult {
+    rx: oneshot::Receiver<Result<(), FinReason>>,
+}
+
+impl Future for ConnectResult {
+    type Output = Result<(), FinReason>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.rx).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(r)) => Poll::Ready(r),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(FinReason::Reset)),
+        }
+    }
+}
+
+fn stream_stats(target: &ConnectRequest, state: &MuxStreamState) -> StreamStats {
+    let (window_stall_time, max_window_stall) = state.window_stall_time();
+    StreamStats {
+        stream_id: state.stream_id,
+        target: target.addr.clone(),
+        metadata: target.metadata.clone(),
+        priority: target.priority,
+        age: state.born_time.elapsed(),
+        send_bytes: state.total_send_bytes.load(Ordering::SeqCst),
+        recv_bytes: state.total_recv_bytes.load(Ordering::SeqCst),
+        send_window: state.flow.send_window(),
+        closed: state.closed.load(Ordering::SeqCst),
+        dropped_window_updates: state.dropped_window_updates.load(Ordering::SeqCst),
+        last_advertised_window: state.last_advertised_window.load(Ordering::SeqCst),
+        close_reason: state.close_reason(),
+        window_stall_time,
+        max_window_stall,
+        window_updates: state.window_updates.load(Ordering::SeqCst),
+    }
+}
+
+fn resume_token(target: &ConnectRequest, state: &MuxStreamState) -> Option<ResumeToken> {
+    target.resume.map(|t| ResumeToken {
+        stream_key: t.stream_key,
+        recv_offset: t.recv_offset + state.read_bytes.load(Ordering::SeqCst),
+    })
+}
+
+// a stream as seen outside of the event loop owning it, without its data channel
+pub(crate) struct StreamHandle {
+    target: ConnectRequest,
+    state: Arc<MuxStreamState>,
+}
+
+impl StreamHandle {
+    pub(crate) fn stats(&self) -> StreamStats {
+        stream_stats(&self.target, &self.state)
+    }
+    // in total, including the stall still going on
+    pub(crate) fn window_stall_time(&self) -> Duration {
+        self.state.window_stall_time().0
+    }
+    pub(crate) fn is_resumable(&self) -> bool {
+        self.target.resume.is_some() && self.target.multipath_id == 0
+    }
+    // Stops the reader where it is, the token resumes exactly after what it delivered. The
+    // stream itself is closed by a FIN through its event loop.
+    pub(crate) fn detach(&self) -> Option<ResumeToken> {
+        if !self.is_resumable() || self.state.closed.load(Ordering::SeqCst) {
+            return None;
+        }
+        self.state.close();
+        resume_token(&self.target, &self.state)
+    }
+    pub(crate) fn target(&self) -> &ConnectRequest {
+        &self.target
+    }
+}
+
 pub struct MuxStream {
     pub target: ConnectRequest,
     event_tx: mpsc::Sender<Event>,
     pub data_tx: Option<mpsc::Sender<Vec<u8>>>,
     pub state: Arc<MuxStreamState>,
     io_state: Arc<Mutex<SharedIOState>>,
+    multipath: Option<Arc<MultipathStream>>,
 }
 
 impl MuxStream {
@@ -209,23 +693,51 @@ impl MuxStream {
         id1: u32,
         evtx: mpsc::Sender<Event>,
         target: ConnectRequest,
+        recv_window: u32,
     ) -> Self {
         let state = MuxStreamState {
             channel: String::from(name),
             session_id: id0,
             stream_id: id1,
-            send_buf_window: AtomicI32::new(128 * 1024),
-            recv_buf_size: AtomicI32::new(0),
+            flow: new_flow_controller(recv_window),
+            recv_window,
             closed: AtomicBool::new(false),
+            peer_closed: AtomicBool::new(false),
+            peer_fin_reason: AtomicU8::new(FinReason::Normal as u8),
+            local_fin_reason: AtomicU8::new(FinReason::Normal as u8),
+            paused: AtomicBool::new(false),
+            close_notified: AtomicBool::new(false),
             total_recv_bytes: AtomicU32::new(0),
+            read_bytes: AtomicU64::new(0),
             total_send_bytes: AtomicU32::new(0),
+            traffic: get_channel_traffic(name),
+            send_seq: AtomicU32::new(0),
+            recv_seq: AtomicU32::new(0),
+            dropped_window_updates: AtomicU32::new(0),
+            last_advertised_window: AtomicU32::new(0),
+            stall_since_micros: AtomicU64::new(0),
+            stall_total_micros: AtomicU64::new(0),
+            stall_max_micros: AtomicU64::new(0),
+            window_updates: AtomicU32::new(0),
+            connected: AtomicBool::new(false),
+            report_connected: AtomicBool::new(false),
+            report_connect_errors: AtomicBool::new(false),
+            connect_waiters: Mutex::new(Vec::new()),
+            buffered: BufferCharge::default(),
+            last_active_unix_secs: AtomicU32::new(SystemClock.now_unix_secs()),
+            keepalive_probe_unix_secs: AtomicU32::new(0),
+            clock: Arc::new(SystemClock),
             born_time: Instant::now(),
         };
         let (dtx, drx) = mpsc::channel(16);
         let io_state = SharedIOState {
             waker: None,
+            read_waker: None,
+            paused_queue: VecDeque::new(),
             data_tx: Some(dtx),
             data_rx: Some(drx),
+            coalesced: Vec::new(),
+            coalesce_flush_armed: false,
         };
         Self {
             target,
@@ -233,12 +745,35 @@ impl MuxStream {
             data_tx: None,
             state: Arc::new(state),
             io_state: Arc::new(Mutex::new(io_state)),
+            multipath: None,
         }
     }
     pub fn id(&self) -> u32 {
         self.state.stream_id
     }
 
+    // the session's clock for the stream's activity, set before the stream is shared
+    pub(crate) fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        let state = Arc::get_mut(&mut self.state).expect("stream already shared");
+        state
+            .last_active_unix_secs
+            .store(clock.now_unix_secs(), Ordering::SeqCst);
+        state.clock = clock;
+    }
+
+    pub(crate) fn set_multipath(&mut self, mp: Arc<MultipathStream>) {
+        self.multipath = Some(mp);
+    }
+    pub(crate) fn multipath(&self) -> Option<Arc<MultipathStream>> {
+        self.multipath.clone()
+    }
+    pub(crate) fn data_sender(&self) -> Option<mpsc::Sender<Vec<u8>>> {
+        if let Some(tx) = &self.data_tx {
+            return Some(tx.clone());
+        }
+        self.io_state.lock().unwrap().data_tx.clone()
+    }
+
     fn check_data_tx(&mut self) {
         if self.data_tx.is_some() {
             return;
@@ -247,26 +782,265 @@ impl MuxStream {
             self.data_tx = Some(tx);
         }
     }
-    pub fn update_send_window(&self, inc: u32) {
-        self.state
-            .send_buf_window
-            .fetch_add(inc as i32, Ordering::SeqCst);
-        if self.state.send_buf_window.load(Ordering::SeqCst) > 0 {
-            if let Some(waker) = self.io_state.lock().unwrap().waker.take() {
+    pub fn stats(&self) -> StreamStats {
+        stream_stats(&self.target, &self.state)
+    }
+    pub(crate) fn handle(&self) -> StreamHandle {
+        StreamHandle {
+            target: self.target.clone(),
+            state: self.state.clone(),
+        }
+    }
+    // returns the expected sequence if it's not the one received
+    pub(crate) fn check_recv_seq(&self, seq: u32) -> Result<(), u32> {
+        let expected = self.state.recv_seq.fetch_add(1, Ordering::SeqCst);
+        if expected != seq {
+            return Err(expected);
+        }
+        Ok(())
+    }
+    pub(crate) fn send_window_gate(&self) -> SendWindowGate {
+        SendWindowGate {
+            state: self.state.clone(),
+            io_state: self.io_state.clone(),
+        }
+    }
+    // initial window advertised by the peer
+    pub(crate) fn set_send_window(&self, window: u32) {
+        self.state.flow.set_peer_window(window);
+        self.state.on_window_credit();
+        self.wake_writer();
+    }
+    fn wake_writer(&self) {
+        let mut io_state = self.io_state.lock().unwrap();
+        if self.state.flow.send_window() > 0 {
+            if let Some(waker) = io_state.waker.take() {
                 waker.wake()
             }
         }
     }
+    // inc is the additive credit of a WIN_UPDATE
+    pub fn update_send_window(&self, inc: u32) {
+        self.state.flow.on_window_update(inc);
+        self.state.window_updates.fetch_add(1, Ordering::SeqCst);
+        self.state.on_window_credit();
+        self.wake_writer();
+    }
     pub async fn offer_data(&mut self, data: Vec<u8>) {
         self.check_data_tx();
         //error!("[{}]off data len:{}.", self.state.stream_id, data.len());
         assert!(!data.is_empty());
+        self.state.touch();
+        self.state.buffered.charge(data.len());
+        // Never waits for the reader, a stalled one would hold up the session's event loop
+        // and the PINGs with it. The backlog is bounded by the recv window. The lock is held
+        // across the send so a reader finding the channel empty sees what was queued.
+        let mut io_state = self.io_state.lock().unwrap();
+        if self.state.paused.load(Ordering::SeqCst) || !io_state.paused_queue.is_empty() {
+            io_state.paused_queue.push_back(data);
+            return;
+        }
         if let Some(tx) = &mut self.data_tx {
-            let _ = tx.send(data).await;
+            if let Err(TrySendError::Full(data)) = tx.try_send(data) {
+                io_state.paused_queue.push_back(data);
+            }
         } else {
             //error!("[{}]Non recv rx for data.", self.state.stream_id);
         }
     }
+    // like offer_data but hands the data back instead of queueing it if the reader is behind
+    pub(crate) fn try_offer_data(&mut self, data: Vec<u8>) -> Result<(), Vec<u8>> {
+        self.check_data_tx();
+        assert!(!data.is_empty());
+        let len = data.len();
+        self.state.buffered.charge(len);
+        {
+            let mut io_state = self.io_state.lock().unwrap();
+            if self.state.paused.load(Ordering::SeqCst) || !io_state.paused_queue.is_empty() {
+                io_state.paused_queue.push_back(data);
+                self.state.touch();
+                return Ok(());
+            }
+        }
+        if let Some(tx) = &mut self.data_tx {
+            if let Err(TrySendError::Full(data)) = tx.try_send(data) {
+                self.state.buffered.credit(len);
+                return Err(data);
+            }
+        }
+        self.state.touch();
+        Ok(())
+    }
+    pub fn priority(&self) -> u8 {
+        self.target.priority
+    }
+    // for resume_stream once the stream broke, e.g. with its session, it covers what was read
+    pub fn resume_token(&self) -> Option<ResumeToken> {
+        resume_token(&self.target, &self.state)
+    }
+    // a future apart from the stream, it may be awaited while the stream is used as usual
+    pub fn connect_result(&self) -> ConnectResult {
+        let (tx, rx) = oneshot::channel();
+        let mut waiters = self.state.connect_waiters.lock().unwrap();
+        if self.state.connected.load(Ordering::SeqCst) {
+            let _ = tx.send(Ok(()));
+        } else if let Some(reason) = self.state.close_reason() {
+            let _ = tx.send(Err(reason));
+        } else {
+            waiters.push(tx);
+        }
+        ConnectResult { rx }
+    }
+    // the peer won't report the connect, the stream counts as connected once opened
+    pub(crate) fn assume_connected(&self) {
+        self.state.connected.store(true, Ordering::SeqCst);
+    }
+    pub(crate) fn on_connected(&self) {
+        self.state.resolve_connect(Ok(()));
+    }
+    // the stream was opened by a peer that waits for FLAG_CONNECTED
+    pub(crate) fn set_report_connected(&self) {
+        self.state.report_connected.store(true, Ordering::SeqCst);
+    }
+    pub(crate) fn set_report_connect_errors(&self) {
+        self.state
+            .report_connect_errors
+            .store(true, Ordering::SeqCst);
+    }
+    // closes the stream whose target failed to connect, telling why if the peer knows the reasons
+    pub(crate) fn close_connect_failed(&mut self, e: &std::io::Error) -> std::io::Result<()> {
+        let reason = if self.state.report_connect_errors.load(Ordering::SeqCst) {
+            FinReason::from_connect_error(e)
+        } else {
+            FinReason::TargetClosed
+        };
+        self.close_with_reason(reason)
+    }
+    // sent before the relay starts, so ahead of any DATA
+    pub(crate) async fn report_connected(&mut self) {
+        if self.state.report_connected.load(Ordering::SeqCst) {
+            let _ = self
+                .event_tx
+                .send(new_connected_event(self.state.stream_id))
+                .await;
+        }
+    }
+    // The peer sent FIN, stop writing but keep data already received readable,
+    // the reader sees EOF right after the last of it.
+    pub(crate) fn close_by_peer(&mut self, reason: FinReason) {
+        self.state
+            .peer_fin_reason
+            .store(reason as u8, Ordering::SeqCst);
+        self.state.peer_closed.store(true, Ordering::SeqCst);
+        self.state.resolve_connect(Err(reason));
+        self.check_data_tx();
+        let mut io_state = self.io_state.lock().unwrap();
+        let sent = io_state.paused_queue.is_empty()
+            && match &mut self.data_tx {
+                Some(tx) => tx.try_send(Vec::new()).is_ok(),
+                None => false,
+            };
+        if !sent {
+            io_state.paused_queue.push_back(Vec::new());
+        }
+        if let Some(waker) = io_state.waker.take() {
+            waker.wake()
+        }
+        if let Some(waker) = io_state.read_waker.take() {
+            waker.wake()
+        }
+    }
+    // reason carried by the peer's FIN, None until it's received
+    pub fn peer_fin_reason(&self) -> Option<FinReason> {
+        self.state.peer_fin_reason()
+    }
+    // close and tell the peer why, close() sends Normal
+    pub fn close_with_reason(&mut self, reason: FinReason) -> std::io::Result<()> {
+        self.state
+            .local_fin_reason
+            .store(reason as u8, Ordering::SeqCst);
+        self.close()
+    }
+    // stop delivering data to the local side and reading from it, the peer would stop
+    // sending once the current window is used up
+    pub fn pause(&self) {
+        self.state.paused.store(true, Ordering::SeqCst);
+    }
+    pub fn resume(&self) {
+        self.state.paused.store(false, Ordering::SeqCst);
+        self.advertise_pending_window();
+        let mut io_state = self.io_state.lock().unwrap();
+        if let Some(waker) = io_state.read_waker.take() {
+            waker.wake()
+        }
+        if let Some(waker) = io_state.waker.take() {
+            waker.wake()
+        }
+    }
+    // Grants the credit of what was read while paused, the peer may have run out of window
+    // with nothing left for the reader to read and report it.
+    fn advertise_pending_window(&self) {
+        let mut window = std::cmp::min(
+            self.state.flow.next_advertised_window(),
+            MAX_WINDOW_UPDATE_CREDIT,
+        );
+        if over_buffer_budget() {
+            window = std::cmp::min(window, PRESSURE_WINDOW_CREDIT);
+        }
+        if window == 0 {
+            return;
+        }
+        let ev = new_window_update_event(self.state.stream_id, window, false);
+        if self.event_tx.clone().try_send(ev).is_ok() {
+            self.state.flow.on_window_advertised(window);
+            self.state
+                .last_advertised_window
+                .store(window, Ordering::SeqCst);
+        } else {
+            // left pending for the next read
+            self.state
+                .dropped_window_updates
+                .fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    pub fn is_paused(&self) -> bool {
+        self.state.paused.load(Ordering::SeqCst)
+    }
+    // since the stream last moved data either way
+    pub(crate) fn idle_secs(&self, now_unix_secs: u32) -> u32 {
+        let active = self.state.last_active_unix_secs.load(Ordering::SeqCst);
+        now_unix_secs.saturating_sub(active)
+    }
+    // returns true if no data moved for the interval and no probe is pending yet
+    pub(crate) fn should_probe(&self, interval_secs: u32, now_unix_secs: u32) -> bool {
+        let probe = self.state.keepalive_probe_unix_secs.load(Ordering::SeqCst);
+        let active = self.state.last_active_unix_secs.load(Ordering::SeqCst);
+        if probe > active {
+            return false;
+        }
+        if now_unix_secs.saturating_sub(active) < interval_secs {
+            return false;
+        }
+        self.state
+            .keepalive_probe_unix_secs
+            .store(now_unix_secs, Ordering::SeqCst);
+        true
+    }
+    pub(crate) fn probe_expired(&self, interval_secs: u32, now_unix_secs: u32) -> bool {
+        let probe = self.state.keepalive_probe_unix_secs.load(Ordering::SeqCst);
+        // any data moved since the probe answers it as well
+        let active = self.state.last_active_unix_secs.load(Ordering::SeqCst);
+        probe > active && now_unix_secs.saturating_sub(probe) >= interval_secs
+    }
+    pub(crate) fn lifetime_exceeded(&self, max: Duration) -> bool {
+        self.state.born_time.elapsed() >= max
+    }
+    pub(crate) fn on_keepalive_pong(&self) {
+        self.state
+            .keepalive_probe_unix_secs
+            .store(0, Ordering::SeqCst);
+        self.state.touch();
+    }
     pub fn clone(&self) -> Self {
         let mut v = Self {
             target: self.target.clone(),
@@ -274,6 +1048,7 @@ impl MuxStream {
             data_tx: None,
             state: self.state.clone(),
             io_state: self.io_state.clone(),
+            multipath: self.multipath.clone(),
         };
         if let Some(tx) = &self.data_tx {
             v.data_tx = Some(tx.clone());
@@ -294,15 +1069,24 @@ impl ChannelStream for MuxStream {
             rx: self.io_state.lock().unwrap().data_rx.take().unwrap(),
             recv_buf: BytesMut::new(),
             state: self.state.clone(),
+            io_state: self.io_state.clone(),
         };
         let w = MuxStreamWriter {
             tx: self.event_tx.clone(),
+            mp_writer: self.multipath.clone().map(MultipathWriter::new),
+            coalesce: match self.multipath {
+                Some(_) => None,
+                None => get_write_coalescing(self.state.channel.as_str()),
+            },
             state: self.state.clone(),
             io_state: self.io_state.clone(),
         };
         //error!("[{}]split.", self.state.stream_id);
         (Box::new(r), Box::new(w))
     }
+    fn connect_result(&self) -> Pin<Box<dyn Future<Output = Result<(), FinReason>> + Send>> {
+        Box::pin(MuxStream::connect_result(self))
+    }
     fn close(&mut self) -> std::io::Result<()> {
         //error!("[{}]####1 Close", self.state.stream_id);
         self.state.close();
@@ -314,11 +1098,366 @@ impl ChannelStream for MuxStream {
             let empty = Vec::new();
             let _ = tx.clone().try_send(empty);
         }
-        if let Some(waker) = self.io_state.lock().unwrap().waker.take() {
-            waker.wake()
+        {
+            let mut io_state = self.io_state.lock().unwrap();
+            if let Some(waker) = io_state.waker.take() {
+                waker.wake()
+            }
+            if let Some(waker) = io_state.read_waker.take() {
+                waker.wake()
+            }
+        }
+        let reason = FinReason::from_u8(self.state.local_fin_reason.load(Ordering::SeqCst));
+        self.state
+            .resolve_connect(Err(self.state.close_reason().unwrap_or(reason)));
+        let fin = match &self.multipath {
+            Some(mp) => {
+                multipath::remove(mp);
+                new_mp_fin_event(self.state.stream_id, mp.sent_frames(), reason)
+            }
+            None => new_fin_event_with_reason(self.state.stream_id, reason),
+        };
+        // the coalesced tail goes right before the FIN
+        let mut tail = Vec::new();
+        {
+            let mut io_state = self.io_state.lock().unwrap();
+            if !io_state.coalesced.is_empty() {
+                let data = std::mem::replace(&mut io_state.coalesced, Vec::new());
+                tail.push(self.state.new_data_event(&data[..]));
+            }
+        }
+        tail.push(fin);
+        // never drop the FIN, it's queued behind the DATA events already sent
+        let mut tail = tail.into_iter();
+        while let Some(ev) = tail.next() {
+            if let Err(TrySendError::Full(ev)) = self.event_tx.try_send(ev) {
+                let mut tx = self.event_tx.clone();
+                let rest: Vec<Event> = std::iter::once(ev).chain(tail).collect();
+                tokio::spawn(async move {
+                    for ev in rest {
+                        if tx.send(ev).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+                break;
+            }
+        }
+        if !self.state.close_notified.swap(true, Ordering::SeqCst) {
+            notify_stream_event(StreamEvent::Close {
+                channel: self.state.channel.clone(),
+                session_id: self.state.session_id,
+                stream_id: self.state.stream_id,
+                send_bytes: self.state.total_send_bytes.load(Ordering::SeqCst),
+                recv_bytes: self.state.total_recv_bytes.load(Ordering::SeqCst),
+                duration: self.state.born_time.elapsed(),
+                reason: self.state.close_reason().unwrap_or(reason),
+            });
         }
-        let fin = new_fin_event(self.state.stream_id, false);
-        let _ = self.event_tx.try_send(fin);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::event::{get_fin_reason, FLAG_DATA, FLAG_FIN, FLAG_WIN_UPDATE};
+    use super::*;
+    use crate::utils::buf_copy;
+    use futures::task::noop_waker_ref;
+
+    #[test]
+    fn test_send_stall_at_window() {
+        let cap: u32 = 16 * 1024;
+        let (evtx, mut evrx) = mpsc::channel(1024);
+        let mut stream = MuxStream::new("", 0, 1, evtx, ConnectRequest::default(), cap);
+        // peer advertised the same cap in its SYN
+        stream.set_send_window(cap);
+        let peer = stream.clone();
+        let chunk = [0u8; 1024];
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut sent = 0;
+        {
+            let (_, mut w) = stream.split();
+            while let Poll::Ready(r) = Pin::new(&mut w).poll_write(&mut cx, &chunk[..]) {
+                sent += r.unwrap();
+                assert!(sent <= cap as usize);
+            }
+            assert_eq!(sent, cap as usize);
+
+            std::thread::sleep(Duration::from_millis(5));
+            peer.update_send_window(4096);
+            let st = peer.stats();
+            assert_eq!(st.window_updates, 1);
+            assert!(st.max_window_stall >= Duration::from_millis(5));
+            assert_eq!(st.window_stall_time, st.max_window_stall);
+            while let Poll::Ready(r) = Pin::new(&mut w).poll_write(&mut cx, &chunk[..]) {
+                sent += r.unwrap();
+            }
+            assert_eq!(sent, cap as usize + 4096);
+        }
+        let mut data_events = 0;
+        while let Ok(ev) = evrx.try_recv() {
+            assert_eq!(ev.header.flags(), FLAG_DATA);
+            data_events += 1;
+        }
+        assert_eq!(data_events, (cap as usize + 4096) / chunk.len());
+    }
+
+    #[test]
+    fn test_fin_after_all_data() {
+        let n: usize = 16 * 1000;
+        let (evtx, mut evrx) = mpsc::channel(1024);
+        let mut sender = MuxStream::new(
+            "",
+            0,
+            1,
+            evtx,
+            ConnectRequest::default(),
+            DEFAULT_STREAM_WINDOW,
+        );
+        let mut cx = Context::from_waker(noop_waker_ref());
+        {
+            let (_, mut w) = sender.split();
+            for chunk in vec![7u8; n].chunks(1000) {
+                match Pin::new(&mut w).poll_write(&mut cx, chunk) {
+                    Poll::Ready(Ok(len)) => assert_eq!(len, chunk.len()),
+                    _ => panic!("write should not stall within the window"),
+                }
+            }
+        }
+        let _ = sender.close();
+
+        let (peer_evtx, _peer_evrx) = mpsc::channel(1024);
+        let mut receiver = MuxStream::new(
+            "",
+            0,
+            1,
+            peer_evtx,
+            ConnectRequest::default(),
+            DEFAULT_STREAM_WINDOW,
+        );
+        let mut peer = receiver.clone();
+        let (mut r, _) = receiver.split();
+        let mut fin_seen = false;
+        while let Ok(ev) = evrx.try_recv() {
+            assert!(!fin_seen, "event after FIN");
+            match ev.header.flags() {
+                FLAG_DATA => futures::executor::block_on(peer.offer_data(ev.body)),
+                FLAG_FIN => {
+                    peer.close_by_peer(get_fin_reason(&ev.body[..]));
+                    fin_seen = true;
+                }
+                f => panic!("unexpected event flags {}", f),
+            }
+        }
+        assert!(fin_seen);
+
+        let mut received = 0;
+        let mut buf = [0u8; 4096];
+        loop {
+            match Pin::new(&mut r).poll_read(&mut cx, &mut buf[..]) {
+                Poll::Ready(Ok(0)) => break,
+                Poll::Ready(Ok(len)) => received += len,
+                other => panic!("unexpected read result {:?}", other),
+            }
+        }
+        assert_eq!(received, n);
+    }
+
+    #[test]
+    fn test_seq_gap_detected() {
+        let (evtx, _evrx) = mpsc::channel(16);
+        let stream = MuxStream::new(
+            "",
+            0,
+            1,
+            evtx,
+            ConnectRequest::default(),
+            DEFAULT_STREAM_WINDOW,
+        );
+        assert_eq!(stream.check_recv_seq(0), Ok(()));
+        assert_eq!(stream.check_recv_seq(1), Ok(()));
+        // event 3 arrives before event 2
+        assert_eq!(stream.check_recv_seq(3), Err(2));
+    }
+
+    #[test]
+    fn test_fin_reason() {
+        let (evtx, mut evrx) = mpsc::channel(16);
+        let req = ConnectRequest::default();
+        let mut sender = MuxStream::new("", 0, 1, evtx.clone(), req.clone(), 1024);
+        let _ = sender.close();
+        let fin = evrx.try_recv().unwrap();
+        assert!(fin.body.is_empty());
+        assert_eq!(get_fin_reason(&fin.body[..]), FinReason::Normal);
+
+        let mut sender = MuxStream::new("", 0, 2, evtx, req.clone(), 1024);
+        let _ = sender.close_with_reason(FinReason::Reset);
+        assert_eq!(sender.stats().close_reason, Some(FinReason::Reset));
+        let fin = evrx.try_recv().unwrap();
+        assert_eq!(fin.header.flags(), FLAG_FIN);
+
+        let (peer_evtx, _peer_evrx) = mpsc::channel(16);
+        let mut receiver = MuxStream::new("", 0, 2, peer_evtx, req, 1024);
+        assert_eq!(receiver.stats().close_reason, None);
+        let mut peer = receiver.clone();
+        let (mut r, _) = receiver.split();
+        peer.close_by_peer(get_fin_reason(&fin.body[..]));
+        assert_eq!(peer.peer_fin_reason(), Some(FinReason::Reset));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut buf = [0u8; 16];
+        match Pin::new(&mut r).poll_read(&mut cx, &mut buf[..]) {
+            Poll::Ready(Err(e)) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+            other => panic!("unexpected read result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_resume_grants_pending_window() {
+        let (evtx, mut evrx) = mpsc::channel(16);
+        let stream = MuxStream::new("", 0, 1, evtx, ConnectRequest::default(), 64 * 1024);
+        stream.pause();
+        // read right as the pause took hold, nothing is left to read and report its credit
+        stream.state.flow.on_data_received(48 * 1024);
+        assert!(evrx.try_recv().is_err());
+        stream.resume();
+        let ev = evrx.try_recv().unwrap();
+        assert_eq!(ev.header.flags(), FLAG_WIN_UPDATE);
+        assert_eq!(ev.header.len(), 48 * 1024);
+        assert_eq!(stream.state.flow.next_advertised_window(), 0);
+        // nothing pending, nothing sent
+        stream.pause();
+        stream.resume();
+        assert!(evrx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_keepalive_probe() {
+        let (evtx, _evrx) = mpsc::channel(16);
+        let stream = MuxStream::new(
+            "",
+            0,
+            1,
+            evtx,
+            ConnectRequest::default(),
+            DEFAULT_STREAM_WINDOW,
+        );
+        let now = SystemClock.now_unix_secs();
+        assert!(!stream.should_probe(30, now));
+        assert!(stream.should_probe(30, now + 30));
+        // only one probe in flight
+        assert!(!stream.should_probe(30, now + 40));
+        assert!(!stream.probe_expired(30, now + 40));
+        assert!(stream.probe_expired(30, now + 60));
+
+        stream.on_keepalive_pong();
+        assert!(!stream.probe_expired(30, now + 60));
+    }
+
+    struct WakeCounter(AtomicU32);
+
+    impl futures::task::ArcWake for WakeCounter {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_credit_unblocks_exact_bytes() {
+        let (evtx, mut evrx) = mpsc::channel(1024);
+        let mut stream = MuxStream::new("", 0, 1, evtx, ConnectRequest::default(), 4096);
+        stream.set_send_window(0);
+        let peer = stream.clone();
+        let counter = Arc::new(WakeCounter(AtomicU32::new(0)));
+        let waker = futures::task::waker(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        let chunk = [0u8; 1024];
+        let (_, mut w) = stream.split();
+        assert!(Pin::new(&mut w)
+            .poll_write(&mut cx, &chunk[..])
+            .is_pending());
+        assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+
+        peer.update_send_window(1500);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        let mut sent = 0;
+        while let Poll::Ready(r) = Pin::new(&mut w).poll_write(&mut cx, &chunk[..]) {
+            sent += r.unwrap();
+        }
+        assert_eq!(sent, 1500);
+        assert_eq!(peer.stats().send_window, 0);
+        let mut received = 0;
+        while let Ok(ev) = evrx.try_recv() {
+            received += ev.body.len();
+        }
+        assert_eq!(received, 1500);
+    }
+
+    #[test]
+    fn test_target_read_bounded_by_window() {
+        let cap: u32 = 4096;
+        let (evtx, _evrx) = mpsc::channel(1024);
+        let mut stream = MuxStream::new("", 0, 2, evtx, ConnectRequest::default(), cap);
+        stream.set_send_window(cap);
+        let peer = stream.clone();
+        let gate = stream.send_window_gate();
+        let data = vec![7u8; 1024 * 1024];
+        let mut target = &data[..];
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let (_, mut w) = stream.split();
+        {
+            let mut gated = gate.gate(&mut target);
+            let copy = buf_copy(&mut gated, &mut w, Box::new([0; 8192]));
+            pin_mut!(copy);
+            assert!(copy.as_mut().poll(&mut cx).is_pending());
+            // a fast target is read no further than the peer's window
+            assert_eq!(peer.stats().send_window, 0);
+            assert_eq!(peer.stats().send_bytes, cap);
+            peer.update_send_window(1500);
+            assert!(copy.as_mut().poll(&mut cx).is_pending());
+        }
+        assert_eq!(data.len() - target.len(), cap as usize + 1500);
+    }
+
+    #[test]
+    fn test_window_updates_close_together() {
+        let cap: u32 = 4096;
+        let (evtx, _evrx) = mpsc::channel(1024);
+        let mut stream = MuxStream::new("", 0, 1, evtx, ConnectRequest::default(), cap);
+        stream.set_send_window(cap);
+        let peer = stream.clone();
+        let counter = Arc::new(WakeCounter(AtomicU32::new(0)));
+        let waker = futures::task::waker(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        let chunk = [0u8; 1024];
+        let (_, mut w) = stream.split();
+        while let Poll::Ready(r) = Pin::new(&mut w).poll_write(&mut cx, &chunk[..]) {
+            r.unwrap();
+        }
+        assert_eq!(peer.stats().send_window, 0);
+
+        // credits accumulate, the second never overwrites the first
+        peer.update_send_window(1000);
+        let first = peer.stats().send_window;
+        peer.update_send_window(2000);
+        let second = peer.stats().send_window;
+        assert_eq!(first, 1000);
+        assert_eq!(second, 3000);
+        assert!(counter.0.load(Ordering::SeqCst) >= 1);
+
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let state = peer.state.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        state.flow.on_window_update(1);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(peer.stats().send_window, 5000);
+    }
+}
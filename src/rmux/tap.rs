@@ -0,0 +1,204 @@
+use super::event::{Event, Header};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, warn};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDirection {
+    Inbound,
+    Outbound,
+}
+
+// A frame of a session in plaintext, as read after its decryption or before its encryption.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TappedFrame {
+    pub direction: FrameDirection,
+    pub unix_micros: u64,
+    pub channel: String,
+    pub tunnel_id: u32,
+    pub stream_id: u32,
+    pub flags: u8,
+    // the len field of the header, the body's len or e.g. the credit of a window update
+    pub len: u32,
+    pub body: Vec<u8>,
+}
+
+impl TappedFrame {
+    // the event as the session had it, e.g. to replay a recording into an event loop
+    pub fn to_event(&self) -> Event {
+        Event {
+            header: Header {
+                flag_len: (self.len << 8) | u32::from(self.flags),
+                stream_id: self.stream_id,
+            },
+            body: self.body.clone(),
+            remote: self.direction == FrameDirection::Inbound,
+        }
+    }
+}
+
+// called on the read and write paths of every session, it should hand the frame off quickly
+pub type FrameTap = Arc<dyn Fn(&TappedFrame) + Send + Sync>;
+
+// frames queued for the writer of a file tap, more are dropped while the disk lags
+const FILE_TAP_QUEUE_LEN: usize = 4096;
+const FILE_TAP_DROP_WARN_THRESHOLD: u64 = 1000;
+
+// sessions check it alone while no tap is set
+static FRAME_TAP_SET: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref FRAME_TAP: RwLock<Option<FrameTap>> = RwLock::new(None);
+}
+
+pub fn set_frame_tap(tap: Option<FrameTap>) {
+    let mut current = FRAME_TAP.write().unwrap();
+    FRAME_TAP_SET.store(tap.is_some(), Ordering::SeqCst);
+    *current = tap;
+}
+
+pub(crate) fn tap_frame(direction: FrameDirection, channel: &str, tunnel_id: u32, ev: &Event) {
+    if !FRAME_TAP_SET.load(Ordering::Relaxed) {
+        return;
+    }
+    let tap = match FRAME_TAP.read().unwrap().clone() {
+        Some(tap) => tap,
+        None => return,
+    };
+    tap(&TappedFrame {
+        direction,
+        unix_micros: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64,
+        channel: String::from(channel),
+        tunnel_id,
+        stream_id: ev.header.stream_id,
+        flags: ev.header.flags(),
+        len: ev.header.len(),
+        body: ev.body.clone(),
+    });
+}
+
+// the frame's bincode encoding prefixed by its len(u32 little endian)
+pub fn write_tapped_frame<W: Write>(w: &mut W, frame: &TappedFrame) -> io::Result<()> {
+    let data = bincode::serialize(frame).unwrap();
+    w.write_all(&(data.len() as u32).to_le_bytes())?;
+    w.write_all(&data[..])
+}
+
+// None at the end of the recording
+pub fn read_tapped_frame<R: Read>(r: &mut R) -> io::Result<Option<TappedFrame>> {
+    let mut len = [0u8; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut data = vec![0u8; u32::from_le_bytes(len) as usize];
+    r.read_exact(&mut data[..])?;
+    bincode::deserialize(&data[..])
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Records the frames to a new file at path, see write_tapped_frame. A thread of its own
+// writes them so sessions never wait for the disk, frames it's too far behind for are
+// dropped and counted. It stops once the tap is dropped.
+pub fn file_frame_tap(path: &str) -> io::Result<FrameTap> {
+    let file = File::create(path)?;
+    let (tx, rx) = mpsc::sync_channel::<TappedFrame>(FILE_TAP_QUEUE_LEN);
+    let path = String::from(path);
+    let tap_path = path.clone();
+    std::thread::spawn(move || {
+        let mut w = BufWriter::new(file);
+        while let Ok(frame) = rx.recv() {
+            let mut next = Some(frame);
+            while let Some(frame) = next {
+                if let Err(e) = write_tapped_frame(&mut w, &frame) {
+                    error!(path = path.as_str(), "frame tap stopped: {}", e);
+                    return;
+                }
+                next = rx.try_recv().ok();
+            }
+            // caught up, what's recorded so far survives a crash
+            if let Err(e) = w.flush() {
+                error!(path = path.as_str(), "frame tap stopped: {}", e);
+                return;
+            }
+        }
+    });
+    let dropped = AtomicU64::new(0);
+    Ok(Arc::new(move |frame: &TappedFrame| {
+        if let Err(TrySendError::Full(_)) = tx.try_send(frame.clone()) {
+            let n = dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if n % FILE_TAP_DROP_WARN_THRESHOLD == 1 {
+                warn!(
+                    path = tap_path.as_str(),
+                    "frame tap behind, {} frames dropped", n
+                );
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::event::{new_data_event, new_window_update_event};
+    use super::*;
+
+    #[test]
+    fn test_file_frame_tap() {
+        let path = std::env::temp_dir().join(format!("rmux_tap_{}", rand::random::<u64>()));
+        let path = path.to_str().unwrap();
+        let tap = file_frame_tap(path).unwrap();
+        let frames: Vec<TappedFrame> = vec![
+            new_data_event(3, b"hello", true),
+            new_window_update_event(3, 64 * 1024, false),
+        ]
+        .into_iter()
+        .map(|ev| TappedFrame {
+            direction: if ev.remote {
+                FrameDirection::Inbound
+            } else {
+                FrameDirection::Outbound
+            },
+            unix_micros: 1,
+            channel: String::from("test_file_frame_tap"),
+            tunnel_id: 7,
+            stream_id: ev.header.stream_id,
+            flags: ev.header.flags(),
+            len: ev.header.len(),
+            body: ev.body,
+        })
+        .collect();
+        for frame in frames.iter() {
+            tap(frame);
+        }
+        // the writer thread flushes once it caught up
+        let mut read = Vec::new();
+        for _ in 0..100 {
+            let mut file = File::open(path).unwrap();
+            read.clear();
+            // a frame being written reads as truncated
+            while let Ok(Some(frame)) = read_tapped_frame(&mut file) {
+                read.push(frame);
+            }
+            if read.len() == frames.len() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(read, frames);
+        let ev = read[1].to_event();
+        assert_eq!(ev.header.len(), 64 * 1024);
+        assert!(!ev.remote);
+        drop(tap);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,110 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+static RELAY_TASKS: AtomicUsize = AtomicUsize::new(0);
+static MAX_RELAY_TASKS: AtomicUsize = AtomicUsize::new(0);
+static ESTABLISHING_SESSIONS: AtomicUsize = AtomicUsize::new(0);
+static MAX_ESTABLISHING_SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref ESTABLISH_LIMITER: RwLock<Option<Arc<Semaphore>>> = RwLock::new(None);
+}
+
+// Bound the tasks spawned for streams opened by peers, over all channels. They live from the
+// SYN to the end of the relay, past it SYNs are refused with a FIN. 0 for no limit.
+pub fn set_max_relay_tasks(n: usize) {
+    MAX_RELAY_TASKS.store(n, Ordering::SeqCst);
+}
+
+pub fn max_relay_tasks() -> usize {
+    MAX_RELAY_TASKS.load(Ordering::SeqCst)
+}
+
+// relay tasks currently running
+pub fn relay_task_count() -> usize {
+    RELAY_TASKS.load(Ordering::SeqCst)
+}
+
+// Counts one relay task until it's dropped.
+pub(crate) struct RelayTaskGuard(());
+
+impl Drop for RelayTaskGuard {
+    fn drop(&mut self) {
+        RELAY_TASKS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// None if the limit is reached
+pub(crate) fn start_relay_task() -> Option<RelayTaskGuard> {
+    let max = max_relay_tasks();
+    let prev = RELAY_TASKS.fetch_add(1, Ordering::SeqCst);
+    if max > 0 && prev >= max {
+        RELAY_TASKS.fetch_sub(1, Ordering::SeqCst);
+        return None;
+    }
+    Some(RelayTaskGuard(()))
+}
+
+// Bound the sessions in their setup at once, from the auth handshake until their event loop
+// runs, over all channels and both ends. Further ones wait their turn, e.g. when every
+// client reconnects after a network blip. 0 for no limit, sessions already waiting keep
+// the limit they started with.
+pub fn set_max_establishing_sessions(n: usize) {
+    MAX_ESTABLISHING_SESSIONS.store(n, Ordering::SeqCst);
+    *ESTABLISH_LIMITER.write().unwrap() = if n == 0 {
+        None
+    } else {
+        Some(Arc::new(Semaphore::new(n)))
+    };
+}
+
+pub fn max_establishing_sessions() -> usize {
+    MAX_ESTABLISHING_SESSIONS.load(Ordering::SeqCst)
+}
+
+// sessions past the wait and not yet in their event loop
+pub fn establishing_session_count() -> usize {
+    ESTABLISHING_SESSIONS.load(Ordering::SeqCst)
+}
+
+// Lets one session go on with its setup until it's dropped.
+pub struct EstablishPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for EstablishPermit {
+    fn drop(&mut self) {
+        ESTABLISHING_SESSIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) async fn start_establishing() -> EstablishPermit {
+    let limiter = ESTABLISH_LIMITER.read().unwrap().clone();
+    let permit = match limiter {
+        Some(sem) => Some(sem.acquire_owned().await),
+        None => None,
+    };
+    ESTABLISHING_SESSIONS.fetch_add(1, Ordering::SeqCst);
+    EstablishPermit { _permit: permit }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn test_establish_limit() {
+        set_max_establishing_sessions(1);
+        let first = start_establishing().await;
+        assert!(start_establishing().now_or_never().is_none());
+        let waiting = tokio::spawn(start_establishing());
+        drop(first);
+        let second = waiting.await.unwrap();
+        assert!(start_establishing().now_or_never().is_none());
+        drop(second);
+        set_max_establishing_sessions(0);
+        assert!(start_establishing().now_or_never().is_some());
+    }
+}
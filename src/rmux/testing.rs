@@ -0,0 +1,1634 @@
+use super::clock::Clock;
+use super::crypto::CryptoContext;
+use super::message::Hello;
+use super::session::{
+    get_channel_session_size, next_tunnel_id, process_rmux_session, MuxContext, WriteBatching,
+    DEFAULT_WRITE_BATCH_FRAMES,
+};
+use super::stream::DEFAULT_STREAM_WINDOW;
+#[cfg(feature = "quic")]
+use crate::utils::{make_io_error, quic_connect, quic_listen, QuicConnection};
+use bytes::BytesMut;
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::JoinHandle;
+
+pub(crate) const TEST_METHOD: &str = "chacha20poly1305";
+pub(crate) const TEST_KEY: &str = "21321321321321312321321321212asdfasdasdas1";
+
+#[derive(Default)]
+struct PipeState {
+    buf: VecDeque<u8>,
+    closed: bool,
+    held: bool,
+    read_waker: Option<Waker>,
+}
+
+impl PipeState {
+    fn close(&mut self) {
+        self.closed = true;
+        // a held pipe loses what it holds, like a connection dying with data in flight
+        if self.held {
+            self.buf.clear();
+        }
+        if let Some(w) = self.read_waker.take() {
+            w.wake();
+        }
+    }
+}
+
+// One direction of an in-memory transport, unbounded since tests move little data.
+pub(crate) struct PipeReader(Arc<Mutex<PipeState>>);
+pub(crate) struct PipeWriter(Arc<Mutex<PipeState>>);
+
+// closes the pipe from outside, the reader sees EOF once the buffered bytes are read
+#[derive(Clone)]
+pub(crate) struct PipeCloser(Arc<Mutex<PipeState>>);
+
+impl PipeCloser {
+    pub(crate) fn close(&self) {
+        self.0.lock().unwrap().close();
+    }
+    // while held the reader gets nothing of what is written, like a stalled path
+    pub(crate) fn hold(&self, held: bool) {
+        let mut state = self.0.lock().unwrap();
+        state.held = held;
+        if !held {
+            if let Some(w) = state.read_waker.take() {
+                w.wake();
+            }
+        }
+    }
+}
+
+pub(crate) fn pipe() -> (PipeReader, PipeWriter, PipeCloser) {
+    let state = Arc::new(Mutex::new(PipeState::default()));
+    (
+        PipeReader(state.clone()),
+        PipeWriter(state.clone()),
+        PipeCloser(state),
+    )
+}
+
+impl AsyncRead for PipeReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.0.lock().unwrap();
+        if state.buf.is_empty() || (state.held && !state.closed) {
+            if state.closed {
+                return Poll::Ready(Ok(0));
+            }
+            state.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = std::cmp::min(buf.len(), state.buf.len());
+        for (dst, src) in buf.iter_mut().zip(state.buf.drain(..n)) {
+            *dst = src;
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for PipeWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.0.lock().unwrap();
+        if state.closed {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+        }
+        state.buf.extend(buf);
+        if let Some(w) = state.read_waker.take() {
+            w.wake();
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.lock().unwrap().close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().close();
+    }
+}
+
+// MuxContext settings of one side of a SessionPair
+#[derive(Clone)]
+pub(crate) struct SessionOptions {
+    pub(crate) recv_window: u32,
+    pub(crate) write_batching: WriteBatching,
+    pub(crate) max_alive_secs: u64,
+    pub(crate) clock: Option<Arc<dyn Clock>>,
+    pub(crate) stream_keepalive_secs: u32,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            recv_window: DEFAULT_STREAM_WINDOW,
+            write_batching: WriteBatching::Frames(DEFAULT_WRITE_BATCH_FRAMES),
+            max_alive_secs: 0,
+            clock: None,
+            stream_keepalive_secs: 0,
+        }
+    }
+}
+
+async fn run_session<R, W>(
+    channel: String,
+    mut reader: R,
+    mut writer: W,
+    nonce: u64,
+    opts: SessionOptions,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut recv_buf = BytesMut::new();
+    let rctx = CryptoContext::new(TEST_METHOD, TEST_KEY, nonce);
+    let wctx = CryptoContext::new(TEST_METHOD, TEST_KEY, nonce);
+    let mut ctx = MuxContext::new(
+        channel.as_str(),
+        next_tunnel_id(),
+        rctx,
+        wctx,
+        opts.max_alive_secs,
+        &mut recv_buf,
+    );
+    ctx.set_stream_recv_window(opts.recv_window);
+    ctx.set_write_batching(opts.write_batching);
+    ctx.set_stream_keepalive_secs(opts.stream_keepalive_secs);
+    if let Some(clock) = opts.clock {
+        ctx.set_clock(clock);
+    }
+    ctx.set_peer_hello(&Hello::local());
+    process_rmux_session(ctx, &mut reader, &mut writer).await
+}
+
+fn spawn_session(
+    channel: &str,
+    reader: PipeReader,
+    writer: PipeWriter,
+    nonce: u64,
+    opts: SessionOptions,
+) -> JoinHandle<io::Result<()>> {
+    tokio::spawn(run_session(
+        String::from(channel),
+        reader,
+        writer,
+        nonce,
+        opts,
+    ))
+}
+
+// what carries the sessions of a SessionPair
+enum Transport {
+    Pipes([PipeCloser; 2]),
+    // the client connection and the server endpoint
+    #[cfg(feature = "quic")]
+    Quic(QuicConnection, quinn::Endpoint),
+}
+
+// A client session of the channel wired to a server session, both already authed,
+// so streams created on the channel are dialed by the server side.
+pub(crate) struct SessionPair {
+    client: JoinHandle<io::Result<()>>,
+    server: JoinHandle<io::Result<()>>,
+    transport: Transport,
+}
+
+async fn wait_channel_session(channel: &str) {
+    for _ in 0..100 {
+        if get_channel_session_size(channel) > 0 {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+}
+
+impl SessionPair {
+    pub(crate) async fn start(channel: &str) -> Self {
+        Self::start_with(
+            channel,
+            SessionOptions::default(),
+            SessionOptions::default(),
+        )
+        .await
+    }
+
+    pub(crate) async fn start_with(
+        channel: &str,
+        client: SessionOptions,
+        server: SessionOptions,
+    ) -> Self {
+        let (client_r, server_w, c1) = pipe();
+        let (server_r, client_w, c2) = pipe();
+        let nonce = rand::random::<u64>();
+        let server = spawn_session("", server_r, server_w, nonce, server);
+        let client = spawn_session(channel, client_r, client_w, nonce, client);
+        wait_channel_session(channel).await;
+        Self {
+            client,
+            server,
+            transport: Transport::Pipes([c1, c2]),
+        }
+    }
+
+    // The sessions run over a bidirectional stream of a loopback QUIC connection, set up
+    // with the certs in testdata.
+    #[cfg(feature = "quic")]
+    pub(crate) async fn start_quic(channel: &str) -> Self {
+        use futures::StreamExt;
+        let testdata = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata");
+        let (endpoint, mut incoming) = quic_listen(
+            "127.0.0.1:0",
+            format!("{}/cert.pem", testdata).as_str(),
+            format!("{}/key.pem", testdata).as_str(),
+        )
+        .await
+        .unwrap();
+        let addr = endpoint.local_addr().unwrap().to_string();
+        let nonce = rand::random::<u64>();
+        // the stream reaches the server with the client's first frame
+        let server = tokio::spawn(async move {
+            let connecting = match incoming.next().await {
+                Some(c) => c,
+                None => return Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+            };
+            let mut conn = connecting
+                .await
+                .map_err(|e| make_io_error(&e.to_string()))?;
+            let (send, recv) = match conn.bi_streams.next().await {
+                Some(s) => s.map_err(|e| make_io_error(&e.to_string()))?,
+                None => return Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+            };
+            run_session(String::new(), recv, send, nonce, SessionOptions::default()).await
+        });
+        let ca = format!("{}/ca.pem", testdata);
+        let (conn, send, recv) = quic_connect(addr.as_str(), "localhost", Some(ca.as_str()))
+            .await
+            .unwrap();
+        let client = tokio::spawn(run_session(
+            String::from(channel),
+            recv,
+            send,
+            nonce,
+            SessionOptions::default(),
+        ));
+        wait_channel_session(channel).await;
+        Self {
+            client,
+            server,
+            transport: Transport::Quic(conn, endpoint),
+        }
+    }
+
+    // holds back what the client session writes until it's released
+    pub(crate) fn hold_uplink(&self, held: bool) {
+        match &self.transport {
+            Transport::Pipes(closers) => closers[1].hold(held),
+            #[cfg(feature = "quic")]
+            Transport::Quic(..) => panic!("can't hold a quic transport"),
+        }
+    }
+
+    // close the transport and wait for both sessions to finish
+    pub(crate) async fn shutdown(self) {
+        let eof = match &self.transport {
+            Transport::Pipes(closers) => {
+                for c in closers.iter() {
+                    c.close();
+                }
+                true
+            }
+            // the sessions' reads fail on a closed connection instead of seeing EOF
+            #[cfg(feature = "quic")]
+            Transport::Quic(conn, _) => {
+                conn.close();
+                false
+            }
+        };
+        let wait = Duration::from_secs(5);
+        let client = tokio::time::timeout(wait, self.client)
+            .await
+            .expect("client session didn't finish")
+            .unwrap();
+        let server = tokio::time::timeout(wait, self.server)
+            .await
+            .expect("server session didn't finish")
+            .unwrap();
+        if eof {
+            client.unwrap();
+            server.unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::clock::MockClock;
+    use super::super::crypto::{read_encrypt_event, write_encrypt_event};
+    use super::super::error::RmuxError;
+    use super::super::event::{
+        new_seq_data_event, new_syn_event, Event, FLAG_DATA, FLAG_FIN, FLAG_SYN,
+    };
+    use super::super::flow::set_channel_initial_send_credit;
+    use super::super::group::define_channel_group;
+    use super::super::hooks::{
+        set_relay_progress, set_service_resolver, set_stream_auth_callback, set_throughput_sampler,
+        FinReason, RelayProgress, RelayProgressOptions, StreamEvent,
+    };
+    use super::super::loops::set_channel_self_addrs;
+    use super::super::message::{ConnectRequest, UdpDatagram, LOCAL_CAPABILITIES};
+    use super::super::multipath::{set_channel_multipath, MP_GAP_TIMEOUT};
+    use super::super::probe::{probe, probe_with_payload, ProbeError};
+    use super::super::session::{
+        channel_is_healthy, channel_stats, close_stream, create_resumable_stream, create_stream,
+        create_stream_with_data, evacuate_session, list_streams, resume_stream,
+        routine_all_sessions, session_params, set_channel_max_sessions, set_lock_hold_tracking,
+        shutdown_channel,
+    };
+    use super::super::stats::ThroughputSample;
+    use super::super::stream::{set_channel_max_stream_lifetime, set_channel_write_coalescing};
+    use super::super::tap::{set_frame_tap, FrameDirection, TappedFrame};
+    use super::super::udp::{read_udp_datagram, write_udp_datagram};
+    use super::super::upstream::{set_channel_dialer, DialFuture};
+    use super::*;
+    use crate::channel::{get_channel_stream, ChannelStream};
+    use crate::tunnel::relay;
+    use futures::future::{select, Either};
+    use std::collections::BTreeMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    async fn start_echo_server() -> String {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = conn.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn check_stream_round_trip(channel: &str, pair: SessionPair) {
+        let echo_addr = start_echo_server().await;
+        assert_eq!(get_channel_session_size(channel), 1);
+
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        {
+            let (mut r, mut w) = stream.split();
+            let data = vec![7u8; 200 * 1024];
+            let mut echo = vec![0u8; data.len()];
+            let (written, read) = futures::join!(w.write_all(&data[..]), r.read_exact(&mut echo));
+            written.unwrap();
+            read.unwrap();
+            assert_eq!(echo, data);
+        }
+        let _ = stream.close();
+
+        pair.shutdown().await;
+        assert_eq!(get_channel_session_size(channel), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stream_round_trip() {
+        let channel = "test_stream_round_trip";
+        let pair = SessionPair::start(channel).await;
+        check_stream_round_trip(channel, pair).await;
+    }
+
+    #[cfg(feature = "quic")]
+    #[tokio::test]
+    async fn test_quic_stream_round_trip() {
+        let channel = "test_quic_stream_round_trip";
+        let pair = SessionPair::start_quic(channel).await;
+        check_stream_round_trip(channel, pair).await;
+    }
+
+    #[tokio::test]
+    async fn test_syn_over_several_frames() {
+        let channel = "test_syn_over_several_frames";
+        let echo_addr = start_echo_server().await;
+        let pair = SessionPair::start(channel).await;
+        let data: Vec<u8> = (0..100 * 1024).map(|i| i as u8).collect();
+        let mut stream = create_stream_with_data(channel, "tcp", echo_addr.as_str(), data.clone())
+            .await
+            .unwrap();
+        let mut echo = vec![0u8; data.len()];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(echo, data);
+        let _ = stream.close();
+
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_oversized_window() {
+        let channel = "test_oversized_window";
+        let echo_addr = start_echo_server().await;
+        // a window past i32::MAX went negative as the server's send window, it's clamped now
+        let client = SessionOptions {
+            recv_window: u32::max_value(),
+            ..Default::default()
+        };
+        let pair = SessionPair::start_with(channel, client, SessionOptions::default()).await;
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        {
+            let (mut r, mut w) = stream.split();
+            let data = vec![5u8; 512 * 1024];
+            let mut echo = vec![0u8; data.len()];
+            let (written, read) = futures::join!(w.write_all(&data[..]), r.read_exact(&mut echo));
+            written.unwrap();
+            read.unwrap();
+            assert_eq!(echo, data);
+        }
+        let _ = stream.close();
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_asymmetric_windows() {
+        let channel = "test_asymmetric_windows";
+        let echo_addr = start_echo_server().await;
+        let client = SessionOptions {
+            recv_window: 16 * 1024,
+            ..Default::default()
+        };
+        let server = SessionOptions {
+            recv_window: 1024 * 1024,
+            ..Default::default()
+        };
+        let server_window = server.recv_window;
+        let pair = SessionPair::start_with(channel, client, server).await;
+
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        // the server's grant raises the client's send window to the server's recv window
+        for _ in 0..100 {
+            if stream.stats().send_window == server_window as i32 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(stream.stats().send_window, server_window as i32);
+        {
+            // the echo back is paced by the client's small window
+            let (mut r, mut w) = stream.split();
+            let data = vec![9u8; 512 * 1024];
+            let mut echo = vec![0u8; data.len()];
+            let (written, read) = futures::join!(w.write_all(&data[..]), r.read_exact(&mut echo));
+            written.unwrap();
+            read.unwrap();
+            assert_eq!(echo, data);
+        }
+        let _ = stream.close();
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_pause_resume_stream() {
+        let channel = "test_pause_resume_stream";
+        let n: usize = 1024 * 1024;
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            if let Ok((mut conn, _)) = listener.accept().await {
+                let data: Vec<u8> = (0..n).map(|i| i as u8).collect();
+                let _ = conn.write_all(&data[..]).await;
+                let mut buf = [0u8; 1];
+                let _ = conn.read(&mut buf).await;
+            }
+        });
+        let client = SessionOptions {
+            recv_window: 64 * 1024,
+            ..Default::default()
+        };
+        let pair = SessionPair::start_with(channel, client, SessionOptions::default()).await;
+        let mut stream = create_stream(channel, "tcp", addr.as_str()).await.unwrap();
+        let control = stream.clone();
+        {
+            let (mut r, _) = stream.split();
+            let mut received = vec![0u8; n];
+            // paused while reading, the server runs out of window until the resume grants it
+            let pause = async {
+                tokio::time::delay_for(Duration::from_millis(20)).await;
+                control.pause();
+                tokio::time::delay_for(Duration::from_millis(200)).await;
+                control.resume();
+            };
+            let (read, _) = futures::join!(
+                tokio::time::timeout(Duration::from_secs(5), r.read_exact(&mut received)),
+                pause
+            );
+            read.expect("no data after resume").unwrap();
+            assert!(received.iter().enumerate().all(|(i, b)| *b == i as u8));
+        }
+        let _ = stream.close();
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_stalled_target_keeps_session_alive() {
+        let channel = "test_stalled_target_keeps_session_alive";
+        let echo_addr = start_echo_server().await;
+        // accepts but never reads, its stream's data piles up on the server side
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalled_addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            while let Ok((conn, _)) = listener.accept().await {
+                conns.push(conn);
+            }
+        });
+        let server = SessionOptions {
+            recv_window: 8 * 1024 * 1024,
+            ..Default::default()
+        };
+        let pair = SessionPair::start_with(channel, SessionOptions::default(), server).await;
+
+        let mut stalled = create_stream(channel, "tcp", stalled_addr.as_str())
+            .await
+            .unwrap();
+        {
+            let (_, mut w) = stalled.split();
+            let data = vec![3u8; 16 * 1024 * 1024];
+            let _ = tokio::time::timeout(Duration::from_secs(2), w.write_all(&data[..])).await;
+        }
+        // the server's event loop still gets to the pings and the other streams
+        routine_all_sessions().await;
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        {
+            let (mut r, mut w) = stream.split();
+            let mut buf = [0u8; 5];
+            w.write_all(b"hello").await.unwrap();
+            tokio::time::timeout(Duration::from_secs(2), r.read_exact(&mut buf))
+                .await
+                .expect("session stuck behind the stalled target")
+                .unwrap();
+            assert_eq!(&buf, b"hello");
+        }
+        assert_eq!(get_channel_session_size(channel), 1);
+        assert!(channel_is_healthy(channel));
+        let _ = stream.close();
+        let _ = stalled.close();
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_channel_group_failover() {
+        let channel = "test_channel_group_failover";
+        let echo_addr = start_echo_server().await;
+        let pair = SessionPair::start(channel).await;
+        // the first member has no session, the group falls over to the healthy one
+        define_channel_group(
+            "test_channel_group",
+            vec![
+                String::from("test_channel_group_down"),
+                String::from(channel),
+            ],
+        );
+        let mut stream = create_stream("test_channel_group", "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        assert_eq!(stream.state.channel, channel);
+        let _ = stream.close();
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_unbatched_write_latency() {
+        let channel = "test_unbatched_write_latency";
+        let echo_addr = start_echo_server().await;
+        let opts = SessionOptions {
+            write_batching: WriteBatching::Off,
+            ..Default::default()
+        };
+        let pair = SessionPair::start_with(channel, opts.clone(), opts).await;
+
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        {
+            // small request/response exchanges, each frame must go out on its own right away
+            let (mut r, mut w) = stream.split();
+            let mut buf = [0u8; 32];
+            let start = std::time::Instant::now();
+            for i in 0..50u8 {
+                w.write_all(&[i; 32]).await.unwrap();
+                r.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, [i; 32]);
+            }
+            assert!(start.elapsed() < Duration::from_secs(2));
+        }
+        let _ = stream.close();
+        pair.shutdown().await;
+    }
+
+    // two sessions of a multipath channel, the stream opened on it runs over both
+    async fn start_multipath_pairs(channel: &str) -> (SessionPair, SessionPair) {
+        set_channel_multipath(channel, true);
+        let first = SessionPair::start(channel).await;
+        let second = SessionPair::start(channel).await;
+        for _ in 0..100 {
+            if get_channel_session_size(channel) == 2 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(get_channel_session_size(channel), 2);
+        (first, second)
+    }
+
+    #[tokio::test]
+    async fn test_multipath_reordered_frames() {
+        let channel = "test_multipath_reordered_frames";
+        let echo_addr = start_echo_server().await;
+        let (first, second) = start_multipath_pairs(channel).await;
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        assert!(stream.multipath().is_some());
+        // the SYN and the join of the other session reach the server
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        {
+            // frames striped over the first session arrive after those of the second
+            first.hold_uplink(true);
+            let (mut r, mut w) = stream.split();
+            let data: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+            let mut echo = vec![0u8; data.len()];
+            let write = async {
+                for chunk in data.chunks(4096) {
+                    w.write_all(chunk).await.unwrap();
+                }
+            };
+            let release = async {
+                tokio::time::delay_for(Duration::from_millis(200)).await;
+                first.hold_uplink(false);
+            };
+            let read = tokio::time::timeout(Duration::from_secs(5), r.read_exact(&mut echo));
+            let (_, _, read) = futures::join!(write, release, read);
+            read.expect("multipath frames not reassembled").unwrap();
+            assert_eq!(echo, data);
+        }
+        let _ = stream.close();
+        first.shutdown().await;
+        second.shutdown().await;
+        set_channel_multipath(channel, false);
+    }
+
+    #[tokio::test]
+    async fn test_multipath_session_lost() {
+        let channel = "test_multipath_session_lost";
+        let echo_addr = start_echo_server().await;
+        let (first, second) = start_multipath_pairs(channel).await;
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        {
+            let (mut r, mut w) = stream.split();
+            let data = vec![6u8; 64 * 1024];
+            let mut echo = vec![0u8; data.len()];
+            // the frames of the first session die with it, leaving a gap never filled
+            first.hold_uplink(true);
+            for chunk in data.chunks(4096) {
+                let _ = w.write_all(chunk).await;
+            }
+            first.shutdown().await;
+            let read = async {
+                let rc = r.read_exact(&mut echo).await;
+                rc.map(|_| echo == data)
+            };
+            let routine = async {
+                loop {
+                    routine_all_sessions().await;
+                    tokio::time::delay_for(Duration::from_millis(500)).await;
+                }
+            };
+            // reassembled over the second session or closed, never left hanging
+            let wait = MP_GAP_TIMEOUT + Duration::from_secs(5);
+            let done = tokio::time::timeout(wait, select(Box::pin(read), Box::pin(routine)))
+                .await
+                .expect("multipath stream left hanging");
+            let done = match done {
+                Either::Left((rc, _)) => rc,
+                Either::Right(_) => unreachable!(),
+            };
+            if let Ok(complete) = done {
+                assert!(complete);
+            }
+        }
+        let _ = stream.close();
+        second.shutdown().await;
+        set_channel_multipath(channel, false);
+    }
+
+    #[tokio::test]
+    async fn test_routine_keeps_live_sessions() {
+        let channel = "test_routine_keeps_live_sessions";
+        set_lock_hold_tracking(true);
+        let pair = SessionPair::start(channel).await;
+        routine_all_sessions().await;
+        routine_all_sessions().await;
+        assert_eq!(get_channel_session_size(channel), 1);
+        assert!(channel_is_healthy(channel));
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_max_stream_lifetime() {
+        let channel = "test_max_stream_lifetime";
+        let echo_addr = start_echo_server().await;
+        set_channel_max_stream_lifetime(channel, 1);
+        let pair = SessionPair::start(channel).await;
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        {
+            let (mut r, mut w) = stream.split();
+            let mut buf = [0u8; 5];
+            w.write_all(b"hello").await.unwrap();
+            r.read_exact(&mut buf).await.unwrap();
+            // still in use, a routine tick before the lifetime keeps it
+            routine_all_sessions().await;
+            w.write_all(b"hello").await.unwrap();
+            r.read_exact(&mut buf).await.unwrap();
+            tokio::time::delay_for(Duration::from_millis(1100)).await;
+            routine_all_sessions().await;
+            let read = tokio::time::timeout(Duration::from_secs(2), r.read(&mut buf))
+                .await
+                .expect("stream past its lifetime wasn't closed");
+            assert_eq!(read.unwrap_or(0), 0);
+        }
+        assert_eq!(stream.stats().send_bytes, 10);
+        // the session outlives its streams
+        assert_eq!(get_channel_session_size(channel), 1);
+        set_channel_max_stream_lifetime(channel, 0);
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_custom_dialer() {
+        let channel = "test_custom_dialer";
+        let echo_addr = start_echo_server().await;
+        let pair = SessionPair::start(channel).await;
+        // server sessions use channel "", other targets still get the direct dial
+        let echo = echo_addr.clone();
+        set_channel_dialer(
+            "",
+            Some(Arc::new(
+                move |_proto: String, addr: String| -> DialFuture {
+                    let addr = if addr == "dialer.test:80" {
+                        echo.clone()
+                    } else {
+                        addr
+                    };
+                    Box::pin(get_channel_stream(String::from("direct"), addr, Vec::new()))
+                },
+            )),
+        );
+        let mut stream = create_stream(channel, "tcp", "dialer.test:80")
+            .await
+            .unwrap();
+        {
+            let (mut r, mut w) = stream.split();
+            let mut buf = [0u8; 5];
+            w.write_all(b"hello").await.unwrap();
+            r.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        }
+        let _ = stream.close();
+        set_channel_dialer("", None);
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_channel() {
+        let channel = "test_shutdown_channel";
+        let echo_addr = start_echo_server().await;
+        let pair = SessionPair::start(channel).await;
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        {
+            let (mut r, mut w) = stream.split();
+            let mut buf = [0u8; 5];
+            w.write_all(b"hello").await.unwrap();
+            r.read_exact(&mut buf).await.unwrap();
+        }
+        // the open stream keeps the session until grace runs out
+        shutdown_channel(channel, Duration::from_millis(300)).await;
+        assert_eq!(get_channel_session_size(channel), 0);
+        assert!(create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .is_err());
+        {
+            let (mut r, _) = stream.split();
+            let mut buf = [0u8; 1];
+            assert_eq!(r.read(&mut buf).await.unwrap_or(0), 0);
+        }
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_service_stream() {
+        let channel = "test_service_stream";
+        let echo_addr = start_echo_server().await;
+        let pair = SessionPair::start(channel).await;
+        set_service_resolver(Some(Arc::new(
+            move |name: &str, _: &BTreeMap<String, String>| {
+                if name == "test_service_stream.echo" {
+                    Some(echo_addr.clone())
+                } else {
+                    None
+                }
+            },
+        )));
+        let mut stream = create_stream(channel, "service", "test_service_stream.echo")
+            .await
+            .unwrap();
+        {
+            let (mut r, mut w) = stream.split();
+            let mut buf = [0u8; 5];
+            w.write_all(b"hello").await.unwrap();
+            r.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        }
+        let _ = stream.close();
+
+        let mut stream = create_stream(channel, "service", "test_service_stream.none")
+            .await
+            .unwrap();
+        {
+            let (mut r, _) = stream.split();
+            let mut buf = [0u8; 1];
+            let n = r.read(&mut buf).await.unwrap_or(0);
+            assert_eq!(n, 0);
+        }
+        assert_eq!(stream.peer_fin_reason(), Some(FinReason::PolicyDenied));
+        set_service_resolver(None);
+        pair.shutdown().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_target() {
+        let channel = "test_unix_socket_target";
+        let path = std::env::temp_dir().join(format!("rsnova_test_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = conn.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        let pair = SessionPair::start(channel).await;
+        let mut stream = create_stream(channel, "unix", path.to_str().unwrap())
+            .await
+            .unwrap();
+        {
+            let (mut r, mut w) = stream.split();
+            let mut buf = [0u8; 5];
+            w.write_all(b"hello").await.unwrap();
+            r.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        }
+        let _ = stream.close();
+        pair.shutdown().await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_udp_stream() {
+        let channel = "test_udp_stream";
+        let mut echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            while let Ok((n, from)) = echo.recv_from(&mut buf).await {
+                let _ = echo.send_to(&buf[..n], from).await;
+            }
+        });
+        let pair = SessionPair::start(channel).await;
+        let mut stream = create_stream(channel, "udp", "").await.unwrap();
+        {
+            let (mut r, mut w) = stream.split();
+            for i in 0..3u8 {
+                let datagram = UdpDatagram {
+                    addr: echo_addr.clone(),
+                    data: vec![i; 100],
+                };
+                write_udp_datagram(&mut w, &datagram).await.unwrap();
+                let reply = read_udp_datagram(&mut r).await.unwrap();
+                assert_eq!(reply, Some(datagram));
+            }
+        }
+        let _ = stream.close();
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_udp_target_denied() {
+        let channel = "test_udp_target_denied";
+        let mut echos = Vec::new();
+        for _ in 0..2 {
+            let mut echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            echos.push(echo.local_addr().unwrap().to_string());
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                while let Ok((n, from)) = echo.recv_from(&mut buf).await {
+                    let _ = echo.send_to(&buf[..n], from).await;
+                }
+            });
+        }
+        let denied = echos[0].clone();
+        set_stream_auth_callback(Some(Arc::new(move |ev: &StreamEvent| match ev {
+            StreamEvent::Open {
+                channel: c, addr, ..
+            } => c != channel || *addr != denied,
+            _ => true,
+        })));
+        let pair = SessionPair::start(channel).await;
+        let mut stream = create_stream(channel, "udp", "").await.unwrap();
+        {
+            let (mut r, mut w) = stream.split();
+            for addr in echos.iter().chain(echos.iter()) {
+                let datagram = UdpDatagram {
+                    addr: addr.clone(),
+                    data: addr.as_bytes().to_vec(),
+                };
+                write_udp_datagram(&mut w, &datagram).await.unwrap();
+            }
+            // only the allowed target echoes, and the denied one stays denied once cached
+            for _ in 0..2 {
+                let reply = read_udp_datagram(&mut r).await.unwrap().unwrap();
+                assert_eq!(reply.addr, echos[1]);
+            }
+            let more = tokio::time::timeout(Duration::from_millis(200), read_udp_datagram(&mut r));
+            assert!(more.await.is_err());
+        }
+        set_stream_auth_callback(None);
+        let _ = stream.close();
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_self_addr_refused() {
+        let channel = "test_self_addr_refused";
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let self_addr = listener.local_addr().unwrap().to_string();
+        // server sessions are of the unnamed channel
+        set_channel_self_addrs("", &[self_addr.clone()]);
+        let pair = SessionPair::start(channel).await;
+        let wait = Duration::from_secs(5);
+        // refused whatever the proto, before the stream is handed to its handler
+        for proto in &["tcp", "udp"] {
+            let mut stream = create_stream(channel, proto, self_addr.as_str())
+                .await
+                .unwrap();
+            let result = tokio::time::timeout(wait, stream.connect_result()).await;
+            assert_eq!(
+                result.expect("no connect result"),
+                Err(FinReason::PolicyDenied)
+            );
+            let _ = stream.close();
+        }
+        set_channel_self_addrs("", &[]);
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_seq_data_gap() {
+        let echo_addr = start_echo_server().await;
+        // a bare client writing frames by hand to a server session
+        let (server_r, mut client_w, c1) = pipe();
+        let (mut client_r, server_w, c2) = pipe();
+        let nonce = rand::random::<u64>();
+        let server = spawn_session("", server_r, server_w, nonce, SessionOptions::default());
+        let mut wctx = CryptoContext::new(TEST_METHOD, TEST_KEY, nonce);
+        let mut rctx = CryptoContext::new(TEST_METHOD, TEST_KEY, nonce);
+        let mut recv_buf = BytesMut::new();
+        let req = ConnectRequest {
+            proto: String::from("tcp"),
+            addr: echo_addr.clone(),
+            ..Default::default()
+        };
+        // the next DATA or FIN of the stream from the server
+        async fn next_event(
+            rctx: &mut CryptoContext,
+            reader: &mut PipeReader,
+            recv_buf: &mut BytesMut,
+            sid: u32,
+        ) -> Event {
+            loop {
+                let read = read_encrypt_event(rctx, reader, recv_buf);
+                let ev = tokio::time::timeout(Duration::from_secs(5), read)
+                    .await
+                    .expect("no event from the server")
+                    .unwrap()
+                    .unwrap();
+                let flags = ev.header.flags();
+                if ev.header.stream_id == sid && (flags == FLAG_DATA || flags == FLAG_FIN) {
+                    return ev;
+                }
+            }
+        }
+        for sid in &[1u32, 3] {
+            let sid = *sid;
+            let frames = vec![
+                new_syn_event(sid, &req),
+                new_seq_data_event(sid, 0, b"first"),
+            ];
+            for ev in frames {
+                write_encrypt_event(&mut wctx, &mut client_w, ev)
+                    .await
+                    .unwrap();
+            }
+            let ev = next_event(&mut rctx, &mut client_r, &mut recv_buf, sid).await;
+            assert_eq!(ev.header.flags(), FLAG_DATA);
+            assert_eq!(&ev.body[..], b"first");
+            if sid == 3 {
+                break;
+            }
+            // seq 1 is skipped, the stream is closed but the session goes on
+            let ev = new_seq_data_event(sid, 2, b"third");
+            write_encrypt_event(&mut wctx, &mut client_w, ev)
+                .await
+                .unwrap();
+            let ev = next_event(&mut rctx, &mut client_r, &mut recv_buf, sid).await;
+            assert_eq!(ev.header.flags(), FLAG_FIN);
+        }
+        c1.close();
+        c2.close();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server session didn't finish")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_frame_tap() {
+        let channel = "test_frame_tap";
+        let echo_addr = start_echo_server().await;
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let tapped = frames.clone();
+        // other tests' sessions run meanwhile, only the frames of this channel are kept
+        set_frame_tap(Some(Arc::new(move |frame: &TappedFrame| {
+            if frame.channel == channel {
+                tapped.lock().unwrap().push(frame.clone());
+            }
+        })));
+        let pair = SessionPair::start(channel).await;
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        {
+            let (mut r, mut w) = stream.split();
+            let mut echo = [0u8; 5];
+            w.write_all(b"hello").await.unwrap();
+            r.read_exact(&mut echo).await.unwrap();
+        }
+        set_frame_tap(None);
+        let sid = stream.state.stream_id;
+        let frames = frames.lock().unwrap().clone();
+        let has = |direction, flags, body: &[u8]| {
+            frames.iter().any(|f| {
+                f.direction == direction
+                    && f.stream_id == sid
+                    && f.flags == flags
+                    && (body.is_empty() || f.body == body)
+            })
+        };
+        assert!(has(FrameDirection::Outbound, FLAG_SYN, b""));
+        assert!(has(FrameDirection::Outbound, FLAG_DATA, b"hello"));
+        let inbound: Vec<u8> = frames
+            .iter()
+            .filter(|f| f.direction == FrameDirection::Inbound && f.stream_id == sid)
+            .filter(|f| f.flags == FLAG_DATA)
+            .flat_map(|f| f.body.clone())
+            .collect();
+        assert_eq!(&inbound[..], b"hello");
+        let _ = stream.close();
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_throughput_sampler() {
+        let channel = "test_throughput_sampler";
+        let echo_addr = start_echo_server().await;
+        let pair = SessionPair::start(channel).await;
+        let samples = Arc::new(Mutex::new(Vec::new()));
+        let collected = samples.clone();
+        set_throughput_sampler(Some(Arc::new(move |s: &[ThroughputSample]| {
+            collected.lock().unwrap().extend_from_slice(s);
+        })));
+
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        {
+            let (mut r, mut w) = stream.split();
+            let mut buf = [0u8; 4096];
+            w.write_all(&buf).await.unwrap();
+            r.read_exact(&mut buf).await.unwrap();
+        }
+        routine_all_sessions().await;
+        set_throughput_sampler(None);
+        // other tests' routines may have taken part of the delta, their samples land here too
+        let bytes: u64 = samples
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|s| s.channel == channel)
+            .map(|s| s.bytes)
+            .sum();
+        assert!(bytes >= 2 * 4096);
+
+        let _ = stream.close();
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_close_stream() {
+        let channel = "test_close_stream";
+        let echo_addr = start_echo_server().await;
+        let pair = SessionPair::start(channel).await;
+
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        let (session_id, stream_id) = (stream.state.session_id, stream.id());
+        // give the event loop time to pick up the pending stream
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        assert!(!close_stream(channel, session_id, stream_id + 1));
+        assert!(close_stream(channel, session_id, stream_id));
+        {
+            let (mut r, _) = stream.split();
+            let mut buf = [0u8; 16];
+            let n = tokio::time::timeout(Duration::from_secs(5), r.read(&mut buf))
+                .await
+                .expect("relay didn't end")
+                .unwrap_or(0);
+            assert_eq!(n, 0);
+        }
+        // the event loop dropped the stream
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        assert!(!close_stream(channel, session_id, stream_id));
+
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_write_coalescing() {
+        let channel = "test_write_coalescing";
+        set_channel_write_coalescing(channel, 1024, 20);
+        let echo_addr = start_echo_server().await;
+        let pair = SessionPair::start(channel).await;
+
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        {
+            let (mut r, mut w) = stream.split();
+            // below the threshold, only the timer sends them
+            for i in 0..10u8 {
+                w.write_all(&[i; 10]).await.unwrap();
+            }
+            let mut buf = [0u8; 100];
+            tokio::time::timeout(Duration::from_secs(5), r.read_exact(&mut buf))
+                .await
+                .expect("coalesced writes not flushed")
+                .unwrap();
+            assert_eq!(buf[99], 9);
+            // crossing it sends them right away
+            let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+            for chunk in data.chunks(100) {
+                w.write_all(chunk).await.unwrap();
+            }
+            let mut echoed = vec![0u8; data.len()];
+            r.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(echoed, data);
+        }
+
+        let _ = stream.close();
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_session_params() {
+        let channel = "test_session_params";
+        let pair = SessionPair::start(channel).await;
+
+        let session_id = channel_stats(channel)[0].session_id;
+        let params = session_params(channel, session_id).unwrap();
+        assert_eq!(params.cipher, TEST_METHOD);
+        assert_eq!(params.capabilities, LOCAL_CAPABILITIES);
+        assert_eq!(params.stream_id_seed, 1);
+        assert_eq!(params.stream_recv_window, DEFAULT_STREAM_WINDOW);
+        assert!(session_params("test_session_params_other", session_id).is_none());
+
+        pair.shutdown().await;
+        assert!(session_params(channel, session_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_connect_result() {
+        let channel = "test_connect_result";
+        let echo_addr = start_echo_server().await;
+        let pair = SessionPair::start(channel).await;
+
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        let result = tokio::time::timeout(wait, stream.connect_result()).await;
+        assert_eq!(result.expect("no connect result"), Ok(()));
+        // resolved already for later callers
+        assert_eq!(stream.connect_result().await, Ok(()));
+        let _ = stream.close();
+
+        let refused_addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let mut stream = create_stream(channel, "tcp", refused_addr.as_str())
+            .await
+            .unwrap();
+        let result = tokio::time::timeout(wait, stream.connect_result()).await;
+        assert_eq!(
+            result.expect("no connect result"),
+            Err(FinReason::ConnectRefused)
+        );
+        let _ = stream.close();
+
+        let mut stream = create_stream(channel, "tcp", "no-such-host.invalid:80")
+            .await
+            .unwrap();
+        let result = tokio::time::timeout(wait, stream.connect_result()).await;
+        assert_eq!(
+            result.expect("no connect result"),
+            Err(FinReason::DnsFailure)
+        );
+        let _ = stream.close();
+
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_list_streams() {
+        let channel = "test_list_streams";
+        let echo_addr = start_echo_server().await;
+        let pair = SessionPair::start(channel).await;
+        let session_id = channel_stats(channel)[0].session_id;
+
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        {
+            let (mut r, mut w) = stream.split();
+            w.write_all(b"listed").await.unwrap();
+            let mut echoed = [0u8; 6];
+            r.read_exact(&mut echoed).await.unwrap();
+        }
+        let streams = list_streams(channel, session_id);
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].stream_id, stream.state.stream_id);
+        assert_eq!(streams[0].target, echo_addr);
+        assert_eq!(streams[0].send_bytes, 6);
+        assert!(list_streams("test_list_streams_other", session_id).is_empty());
+
+        let _ = stream.close();
+        let wait = Duration::from_secs(5);
+        let gone = tokio::time::timeout(wait, async {
+            while !list_streams(channel, session_id).is_empty() {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(gone.is_ok(), "closed stream still listed");
+
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_initial_send_credit() {
+        let channel = "test_initial_send_credit";
+        let credit = 4 * DEFAULT_STREAM_WINDOW;
+        set_channel_initial_send_credit(channel, credit);
+        let echo_addr = start_echo_server().await;
+        let server = SessionOptions {
+            recv_window: credit,
+            ..Default::default()
+        };
+        let pair = SessionPair::start_with(channel, SessionOptions::default(), server).await;
+
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        // the whole credit is there before the peer could have granted anything
+        assert_eq!(stream.stats().send_window, credit as i32);
+        let stats = stream.handle();
+        {
+            let (mut r, mut w) = stream.split();
+            let data = vec![3u8; 3 * DEFAULT_STREAM_WINDOW as usize];
+            w.write_all(&data[..]).await.unwrap();
+            let st = stats.stats();
+            assert_eq!(st.window_stall_time, Duration::from_secs(0));
+            assert!(st.send_window >= (credit as usize - data.len()) as i32);
+            let mut echo = vec![0u8; data.len()];
+            r.read_exact(&mut echo).await.unwrap();
+            assert_eq!(echo, data);
+        }
+        let _ = stream.close();
+
+        pair.shutdown().await;
+        set_channel_initial_send_credit(channel, 0);
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let channel = "test_probe";
+        let echo_addr = start_echo_server().await;
+        let pair = SessionPair::start(channel).await;
+        let wait = Duration::from_secs(5);
+
+        let result = probe(channel, "tcp", echo_addr.as_str(), wait).await;
+        assert!(result.is_ok(), "{:?}", result.error);
+        assert!(result.round_trip.is_none());
+        let result =
+            probe_with_payload(channel, "tcp", echo_addr.as_str(), b"ping".to_vec(), wait).await;
+        assert!(result.is_ok(), "{:?}", result.error);
+        assert!(result.round_trip.is_some());
+
+        let refused_addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let result = probe(channel, "tcp", refused_addr.as_str(), wait).await;
+        assert_eq!(
+            result.error,
+            Some(ProbeError::Refused(FinReason::ConnectRefused))
+        );
+        let result = probe("test_probe_no_session", "tcp", echo_addr.as_str(), wait).await;
+        match result.error {
+            Some(ProbeError::Open(_)) => {}
+            e => panic!("unexpected probe error:{:?}", e),
+        }
+
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_max_sessions() {
+        let channel = "test_max_sessions";
+        set_channel_max_sessions(channel, 1);
+        let pair = SessionPair::start(channel).await;
+        assert_eq!(get_channel_session_size(channel), 1);
+
+        // one more session of the channel is rejected, the live one is kept
+        let (reader, _, _) = pipe();
+        let (_, writer, _) = pipe();
+        let extra = spawn_session(channel, reader, writer, 1, SessionOptions::default());
+        let err = tokio::time::timeout(Duration::from_secs(5), extra)
+            .await
+            .expect("extra session wasn't rejected")
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(RmuxError::from_io(&err), Some(RmuxError::TooManySessions));
+        assert_eq!(get_channel_session_size(channel), 1);
+
+        pair.shutdown().await;
+        set_channel_max_sessions(channel, 0);
+    }
+
+    #[tokio::test]
+    async fn test_mock_clock_retires_session() {
+        let channel = "test_mock_clock_retires_session";
+        let clock = Arc::new(MockClock::default());
+        let client = SessionOptions {
+            max_alive_secs: 600,
+            clock: Some(clock.clone()),
+            ..Default::default()
+        };
+        let pair = SessionPair::start_with(channel, client, SessionOptions::default()).await;
+        let session_id = channel_stats(channel)[0].session_id;
+
+        clock.advance(Duration::from_secs(500));
+        routine_all_sessions().await;
+        assert_eq!(channel_stats(channel)[0].age_secs, 500);
+        assert_eq!(get_channel_session_size(channel), 1);
+
+        // past the max age and its jitter of at most 60 secs
+        clock.advance(Duration::from_secs(200));
+        routine_all_sessions().await;
+        assert_eq!(get_channel_session_size(channel), 0);
+        assert!(session_params(channel, session_id).is_some());
+
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_mock_clock_probes_idle_stream() {
+        let channel = "test_mock_clock_probes_idle_stream";
+        let echo_addr = start_echo_server().await;
+        let clock = Arc::new(MockClock::default());
+        let client = SessionOptions {
+            clock: Some(clock.clone()),
+            stream_keepalive_secs: 30,
+            ..Default::default()
+        };
+        let pair = SessionPair::start_with(channel, client, SessionOptions::default()).await;
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        routine_all_sessions().await;
+        assert_eq!(stream.idle_secs(clock.now_unix_secs()), 0);
+
+        for _ in 0..2 {
+            clock.advance(Duration::from_secs(30));
+            assert_eq!(stream.idle_secs(clock.now_unix_secs()), 30);
+            // the routine probes the idle stream, the answer resets it at the mocked time
+            routine_all_sessions().await;
+            for _ in 0..100 {
+                if stream.idle_secs(clock.now_unix_secs()) == 0 {
+                    break;
+                }
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+            assert_eq!(stream.idle_secs(clock.now_unix_secs()), 0);
+            assert!(!stream.stats().closed);
+        }
+        let _ = stream.close();
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_resume_stream() {
+        let channel = "test_resume_stream";
+        let content: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let served = content.clone();
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = listener.accept().await {
+                let served = served.clone();
+                tokio::spawn(async move {
+                    let _ = conn.write_all(&served[..]).await;
+                });
+            }
+        });
+        let pair = SessionPair::start(channel).await;
+
+        let mut stream = create_resumable_stream(channel, "tcp", addr.as_str(), Vec::new())
+            .await
+            .unwrap();
+        let mut head = vec![0u8; 300];
+        stream.read_exact(&mut head).await.unwrap();
+        let token = stream.resume_token().unwrap();
+        assert_eq!(token.recv_offset, 300);
+        let _ = stream.close();
+
+        let mut stream = resume_stream(channel, "tcp", addr.as_str(), Vec::new(), token)
+            .await
+            .unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        head.extend_from_slice(&rest);
+        assert_eq!(head, content);
+        // plain streams have no token
+        let mut stream = create_stream(channel, "tcp", addr.as_str()).await.unwrap();
+        assert!(stream.resume_token().is_none());
+        let _ = stream.close();
+
+        pair.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_evacuate_session() {
+        let channel = "test_evacuate_session";
+        let content: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let served = content.clone();
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = listener.accept().await {
+                let served = served.clone();
+                tokio::spawn(async move {
+                    let _ = conn.write_all(&served[..]).await;
+                });
+            }
+        });
+        let pair1 = SessionPair::start(channel).await;
+        let pair2 = SessionPair::start(channel).await;
+        for _ in 0..100 {
+            if get_channel_session_size(channel) == 2 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(get_channel_session_size(channel), 2);
+
+        let mut stream = create_resumable_stream(channel, "tcp", addr.as_str(), Vec::new())
+            .await
+            .unwrap();
+        let mut head = vec![0u8; 300];
+        stream.read_exact(&mut head).await.unwrap();
+        let session_id = stream.state.session_id;
+        let mut moved = evacuate_session(channel, session_id, Duration::from_secs(1)).await;
+        assert_eq!(get_channel_session_size(channel), 1);
+        assert_eq!(moved.len(), 1);
+        let (stream_id, resumed) = moved.pop().unwrap();
+        assert_eq!(stream_id, stream.id());
+        let mut resumed = resumed.unwrap();
+        assert_ne!(resumed.state.session_id, session_id);
+        // the old stream's reader stopped where the new one goes on
+        let mut buf = [0u8; 16];
+        assert!(stream.read(&mut buf).await.is_err());
+        let mut rest = Vec::new();
+        resumed.read_to_end(&mut rest).await.unwrap();
+        head.extend_from_slice(&rest);
+        assert_eq!(head, content);
+        // the evacuated session isn't there anymore
+        assert!(
+            evacuate_session(channel, session_id, Duration::from_secs(1))
+                .await
+                .is_empty()
+        );
+
+        pair1.shutdown().await;
+        pair2.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_relay_progress() {
+        let id = u32::MAX - 873;
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let seen = reports.clone();
+        set_relay_progress(Some(RelayProgressOptions {
+            callback: Arc::new(move |p: &RelayProgress| {
+                if p.id == id {
+                    seen.lock().unwrap().push(p.clone());
+                }
+            }),
+            every_bytes: 10000,
+            interval: Some(Duration::from_secs(60)),
+        }));
+        let upload = vec![1u8; 30000];
+        let download = vec![2u8; 5000];
+        let (mut local_reader, mut remote_reader) = (&upload[..], &download[..]);
+        let (mut local_writer, mut remote_writer) = (Vec::new(), Vec::new());
+        relay(
+            id,
+            &mut local_reader,
+            &mut local_writer,
+            &mut remote_reader,
+            &mut remote_writer,
+        )
+        .await
+        .unwrap();
+        set_relay_progress(None);
+        assert_eq!(remote_writer, upload);
+        assert_eq!(local_writer, download);
+
+        let reports = reports.lock().unwrap();
+        // passing 10000, 20000 & 30000 uploaded, then the last one
+        assert_eq!(reports.len(), 4);
+        let last = reports.last().unwrap();
+        assert!(last.done);
+        assert_eq!(last.client_to_server, 30000);
+        assert_eq!(last.server_to_client, 5000);
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// throughput is averaged over the complete seconds of this window
+const THROUGHPUT_WINDOW_SECS: usize = 8;
+
+#[derive(Default)]
+struct Bucket {
+    unix_secs: AtomicU32,
+    send_bytes: AtomicU64,
+    recv_bytes: AtomicU64,
+}
+
+#[derive(Default)]
+pub(crate) struct ChannelTraffic {
+    total_send_bytes: AtomicU64,
+    total_recv_bytes: AtomicU64,
+    // ring of per second buckets indexed by unix secs
+    buckets: [Bucket; THROUGHPUT_WINDOW_SECS],
+}
+
+lazy_static! {
+    static ref CHANNEL_TRAFFIC: Mutex<HashMap<String, Arc<ChannelTraffic>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn now_unix_secs() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32
+}
+
+impl ChannelTraffic {
+    fn bucket(&self, now: u32) -> &Bucket {
+        let b = &self.buckets[now as usize % THROUGHPUT_WINDOW_SECS];
+        // a racing writer of the same new second may lose a few bytes, fine for an estimate
+        if b.unix_secs.swap(now, Ordering::SeqCst) != now {
+            b.send_bytes.store(0, Ordering::SeqCst);
+            b.recv_bytes.store(0, Ordering::SeqCst);
+        }
+        b
+    }
+    pub(crate) fn add_send(&self, n: usize) {
+        self.total_send_bytes.fetch_add(n as u64, Ordering::SeqCst);
+        self.bucket(now_unix_secs())
+            .send_bytes
+            .fetch_add(n as u64, Ordering::SeqCst);
+    }
+    pub(crate) fn add_recv(&self, n: usize) {
+        self.total_recv_bytes.fetch_add(n as u64, Ordering::SeqCst);
+        self.bucket(now_unix_secs())
+            .recv_bytes
+            .fetch_add(n as u64, Ordering::SeqCst);
+    }
+    fn throughput(&self, now: u32) -> (u64, u64) {
+        let mut send = 0;
+        let mut recv = 0;
+        for b in self.buckets.iter() {
+            let secs = b.unix_secs.load(Ordering::SeqCst);
+            // the current second is still filling up
+            if secs < now && now - secs < THROUGHPUT_WINDOW_SECS as u32 {
+                send += b.send_bytes.load(Ordering::SeqCst);
+                recv += b.recv_bytes.load(Ordering::SeqCst);
+            }
+        }
+        let secs = THROUGHPUT_WINDOW_SECS as u64 - 1;
+        (send / secs, recv / secs)
+    }
+}
+
+pub(crate) fn get_channel_traffic(channel: &str) -> Arc<ChannelTraffic> {
+    CHANNEL_TRAFFIC
+        .lock()
+        .unwrap()
+        .entry(String::from(channel))
+        .or_insert_with(|| Arc::new(ChannelTraffic::default()))
+        .clone()
+}
+
+// (up, down) bytes per second of all streams of the channel over the last few seconds
+pub fn channel_throughput(channel: &str) -> (u64, u64) {
+    match CHANNEL_TRAFFIC.lock().unwrap().get(channel) {
+        Some(t) => t.throughput(now_unix_secs()),
+        None => (0, 0),
+    }
+}
+
+// (up, down) bytes of all streams of the channel since start
+pub fn channel_total_bytes(channel: &str) -> (u64, u64) {
+    match CHANNEL_TRAFFIC.lock().unwrap().get(channel) {
+        Some(t) => (
+            t.total_send_bytes.load(Ordering::SeqCst),
+            t.total_recv_bytes.load(Ordering::SeqCst),
+        ),
+        None => (0, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throughput_window() {
+        let t = ChannelTraffic::default();
+        let now = 1_000_000;
+        t.bucket(now - 1)
+            .send_bytes
+            .fetch_add(7000, Ordering::SeqCst);
+        t.bucket(now - 3)
+            .recv_bytes
+            .fetch_add(700, Ordering::SeqCst);
+        // the current second and anything older than the window are left out
+        t.bucket(now)
+            .send_bytes
+            .fetch_add(1 << 20, Ordering::SeqCst);
+        t.bucket(now - THROUGHPUT_WINDOW_SECS as u32 - 2)
+            .recv_bytes
+            .fetch_add(1 << 20, Ordering::SeqCst);
+        assert_eq!(t.throughput(now), (1000, 100));
+    }
+}
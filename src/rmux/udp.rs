@@ -0,0 +1,276 @@
+use super::hooks::{authorize_stream, StreamEvent};
+use super::loops::is_self_addr;
+use super::message::UdpDatagram;
+use super::redact::redact_addr;
+use super::stream::{MuxStream, MuxStreamState};
+use crate::utils::make_io_error;
+use futures::future::{abortable, select, AbortHandle, Either};
+use futures::pin_mut;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+pub const DEFAULT_UDP_IDLE_SECS: u64 = 60;
+// an encoded datagram, the largest UDP payload with its address
+const MAX_UDP_FRAME_LEN: usize = 65 * 1024;
+// replies not yet written to the association, more are dropped like on a full socket buffer
+const UDP_REPLY_QUEUE_LEN: usize = 256;
+// resolved domain targets and target verdicts kept by an association
+const MAX_RESOLVED_TARGETS: usize = 256;
+
+lazy_static! {
+    static ref UDP_IDLE_TIMEOUTS: Mutex<HashMap<String, Duration>> = Mutex::new(HashMap::new());
+}
+
+// UDP associations of the channel are closed after secs without a datagram either way, like
+// a NAT forgetting the mapping. "" is the channel of server sessions.
+pub fn set_channel_udp_idle_timeout(channel: &str, secs: u64) {
+    UDP_IDLE_TIMEOUTS
+        .lock()
+        .unwrap()
+        .insert(String::from(channel), Duration::from_secs(secs));
+}
+
+pub(crate) fn get_udp_idle_timeout(channel: &str) -> Duration {
+    UDP_IDLE_TIMEOUTS
+        .lock()
+        .unwrap()
+        .get(channel)
+        .cloned()
+        .unwrap_or_else(|| Duration::from_secs(DEFAULT_UDP_IDLE_SECS))
+}
+
+// the datagram's encoding prefixed by its len(u32 little endian)
+pub async fn write_udp_datagram<W: AsyncWrite + Unpin + ?Sized>(
+    w: &mut W,
+    datagram: &UdpDatagram,
+) -> io::Result<()> {
+    let data = datagram.encode();
+    let mut frame = Vec::with_capacity(4 + data.len());
+    frame.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&data[..]);
+    w.write_all(&frame[..]).await
+}
+
+// None once the stream ended between two datagrams
+pub async fn read_udp_datagram<R: AsyncRead + Unpin + ?Sized>(
+    r: &mut R,
+) -> io::Result<Option<UdpDatagram>> {
+    let mut len = [0u8; 4];
+    match r.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_UDP_FRAME_LEN {
+        return Err(make_io_error("udp datagram too large"));
+    }
+    let mut data = vec![0u8; len];
+    r.read_exact(&mut data[..]).await?;
+    UdpDatagram::decode(&data[..])
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Sends datagrams to their targets from one local port per address family, like a full cone
+// NAT. What comes back to these ports is queued as replies, addressed with their source.
+pub(crate) struct UdpNat {
+    v4: Option<SendHalf>,
+    v6: Option<SendHalf>,
+    resolved: HashMap<String, SocketAddr>,
+    replies: mpsc::Sender<UdpDatagram>,
+    receivers: Vec<AbortHandle>,
+}
+
+impl UdpNat {
+    pub(crate) fn new() -> (Self, mpsc::Receiver<UdpDatagram>) {
+        let (tx, rx) = mpsc::channel(UDP_REPLY_QUEUE_LEN);
+        let nat = Self {
+            v4: None,
+            v6: None,
+            resolved: HashMap::new(),
+            replies: tx,
+            receivers: Vec::new(),
+        };
+        (nat, rx)
+    }
+
+    async fn resolve(&mut self, addr: &str) -> io::Result<SocketAddr> {
+        if let Ok(a) = addr.parse::<SocketAddr>() {
+            return Ok(a);
+        }
+        if let Some(a) = self.resolved.get(addr) {
+            return Ok(*a);
+        }
+        let a = match tokio::net::lookup_host(addr).await?.next() {
+            Some(a) => a,
+            None => return Err(make_io_error("no address for the udp target")),
+        };
+        if self.resolved.len() >= MAX_RESOLVED_TARGETS {
+            self.resolved.clear();
+        }
+        self.resolved.insert(String::from(addr), a);
+        Ok(a)
+    }
+
+    pub(crate) async fn send(&mut self, datagram: &UdpDatagram) -> io::Result<()> {
+        let target = self.resolve(datagram.addr.as_str()).await?;
+        let (half, bind) = if target.is_ipv4() {
+            (&mut self.v4, "0.0.0.0:0")
+        } else {
+            (&mut self.v6, "[::]:0")
+        };
+        if half.is_none() {
+            let (recv, send) = UdpSocket::bind(bind).await?.split();
+            let (task, handle) = abortable(recv_replies(recv, self.replies.clone()));
+            tokio::spawn(task);
+            self.receivers.push(handle);
+            *half = Some(send);
+        }
+        half.as_mut()
+            .unwrap()
+            .send_to(&datagram.data[..], &target)
+            .await?;
+        Ok(())
+    }
+}
+
+impl Drop for UdpNat {
+    fn drop(&mut self) {
+        for handle in self.receivers.iter() {
+            handle.abort();
+        }
+    }
+}
+
+async fn recv_replies(mut recv: RecvHalf, mut replies: mpsc::Sender<UdpDatagram>) {
+    let mut buf = vec![0u8; 64 * 1024];
+    while let Ok((n, from)) = recv.recv_from(&mut buf[..]).await {
+        let reply = UdpDatagram {
+            addr: from.to_string(),
+            data: buf[..n].to_vec(),
+        };
+        if let Err(TrySendError::Closed(_)) = replies.try_send(reply) {
+            return;
+        }
+    }
+}
+
+pub(crate) fn touch(last_active: &Mutex<Instant>) {
+    *last_active.lock().unwrap() = Instant::now();
+}
+
+// Runs both directions of an association until one of them ends or fails, or neither
+// touched last_active for idle.
+pub(crate) async fn run_association<U, D>(
+    uplink: U,
+    downlink: D,
+    last_active: &Mutex<Instant>,
+    idle: Duration,
+) -> io::Result<()>
+where
+    U: Future<Output = io::Result<()>>,
+    D: Future<Output = io::Result<()>>,
+{
+    let watchdog = async {
+        loop {
+            let since = last_active.lock().unwrap().elapsed();
+            if since >= idle {
+                return Ok::<(), io::Error>(());
+            }
+            tokio::time::delay_for(idle - since).await;
+        }
+    };
+    pin_mut!(uplink, downlink, watchdog);
+    match select(select(uplink, downlink), watchdog).await {
+        Either::Left((Either::Left((r, _)), _)) => r,
+        Either::Left((Either::Right((r, _)), _)) => r,
+        Either::Right((r, _)) => r,
+    }
+}
+
+// Datagram targets get the checks of stream targets, the stream auth callback sees each one as
+// an Open of proto "udp" and the proxy itself is refused.
+async fn authorize_udp_target(
+    state: &MuxStreamState,
+    metadata: &BTreeMap<String, String>,
+    addr: &str,
+) -> bool {
+    let ev = StreamEvent::Open {
+        channel: state.channel.clone(),
+        session_id: state.session_id,
+        stream_id: state.stream_id,
+        proto: String::from("udp"),
+        addr: String::from(addr),
+        metadata: metadata.clone(),
+    };
+    authorize_stream(&ev) && !is_self_addr(state.channel.as_str(), addr).await
+}
+
+// A stream with proto "udp" carries one association, the requester's datagrams framed by
+// write_udp_datagram and the replies back the same way.
+pub(crate) async fn handle_udp_rmux_stream(mut stream: MuxStream) -> Result<(), Box<dyn Error>> {
+    let stream_id = stream.state.stream_id;
+    let idle = get_udp_idle_timeout(stream.state.channel.as_str());
+    let (mut nat, mut replies) = UdpNat::new();
+    let last_active = Mutex::new(Instant::now());
+    let state = stream.state.clone();
+    let metadata = stream.target.metadata.clone();
+    let mut verdicts: HashMap<String, bool> = HashMap::new();
+    stream.report_connected().await;
+    let result = {
+        let (mut ri, mut wi) = stream.split();
+        let uplink = async {
+            while let Some(datagram) = read_udp_datagram(&mut ri).await? {
+                touch(&last_active);
+                let allowed = match verdicts.get(&datagram.addr) {
+                    Some(v) => *v,
+                    None => {
+                        let v = authorize_udp_target(&state, &metadata, &datagram.addr).await;
+                        if verdicts.len() >= MAX_RESOLVED_TARGETS {
+                            verdicts.clear();
+                        }
+                        if !v {
+                            warn!(
+                                "[{}]udp target denied:{}",
+                                stream_id,
+                                redact_addr(datagram.addr.as_str())
+                            );
+                        }
+                        verdicts.insert(datagram.addr.clone(), v);
+                        v
+                    }
+                };
+                if !allowed {
+                    continue;
+                }
+                // like any lost datagram, the requester's app retries or gives up
+                if let Err(e) = nat.send(&datagram).await {
+                    debug!("[{}]udp send failed:{}", stream_id, e);
+                }
+            }
+            Ok::<(), io::Error>(())
+        };
+        let downlink = async {
+            while let Some(reply) = replies.recv().await {
+                touch(&last_active);
+                write_udp_datagram(&mut wi, &reply).await?;
+            }
+            Ok::<(), io::Error>(())
+        };
+        run_association(uplink, downlink, &last_active, idle).await
+    };
+    let _ = stream.close();
+    info!("[{}]udp association closed", stream_id);
+    result.map_err(|e| Box::new(e) as Box<dyn Error>)
+}
@@ -0,0 +1,119 @@
+use crate::channel::ChannelStream;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone)]
+pub(crate) struct ProxyUpstream {
+    pub addr: String,
+    pub username: String,
+    pub password: String,
+}
+
+lazy_static! {
+    static ref SOCKS5_UPSTREAMS: Mutex<HashMap<String, ProxyUpstream>> =
+        Mutex::new(HashMap::new());
+    static ref HTTP_CONNECT_UPSTREAMS: Mutex<HashMap<String, ProxyUpstream>> =
+        Mutex::new(HashMap::new());
+    // (proto, username, password) of streams opened on the channel
+    static ref STREAM_PROTOS: Mutex<HashMap<String, (String, String, String)>> =
+        Mutex::new(HashMap::new());
+    static ref DIALERS: Mutex<HashMap<String, Dialer>> = Mutex::new(HashMap::new());
+}
+
+pub type DialFuture =
+    Pin<Box<dyn Future<Output = Result<Box<dyn ChannelStream + Send>, std::io::Error>> + Send>>;
+// connects (proto, addr) of a stream opened by the peer, the initial data is written after; an
+// addr that doesn't resolve fails with RmuxError::DnsFailure so the peer learns why
+pub type Dialer = Arc<dyn Fn(String, String) -> DialFuture + Send + Sync>;
+
+// Outbound conns of the streams the peer opens on the channel come from the dialer instead
+// of a direct dial, "" is the channel of server sessions. Streams with proto "socks5" or
+// "http-connect" and channels with a conn pool keep their own dial. None restores the direct dial.
+pub fn set_channel_dialer(channel: &str, dialer: Option<Dialer>) {
+    let mut dialers = DIALERS.lock().unwrap();
+    match dialer {
+        Some(d) => {
+            dialers.insert(String::from(channel), d);
+        }
+        None => {
+            dialers.remove(channel);
+        }
+    }
+}
+
+pub(crate) fn get_channel_dialer(channel: &str) -> Option<Dialer> {
+    DIALERS.lock().unwrap().get(channel).cloned()
+}
+
+fn set_upstream(
+    upstreams: &Mutex<HashMap<String, ProxyUpstream>>,
+    channel: &str,
+    addr: &str,
+    username: &str,
+    password: &str,
+) {
+    let mut upstreams = upstreams.lock().unwrap();
+    if addr.is_empty() {
+        upstreams.remove(channel);
+        return;
+    }
+    upstreams.insert(
+        String::from(channel),
+        ProxyUpstream {
+            addr: String::from(addr),
+            username: String::from(username),
+            password: String::from(password),
+        },
+    );
+}
+
+// Proxy that streams opened by the peer with proto "socks5" are connected through.
+// The credentials are used unless the stream carries its own, an empty addr removes it.
+pub fn set_channel_socks5_upstream(channel: &str, addr: &str, username: &str, password: &str) {
+    set_upstream(&SOCKS5_UPSTREAMS, channel, addr, username, password);
+}
+
+// HTTP proxy that streams opened by the peer with proto "http-connect" are connected through
+// with a CONNECT, e.g. where only a corporate proxy may reach out. Credentials as for socks5.
+pub fn set_channel_http_connect_upstream(
+    channel: &str,
+    addr: &str,
+    username: &str,
+    password: &str,
+) {
+    set_upstream(&HTTP_CONNECT_UPSTREAMS, channel, addr, username, password);
+}
+
+// of the proxying proto, "socks5" or "http-connect"
+pub(crate) fn get_proxy_upstream(channel: &str, proto: &str) -> Option<ProxyUpstream> {
+    let upstreams = match proto {
+        "socks5" => &*SOCKS5_UPSTREAMS,
+        "http-connect" => &*HTTP_CONNECT_UPSTREAMS,
+        _ => return None,
+    };
+    upstreams.lock().unwrap().get(channel).cloned()
+}
+
+// Streams of the channel ask the peer to connect their target with proto, e.g. "socks5" or
+// "http-connect" with optional credentials for its upstream. Empty or "tcp" restores the direct dial.
+pub fn set_channel_stream_proto(channel: &str, proto: &str, username: &str, password: &str) {
+    let mut protos = STREAM_PROTOS.lock().unwrap();
+    if proto.is_empty() || proto == "tcp" {
+        protos.remove(channel);
+        return;
+    }
+    protos.insert(
+        String::from(channel),
+        (
+            String::from(proto),
+            String::from(username),
+            String::from(password),
+        ),
+    );
+}
+
+pub(crate) fn get_stream_proto(channel: &str) -> Option<(String, String, String)> {
+    STREAM_PROTOS.lock().unwrap().get(channel).cloned()
+}
@@ -1,4 +1,4 @@
-use super::relay::{relay_connection, relay_stream};
+use super::relay::{connect_target, relay_channel_stream};
 use crate::utils::{make_error, read_until_separator};
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
@@ -10,11 +10,12 @@ use std::net::Shutdown;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::io::AsyncRead;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use unicase::Ascii;
 
 use crate::config::TunnelConfig;
+use crate::rmux::{redact_addr, FinReason};
 use crate::utils::fill_read_buf;
 
 #[derive(Clone, PartialEq, Debug, Default)]
@@ -371,6 +372,28 @@ impl<T: AsyncRead + ?Sized + Unpin> AsyncRead for HttpReader<'_, T> {
     }
 }
 
+// answers a proxy request whose target couldn't be connected
+async fn write_connect_error<W: AsyncWrite + Unpin + ?Sized>(
+    w: &mut W,
+    target: &str,
+    reason: FinReason,
+) -> Result<(), Box<dyn Error>> {
+    let status = match reason {
+        FinReason::ConnectTimeout => "504 Gateway Timeout",
+        FinReason::PolicyDenied => "403 Forbidden",
+        _ => "502 Bad Gateway",
+    };
+    let body = format!("failed to connect {}: {:?}\r\n", target, reason);
+    let res = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    w.write_all(res.as_bytes()).await?;
+    Err(make_error(body.trim_end()))
+}
+
 pub async fn handle_http(
     tunnel_id: u32,
     mut inbound: TcpStream,
@@ -397,8 +420,24 @@ pub async fn handle_http(
     if target.find(':').is_none() {
         target.push_str(":80");
     }
-    info!("[{}]Handle HTTP proxy to {} ", tunnel_id, target);
-    relay_stream(tunnel_id, &mut hreader, &mut wi, target, cfg, Vec::new()).await?;
+    info!(
+        "[{}]Handle HTTP proxy to {} ",
+        tunnel_id,
+        redact_addr(target.as_str())
+    );
+    let remote = match connect_target(cfg, target.as_str()).await {
+        Ok(r) => r,
+        Err(reason) => return write_connect_error(&mut wi, target.as_str(), reason).await,
+    };
+    relay_channel_stream(
+        tunnel_id,
+        &mut hreader,
+        &mut wi,
+        remote,
+        target.as_str(),
+        Vec::new(),
+    )
+    .await?;
     let _ = inbound.shutdown(Shutdown::Both);
     Ok(())
 }
@@ -422,10 +461,30 @@ pub async fn handle_https(
         }
     };
 
+    let remote = match connect_target(cfg, target.as_str()).await {
+        Ok(r) => r,
+        Err(reason) => return write_connect_error(&mut inbound, target.as_str(), reason).await,
+    };
     let conn_res = "HTTP/1.0 200 Connection established\r\n\r\n";
     inbound.write_all(conn_res.as_bytes()).await?;
 
-    info!("[{}]Handle HTTPS proxy to {} ", tunnel_id, target);
-    relay_connection(tunnel_id, inbound, cfg, target, Vec::new()).await?;
+    info!(
+        "[{}]Handle HTTPS proxy to {} ",
+        tunnel_id,
+        redact_addr(target.as_str())
+    );
+    {
+        let (mut ri, mut wi) = inbound.split();
+        relay_channel_stream(
+            tunnel_id,
+            &mut ri,
+            &mut wi,
+            remote,
+            target.as_str(),
+            Vec::new(),
+        )
+        .await?;
+    }
+    let _ = inbound.shutdown(Shutdown::Both);
     Ok(())
 }
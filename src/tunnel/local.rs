@@ -1,22 +1,34 @@
 use super::http::handle_http;
 use super::http::handle_https;
+#[cfg(feature = "quic")]
+use super::quic::start_quic_server;
 use super::relay::relay_connection;
-use super::rmux::handle_rmux;
+use super::rmux::{handle_rmux, handle_rmux_tls};
 use super::socks5::handle_socks5;
 use super::tls::handle_tls;
 use super::tls::valid_tls_version;
 use super::ws::handle_websocket;
-use crate::utils::{get_origin_dst, make_error};
+use crate::utils::{get_origin_dst, make_error, tls_acceptor};
 
 use futures::FutureExt;
 use std::env;
 use std::error::Error;
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
 
-use std::sync::atomic::{AtomicU32, Ordering};
 use url::Url;
 
 use crate::config::TunnelConfig;
+use crate::rmux::{
+    add_self_addr, next_tunnel_id, rotate_channel_key, set_channel_conn_pool,
+    set_channel_data_quantum, set_channel_dial_limit, set_channel_dial_retry,
+    set_channel_http_connect_upstream, set_channel_max_sessions, set_channel_max_stream_lifetime,
+    set_channel_orphan_data_policy, set_channel_password, set_channel_send_dwell_limit,
+    set_channel_socks5_upstream, set_channel_udp_idle_timeout, set_channel_unknown_flags_policy,
+    set_channel_write_coalescing, share_channel_auth_only_frames, OrphanDataPolicy,
+    UnknownFlagsPolicy, DEFAULT_CONN_POOL_IDLE_SECS, DEFAULT_DIAL_RETRY_DELAY_MS,
+    DEFAULT_DIAL_RETRY_MAX_MS, DEFAULT_MAX_ORPHAN_DATA_FRAMES, DEFAULT_PREVIOUS_KEY_SECS,
+};
 
 async fn handle_inbound(
     tunnel_id: u32,
@@ -106,10 +118,121 @@ pub async fn start_tunnel_server(mut cfg: TunnelConfig) -> Result<(), Box<dyn Er
         listen_url.port().unwrap()
     );
 
+    if let Some(password) = cfg.cipher.as_ref().and_then(|c| c.password.as_deref()) {
+        // one for all tunnels of the server
+        set_channel_password("", password);
+    }
+    if let Some(cipher) = cfg.cipher.as_ref() {
+        if let Some(previous_key) = cipher.previous_key.as_deref() {
+            let secs = cipher
+                .previous_key_secs
+                .unwrap_or(DEFAULT_PREVIOUS_KEY_SECS);
+            rotate_channel_key(
+                "",
+                previous_key,
+                cipher.key.as_str(),
+                Duration::from_secs(secs as u64),
+            );
+        }
+    }
+    if let Some(cipher) = cfg.cipher.as_ref() {
+        // a setting for all tunnels of the server, they can't tell their sessions apart
+        if !share_channel_auth_only_frames("", cipher.auth_only.unwrap_or(false)) {
+            error!(
+                "auth_only of tunnel {} differs from other tunnels",
+                listen_str
+            );
+            return Err(make_error("conflicting auth_only of tunnels"));
+        }
+    }
+    if let Some(n) = cfg.max_concurrent_dials {
+        // server sessions share the unnamed channel
+        set_channel_dial_limit("", n as usize);
+    }
+    if let Some(n) = cfg.dial_retry_attempts {
+        set_channel_dial_retry(
+            "",
+            n,
+            cfg.dial_retry_delay_ms
+                .map_or(DEFAULT_DIAL_RETRY_DELAY_MS, u64::from),
+            cfg.dial_retry_max_ms
+                .map_or(DEFAULT_DIAL_RETRY_MAX_MS, u64::from),
+        );
+    }
+    if let Some(n) = cfg.data_quantum {
+        set_channel_data_quantum("", n as usize);
+    }
+    if let Some(ms) = cfg.send_dwell_limit_ms {
+        set_channel_send_dwell_limit("", ms as u64);
+    }
+    if let (Some(n), Some(ms)) = (cfg.write_coalesce_bytes, cfg.write_coalesce_ms) {
+        set_channel_write_coalescing("", n as usize, ms as u64);
+    }
+    if let Some(secs) = cfg.max_stream_lifetime_secs {
+        set_channel_max_stream_lifetime("", secs as u64);
+    }
+    if let Some(secs) = cfg.udp_idle_secs {
+        set_channel_udp_idle_timeout("", secs as u64);
+    }
+    if let Some(n) = cfg.max_sessions {
+        set_channel_max_sessions("", n as usize);
+    }
+    if let Some(n) = cfg.max_unknown_frames {
+        set_channel_unknown_flags_policy("", UnknownFlagsPolicy::with_max_frames(n));
+    }
+    if let Some(name) = cfg.orphan_data.as_deref() {
+        let max_frames = cfg
+            .max_orphan_data_frames
+            .unwrap_or(DEFAULT_MAX_ORPHAN_DATA_FRAMES);
+        match OrphanDataPolicy::from_name(name, max_frames) {
+            Some(policy) => set_channel_orphan_data_policy("", policy),
+            None => warn!("unknown orphan_data:{}, kept as fin", name),
+        }
+    }
+    if let Some(n) = cfg.conn_pool_size {
+        let idle_secs = cfg
+            .conn_pool_idle_secs
+            .map_or(DEFAULT_CONN_POOL_IDLE_SECS, u64::from);
+        set_channel_conn_pool("", idle_secs, n as usize);
+    }
+    if let Some(upstream) = &cfg.socks5_upstream {
+        set_channel_socks5_upstream(
+            "",
+            upstream.as_str(),
+            cfg.socks5_username.as_deref().unwrap_or(""),
+            cfg.socks5_password.as_deref().unwrap_or(""),
+        );
+    }
+    if let Some(upstream) = &cfg.http_connect_upstream {
+        set_channel_http_connect_upstream(
+            "",
+            upstream.as_str(),
+            cfg.http_connect_username.as_deref().unwrap_or(""),
+            cfg.http_connect_password.as_deref().unwrap_or(""),
+        );
+    }
+    if listen_url.scheme() == "quic" {
+        #[cfg(feature = "quic")]
+        return start_quic_server(addr.as_str(), cfg).await;
+        #[cfg(not(feature = "quic"))]
+        return Err(make_error("quic support is not enabled in this build"));
+    }
+    let acceptor = if listen_url.scheme() == "tls" {
+        match (&cfg.cert, &cfg.key) {
+            (Some(c), Some(k)) => Some(tls_acceptor(c.as_str(), k.as_str(), cfg.alpn.as_deref())?),
+            _ => return Err(make_error("tls listen requires cert & key")),
+        }
+    } else {
+        None
+    };
+    // streams targeting this listener or the configured aliases would loop back into it
+    add_self_addr(addr.as_str());
+    for a in cfg.self_addrs.iter().flatten() {
+        add_self_addr(a.as_str());
+    }
     let mut listener = TcpListener::bind(addr).await?;
-    let tunnel_id_seed = AtomicU32::new(0);
     while let Ok((inbound, _)) = listener.accept().await {
-        let tunnel_id = tunnel_id_seed.fetch_add(1, Ordering::SeqCst);
+        let tunnel_id = next_tunnel_id();
         if listen_url.scheme() == "local" {
             let handle = handle_inbound(tunnel_id, inbound, cfg.clone()).map(move |r| {
                 if let Err(e) = r {
@@ -131,6 +254,14 @@ pub async fn start_tunnel_server(mut cfg: TunnelConfig) -> Result<(), Box<dyn Er
                 }
             });
             tokio::spawn(handle);
+        } else if let Some(acceptor) = &acceptor {
+            let handle =
+                handle_rmux_tls(tunnel_id, inbound, acceptor.clone(), cfg.clone()).map(move |r| {
+                    if let Err(e) = r {
+                        error!("[{}]Failed to handle; error={}", tunnel_id, e);
+                    }
+                });
+            tokio::spawn(handle);
         }
     }
 
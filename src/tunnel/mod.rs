@@ -1,10 +1,15 @@
 mod http;
 mod local;
+#[cfg(feature = "quic")]
+mod quic;
 mod relay;
 mod rmux;
+mod route;
 mod socks5;
 mod tls;
 mod ws;
 
 pub use self::local::start_tunnel_server;
 pub use self::relay::relay;
+pub(crate) use self::relay::relay_reusable;
+pub use self::route::load_geoip_file;
@@ -0,0 +1,139 @@
+use super::rmux::handle_rmux_io;
+use crate::config::TunnelConfig;
+use crate::rmux::next_tunnel_id;
+use crate::utils::{make_error, quic_listen};
+
+use futures::StreamExt;
+use std::error::Error;
+
+// every bidirectional stream opened by the client carries one mux session
+async fn handle_quic_connection(connecting: quinn::Connecting, cfg: TunnelConfig) {
+    let quinn::NewConnection {
+        connection,
+        mut bi_streams,
+        ..
+    } = match connecting.await {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Failed to accept QUIC connection; error={}", e);
+            return;
+        }
+    };
+    info!(
+        "Accept QUIC connection from {}",
+        connection.remote_address()
+    );
+    while let Some(stream) = bi_streams.next().await {
+        let (mut send, mut recv) = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                info!(
+                    "QUIC connection from {} closed:{}",
+                    connection.remote_address(),
+                    e
+                );
+                break;
+            }
+        };
+        let tunnel_id = next_tunnel_id();
+        let cfg = cfg.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_rmux_io(tunnel_id, &mut recv, &mut send, &cfg).await {
+                error!("[{}]Failed to handle; error={}", tunnel_id, e);
+            }
+            let _ = send.finish().await;
+        });
+    }
+}
+
+pub async fn start_quic_server(addr: &str, cfg: TunnelConfig) -> Result<(), Box<dyn Error>> {
+    let (cert, key) = match (&cfg.cert, &cfg.key) {
+        (Some(c), Some(k)) => (c.clone(), k.clone()),
+        _ => return Err(make_error("quic listen requires cert & key")),
+    };
+    let (_endpoint, mut incoming) = quic_listen(addr, cert.as_str(), key.as_str()).await?;
+    while let Some(connecting) = incoming.next().await {
+        tokio::spawn(handle_quic_connection(connecting, cfg.clone()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::{init_rmux_client, ChannelStream};
+    use crate::config::ChannelConfig;
+    use crate::rmux::{create_stream, get_channel_session_size};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const CIPHER: &str =
+        "cipher = { key = \"quic_channel_test_key\", method = \"chacha20poly1305\" }";
+
+    // a quic:// channel dials the quic listener, the mux session runs over its bi stream
+    #[tokio::test]
+    async fn test_quic_channel() {
+        let channel = "test_quic_channel";
+        let testdata = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata");
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let tunnel: TunnelConfig = toml::from_str(
+            format!(
+                "listen = \"quic://127.0.0.1:{}\"\npac = []\ncert = \"{}/cert.pem\"\nkey = \"{}/key.pem\"\n{}",
+                port, testdata, testdata, CIPHER
+            )
+            .as_str(),
+        )
+        .unwrap();
+        let addr = format!("127.0.0.1:{}", port);
+        tokio::spawn(async move {
+            if let Err(e) = start_quic_server(addr.as_str(), tunnel).await {
+                error!("quic server failed:{}", e);
+            }
+        });
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+
+        let config: ChannelConfig = toml::from_str(
+            format!(
+                "name = \"{}\"\nurl = \"quic://127.0.0.1:{}\"\nsni = \"localhost\"\nca = \"{}/ca.pem\"\nping_interval_sec = 30\nconns_per_host = 1\nmax_alive_mins = 10\n{}",
+                channel, port, testdata, CIPHER
+            )
+            .as_str(),
+        )
+        .unwrap();
+        tokio::spawn(init_rmux_client(config, next_tunnel_id()));
+        for _ in 0..100 {
+            if get_channel_session_size(channel) > 0 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(get_channel_session_size(channel), 1);
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            if let Ok((mut conn, _)) = listener.accept().await {
+                let (mut r, mut w) = conn.split();
+                let _ = tokio::io::copy(&mut r, &mut w).await;
+            }
+        });
+        let mut stream = create_stream(channel, "tcp", echo_addr.as_str())
+            .await
+            .unwrap();
+        {
+            let (mut r, mut w) = stream.split();
+            let data = vec![7u8; 200 * 1024];
+            let mut echo = vec![0u8; data.len()];
+            let (written, read) = futures::join!(w.write_all(&data[..]), r.read_exact(&mut echo));
+            written.unwrap();
+            read.unwrap();
+            assert_eq!(echo, data);
+        }
+        let _ = stream.close();
+    }
+}
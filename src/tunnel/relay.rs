@@ -1,13 +1,33 @@
-use crate::channel::get_channel_stream;
+use super::route::{route, Route};
+use crate::channel::{get_channel_stream, ChannelStream};
 use crate::config::TunnelConfig;
-use crate::rmux::get_channel_session_size;
-use crate::utils::{buf_copy, make_error};
+use crate::rmux::{
+    redact_addr, relay_progress, FinReason, RelayProgress, RelayProgressOptions, SendWindowGate,
+    MAX_INITIAL_DATA_LEN,
+};
+use crate::utils::{buf_copy, buf_copy_observed, make_error};
 
 use futures::future::join;
+use futures::FutureExt;
 use std::error::Error;
 use std::net::Shutdown;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::{info, info_span, Instrument};
+
+// The channel the pac routes target to, Err(PolicyDenied) if a rule rejects it and
+// Err(TargetClosed) if none matches.
+pub(crate) async fn select_channel(cfg: &TunnelConfig, target: &str) -> Result<String, FinReason> {
+    match route(cfg, target).await {
+        Some(Route::Direct) => Ok(String::from("direct")),
+        Some(Route::Channel(c)) => Ok(c),
+        Some(Route::Reject) => Err(FinReason::PolicyDenied),
+        None => Err(FinReason::TargetClosed),
+    }
+}
 
 pub async fn relay_connection(
     tunnel_id: u32,
@@ -28,37 +48,78 @@ pub async fn relay_stream<'a, A, B>(
     local_writer: &'a mut B,
     target: String,
     cfg: &TunnelConfig,
-    relay_buf: Vec<u8>,
+    mut relay_buf: Vec<u8>,
 ) -> Result<(), Box<dyn Error>>
 where
     A: AsyncRead + Unpin + ?Sized,
     B: AsyncWrite + Unpin + ?Sized,
 {
-    let mut channel = String::new();
-    for pac in cfg.pac.iter() {
-        if pac.is_match(target.as_str()) {
-            channel = String::from(pac.channel.as_str());
-            if channel.as_str() != "direct" && get_channel_session_size(channel.as_str()) == 0 {
-                continue;
-            }
-            break;
-        }
+    let channel = match select_channel(cfg, target.as_str()).await {
+        Ok(c) => c,
+        Err(FinReason::PolicyDenied) => return Err(make_error("target rejected by pac.")),
+        Err(_) => return Err(make_error("no valid channel found.")),
+    };
+
+    // small leading data rides with the connect request to save a round trip
+    let mut initial_data = Vec::new();
+    if relay_buf.len() <= MAX_INITIAL_DATA_LEN {
+        initial_data = std::mem::replace(&mut relay_buf, Vec::new());
     }
-    if channel.is_empty() {
-        return Err(make_error("no valid channel found."));
+    let remote = get_channel_stream(channel, target.clone(), initial_data).await?;
+    relay_channel_stream(
+        tunnel_id,
+        local_reader,
+        local_writer,
+        remote,
+        target.as_str(),
+        relay_buf,
+    )
+    .await
+}
+
+// Opens a stream to target over the channel the pac picks and waits until it's connected,
+// for local proxies answering their client only then. The error tells why it failed.
+pub(crate) async fn connect_target(
+    cfg: &TunnelConfig,
+    target: &str,
+) -> Result<Box<dyn ChannelStream + Send>, FinReason> {
+    let channel = select_channel(cfg, target).await?;
+    let mut remote = match get_channel_stream(channel, String::from(target), Vec::new()).await {
+        Ok(s) => s,
+        Err(e) => return Err(FinReason::from_connect_error(&e)),
+    };
+    if let Err(reason) = remote.connect_result().await {
+        let _ = remote.close();
+        return Err(reason);
     }
+    Ok(remote)
+}
 
-    let remote_target = String::from(target.as_str());
-    let mut remote = get_channel_stream(channel, target).await?;
+pub(crate) async fn relay_channel_stream<'a, A, B>(
+    tunnel_id: u32,
+    local_reader: &'a mut A,
+    local_writer: &'a mut B,
+    mut remote: Box<dyn ChannelStream + Send>,
+    target: &str,
+    relay_buf: Vec<u8>,
+) -> Result<(), Box<dyn Error>>
+where
+    A: AsyncRead + Unpin + ?Sized,
+    B: AsyncWrite + Unpin + ?Sized,
+{
+    let remote_target = redact_addr(target);
     {
         let (mut ro, mut wo) = remote.split();
         if !relay_buf.is_empty() {
             wo.write_all(&relay_buf[..]).await?;
         }
-        relay(tunnel_id, local_reader, local_writer, &mut ro, &mut wo).await?;
+        let span = info_span!("relay", tunnel_id, target = remote_target.as_str());
+        relay(tunnel_id, local_reader, local_writer, &mut ro, &mut wo)
+            .instrument(span)
+            .await?;
     }
     let _ = remote.close();
-    info!("[{}][{}]Stream close", tunnel_id, remote_target);
+    info!(tunnel_id, target = remote_target.as_str(), "stream close");
     Ok(())
 }
 
@@ -75,18 +136,160 @@ where
     A: AsyncRead + Unpin + ?Sized,
     B: AsyncWrite + Unpin + ?Sized,
 {
+    if let Some(opts) = relay_progress() {
+        return relay_observed(
+            tunnel_id,
+            opts,
+            local_reader,
+            local_writer,
+            remote_reader,
+            remote_writer,
+        )
+        .await;
+    }
     let client_to_server = async {
         let _ = buf_copy(local_reader, remote_writer, Box::new([0; 8192])).await;
-        info!("[{}]Stream close client_to_server", tunnel_id);
+        info!(tunnel_id, "stream close client_to_server");
         let _ = remote_writer.shutdown().await;
         //()
     };
     let server_to_client = async {
-        let _ = buf_copy(remote_reader, local_writer, Box::new([0; 8192])).await;
-        info!("[{}]Stream close server_to_client", tunnel_id);
+        // e.g. a mux stream reset by its peer
+        if let Err(e) = buf_copy(remote_reader, local_writer, Box::new([0; 8192])).await {
+            info!(tunnel_id, "stream server_to_client failed:{}", e);
+        }
+        info!(tunnel_id, "stream close server_to_client");
         let _ = local_writer.shutdown().await;
         //()
     };
     join(client_to_server, server_to_client).await;
     Ok(())
 }
+
+fn passes_multiple(before: u64, n: usize, every: u64) -> bool {
+    every > 0 && before / every != (before + n as u64) / every
+}
+
+// relay counting the copied bytes for the progress callback, one atomic add per chunk
+async fn relay_observed<'a, R, W, A, B>(
+    tunnel_id: u32,
+    opts: RelayProgressOptions,
+    local_reader: &'a mut A,
+    local_writer: &'a mut B,
+    remote_reader: &'a mut R,
+    remote_writer: &'a mut W,
+) -> Result<(), Box<dyn Error>>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+    A: AsyncRead + Unpin + ?Sized,
+    B: AsyncWrite + Unpin + ?Sized,
+{
+    let copied = [AtomicU64::new(0), AtomicU64::new(0)];
+    let report = |stalled_for: Duration, done: bool| {
+        (opts.callback)(&RelayProgress {
+            id: tunnel_id,
+            client_to_server: copied[0].load(Ordering::Relaxed),
+            server_to_client: copied[1].load(Ordering::Relaxed),
+            stalled_for,
+            done,
+        });
+    };
+    let on_client_to_server = |n: usize| {
+        let before = copied[0].fetch_add(n as u64, Ordering::Relaxed);
+        if passes_multiple(before, n, opts.every_bytes) {
+            report(Duration::default(), false);
+        }
+    };
+    let on_server_to_client = |n: usize| {
+        let before = copied[1].fetch_add(n as u64, Ordering::Relaxed);
+        if passes_multiple(before, n, opts.every_bytes) {
+            report(Duration::default(), false);
+        }
+    };
+    let client_to_server = async {
+        let _ = buf_copy_observed(
+            local_reader,
+            remote_writer,
+            Box::new([0; 8192]),
+            &on_client_to_server,
+        )
+        .await;
+        info!(tunnel_id, "stream close client_to_server");
+        let _ = remote_writer.shutdown().await;
+    };
+    let server_to_client = async {
+        if let Err(e) = buf_copy_observed(
+            remote_reader,
+            local_writer,
+            Box::new([0; 8192]),
+            &on_server_to_client,
+        )
+        .await
+        {
+            info!(tunnel_id, "stream server_to_client failed:{}", e);
+        }
+        info!(tunnel_id, "stream close server_to_client");
+        let _ = local_writer.shutdown().await;
+    };
+    let copies = join(client_to_server, server_to_client);
+    pin_mut!(copies);
+    match opts.interval {
+        None => {
+            copies.await;
+        }
+        Some(period) => {
+            let mut last_total = 0;
+            let mut last_moved = Instant::now();
+            while timeout(period, copies.as_mut()).await.is_err() {
+                let total = copied[0].load(Ordering::Relaxed) + copied[1].load(Ordering::Relaxed);
+                if total != last_total {
+                    last_total = total;
+                    last_moved = Instant::now();
+                }
+                report(last_moved.elapsed(), false);
+            }
+        }
+    }
+    report(Duration::default(), true);
+    Ok(())
+}
+
+// Relay until the local side closes and leave the remote conn open so it could be reused.
+// Returns false if the remote side closed or failed first, the conn is closed then. The
+// remote conn is read as fast as the gate's stream takes it.
+pub(crate) async fn relay_reusable<'a, A, B>(
+    tunnel_id: u32,
+    local_reader: &'a mut A,
+    local_writer: &'a mut B,
+    remote: &'a mut TcpStream,
+    gate: &SendWindowGate,
+) -> bool
+where
+    A: AsyncRead + Unpin + ?Sized,
+    B: AsyncWrite + Unpin + ?Sized,
+{
+    let mut reusable = false;
+    {
+        let (mut ro, mut wo) = remote.split();
+        let mut ro = gate.gate(&mut ro);
+        let client_to_server = buf_copy(local_reader, &mut wo, Box::new([0; 8192])).fuse();
+        let server_to_client = buf_copy(&mut ro, local_writer, Box::new([0; 8192])).fuse();
+        pin_mut!(client_to_server, server_to_client);
+        select! {
+            r = client_to_server => {
+                reusable = r.is_ok();
+            }
+            _ = server_to_client => {
+                info!(tunnel_id, "stream close server_to_client");
+            }
+        }
+    }
+    if !reusable {
+        let _ = local_writer.shutdown().await;
+        let (_, mut wo) = remote.split();
+        let _ = buf_copy(local_reader, &mut wo, Box::new([0; 8192])).await;
+        let _ = remote.shutdown(Shutdown::Both);
+    }
+    reusable
+}
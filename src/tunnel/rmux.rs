@@ -1,12 +1,15 @@
 use crate::config::TunnelConfig;
 use crate::rmux::{
-    handle_rmux_session, new_auth_event, read_encrypt_event, AuthRequest, AuthResponse,
-    CryptoContext,
+    decode_auth, handle_rmux_session, new_auth_event, process_rmux_session, read_auth_event,
+    record_auth_failure, start_establishing, AuthRequest, AuthResponse, CryptoContext, MuxContext,
+    RmuxError, DEFAULT_STREAM_WINDOW,
 };
 use crate::utils::make_io_error;
 use bytes::BytesMut;
-use tokio::io::AsyncWriteExt;
+use std::error::Error;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
 
 //use rand::Rng;
 // use std::sync::atomic::{AtomicU32, Ordering};
@@ -16,20 +19,27 @@ pub async fn handle_rmux(
     mut inbound: TcpStream,
     cfg: TunnelConfig,
 ) -> Result<(), std::io::Error> {
+    let establishing = start_establishing().await;
     let key = String::from(cfg.cipher.as_ref().unwrap().key.as_str());
     let method = String::from(cfg.cipher.as_ref().unwrap().method.as_str());
-    let mut rctx = CryptoContext::new(method.as_str(), key.as_str(), 0);
-    let mut wctx = CryptoContext::new(method.as_str(), key.as_str(), 0);
     //1. auth connection
     let mut recv_buf = BytesMut::new();
-    let recv_ev = match read_encrypt_event(&mut rctx, &mut inbound, &mut recv_buf).await {
-        Err(_) => return Err(make_io_error("can NOT read first auth envent.")),
-        Ok(Some(ev)) => ev,
+    let (recv_ev, key) = match read_auth_event("", &method, &key, &mut inbound, &mut recv_buf).await
+    {
+        Err(e) => {
+            if RmuxError::from_io(&e) == Some(RmuxError::AuthFailure) {
+                let peer = inbound.peer_addr().ok().map(|a| a.ip().to_string());
+                record_auth_failure("", 0, peer.as_deref());
+            }
+            return Err(make_io_error("can NOT read first auth envent."));
+        }
+        Ok(Some(r)) => r,
         Ok(None) => {
             return Err(make_io_error("can NOT read first auth envent."));
         }
     };
-    let auth_req: AuthRequest = match bincode::deserialize(&recv_ev.body[..]) {
+    let mut wctx = CryptoContext::new_for_channel_key("", method.as_str(), key.as_str(), 0);
+    let (auth_req, peer): (AuthRequest, _) = match decode_auth(&recv_ev.body[..]) {
         Ok(m) => m,
         Err(err) => {
             error!(
@@ -49,12 +59,183 @@ pub async fn handle_rmux(
         //rand: 1,
         method: auth_req.method,
     };
-    let mut res = new_auth_event(0, &auth_res);
+    let mut res = new_auth_event("", 0, &auth_res);
     let mut buf = BytesMut::new();
     wctx.encrypt(&mut res, &mut buf);
     inbound.write_all(&buf[..]).await?;
-    let rctx = CryptoContext::new(auth_res.method.as_str(), key.as_str(), auth_res.rand);
-    let wctx = CryptoContext::new(auth_res.method.as_str(), key.as_str(), auth_res.rand);
-    handle_rmux_session("", tunnel_id, inbound, rctx, wctx, &mut recv_buf, 0).await?;
+    handle_rmux_session(
+        "",
+        tunnel_id,
+        inbound,
+        auth_res.method.as_str(),
+        key.as_str(),
+        auth_res.rand,
+        &mut recv_buf,
+        0,
+        cfg.stream_recv_window.unwrap_or(DEFAULT_STREAM_WINDOW),
+        &peer,
+        establishing,
+    )
+    .await?;
     Ok(())
 }
+
+// auth the client and run the mux session over an already established transport
+pub(crate) async fn handle_rmux_io<R, W>(
+    tunnel_id: u32,
+    reader: &mut R,
+    writer: &mut W,
+    cfg: &TunnelConfig,
+) -> Result<(), std::io::Error>
+where
+    R: AsyncRead + Unpin + Sized,
+    W: AsyncWrite + Unpin + Sized,
+{
+    let establishing = start_establishing().await;
+    let key = String::from(cfg.cipher.as_ref().unwrap().key.as_str());
+    let method = String::from(cfg.cipher.as_ref().unwrap().method.as_str());
+    //1. auth connection
+    let mut recv_buf = BytesMut::new();
+    let (recv_ev, key) = match read_auth_event("", &method, &key, reader, &mut recv_buf).await {
+        Err(e) => {
+            if RmuxError::from_io(&e) == Some(RmuxError::AuthFailure) {
+                record_auth_failure("", 0, None);
+            }
+            return Err(make_io_error(e.description()));
+        }
+        Ok(Some(r)) => r,
+        Ok(None) => {
+            return Err(make_io_error("can NOT read first auth envent."));
+        }
+    };
+    let mut wctx = CryptoContext::new_for_channel_key("", method.as_str(), key.as_str(), 0);
+    let (auth_req, peer): (AuthRequest, _) = match decode_auth(&recv_ev.body[..]) {
+        Ok(m) => m,
+        Err(err) => {
+            error!(
+                "Failed to parse AuthRequest with error:{} while data len:{} {}",
+                err,
+                recv_ev.body.len(),
+                recv_ev.header.len(),
+            );
+            return Err(make_io_error("Failed to parse AuthRequest"));
+        }
+    };
+    let auth_res = AuthResponse {
+        success: true,
+        err: String::new(),
+        rand: rand::random::<u64>(),
+        method: auth_req.method,
+    };
+    let mut res = new_auth_event("", 0, &auth_res);
+    let mut buf = BytesMut::new();
+    wctx.encrypt(&mut res, &mut buf);
+    writer.write_all(&buf[..]).await?;
+    let rctx = CryptoContext::new_for_channel_key(
+        "",
+        auth_res.method.as_str(),
+        key.as_str(),
+        auth_res.rand,
+    );
+    let wctx = CryptoContext::new_for_channel_key(
+        "",
+        auth_res.method.as_str(),
+        key.as_str(),
+        auth_res.rand,
+    );
+    let mut ctx = MuxContext::new("", tunnel_id, rctx, wctx, 0, &mut recv_buf);
+    ctx.set_stream_recv_window(cfg.stream_recv_window.unwrap_or(DEFAULT_STREAM_WINDOW));
+    ctx.set_peer_hello(&peer);
+    ctx.set_establish_permit(establishing);
+    process_rmux_session(ctx, reader, writer).await?;
+    Ok(())
+}
+
+// the mux session runs inside a TLS connection, for paths that must look like HTTPS
+pub async fn handle_rmux_tls(
+    tunnel_id: u32,
+    inbound: TcpStream,
+    acceptor: TlsAcceptor,
+    cfg: TunnelConfig,
+) -> Result<(), std::io::Error> {
+    let tls_stream = acceptor.accept(inbound).await?;
+    let (mut reader, mut writer) = tokio::io::split(tls_stream);
+    let rc = handle_rmux_io(tunnel_id, &mut reader, &mut writer, &cfg).await;
+    let _ = writer.shutdown().await;
+    rc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::init_rmux_client;
+    use crate::config::ChannelConfig;
+    use crate::rmux::{get_channel_session_size, next_tunnel_id, set_channel_key};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    async fn start_server(key: &str) -> String {
+        let cfg: TunnelConfig = toml::from_str(
+            format!(
+                "listen = \"rmux://127.0.0.1:0\"\npac = []\ncipher = {{ key = \"{}\", method = \"chacha20poly1305\" }}",
+                key
+            )
+            .as_str(),
+        )
+        .unwrap();
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                tokio::spawn(handle_rmux(next_tunnel_id(), conn, cfg.clone()));
+            }
+        });
+        addr
+    }
+
+    fn channel_config(name: &str, addr: &str) -> ChannelConfig {
+        toml::from_str(
+            format!(
+                "name = \"{}\"\nurl = \"rmux://{}\"\nping_interval_sec = 30\nconns_per_host = 1\nmax_alive_mins = 10\ncipher = {{ key = \"key_of_the_config\", method = \"chacha20poly1305\" }}",
+                name, addr
+            )
+            .as_str(),
+        )
+        .unwrap()
+    }
+
+    // each channel authenticates with the server of its own key only
+    #[tokio::test]
+    async fn test_channel_keys() {
+        let channels = ["test_channel_keys_a", "test_channel_keys_b"];
+        let keys = ["key_of_channel_a_1234", "key_of_channel_b_5678"];
+        let mut servers = Vec::new();
+        for (channel, key) in channels.iter().zip(keys.iter()) {
+            set_channel_key(channel, key);
+            servers.push(start_server(key).await);
+        }
+        for (i, channel) in channels.iter().enumerate() {
+            let other = &servers[1 - i];
+            let rc = tokio::time::timeout(
+                Duration::from_secs(5),
+                init_rmux_client(channel_config(channel, other), next_tunnel_id()),
+            )
+            .await
+            .expect("handshake with a wrong key didn't fail");
+            assert!(rc.is_err());
+            assert_eq!(get_channel_session_size(channel), 0);
+
+            tokio::spawn(init_rmux_client(
+                channel_config(channel, servers[i].as_str()),
+                next_tunnel_id(),
+            ));
+            for _ in 0..100 {
+                if get_channel_session_size(channel) > 0 {
+                    break;
+                }
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+            assert_eq!(get_channel_session_size(channel), 1);
+        }
+    }
+}
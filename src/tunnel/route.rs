@@ -0,0 +1,253 @@
+use crate::config::{PACConfig, TunnelConfig};
+use crate::rmux::get_channel_session_size;
+use crate::utils::IpCidr;
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route {
+    Direct,
+    Channel(String),
+    Reject,
+}
+
+// ranges of the networks in a geoip file, sorted by their first address
+#[derive(Default)]
+struct GeoIpTable {
+    v4: Vec<(u128, u128, [u8; 2])>,
+    v6: Vec<(u128, u128, [u8; 2])>,
+}
+
+impl GeoIpTable {
+    fn country(&self, ip: &IpAddr) -> Option<[u8; 2]> {
+        let (ranges, n) = match ip {
+            IpAddr::V4(a) => (&self.v4, u128::from(u32::from(*a))),
+            IpAddr::V6(a) => (&self.v6, u128::from(*a)),
+        };
+        let i = match ranges.binary_search_by(|r| r.0.cmp(&n)) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let (_, last, country) = ranges[i];
+        if n <= last {
+            Some(country)
+        } else {
+            None
+        }
+    }
+}
+
+lazy_static! {
+    static ref GEOIP: RwLock<Option<Arc<GeoIpTable>>> = RwLock::new(None);
+}
+
+fn parse_geoip(data: &str) -> GeoIpTable {
+    let mut table = GeoIpTable::default();
+    for line in data.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.splitn(2, ',');
+        let cidr = fields.next().and_then(IpCidr::parse);
+        let country = fields.next().unwrap_or("").trim().to_ascii_uppercase();
+        let (cidr, country) = match cidr {
+            Some(cidr) if country.len() == 2 => (cidr, country.as_bytes()),
+            _ => {
+                debug!("skip invalid geoip line:{}", line);
+                continue;
+            }
+        };
+        let (first, last) = cidr.range();
+        let range = (first, last, [country[0], country[1]]);
+        if cidr.is_ipv4() {
+            table.v4.push(range);
+        } else {
+            table.v6.push(range);
+        }
+    }
+    table.v4.sort();
+    table.v6.sort();
+    table
+}
+
+// Loads the countries of ip networks from a file of "network,country" lines like
+// "1.0.1.0/24,CN", with the networks not overlapping. Returns the number of networks.
+pub fn load_geoip_file(path: &str) -> io::Result<usize> {
+    let table = parse_geoip(std::fs::read_to_string(path)?.as_str());
+    let n = table.v4.len() + table.v6.len();
+    *GEOIP.write().unwrap() = Some(Arc::new(table));
+    Ok(n)
+}
+
+fn geoip_country(ip: &IpAddr) -> Option<[u8; 2]> {
+    let table = GEOIP.read().unwrap().clone()?;
+    table.country(ip)
+}
+
+// the host of "host:port", "[v6]:port" or a bare host
+fn target_host(target: &str) -> &str {
+    if target.starts_with('[') {
+        if let Some(end) = target.find(']') {
+            return &target[1..end];
+        }
+    }
+    match target.rfind(':') {
+        Some(pos) if target.find(':') == Some(pos) => &target[..pos],
+        _ => target,
+    }
+}
+
+fn is_ip_match(pac: &PACConfig, ip: &IpAddr) -> bool {
+    if pac.cidrs.iter().any(|c| c.contains(ip)) {
+        return true;
+    }
+    match (pac.geoip.as_ref(), geoip_country(ip)) {
+        (Some(countries), Some(country)) => countries
+            .iter()
+            .any(|c| c.as_bytes().eq_ignore_ascii_case(&country[..])),
+        _ => false,
+    }
+}
+
+// The route of the first pac rule matching target in any of its conditions, skipping the
+// channels without sessions. None if no rule matches.
+pub(crate) async fn route(cfg: &TunnelConfig, target: &str) -> Option<Route> {
+    let host = target_host(target).to_ascii_lowercase();
+    let mut ip = host.parse::<IpAddr>().ok();
+    let mut resolved = ip.is_some();
+    for pac in cfg.pac.iter() {
+        let mut matched = pac.is_match(target) || pac.is_domain_match(host.as_str());
+        if !matched && pac.has_ip_conditions() {
+            if !resolved && pac.resolve.unwrap_or(false) {
+                resolved = true;
+                ip = match tokio::net::lookup_host(target).await {
+                    Ok(mut addrs) => addrs.next().map(|a| a.ip()),
+                    Err(e) => {
+                        debug!("failed to resolve {} for routing:{}", target, e);
+                        None
+                    }
+                };
+            }
+            matched = ip.as_ref().map_or(false, |ip| is_ip_match(pac, ip));
+        }
+        if !matched {
+            continue;
+        }
+        match pac.channel.as_str() {
+            "direct" => return Some(Route::Direct),
+            "reject" => return Some(Route::Reject),
+            channel if get_channel_session_size(channel) == 0 => continue,
+            channel => return Some(Route::Channel(String::from(channel))),
+        }
+    }
+    None
+}
+
+// targets of an association whose routes are kept, it's reset past it
+const MAX_ROUTED_TARGETS: usize = 256;
+
+// Routes the datagrams of a UDP association, which all go the way of its first one. Later
+// targets the pac rejects or routes another way are refused, with the verdict kept per target.
+pub(crate) struct DatagramRouter {
+    route: Route,
+    verdicts: HashMap<String, bool>,
+}
+
+impl DatagramRouter {
+    // None if the first target is rejected or no rule routes it
+    pub(crate) async fn new(cfg: &TunnelConfig, first_target: &str) -> Option<Self> {
+        let route = match route(cfg, first_target).await {
+            Some(Route::Reject) | None => return None,
+            Some(r) => r,
+        };
+        let mut verdicts = HashMap::new();
+        verdicts.insert(String::from(first_target), true);
+        Some(Self { route, verdicts })
+    }
+
+    pub(crate) fn route(&self) -> &Route {
+        &self.route
+    }
+
+    pub(crate) async fn allows(&mut self, cfg: &TunnelConfig, target: &str) -> bool {
+        if let Some(v) = self.verdicts.get(target) {
+            return *v;
+        }
+        let v = route(cfg, target).await.as_ref() == Some(&self.route);
+        if !v {
+            debug!(
+                "udp datagrams to {} not routed with the association",
+                target
+            );
+        }
+        if self.verdicts.len() >= MAX_ROUTED_TARGETS {
+            self.verdicts.clear();
+        }
+        self.verdicts.insert(String::from(target), v);
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(channel: &str, init: impl FnOnce(&mut PACConfig)) -> PACConfig {
+        let mut pac: PACConfig =
+            toml::from_str(format!("channel = \"{}\"", channel).as_str()).unwrap();
+        init(&mut pac);
+        pac.init();
+        pac
+    }
+
+    #[tokio::test]
+    async fn test_route() {
+        let mut cfg: TunnelConfig = toml::from_str("listen = \"127.0.0.1:0\"\npac = []").unwrap();
+        cfg.pac = vec![
+            rule("reject", |p| {
+                p.domain_suffix = Some(vec![String::from(".Ads.example.com")])
+            }),
+            rule("direct", |p| {
+                p.ip_cidr = Some(vec![String::from("10.0.0.0/8"), String::from("fd00::/8")])
+            }),
+            rule("direct", |p| p.geoip = Some(vec![String::from("cn")])),
+            rule("test_route_no_sessions", |p| p.host = String::from(".*")),
+            rule("reject", |p| p.host = String::from(":25$")),
+        ];
+        *GEOIP.write().unwrap() = Some(Arc::new(parse_geoip(
+            "# network,country\n1.0.1.0/24,CN\n8.8.8.0/24,US\nbad line\n",
+        )));
+
+        assert_eq!(
+            route(&cfg, "ads.example.com:443").await,
+            Some(Route::Reject)
+        );
+        assert_eq!(
+            route(&cfg, "x.ADS.example.com:80").await,
+            Some(Route::Reject)
+        );
+        assert_eq!(route(&cfg, "10.1.2.3:80").await, Some(Route::Direct));
+        assert_eq!(route(&cfg, "[fd12::1]:443").await, Some(Route::Direct));
+        assert_eq!(route(&cfg, "1.0.1.9:443").await, Some(Route::Direct));
+        // no sessions on the channel, the next rule decides
+        assert_eq!(route(&cfg, "8.8.8.8:25").await, Some(Route::Reject));
+        assert_eq!(route(&cfg, "badads.example.com:443").await, None);
+        assert_eq!(route(&cfg, "11.0.0.1:443").await, None);
+
+        // an association going direct refuses later targets rejected or routed elsewhere
+        let mut router = DatagramRouter::new(&cfg, "1.0.1.9:53").await.unwrap();
+        assert_eq!(router.route(), &Route::Direct);
+        assert!(router.allows(&cfg, "1.0.1.9:53").await);
+        assert!(!router.allows(&cfg, "ads.example.com:53").await);
+        assert!(router.allows(&cfg, "10.1.2.3:53").await);
+        assert!(!router.allows(&cfg, "8.8.8.8:25").await);
+        assert!(!router.allows(&cfg, "11.0.0.1:53").await);
+        assert!(DatagramRouter::new(&cfg, "ads.example.com:53")
+            .await
+            .is_none());
+    }
+}
@@ -1,12 +1,24 @@
-use super::relay::relay_connection;
+use super::relay::{connect_target, relay_channel_stream};
+use super::route::{DatagramRouter, Route};
+use crate::rmux::{
+    create_stream, get_udp_idle_timeout, read_udp_datagram, run_association, touch,
+    write_udp_datagram, FinReason, UdpDatagram, UdpNat,
+};
 use crate::utils::make_error;
 
 use crate::config::TunnelConfig;
+use futures::future::{select, Either};
+use futures::pin_mut;
 use std::error::Error;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::sync::Mutex;
+use std::time::Instant;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::net::{TcpStream, UdpSocket};
 
 mod v5 {
     pub const VERSION: u8 = 5;
@@ -24,6 +36,24 @@ mod v5 {
     pub const ATYP_DOMAIN: u8 = 3;
 
     pub const SOCKS_RESP_SUUCESS: u8 = 0;
+    pub const SOCKS_RESP_GENERAL_FAILURE: u8 = 1;
+    pub const SOCKS_RESP_NOT_ALLOWED: u8 = 2;
+    pub const SOCKS_RESP_NETWORK_UNREACHABLE: u8 = 3;
+    pub const SOCKS_RESP_HOST_UNREACHABLE: u8 = 4;
+    pub const SOCKS_RESP_CONNECTION_REFUSED: u8 = 5;
+    pub const SOCKS_RESP_TTL_EXPIRED: u8 = 6;
+}
+
+// REP for a target that couldn't be connected
+fn reply_code(reason: FinReason) -> u8 {
+    match reason {
+        FinReason::PolicyDenied => v5::SOCKS_RESP_NOT_ALLOWED,
+        FinReason::Unreachable => v5::SOCKS_RESP_NETWORK_UNREACHABLE,
+        FinReason::DnsFailure => v5::SOCKS_RESP_HOST_UNREACHABLE,
+        FinReason::ConnectRefused => v5::SOCKS_RESP_CONNECTION_REFUSED,
+        FinReason::ConnectTimeout => v5::SOCKS_RESP_TTL_EXPIRED,
+        _ => v5::SOCKS_RESP_GENERAL_FAILURE,
+    }
 }
 
 // Extracts the name and port from addr_buf and returns them, converting
@@ -65,7 +95,7 @@ pub async fn handle_socks5(
     if head[0] != v5::VERSION {
         return Err(make_error("didn't confirm with v5 version"));
     }
-    if head[1] != v5::CMD_CONNECT {
+    if head[1] != v5::CMD_CONNECT && head[1] != v5::CMD_UDP_ASSOCIATE {
         return Err(make_error("unsupported command"));
     }
     let target_addr = match head[3] {
@@ -109,20 +139,30 @@ pub async fn handle_socks5(
             return Err(make_error(msg.as_str()));
         }
     };
+    if head[1] == v5::CMD_UDP_ASSOCIATE {
+        return handle_udp_associate(tunnel_id, inbound, cfg).await;
+    }
+    let remote = connect_target(cfg, target_addr.as_str()).await;
     let mut resp = [0u8; 10];
     // VER - protocol version
     resp[0] = 5;
     // REP - "reply field" -- what happened with the actual connect.
-    //
-    // In theory this should reply back with a bunch more kinds of
-    // errors if possible, but for now we just recognize a few concrete
-    // errors.
-    resp[1] = v5::SOCKS_RESP_SUUCESS;
+    resp[1] = match &remote {
+        Ok(_) => v5::SOCKS_RESP_SUUCESS,
+        Err(reason) => reply_code(*reason),
+    };
 
     // RSV - reserved
     resp[2] = 0;
     resp[3] = 1; // socksAtypeV4         = 0x01
     inbound.write_all(&resp).await?;
+    let remote = match remote {
+        Ok(r) => r,
+        Err(reason) => {
+            let msg = format!("failed to connect {}: {:?}", target_addr, reason);
+            return Err(make_error(msg.as_str()));
+        }
+    };
 
     info!(
         "[{}]Handle SOCKS5 proxy to {} with local:{} remote:{}",
@@ -131,6 +171,226 @@ pub async fn handle_socks5(
         inbound.local_addr().unwrap(),
         inbound.peer_addr().unwrap()
     );
-    relay_connection(tunnel_id, inbound, cfg, target_addr, Vec::new()).await?;
+    {
+        let (mut ri, mut wi) = inbound.split();
+        relay_channel_stream(
+            tunnel_id,
+            &mut ri,
+            &mut wi,
+            remote,
+            target_addr.as_str(),
+            Vec::new(),
+        )
+        .await?;
+    }
+    let _ = inbound.shutdown(Shutdown::Both);
+    Ok(())
+}
+
+// ATYP, address & port as in requests, replies and the header of UDP datagrams
+fn encode_addr(addr: &SocketAddr) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(19);
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(v5::ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(v5::ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+    buf
+}
+
+// the target and payload of a datagram from the client, None for fragments & malformed ones
+fn parse_udp_request(buf: &[u8]) -> Option<(String, &[u8])> {
+    // RSV(2) FRAG(1) ATYP(1)
+    if buf.len() < 4 || buf[2] != 0 {
+        return None;
+    }
+    let (target, n) = match buf[3] {
+        v5::ATYP_IPV4 if buf.len() >= 10 => {
+            let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+            let port = u16::from_be_bytes([buf[8], buf[9]]);
+            (SocketAddr::new(IpAddr::V4(ip), port).to_string(), 10)
+        }
+        v5::ATYP_IPV6 if buf.len() >= 22 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&buf[4..20]);
+            let port = u16::from_be_bytes([buf[20], buf[21]]);
+            (
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port).to_string(),
+                22,
+            )
+        }
+        v5::ATYP_DOMAIN if buf.len() > 4 && buf.len() >= 7 + buf[4] as usize => {
+            let n = 5 + buf[4] as usize + 2;
+            (name_port(&buf[5..n])?, n)
+        }
+        _ => return None,
+    };
+    Some((target, &buf[n..]))
+}
+
+// Datagrams of the association, from the client to its targets. Only the client's host may
+// send them; the port it sends from last gets the replies.
+async fn recv_local(
+    recv: &mut RecvHalf,
+    buf: &mut [u8],
+    client_ip: IpAddr,
+    client: &Mutex<Option<SocketAddr>>,
+) -> io::Result<UdpDatagram> {
+    loop {
+        let (n, from) = recv.recv_from(buf).await?;
+        if from.ip() != client_ip {
+            continue;
+        }
+        if let Some((addr, data)) = parse_udp_request(&buf[..n]) {
+            *client.lock().unwrap() = Some(from);
+            return Ok(UdpDatagram {
+                addr,
+                data: data.to_vec(),
+            });
+        }
+    }
+}
+
+async fn send_local(
+    send: &mut SendHalf,
+    client: &Mutex<Option<SocketAddr>>,
+    reply: UdpDatagram,
+) -> io::Result<()> {
+    let (to, from) = match (*client.lock().unwrap(), reply.addr.parse::<SocketAddr>()) {
+        (Some(to), Ok(from)) => (to, from),
+        _ => return Ok(()),
+    };
+    let mut datagram = vec![0u8, 0, 0];
+    datagram.extend_from_slice(&encode_addr(&from)[..]);
+    datagram.extend_from_slice(&reply.data[..]);
+    send.send_to(&datagram[..], &to).await?;
+    Ok(())
+}
+
+// whichever of the two ends first
+async fn either<A, B>(a: A, b: B) -> io::Result<()>
+where
+    A: Future<Output = io::Result<()>>,
+    B: Future<Output = io::Result<()>>,
+{
+    pin_mut!(a, b);
+    match select(a, b).await {
+        Either::Left((r, _)) | Either::Right((r, _)) => r,
+    }
+}
+
+// The association relays the client's datagrams over the channel the pac picks for the
+// target of the first one, a "udp" mux stream or a local NAT for "direct". Datagrams to
+// targets the pac rejects or routes elsewhere are dropped. It ends with the control
+// connection or once idle.
+async fn handle_udp_associate(
+    tunnel_id: u32,
+    mut inbound: TcpStream,
+    cfg: &TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let client_ip = inbound.peer_addr()?.ip();
+    let socket = UdpSocket::bind(SocketAddr::new(inbound.local_addr()?.ip(), 0)).await?;
+    let bound = socket.local_addr()?;
+    let mut resp = vec![v5::VERSION, v5::SOCKS_RESP_SUUCESS, 0];
+    resp.extend_from_slice(&encode_addr(&bound)[..]);
+    inbound.write_all(&resp[..]).await?;
+    info!(
+        "[{}]Handle SOCKS5 UDP associate with local:{} remote:{}",
+        tunnel_id,
+        bound,
+        inbound.peer_addr().unwrap()
+    );
+
+    let (mut recv, mut send) = socket.split();
+    let client = Mutex::new(None);
+    let mut buf = vec![0u8; 64 * 1024];
+    let (mut control, _) = inbound.split();
+    let mut closed = [0u8; 1];
+    let first = {
+        let datagram = recv_local(&mut recv, &mut buf[..], client_ip, &client);
+        let control_closed = control.read(&mut closed);
+        pin_mut!(datagram, control_closed);
+        match select(datagram, control_closed).await {
+            Either::Left((r, _)) => r?,
+            Either::Right(_) => return Ok(()),
+        }
+    };
+    let mut router = match DatagramRouter::new(cfg, first.addr.as_str()).await {
+        Some(r) => r,
+        None => {
+            return Err(make_error(
+                "no valid channel found or target rejected by pac.",
+            ))
+        }
+    };
+    let channel = match router.route() {
+        Route::Channel(c) => c.clone(),
+        _ => String::from("direct"),
+    };
+    let idle = get_udp_idle_timeout(channel.as_str());
+    let last_active = Mutex::new(Instant::now());
+    let control_closed = async {
+        let _ = control.read(&mut closed).await;
+        Ok::<(), io::Error>(())
+    };
+    if channel == "direct" {
+        let (mut nat, mut replies) = UdpNat::new();
+        let uplink = async {
+            let mut next = Some(first);
+            while let Some(datagram) = next.take() {
+                touch(&last_active);
+                if router.allows(cfg, datagram.addr.as_str()).await {
+                    if let Err(e) = nat.send(&datagram).await {
+                        debug!("[{}]udp send failed:{}", tunnel_id, e);
+                    }
+                }
+                next = Some(recv_local(&mut recv, &mut buf[..], client_ip, &client).await?);
+            }
+            Ok::<(), io::Error>(())
+        };
+        let downlink = async {
+            while let Some(reply) = replies.recv().await {
+                touch(&last_active);
+                send_local(&mut send, &client, reply).await?;
+            }
+            Ok::<(), io::Error>(())
+        };
+        let uplink = either(uplink, control_closed);
+        run_association(uplink, downlink, &last_active, idle).await?;
+        return Ok(());
+    }
+    let mut stream = create_stream(channel.as_str(), "udp", "").await?;
+    let result = {
+        let (mut ro, mut wo) = stream.split();
+        let uplink = async {
+            let mut next = Some(first);
+            while let Some(datagram) = next.take() {
+                touch(&last_active);
+                if router.allows(cfg, datagram.addr.as_str()).await {
+                    write_udp_datagram(&mut wo, &datagram).await?;
+                }
+                next = Some(recv_local(&mut recv, &mut buf[..], client_ip, &client).await?);
+            }
+            Ok::<(), io::Error>(())
+        };
+        let downlink = async {
+            while let Some(reply) = read_udp_datagram(&mut ro).await? {
+                touch(&last_active);
+                send_local(&mut send, &client, reply).await?;
+            }
+            Ok::<(), io::Error>(())
+        };
+        let uplink = either(uplink, control_closed);
+        run_association(uplink, downlink, &last_active, idle).await
+    };
+    let _ = stream.close();
+    info!("[{}]SOCKS5 UDP associate closed", tunnel_id);
+    result?;
     Ok(())
 }
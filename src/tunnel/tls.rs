@@ -8,6 +8,7 @@ use tokio::io::AsyncReadExt;
 use tokio::net::TcpStream;
 
 use crate::config::TunnelConfig;
+use crate::rmux::redact_addr;
 
 pub fn valid_tls_version(buf: &[u8]) -> bool {
     if buf.len() < 3 {
@@ -126,7 +127,11 @@ pub async fn handle_tls(
     let mut target = sni;
     target.push_str(":443");
 
-    info!("[{}]Handle TLS proxy to {}", tunnel_id, target);
+    info!(
+        "[{}]Handle TLS proxy to {}",
+        tunnel_id,
+        redact_addr(target.as_str())
+    );
     relay_connection(tunnel_id, inbound, cfg, target, peek_buf).await?;
     Ok(())
 }
@@ -1,21 +1,142 @@
+use super::rmux::handle_rmux_io;
 use crate::config::TunnelConfig;
-use crate::rmux::{
-    new_auth_event, process_rmux_session, read_encrypt_event, AuthRequest, AuthResponse,
-    CryptoContext, MuxContext,
-};
 use crate::utils::{make_io_error, WebsocketReader, WebsocketWriter};
-use bytes::BytesMut;
 use futures::StreamExt;
 use std::error::Error;
+use std::time::Duration;
 
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
+const MAX_REQUEST_HEAD_LEN: usize = 8 * 1024;
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DEFAULT_PAGE: &str = "<!DOCTYPE html>\n<html>\n<head><title>Welcome</title></head>\n<body>\n<h1>Welcome</h1>\n<p>The site is under construction.</p>\n</body>\n</html>\n";
+
+#[derive(Debug, PartialEq)]
+enum RequestAction {
+    Upgrade,
+    // answer as a plain web server with this status, then close
+    Respond(u16, bool),
+}
+
+// Only a websocket GET to ws_path(any path if unset) is upgraded, everything else looks
+// like a small static site.
+fn route_request(head: &[u8], ws_path: Option<&str>) -> RequestAction {
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        _ => return RequestAction::Respond(400, false),
+    }
+    let method = req.method.unwrap_or("");
+    let path = req.path.unwrap_or("/");
+    let path = path.split('?').next().unwrap_or("/");
+    let head_only = method == "HEAD";
+    if method != "GET" && !head_only {
+        return RequestAction::Respond(405, false);
+    }
+    let upgrade = req.headers.iter().any(|h| {
+        h.name.eq_ignore_ascii_case("upgrade")
+            && String::from_utf8_lossy(h.value)
+                .to_ascii_lowercase()
+                .contains("websocket")
+    });
+    if upgrade && !head_only && ws_path.map_or(true, |p| p == path) {
+        return RequestAction::Upgrade;
+    }
+    match path {
+        "/" | "/index.html" => RequestAction::Respond(200, head_only),
+        _ => RequestAction::Respond(404, head_only),
+    }
+}
+
+fn error_page(status: u16, reason: &str) -> String {
+    format!(
+        "<html>\r\n<head><title>{0} {1}</title></head>\r\n<body>\r\n<center><h1>{0} {1}</h1></center>\r\n<hr><center>nginx</center>\r\n</body>\r\n</html>\r\n",
+        status, reason
+    )
+}
+
+async fn respond(
+    inbound: &mut TcpStream,
+    status: u16,
+    head_only: bool,
+    cfg: &TunnelConfig,
+) -> Result<(), std::io::Error> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        _ => "Not Allowed",
+    };
+    let body = if status != 200 {
+        error_page(status, reason)
+    } else if let Some(page) = cfg.ws_site_page.as_deref() {
+        match tokio::fs::read_to_string(page).await {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("failed to read ws_site_page:{} with error:{}", page, e);
+                String::from(DEFAULT_PAGE)
+            }
+        }
+    } else {
+        String::from(DEFAULT_PAGE)
+    };
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nServer: nginx\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        body.len()
+    );
+    if !head_only {
+        response.push_str(body.as_str());
+    }
+    inbound.write_all(response.as_bytes()).await?;
+    inbound.shutdown(std::net::Shutdown::Write)
+}
+
+// the request head, still left in the socket for the websocket handshake
+async fn peek_request_head(inbound: &mut TcpStream) -> Result<Vec<u8>, std::io::Error> {
+    let mut buf = vec![0u8; MAX_REQUEST_HEAD_LEN];
+    loop {
+        let n = inbound.peek(&mut buf).await?;
+        if n == 0 {
+            return Err(make_io_error("closed before the request head"));
+        }
+        if let Some(pos) = twoway::find_bytes(&buf[..n], b"\r\n\r\n") {
+            buf.truncate(pos + 4);
+            return Ok(buf);
+        }
+        if n == buf.len() {
+            return Ok(buf);
+        }
+        // peek returns what's there already, give the rest of the head time to come
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+}
+
 pub async fn handle_websocket(
     tunnel_id: u32,
-    inbound: TcpStream,
+    mut inbound: TcpStream,
     cfg: TunnelConfig,
 ) -> Result<(), std::io::Error> {
+    let head =
+        match tokio::time::timeout(REQUEST_HEAD_TIMEOUT, peek_request_head(&mut inbound)).await {
+            Ok(r) => r?,
+            Err(_) => return Err(make_io_error("timeout reading the request head")),
+        };
+    if let RequestAction::Respond(status, head_only) =
+        route_request(&head[..], cfg.ws_path.as_deref())
+    {
+        info!(
+            "[{}]Answer non websocket request with {}",
+            tunnel_id, status
+        );
+        let mut discard = vec![0u8; head.len()];
+        inbound.read_exact(&mut discard[..]).await?;
+        return respond(&mut inbound, status, head_only, &cfg).await;
+    }
     let ws_stream = match tokio_tungstenite::accept_async(inbound).await {
         Ok(s) => s,
         Err(e) => {
@@ -25,57 +146,41 @@ pub async fn handle_websocket(
     let (write, read) = ws_stream.split();
     let mut reader = WebsocketReader::new(read);
     let mut writer = WebsocketWriter::new(write);
-    let key = String::from(cfg.cipher.as_ref().unwrap().key.as_str());
-    let method = String::from(cfg.cipher.as_ref().unwrap().method.as_str());
-    let mut rctx = CryptoContext::new(method.as_str(), key.as_str(), 0);
-    let mut wctx = CryptoContext::new(method.as_str(), key.as_str(), 0);
-    //1. auth connection
-    let mut recv_buf = BytesMut::new();
-    let recv_ev = match read_encrypt_event(&mut rctx, &mut reader, &mut recv_buf).await {
-        Err(e) => return Err(make_io_error(e.description())),
-        Ok(Some(ev)) => ev,
-        Ok(None) => {
-            return Err(make_io_error("can NOT read first auth envent."));
-        }
-    };
-    let auth_req: AuthRequest = match bincode::deserialize(&recv_ev.body[..]) {
-        Ok(m) => m,
-        Err(err) => {
-            error!(
-                "Failed to parse AuthRequest with error:{} while data len:{} {}",
-                err,
-                recv_ev.body.len(),
-                recv_ev.header.len(),
-            );
-            return Err(make_io_error("Failed to parse AuthRequest"));
-        }
-    };
-    //let mut rng = rand::thread_rng();
-    let auth_res = AuthResponse {
-        success: true,
-        err: String::new(),
-        rand: rand::random::<u64>(),
-        //rand: 1,
-        method: auth_req.method,
-    };
-    let mut res = new_auth_event(0, &auth_res);
-    let mut buf = BytesMut::new();
-    wctx.encrypt(&mut res, &mut buf);
-    writer.write_all(&buf[..]).await?;
-    let rctx = CryptoContext::new(auth_res.method.as_str(), key.as_str(), auth_res.rand);
-    let wctx = CryptoContext::new(auth_res.method.as_str(), key.as_str(), auth_res.rand);
-    let ctx = MuxContext::new("", tunnel_id, rctx, wctx, 0, &mut recv_buf);
-    process_rmux_session(
-        ctx,
-        // "",
-        // tunnel_id,
-        &mut reader,
-        &mut writer,
-        // rctx,
-        // wctx,
-        // &mut recv_buf,
-        // 0,
-    )
-    .await?;
-    Ok(())
+    handle_rmux_io(tunnel_id, &mut reader, &mut writer, &cfg).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_request() {
+        let upgrade = b"GET /updates?v=2 HTTP/1.1\r\nHost: example.com\r\nUpgrade: WebSocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        assert_eq!(route_request(upgrade, None), RequestAction::Upgrade);
+        assert_eq!(
+            route_request(upgrade, Some("/updates")),
+            RequestAction::Upgrade
+        );
+        assert_eq!(
+            route_request(upgrade, Some("/other")),
+            RequestAction::Respond(404, false)
+        );
+
+        let page = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert_eq!(
+            route_request(page, Some("/updates")),
+            RequestAction::Respond(200, false)
+        );
+        let head = b"HEAD /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert_eq!(route_request(head, None), RequestAction::Respond(200, true));
+        let post = b"POST /updates HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert_eq!(
+            route_request(post, None),
+            RequestAction::Respond(405, false)
+        );
+        assert_eq!(
+            route_request(b"\x16\x03\x01\x02\x00\r\n\r\n", None),
+            RequestAction::Respond(400, false)
+        );
+    }
 }
@@ -36,6 +36,10 @@ impl VBuf {
     pub fn vlen(&self) -> usize {
         self.inner.len()
     }
+    // push refuses more data once full
+    pub fn is_full(&self) -> bool {
+        self.inner.len() >= MAX_VEC_BUF
+    }
     pub fn push(&mut self, data: Vec<u8>) -> bool {
         if self.inner.len() >= MAX_VEC_BUF || data.is_empty() {
             return false;
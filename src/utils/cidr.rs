@@ -0,0 +1,57 @@
+use std::net::IpAddr;
+
+// An IPv4 or IPv6 network like 10.0.0.0/8, a bare address is a network of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+// the address as a number and the width of its family
+fn ip_bits(ip: &IpAddr) -> (u128, u32) {
+    match ip {
+        IpAddr::V4(a) => (u128::from(u32::from(*a)), 32),
+        IpAddr::V6(a) => (u128::from(*a), 128),
+    }
+}
+
+impl IpCidr {
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr: IpAddr = parts.next()?.parse().ok()?;
+        let (_, width) = ip_bits(&addr);
+        let prefix = match parts.next() {
+            Some(p) => p.parse().ok()?,
+            None => width,
+        };
+        if prefix > width {
+            return None;
+        }
+        Some(Self { addr, prefix })
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        self.addr.is_ipv4()
+    }
+
+    // the first and last address, as numbers like u32::from(Ipv4Addr) or u128::from(Ipv6Addr)
+    pub fn range(&self) -> (u128, u128) {
+        let (n, width) = ip_bits(&self.addr);
+        let host_bits = width - self.prefix;
+        let host_mask = if host_bits >= 128 {
+            u128::max_value()
+        } else {
+            (1u128 << host_bits) - 1
+        };
+        (n & !host_mask, n | host_mask)
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        if self.addr.is_ipv4() != ip.is_ipv4() {
+            return false;
+        }
+        let (n, _) = ip_bits(ip);
+        let (first, last) = self.range();
+        n >= first && n <= last
+    }
+}
@@ -24,6 +24,8 @@ pub struct BufCopy<'a, R: ?Sized, W: ?Sized> {
     cap: usize,
     amt: u64,
     buf: Box<[u8]>,
+    // told the len of every chunk written
+    on_copied: Option<&'a (dyn Fn(usize) + Send + Sync)>,
 }
 
 pub fn buf_copy<'a, R, W>(reader: &'a mut R, writer: &'a mut W, buf: Box<[u8]>) -> BufCopy<'a, R, W>
@@ -39,9 +41,26 @@ where
         pos: 0,
         cap: 0,
         buf,
+        on_copied: None,
     }
 }
 
+// buf_copy calling on_copied after each write, it runs in the copy's poll so should be quick
+pub fn buf_copy_observed<'a, R, W>(
+    reader: &'a mut R,
+    writer: &'a mut W,
+    buf: Box<[u8]>,
+    on_copied: &'a (dyn Fn(usize) + Send + Sync),
+) -> BufCopy<'a, R, W>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut copy = buf_copy(reader, writer, buf);
+    copy.on_copied = Some(on_copied);
+    copy
+}
+
 impl<R, W> Future for BufCopy<'_, R, W>
 where
     R: AsyncRead + Unpin + ?Sized,
@@ -76,6 +95,9 @@ where
                 } else {
                     self.pos += i;
                     self.amt += i as u64;
+                    if let Some(f) = self.on_copied {
+                        f(i);
+                    }
                 }
             }
 
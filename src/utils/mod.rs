@@ -1,12 +1,23 @@
 mod buf;
+mod cidr;
 mod io;
 mod net;
 mod net2;
+#[cfg(feature = "quic")]
+mod quic;
+mod tls;
 mod ws;
 
 pub use self::buf::{fill_read_buf, VBuf};
+pub use self::cidr::IpCidr;
 pub use self::io::make_error;
-pub use self::io::{buf_copy, make_io_error, read_until_separator};
-pub use self::net::{get_origin_dst, http_proxy_connect, AsyncTcpStream};
+pub use self::io::{buf_copy, buf_copy_observed, make_io_error, read_until_separator};
+pub use self::net::{
+    get_origin_dst, http_connect_proxy_connect, http_proxy_connect, socks5_proxy_connect,
+    AsyncTcpStream,
+};
 pub use self::net2::AsyncTokioIO;
+#[cfg(feature = "quic")]
+pub use self::quic::{quic_connect, quic_listen, QuicConnection};
+pub use self::tls::{tls_acceptor, tls_client_config, tls_connect};
 pub use self::ws::{WebsocketReader, WebsocketWriter};
@@ -1,11 +1,11 @@
-use super::io::read_until_separator;
+use super::io::{make_io_error, read_until_separator};
 
 use httparse::Status;
 use std::net::SocketAddr;
 
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use url::Url;
 
@@ -72,6 +72,186 @@ pub async fn http_proxy_connect(proxy: &Url, remote: &str) -> Result<TcpStream,
     Err(std::io::Error::from(std::io::ErrorKind::ConnectionAborted))
 }
 
+// CONNECT to remote through a SOCKS5 proxy, (username, password) selects the RFC 1929 auth
+pub async fn socks5_proxy_connect(
+    proxy: &str,
+    remote: &str,
+    auth: Option<(&str, &str)>,
+) -> Result<TcpStream, std::io::Error> {
+    let conn = TcpStream::connect(proxy);
+    let dur = std::time::Duration::from_secs(3);
+    let mut socket = tokio::time::timeout(dur, conn).await??;
+
+    let method = if auth.is_some() { 2u8 } else { 0u8 };
+    socket.write_all(&[5, 1, method]).await?;
+    let mut reply = [0u8; 2];
+    socket.read_exact(&mut reply).await?;
+    if reply[0] != 5 || reply[1] != method {
+        return Err(make_io_error("socks5 proxy rejected the auth method"));
+    }
+    if let Some((user, pass)) = auth {
+        if user.len() > 255 || pass.len() > 255 {
+            return Err(make_io_error("socks5 username or password too long"));
+        }
+        let mut req = vec![1u8, user.len() as u8];
+        req.extend_from_slice(user.as_bytes());
+        req.push(pass.len() as u8);
+        req.extend_from_slice(pass.as_bytes());
+        socket.write_all(&req[..]).await?;
+        socket.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        }
+    }
+
+    let mut req = vec![5u8, 1, 0];
+    match remote.parse::<SocketAddr>() {
+        Ok(SocketAddr::V4(a)) => {
+            req.push(1);
+            req.extend_from_slice(&a.ip().octets());
+            req.extend_from_slice(&a.port().to_be_bytes());
+        }
+        Ok(SocketAddr::V6(a)) => {
+            req.push(4);
+            req.extend_from_slice(&a.ip().octets());
+            req.extend_from_slice(&a.port().to_be_bytes());
+        }
+        Err(_) => {
+            let (host, port) = match remote.rfind(':') {
+                Some(pos) => (&remote[..pos], &remote[pos + 1..]),
+                None => return Err(make_io_error("invalid socks5 target addr")),
+            };
+            let port: u16 = match port.parse() {
+                Ok(p) => p,
+                Err(_) => return Err(make_io_error("invalid socks5 target port")),
+            };
+            if host.is_empty() || host.len() > 255 {
+                return Err(make_io_error("invalid socks5 target host"));
+            }
+            req.push(3);
+            req.push(host.len() as u8);
+            req.extend_from_slice(host.as_bytes());
+            req.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+    socket.write_all(&req[..]).await?;
+    let mut head = [0u8; 4];
+    socket.read_exact(&mut head).await?;
+    if head[0] != 5 || head[1] != 0 {
+        error!(
+            "socks5 proxy {} failed to connect {} with reply:{}",
+            proxy, remote, head[1]
+        );
+        return Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+    }
+    // skip the bound addr
+    let addr_len = match head[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8; 1];
+            socket.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        _ => return Err(make_io_error("invalid socks5 reply addr type")),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    socket.read_exact(&mut bound).await?;
+    Ok(socket)
+}
+
+// the response head of a CONNECT, read byte by byte so nothing the target sends right after
+// it is consumed
+const MAX_CONNECT_RESPONSE_LEN: usize = 8192;
+
+fn base64_encode(data: &[u8]) -> String {
+    const TABLE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(TABLE[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// the error a CONNECT answered with status maps to, denials read as PermissionDenied
+fn http_connect_error(status: u16) -> std::io::Error {
+    let (kind, desc) = match status {
+        407 => (
+            std::io::ErrorKind::PermissionDenied,
+            "http proxy requires authentication",
+        ),
+        403 => (
+            std::io::ErrorKind::PermissionDenied,
+            "http proxy forbids the target",
+        ),
+        502 | 503 | 504 => (
+            std::io::ErrorKind::ConnectionRefused,
+            "http proxy failed to reach the target",
+        ),
+        _ => (
+            std::io::ErrorKind::ConnectionAborted,
+            "http proxy refused the CONNECT",
+        ),
+    };
+    std::io::Error::new(kind, format!("{}, status {}", desc, status))
+}
+
+// CONNECT to remote through an HTTP proxy, (username, password) is sent as basic
+// Proxy-Authorization
+pub async fn http_connect_proxy_connect(
+    proxy: &str,
+    remote: &str,
+    auth: Option<(&str, &str)>,
+) -> Result<TcpStream, std::io::Error> {
+    let conn = TcpStream::connect(proxy);
+    let dur = std::time::Duration::from_secs(3);
+    let mut socket = tokio::time::timeout(dur, conn).await??;
+
+    let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", remote, remote);
+    if let Some((user, pass)) = auth {
+        let credentials = base64_encode(format!("{}:{}", user, pass).as_bytes());
+        req.push_str(format!("Proxy-Authorization: Basic {}\r\n", credentials).as_str());
+    }
+    req.push_str("\r\n");
+    socket.write_all(req.as_bytes()).await?;
+
+    let mut head = Vec::with_capacity(256);
+    let mut b = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_CONNECT_RESPONSE_LEN {
+            return Err(make_io_error("http proxy response too large"));
+        }
+        socket.read_exact(&mut b).await?;
+        head.push(b[0]);
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut res = httparse::Response::new(&mut headers);
+    let status = match res.parse(&head[..]) {
+        Ok(Status::Complete(_)) => res.code.unwrap_or(0),
+        _ => return Err(make_io_error("invalid http proxy response")),
+    };
+    if status / 100 != 2 {
+        error!(
+            "http proxy {} failed to connect {} with status:{}",
+            proxy, remote, status
+        );
+        return Err(http_connect_error(status));
+    }
+    Ok(socket)
+}
+
 pub struct AsyncTcpStream {
     s: TcpStream,
 }
@@ -121,3 +301,50 @@ impl futures::AsyncWrite for AsyncTcpStream {
         s.poll_shutdown(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_base64_encode() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"user:pass"), "dXNlcjpwYXNz");
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_proxy_connect() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            for answer in [
+                &b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n"[..],
+                b"HTTP/1.1 200 Connection established\r\n\r\nbanner",
+            ]
+            .iter()
+            {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut req = vec![0u8; 1024];
+                let n = conn.read(&mut req).await.unwrap();
+                let req = String::from_utf8_lossy(&req[..n]).to_string();
+                assert!(req.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+                conn.write_all(answer).await.unwrap();
+            }
+        });
+        let err = http_connect_proxy_connect(&proxy, "example.com:443", None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        let mut conn = http_connect_proxy_connect(&proxy, "example.com:443", Some(("u", "p")))
+            .await
+            .unwrap();
+        // what the target sent along with the response is left for the relay
+        let mut banner = [0u8; 6];
+        conn.read_exact(&mut banner).await.unwrap();
+        assert_eq!(&banner, b"banner");
+    }
+}
@@ -0,0 +1,96 @@
+use super::io::make_io_error;
+use quinn::{
+    Certificate, CertificateChain, ClientConfigBuilder, Connection, Endpoint, Incoming, PrivateKey,
+    RecvStream, SendStream, ServerConfigBuilder,
+};
+use std::io::BufReader;
+use std::net::SocketAddr;
+
+const QUIC_ALPN: &[&[u8]] = &[b"rmux"];
+
+fn quic_error<E: std::fmt::Display>(e: E) -> std::io::Error {
+    make_io_error(e.to_string().as_str())
+}
+
+// the endpoint must outlive the connection, it drives all IO of it
+pub struct QuicConnection {
+    pub endpoint: Endpoint,
+    pub connection: Connection,
+}
+
+impl QuicConnection {
+    pub fn close(&self) {
+        self.connection.close(0u32.into(), b"");
+    }
+}
+
+// connect and open the bidirectional stream which carries the whole mux session
+pub async fn quic_connect(
+    addr: &str,
+    domain: &str,
+    ca: Option<&str>,
+) -> Result<(QuicConnection, SendStream, RecvStream), std::io::Error> {
+    let remote = match tokio::net::lookup_host(addr).await?.next() {
+        Some(a) => a,
+        None => return Err(make_io_error("can NOT resolve quic addr")),
+    };
+    let mut client_cfg = ClientConfigBuilder::default();
+    client_cfg.protocols(QUIC_ALPN);
+    if let Some(path) = ca {
+        let pem = tokio::fs::read(path).await?;
+        let certs = match rustls::internal::pemfile::certs(&mut BufReader::new(&pem[..])) {
+            Ok(c) => c,
+            Err(_) => return Err(make_io_error("invalid ca pem file")),
+        };
+        for c in certs {
+            let cert = Certificate::from_der(&c.0).map_err(quic_error)?;
+            client_cfg
+                .add_certificate_authority(cert)
+                .map_err(quic_error)?;
+        }
+    }
+    let mut builder = Endpoint::builder();
+    builder.default_client_config(client_cfg.build());
+    let local: SocketAddr = if remote.is_ipv6() {
+        "[::]:0".parse().unwrap()
+    } else {
+        "0.0.0.0:0".parse().unwrap()
+    };
+    let (endpoint, _) = builder.bind(&local).map_err(quic_error)?;
+    let conn = endpoint
+        .connect(&remote, domain)
+        .map_err(quic_error)?
+        .await
+        .map_err(quic_error)?;
+    let (send, recv) = conn.connection.open_bi().await.map_err(quic_error)?;
+    Ok((
+        QuicConnection {
+            endpoint,
+            connection: conn.connection,
+        },
+        send,
+        recv,
+    ))
+}
+
+pub async fn quic_listen(
+    addr: &str,
+    cert: &str,
+    key: &str,
+) -> Result<(Endpoint, Incoming), std::io::Error> {
+    let local = match tokio::net::lookup_host(addr).await?.next() {
+        Some(a) => a,
+        None => return Err(make_io_error("can NOT resolve quic listen addr")),
+    };
+    let cert_chain =
+        CertificateChain::from_pem(&tokio::fs::read(cert).await?).map_err(quic_error)?;
+    let key = PrivateKey::from_pem(&tokio::fs::read(key).await?).map_err(quic_error)?;
+    let mut server_cfg = ServerConfigBuilder::default();
+    server_cfg.protocols(QUIC_ALPN);
+    server_cfg
+        .certificate(cert_chain, key)
+        .map_err(quic_error)?;
+    let mut builder = Endpoint::builder();
+    builder.listen(server_cfg.build());
+    builder.bind(&local).map_err(quic_error)
+}